@@ -1,40 +1,117 @@
 use chrono::Utc;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{App, Arg};
 use log::LevelFilter;
-use std::{path::Path, fs};
-use log4rs::{append::{console::ConsoleAppender, file::FileAppender}, Config, config::{Appender, Root}, encode::pattern::PatternEncoder, Handle};
+use std::{ffi::OsString, path::Path, fs};
+use core::logging;
+use log4rs::{append::{console::ConsoleAppender, file::FileAppender}, Config, config::{Appender, Root}, Handle};
 
 // Ref: https://docs.rs/log4rs/latest/log4rs/encode/pattern/index.html
 const CONSOLE_PATTERN: &str = "[{d(%Y-%m-%d %H:%M:%S%.3f)} {h({l:<5})}] {m}{n}";
 const FILE_PATTERN: &str = "[{d(%Y-%m-%d %H:%M:%S%.3f)} {l:<5}] {m}{n}";
 
 pub fn main() -> Result<()> {
+    run(std::env::args_os())
+}
+
+///
+/// Parse the command line arguments and run (or validate) the charter.
+///
+/// The charter and control dir can be given as positional arguments (as steward does) or with --charter and --base-dir.
+///
+fn run<I, T>(args: I) -> Result<()>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone {
 
     let options = App::new("celerity")
         .version("1.0")
         .about("Celerity is a reconciliation engine used to group and match data from CSV files. Leaving only unmatched data behind. Data must be in the correct format and placed in the waiting folder. Results are written to the matched and unmatched folders. Incoming files are recorded in the archive/celerity folder. Refer to the README.md for more details.")
         .arg(Arg::with_name("charter_path")
             .help("The full path to the charter yaml file containing the instructions for matching")
-            .required(true)
+            .required_unless("charter")
+            .conflicts_with("charter")
             .takes_value(true))
         .arg(Arg::with_name("control_dir")
             .help("The base directory where data files will be processed. This should be distinct from any other control's directory")
-            .required(true)
+            .required_unless_one(&["validate", "base_dir"])
+            .conflicts_with("base_dir")
+            .takes_value(true))
+        .arg(Arg::with_name("charter")
+            .long("charter")
+            .help("The full path to the charter yaml file, instead of the first positional argument")
+            .takes_value(true))
+        .arg(Arg::with_name("base_dir")
+            .long("base-dir")
+            .help("The base directory where data files will be processed, instead of the second positional argument")
             .takes_value(true))
-        .get_matches();
+        .arg(Arg::with_name("validate")
+            .long("validate")
+            .help("Check the charter, including compiling it's Lua scripts, and report any problems without running it. No control dir is required"))
+        .arg(Arg::with_name("shadow")
+            .long("shadow")
+            .help("The path to a candidate charter to run against the same data. Differences in the groups matched are written to a shadow_diff.json file, only the primary charter's results are kept")
+            .takes_value(true))
+        .arg(Arg::with_name("single_thread")
+            .long("single-thread")
+            .help("Derive each file in turn rather than in parallel, for the lowest memory usage. The same as setting derive_threads: 1 in the charter"))
+        .arg(Arg::with_name("explain")
+            .long("explain")
+            .help("Write the constraints each unmatched group failed to a diagnostics.json file in the matched folder. The same as setting explain_unmatched: true in the charter"))
+        .get_matches_from(args);
 
     dotenv::dotenv().ok();
 
-    let charter_path = Path::new(options.value_of("charter_path").expect("no charter specified"));
-    let base_path = Path::new(options.value_of("control_dir").expect("no control dir specififed"));
+    if options.is_present("single_thread") {
+        std::env::set_var("OPENREC_DERIVE_THREADS", "1");
+    }
+
+    if options.is_present("explain") {
+        std::env::set_var("OPENREC_EXPLAIN_UNMATCHED", "true");
+    }
+
+    let charter_path = Path::new(options.value_of("charter").or_else(|| options.value_of("charter_path")).expect("no charter specified"));
+    if !charter_path.is_file() {
+        bail!("The charter {} does not exist", charter_path.to_string_lossy())
+    }
+
+    if options.is_present("validate") {
+        return validate(charter_path)
+    }
+
+    let base_path = Path::new(options.value_of("base_dir").or_else(|| options.value_of("control_dir")).expect("no control dir specififed"));
+    if !base_path.is_dir() {
+        bail!("The base directory {} does not exist", base_path.to_string_lossy())
+    }
+
     let _handle = init_logging(base_path);
 
-    celerity::run_charter(charter_path, base_path)?;
+    match options.value_of("shadow") {
+        Some(candidate) => celerity::run_shadow(charter_path, Path::new(candidate), base_path)?,
+        None => celerity::run_charter(charter_path, base_path)?,
+    }
 
     Ok(())
 }
 
+///
+/// Print any problems with the charter and exit with a non-zero status if there are any.
+///
+fn validate(charter_path: &Path) -> Result<()> {
+    let problems = celerity::validate_charter(charter_path)?;
+
+    if problems.is_empty() {
+        println!("{} is valid", charter_path.to_string_lossy());
+        return Ok(())
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    std::process::exit(1)
+}
+
 fn init_logging(base_path: &Path) -> Handle {
 
     // Set the log filter level.
@@ -54,11 +131,11 @@ fn init_logging(base_path: &Path) -> Handle {
 
     // Initialise logging.
     let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(CONSOLE_PATTERN)))
+        .encoder(logging::encoder(CONSOLE_PATTERN))
         .build();
 
     let log_file = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(FILE_PATTERN)))
+        .encoder(logging::encoder(FILE_PATTERN))
         .build(&log_path.join(format!("{}_celerity.log", Utc::now().format("%Y%m%d").to_string())))
         .unwrap_or_else(|_| panic!("cannot create log file appended to {}", log_path.to_string_lossy()));
 
@@ -72,4 +149,34 @@ fn init_logging(base_path: &Path) -> Handle {
         .unwrap();
 
     log4rs::init_config(config).unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charter_and_base_dir_options_run_the_charter() {
+        let base_dir = std::env::temp_dir().join(format!("celerity_cli_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(base_dir.join("waiting")).unwrap();
+
+        let charter = base_dir.join("charter.yaml");
+        fs::write(&charter, "name: cli test\nversion: 1\nmatching:\n  source_files:\n    - pattern: .*.csv\n").unwrap();
+
+        fs::write(base_dir.join("waiting/20211219_082900000_transactions.csv"), "\"OpenRecStatus\",\"Ref\"\n\"IN\",\"ST\"\n\"0\",\"A\"\n").unwrap();
+
+        // Missing paths are rejected before anything is run.
+        let err = run(vec!("celerity", "--charter", "no-such-charter.yaml", "--base-dir", &base_dir.to_string_lossy())).unwrap_err();
+        assert_eq!(err.to_string(), "The charter no-such-charter.yaml does not exist");
+
+        let missing = base_dir.join("no-such-dir");
+        let err = run(vec!("celerity", "--charter", &charter.to_string_lossy(), "--base-dir", &missing.to_string_lossy())).unwrap_err();
+        assert_eq!(err.to_string(), format!("The base directory {} does not exist", missing.to_string_lossy()));
+
+        run(vec!("celerity", "--charter", &charter.to_string_lossy(), "--base-dir", &base_dir.to_string_lossy())).unwrap();
+
+        assert!(base_dir.join("unmatched/20211219_082900000_transactions.unmatched.csv").exists());
+        assert!(fs::read_dir(base_dir.join("waiting")).unwrap().next().is_none());
+    }
+}
@@ -1,14 +1,16 @@
 use chrono::{DateTime, Utc};
+use itertools::Itertools;
 use anyhow::Context as ErrContext;
 use serde::{Deserialize, Serialize};
 use core::lua::init_context;
 use std::{io::BufReader, fs::File, collections::HashMap, time::{Duration, Instant}};
-use crate::{Context, error::{MatcherError, here}, folders::{self, ToCanoncialString}, lua, model::{grid::Grid, datafile::DataFile, record::Record, schema::GridSchema}, formatted_duration_rate, blue, utils::{self, csv::{CsvWriters, CsvWriter}}};
+use crate::{Context, error::{MatcherError, here}, folders::{self, ToCanoncialString}, instructions::project_col, lua, model::{grid::Grid, datafile::DataFile, record::Record, schema::{AGE, GridSchema, ID, STATUS}}, formatted_duration_rate, blue, utils::{self, csv::{CsvWriters, CsvWriter}}};
 
 /*
     Whilst changesets are being applied, new data files are written into the matching folder with the .modifying extension. These files
     contain the original data with any changeset modifications applied. Note: If a record is ignored by a changeset, it is absent from the
-    new file.
+    new file. Whilst the changesets are applied to a record, an ignored record is only marked as skipped, so a later UnignoreRecords
+    changeset in the same job can bring it back. Once the job has run, the ignored record can no longer be restored.
 
     At the end of ChangeSet processing, the original file is immediately moved to the archive folder.
 
@@ -24,7 +26,10 @@ use crate::{Context, error::{MatcherError, here}, folders::{self, ToCanoncialStr
 #[serde(tag = "type")]
 pub enum Change {
     UpdateFields { updates: Vec<FieldChange>, lua_filter: String },
+    ClearFields { fields: Vec<String>, lua_filter: String },
     IgnoreRecords { lua_filter: String },
+    UnignoreRecords { lua_filter: String },
+    AddRecords { file: String, records: Vec<HashMap<String, String>> },
     DeleteFile { filename: String },
 }
 
@@ -34,12 +39,20 @@ pub struct FieldChange {
     value: String
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Scope {
+    files: Vec<String>, // The shortnames of the files the changeset may modify, e.g. invoices.
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ChangeSet {
     id: uuid::Uuid,                     // A unique UUID for the changeset. May be used in logs.
     change: Change,                     // The change to apply.
     timestamp: DateTime<Utc>,           // The time this change was created.
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<Scope>,               // If present, only records from these files are considered.
+
     #[serde(skip)]
     effected: usize,
 
@@ -48,6 +61,9 @@ pub struct ChangeSet {
 
     #[serde(skip)]
     filename: String,
+
+    #[serde(skip)]
+    dry_run: bool,
 }
 
 impl ChangeSet {
@@ -59,6 +75,16 @@ impl ChangeSet {
         &self.change
     }
 
+    ///
+    /// Returns true if the changeset is unscoped or the data file's shortname is listed in its scope.
+    ///
+    pub fn in_scope(&self, data_file: &DataFile) -> bool {
+        match &self.scope {
+            Some(scope) => scope.files.iter().any(|file| file == data_file.shortname()),
+            None => true,
+        }
+    }
+
     pub fn effected(&self) -> usize {
         self.effected
     }
@@ -70,6 +96,13 @@ impl ChangeSet {
     pub fn set_filename(&mut self, filename: String) {
         self.filename = filename;
     }
+
+    ///
+    /// True if the changeset was only evaluated, the records it effected were left unchanged.
+    ///
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
 }
 
 ///
@@ -95,17 +128,31 @@ pub fn apply(ctx: &Context) -> Result<Vec<ChangeSet>, MatcherError> {
     let mut changesets = load_changesets(ctx)?;
 
     if !changesets.is_empty() {
+        // In a dry-run, changesets are evaluated and their effect reported but no data is modified.
+        let dry_run = ctx.charter().changeset_dry_run();
+
         // Apply DeleteFiles first.
-        delete_files_now(ctx, &changesets)?;
+        if !dry_run {
+            delete_files_now(ctx, &changesets)?;
+        }
 
         let mut grid = Grid::load(ctx)?;
 
         // Clone the grid schema - some lower level fns need mut accessor, mut grid and an immutable schema.
         let schema = grid.schema().clone();
 
+        // Ensure every column the changesets reference exists, bare or prefixed, in the data.
+        validate_columns(&changesets, &schema)?;
+
+        // Ensure any added values can be read as their column's data type, rather than failing later on.
+        validate_added_values(&changesets, &schema)?;
+
+        // Resolve bare column names in each filter to the prefixed columns of each file schema.
+        let filters = resolve_filters(&changesets, &schema);
+
         // Create a DataAccessor to read real CSV data only (derived data wont exist yet) and to write any
         // required modified data out to new files.
-        let mut writers = writers(&grid)?;
+        let mut writers = writers(ctx, &grid)?;
 
         // Debug the grid if we have any changesets - before they are evaluated.
         grid.debug_grid(ctx, 1);
@@ -126,7 +173,7 @@ pub fn apply(ctx: &Context) -> Result<Vec<ChangeSet>, MatcherError> {
                 eval_ctx.file = record.file_idx();
 
                 let data_file = &schema.files()[record.file_idx()];
-                let mut deleted = false;
+                let mut skipped = false;
 
                 // Populate all the fields of the record into it's writer buffer.
                 record.load_buffer();
@@ -135,11 +182,16 @@ pub fn apply(ctx: &Context) -> Result<Vec<ChangeSet>, MatcherError> {
                     let started = Instant::now();
                     eval_ctx.change_idx = c_idx;
 
+                    if !changeset.in_scope(data_file) {
+                        continue
+                    }
+
                     match changeset.change() {
-                        Change::UpdateFields { updates, lua_filter } => {
-                            if record_effected(&record, lua_filter, &lua_ctx, &schema)? {
+                        Change::UpdateFields { updates, .. } => {
+                            if record_effected(&record, &filters[c_idx][data_file.schema_idx()], &lua_ctx, &schema)? {
                                 for update in updates {
-                                    record.update(&update.field, &update.value)?; // Modify the record in a buffer.
+                                    let field = resolve_header(&schema, data_file.schema_idx(), &update.field);
+                                    record.update(&field, &update.value)?; // Modify the record in a buffer.
                                 }
                                 metrics.get_mut(data_file).expect("No metrics for record").modified += 1;
                                 changeset.effected += 1;
@@ -147,21 +199,42 @@ pub fn apply(ctx: &Context) -> Result<Vec<ChangeSet>, MatcherError> {
                             }
 
                         },
-                        Change::IgnoreRecords { lua_filter } => {
-                            if record_effected(&record, lua_filter, &lua_ctx, &schema)? {
+                        Change::ClearFields { fields, .. } => {
+                            if record_effected(&record, &filters[c_idx][data_file.schema_idx()], &lua_ctx, &schema)? {
+                                for field in fields {
+                                    let field = resolve_header(&schema, data_file.schema_idx(), field);
+                                    record.update(&field, "")?; // Blank the field in the buffer.
+                                }
+                                metrics.get_mut(data_file).expect("No metrics for record").modified += 1;
+                                changeset.effected += 1;
+                                changeset.elapsed += started.elapsed();
+                            }
+                        },
+                        Change::IgnoreRecords { .. } => {
+                            if !skipped && record_effected(&record, &filters[c_idx][data_file.schema_idx()], &lua_ctx, &schema)? {
                                 // Stops the modified record being written and index is removed from memory.
-                                deleted = true;
+                                skipped = true;
                                 metrics.get_mut(data_file).expect("No metrics for record").ignored += 1;
                                 changeset.effected += 1;
                                 changeset.elapsed += started.elapsed();
                             }
                         },
-                        Change::DeleteFile { .. } => {}, // Already applied to the files.
+                        Change::UnignoreRecords { .. } => {
+                            // Only records skipped by an earlier IgnoreRecords changeset can be restored.
+                            if skipped && record_effected(&record, &filters[c_idx][data_file.schema_idx()], &lua_ctx, &schema)? {
+                                skipped = false;
+                                metrics.get_mut(data_file).expect("No metrics for record").ignored -= 1;
+                                changeset.effected += 1;
+                                changeset.elapsed += started.elapsed();
+                            }
+                        },
+                        Change::AddRecords { .. } => {}, // Appended once all the existing records are written.
+                        Change::DeleteFile { .. } => {}, // Already applied to the files (unless a dry-run).
                     }
                 }
 
                 // Copy the record across now as-is or modified - or skip if ignored.
-                if !deleted {
+                if !skipped {
                     let csv = record.flush();
                     let writer = &mut writers[record.file_idx()];
                     writer.write_byte_record(&csv).map_err(MatcherError::CSVError)?;
@@ -176,12 +249,24 @@ pub fn apply(ctx: &Context) -> Result<Vec<ChangeSet>, MatcherError> {
             source
         })?;
 
+        // Append any new records to the end of their target files.
+        add_records(&mut changesets, &schema, &mut writers, &mut metrics)?;
+
         // Finalise the modifying files, renaming and archiving things as required.
-        finalise_files(ctx, &metrics, &mut grid)?;
+        finalise_files(ctx, &metrics, &mut grid, dry_run)?;
+
+        for changeset in &mut changesets {
+            changeset.dry_run = dry_run;
+        }
 
         for changeset in &changesets {
             let (duration, rate) = formatted_duration_rate(grid.len(), changeset.elapsed);
-            log::info!("ChangeSet {} effected {} record(s) in {} ({}/row)", changeset.id, changeset.effected, blue(&duration), rate);
+            log::info!("ChangeSet {} {} {} record(s) in {} ({}/row)",
+                changeset.id,
+                if dry_run { "would have effected" } else { "effected" },
+                changeset.effected,
+                blue(&duration),
+                rate);
         }
     }
 
@@ -209,10 +294,13 @@ fn delete_files_now(ctx: &Context, changesets: &[ChangeSet]) -> Result<(), Match
 ///
 /// Report on any changes made to the data.
 ///
-fn finalise_files(ctx: &Context, metrics: &HashMap<DataFile, Metrics>, grid: &mut Grid) -> Result<(), MatcherError> {
+/// In a dry-run, all the modifying files are discarded, the original data is left in place and the changesets are
+/// left in the waiting folder.
+///
+fn finalise_files(ctx: &Context, metrics: &HashMap<DataFile, Metrics>, grid: &mut Grid, dry_run: bool) -> Result<(), MatcherError> {
 
     for (data_file, metric) in metrics.iter() {
-        if metric.modified > 0 || metric.ignored > 0 {
+        if !dry_run && (metric.modified > 0 || metric.ignored > 0) {
             if !is_unmatched(data_file) {
                 // For new data files, we need to archive the original file immediately. Find the mutable grid instance
                 // so we can archive and set the archived filename.
@@ -239,14 +327,183 @@ fn finalise_files(ctx: &Context, metrics: &HashMap<DataFile, Metrics>, grid: &mu
     }
 
     // Move all the changesets (.json) to the matched folder now. This means, any future error wont
-    // attempt to re-apply them to already modified data.
+    // attempt to re-apply them to already modified data. A dry-run hasn't applied them, so they're
+    // returned to the waiting folder to be applied for real later.
     for file in &folders::changesets_in_matching(ctx)? {
-        folders::progress_to_archive_now(ctx, file)?;
+        match dry_run {
+            true  => folders::rename(file.path(), folders::waiting(ctx).join(file.file_name()))?,
+            false => folders::progress_to_archive_now(ctx, file)?,
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Ensure any column referenced in a changeset's filter or field updates can be found in at least one
+/// of the data files - either as-is or with the file's field prefix applied.
+///
+fn validate_columns(changesets: &[ChangeSet], schema: &GridSchema) -> Result<(), MatcherError> {
+    if schema.files().is_empty() {
+        return Ok(())
+    }
+
+    for changeset in changesets {
+        let headers = match changeset.change() {
+            Change::UpdateFields { updates, lua_filter } => lua::referenced_headers(lua_filter)
+                .into_iter()
+                .chain(updates.iter().map(|update| update.field.clone()))
+                .collect(),
+            Change::ClearFields { fields, lua_filter } => lua::referenced_headers(lua_filter)
+                .into_iter()
+                .chain(fields.iter().cloned())
+                .collect(),
+            Change::IgnoreRecords { lua_filter }   |
+            Change::UnignoreRecords { lua_filter } => lua::referenced_headers(lua_filter),
+            Change::AddRecords { file, records } => {
+                if target_file(schema, file).is_none() {
+                    return Err(MatcherError::ChangeSetFileMissing { changeset: changeset.id().to_string(), file: file.clone() })
+                }
+                records.iter().flat_map(|record| record.keys().cloned()).unique().collect()
+            },
+            Change::DeleteFile { .. } => vec!(),
+        };
+
+        for header in headers {
+            if !(0..schema.file_schemas().len()).any(|idx| schema.column(&resolve_header(schema, idx, &header)).is_some()) {
+                return Err(MatcherError::ChangeSetColumnMissing { changeset: changeset.id().to_string(), column: header })
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Ensure every non-blank value an AddRecords changeset adds can be parsed as its column's data type.
+///
+fn validate_added_values(changesets: &[ChangeSet], schema: &GridSchema) -> Result<(), MatcherError> {
+    for changeset in changesets {
+        if let Change::AddRecords { file, records } = changeset.change() {
+            let file_idx = target_file(schema, file).expect("AddRecords target not validated");
+            let columns = schema.file_schemas()[schema.files()[file_idx].schema_idx()].columns();
+
+            for (header, value) in records.iter().flatten().filter(|(_, value)| !value.is_empty()) {
+                if let Some(col) = columns.iter().find(|col| col.header_no_prefix() == header || col.header() == header) {
+                    project_col::validate_constant(col.header(), *col.data_type(), value)
+                        .map_err(|_| MatcherError::ChangeSetInvalidValue {
+                            changeset: changeset.id().to_string(),
+                            column: header.clone(),
+                            value: value.clone(),
+                            data_type: col.data_type().as_str().into() })?;
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+///
+/// For each changeset, build a copy of the Lua filter for each file schema with any bare column names
+/// replaced with that file's prefixed column names. Indexed by [changeset][file schema].
+///
+fn resolve_filters(changesets: &[ChangeSet], schema: &GridSchema) -> Vec<Vec<String>> {
+    changesets.iter()
+        .map(|changeset| {
+            let lua_filter = match changeset.change() {
+                Change::UpdateFields { lua_filter, .. } |
+                Change::ClearFields { lua_filter, .. }  |
+                Change::IgnoreRecords { lua_filter }    |
+                Change::UnignoreRecords { lua_filter }  => lua_filter.as_str(),
+                Change::AddRecords { .. }               |
+                Change::DeleteFile { .. }               => "",
+            };
+
+            (0..schema.file_schemas().len())
+                .map(|idx| lua::replace_headers(lua_filter, |header| resolve_header(schema, idx, header)))
+                .collect()
+        })
+        .collect()
+}
+
+///
+/// Append the records of any AddRecords changesets to their target file.
+///
+/// The new records are given a fresh OpenRecId (if the file has one) and an unmatched status, any column not
+/// provided in the changeset is left blank.
+///
+fn add_records(
+    changesets: &mut [ChangeSet],
+    schema: &GridSchema,
+    writers: &mut CsvWriters,
+    metrics: &mut HashMap<DataFile, Metrics>) -> Result<(), MatcherError> {
+
+    for changeset in changesets.iter_mut() {
+        let started = Instant::now();
+
+        if let Change::AddRecords { file, records } = &changeset.change {
+            let file_idx = target_file(schema, file).expect("AddRecords target not validated");
+            let data_file = &schema.files()[file_idx];
+            let columns = schema.file_schemas()[data_file.schema_idx()].columns();
+
+            for fields in records {
+                let mut row = columns.iter()
+                    .map(|col| match col.header_no_prefix() {
+                        STATUS => "0".to_string(),
+                        ID     => uuid::Uuid::new_v4().to_hyphenated().to_string(),
+                        header => fields.get(header).or_else(|| fields.get(col.header())).cloned().unwrap_or_default(),
+                    })
+                    .collect::<Vec<String>>();
+
+                if data_file.aged() {
+                    row.push(String::new());
+                }
+
+                writers[file_idx].write_record(&row).map_err(MatcherError::CSVError)?;
+            }
+
+            metrics.get_mut(data_file).expect("No metrics for file").modified += records.len();
+            changeset.effected += records.len();
+            changeset.elapsed += started.elapsed();
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Find the file new records should be added to, by shortname. An unmatched file is preferred so the records
+/// sit alongside the data they are correcting, otherwise the earliest new file is used.
+///
+fn target_file(schema: &GridSchema, shortname: &str) -> Option<usize> {
+    let candidates = schema.files().iter()
+        .enumerate()
+        .filter(|(_, file)| file.shortname() == shortname)
+        .collect::<Vec<(usize, &DataFile)>>();
+
+    candidates.iter()
+        .find(|(_, file)| is_unmatched(file))
+        .or_else(|| candidates.first())
+        .map(|(idx, _)| *idx)
+}
+
+///
+/// If the header isn't a column in the grid but the file schema has a prefix, return the prefixed header
+/// if that is a column, e.g. Amount -> INV.Amount. Otherwise the header is returned as-is.
+///
+fn resolve_header(schema: &GridSchema, schema_idx: usize, header: &str) -> String {
+    if schema.column(header).is_none() {
+        if let Some(prefix) = schema.file_schemas()[schema_idx].prefix() {
+            let prefixed = format!("{}.{}", prefix, header);
+            if schema.column(&prefixed).is_some() {
+                return prefixed
+            }
+        }
+    }
+    header.to_string()
+}
+
 ///
 /// Returns true if the record matches the filter criteria evaluated from the Lua script.
 ///
@@ -313,11 +570,11 @@ fn is_unmatched(file: &DataFile) -> bool {
 ///
 /// A list of open CSV writers in the order of files sourced into the Grid.
 ///
-fn writers(grid: &Grid) -> Result<CsvWriters, MatcherError> {
+fn writers(ctx: &Context, grid: &Grid) -> Result<CsvWriters, MatcherError> {
     let mut writers = grid.schema()
         .files()
         .iter()
-        .map(|f| utils::csv::writer(f.modifying_path()))
+        .map(|f| utils::csv::output_writer(f.modifying_path(), ctx.charter()))
         .collect::<Vec<CsvWriter>>();
 
     // Write the headers and schema rows.
@@ -325,10 +582,19 @@ fn writers(grid: &Grid) -> Result<CsvWriters, MatcherError> {
         let writer = &mut writers[idx];
         let schema = &grid.schema().file_schemas()[file.schema_idx()];
 
-        writer.write_record(schema.columns().iter().map(|c| c.header_no_prefix()).collect::<Vec<&str>>())
+        let mut headers = schema.columns().iter().map(|c| c.header_no_prefix()).collect::<Vec<&str>>();
+        let mut types = schema.columns().iter().map(|c| c.data_type().as_str()).collect::<Vec<&str>>();
+
+        // Keep any unmatched age column, it's not part of the schema but the records still have it.
+        if file.aged() {
+            headers.push(AGE);
+            types.push("IN");
+        }
+
+        writer.write_record(headers)
             .map_err(|source| MatcherError::CannotWriteHeaders{ filename: file.derived_filename().into(), source })?;
 
-        writer.write_record(schema.columns().iter().map(|c| c.data_type().as_str()).collect::<Vec<&str>>())
+        writer.write_record(types)
             .map_err(|source| MatcherError::CannotWriteSchema{ filename: file.derived_filename().into(), source })?;
 
         writer.flush()?;
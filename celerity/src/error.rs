@@ -43,6 +43,9 @@ pub enum MatcherError {
     #[error("Unable to write matched record row to {filename}")]
     CannotWriteMatchedRecord { filename: String, source: serde_json::Error },
 
+    #[error("Unable to write duplicate record row {row} from {filename}")]
+    CannotWriteDuplicateRecord { filename: String, row: usize, source: csv::Error },
+
     #[error("Unable to write unmatched record row {row} to {filename}")]
     CannotWriteUnmatchedRecord { filename: String, row: usize, source: csv::Error },
 
@@ -67,27 +70,48 @@ pub enum MatcherError {
     #[error("Charter contained an invalid regular expression")]
     InvalidSourceFileRegEx { source: regex::Error },
 
+    #[error("The source file glob {pattern} is invalid: {reason}")]
+    InvalidSourceFileGlob { pattern: String, reason: String },
+
     #[error("Schemas for {filename} must be the same, found these two schemas: -\n[{first}]\n[{second}]")]
     SchemaMismatch { filename: String, first: String, second: String },
 
     #[error("Two files are being loaded with different schemas but with a common header name. You should use field_prefix arguments to ensure headers are unique.")]
     TwoSchemaWithDuplicateHeader { header: String },
 
+    #[error("The constant {value} projected into column {column} is not a valid {data_type}")]
+    InvalidConstant { column: String, value: String, data_type: String },
+
+    #[error("The projection of column {column} assigns to {header} which isn't derived by an earlier projection or merge")]
+    CannotAssignColumn { column: String, header: String },
+
     #[error("Projected column name {header} already exists")]
     ProjectedColumnExists { header: String, },
 
     #[error("Merged column name {header} already exists")]
     MergedColumnExists { header: String },
 
-    #[error("Instruction {instruction} was missing a set of script columms")]
-    MissingScriptCols { instruction: usize },
+    #[error("Instruction {instruction} uses the column {column} but the instruction which derives it is disabled")]
+    DisabledColumnReferenced { column: String, instruction: usize },
 
     #[error("Lua error in script\neval: {eval}\nreturn type: {data_type}\nwhen: {when}\row: {row}")]
     ProjectColScriptError { eval: String, when: String, data_type: String, row: usize, source: rlua::Error },
 
+    #[error("Error in the charter's global_lua, {reason}")]
+    InvalidGlobalLua { reason: String },
+
     #[error("Error in custom Lua constraint: {reason}")]
     CustomConstraintError { reason: String, source: rlua::Error },
 
+    #[error("Column {column} doesn't exist in the grid and cannot be used to sort groups")]
+    SortColumnMissing { column: String },
+
+    #[error("Cannot rename {from} to {to}, the column {to} already exists")]
+    RenameColumnExists { from: String, to: String },
+
+    #[error("Cannot rename {from} to {to}, it has type {this_type:?} but {to} has type {other_type:?}")]
+    RenameDataTypeMismatch { from: String, to: String, this_type: DataType, other_type: DataType },
+
     #[error("Column {header} doesn't exist in the source data and cannot be used to merge")]
     MissingSourceColumn { header: String },
 
@@ -97,18 +121,51 @@ pub enum MatcherError {
     #[error("The source column {header} has type {this_type:?} which wont merge with {other_type:?}")]
     InvalidSourceDataType { header: String, this_type: DataType, other_type: DataType},
 
+    #[error("The source column {header} has type {this_type:?} which can't be coerced to {coerce_to:?}")]
+    CannotCoerceSourceDataType { header: String, this_type: DataType, coerce_to: DataType },
+
     #[error("An error occured processing changeset {changeset} on record {row} from file {file}")]
     ChangeSetError { changeset: String, row: usize, file: String, source: rlua::Error },
 
+    #[error("ChangeSet {changeset} references the column {column} which doesn't exist in any data file")]
+    ChangeSetColumnMissing { changeset: String, column: String },
+
+    #[error("ChangeSet {changeset} adds records to {file} but there is no data file with that name")]
+    ChangeSetFileMissing { changeset: String, file: String },
+
+    #[error("ChangeSet {changeset} adds the value {value} to the column {column} which isn't a valid {data_type}")]
+    ChangeSetInvalidValue { changeset: String, column: String, value: String, data_type: String },
+
     #[error("An error occured processing instruction {instruction} on record {row} from file {file} : {err}")]
     DeriveDataError { instruction: String, row: usize, file: String, err: String },
 
+    #[error("A problem occured filtering records")]
+    FilterError { source: rlua::Error },
+
+    #[error("The match job was cancelled during the {phase} phase")]
+    JobCancelled { phase: String },
+
     #[error("A problem occured during the match")]
     MatchGroupError { source: rlua::Error },
 
+    #[error("A group with the key [{key}] has more than the max_group_size of {max_group_size} records, check the group-by columns are correct")]
+    MaxGroupSizeExceeded { max_group_size: usize, key: String },
+
     #[error("The colmun {column} was referenced in a group-by instruction but doesn't exist")]
     GroupByColumnMissing { column: String },
 
+    #[error("The column {column} in group-by instruction {instruction} doesn't exist, the available columns are {headers}")]
+    UnknownGroupByColumn { column: String, instruction: usize, headers: String },
+
+    #[error("The shadow charter {candidate} must project, merge and rename the same columns as the primary charter, as it's matched against the primary's derived data")]
+    ShadowDerivesDiffer { candidate: String },
+
+    #[error("The column {column} was grouped by with :date_only but isn't a datetime column")]
+    GroupByDateOnlyNotDatetime { column: String },
+
+    #[error("The date_tolerance column {column} must be a datetime column")]
+    DateToleranceNotDatetime { column: String },
+
     #[error("The constraint column {column} is not present")]
     ConstraintColumnMissing { column: String },
 
@@ -124,12 +181,18 @@ pub enum MatcherError {
     #[error("Unmatched file {filename} was not found in the unmatched handler")]
     UnmatchedFileNotInHandler { filename: String },
 
+    #[error("Unable to write the job results to the database {path}")]
+    CannotWriteDatabase { path: String, source: rusqlite::Error },
+
     #[error("Constraint {index} evaluation failed")]
     ConstraintError { index: usize, source: rlua::Error },
 
     #[error("Charter failed to load")]
     CharterLoadError ( #[from] core::error::Error ),
 
+    #[error(transparent)]
+    JobLockError ( core::error::Error ),
+
     #[error("An error occured sourcing data")]
     GridSourceError { source: rlua::Error },
 
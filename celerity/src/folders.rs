@@ -1,9 +1,12 @@
 use regex::Regex;
-use chrono::{Utc, TimeZone};
+use itertools::Itertools;
+use chrono::{SecondsFormat, Utc, TimeZone};
 use lazy_static::lazy_static;
 use anyhow::Context as ErrContext;
-use std::{fs::{self, DirEntry}, path::{Path, PathBuf}};
-use crate::{model::{datafile::DataFile, grid::Grid}, error::{MatcherError, here}, Context};
+use flate2::{Compression, write::GzEncoder};
+use std::{fs::{self, DirEntry, File, OpenOptions}, io::{self, BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+use core::charter::MatchingSourceFile;
+use crate::{model::{datafile::DataFile, grid::Grid}, error::{MatcherError, here}, matching::MILLIS_IN_A_DAY, Context, Phase};
 
 ///
 /// This module provides file and folder util methods.
@@ -11,9 +14,11 @@ use crate::{model::{datafile::DataFile, grid::Grid}, error::{MatcherError, here}
 
 pub const IN_PROGRESS: &str = ".inprogress";
 pub const UNMATCHED: &str = ".unmatched.csv";
+pub const DUPLICATES: &str = ".duplicates.csv";
 pub const DERIVED: &str = "derived.csv";
 pub const MODIFYING: &str = "modifying";
 pub const PRE_MODIFIED: &str = "pre_modified";
+pub const GZ: &str = ".gz";
 const CHANGESET_PATTERN: &str = r"^(\d{8}_\d{9})_changeset\.json$";
 
 lazy_static! {
@@ -22,6 +27,7 @@ lazy_static! {
     static ref DERIVED_REGEX: Regex = Regex::new(r"^(\d{8}_\d{9})_(.*)\.derived\.csv$").expect("bad regex for DERIVED_REGEX");
     static ref CHANGESET_REGEX: Regex = Regex::new(CHANGESET_PATTERN).expect("bad regex for CHANGESET_REGEX");
     static ref TIMESTAMP_REGEX: Regex = Regex::new(r"^(\d{4})(\d{2})(\d{2})_(\d{2})(\d{2})(\d{2})(\d{3})").expect("bad regex for TIMESTAMP_REGEX");
+    static ref MATCHED_REGEX: Regex = Regex::new(r"^(\d{8}_\d{9})_(matched\.json|matched\.csv|diagnostics\.json|manifest\.json)$").expect("bad regex for MATCHED_REGEX");
     pub static ref UNMATCHED_REGEX: Regex = Regex::new(r"^(\d{8}_\d{9})_(.*)\.unmatched\.csv$").expect("bad regex for UNMATCHED_REGEX");
 }
 
@@ -66,7 +72,7 @@ pub fn ensure_dirs_exist(ctx: &Context) -> Result<(), MatcherError> {
     log::debug!("Creating folder structure in [{}]", home.to_canoncial_string());
 
     let mut folders = vec!(waiting(ctx), matching(ctx), matched(ctx), unmatched(ctx), archive(ctx));
-    if ctx.charter().debug() {
+    if ctx.charter().debug() || ctx.charter().keep_derived() {
         folders.push(debug_path(ctx));
     }
 
@@ -90,6 +96,23 @@ pub fn progress_to_matching(ctx: &Context) -> Result<(), MatcherError> {
         }
     }
 
+    // Unmatched records may have been split into a sub-folder per group instruction. A data file's records can be
+    // spread across several sub-folders, so they're merged back into one unmatched file.
+    for folder in unmatched_subfolders(ctx)? {
+        for entry in (folder.read_dir()?).flatten() {
+            if is_unmatched_data_file(&entry.path()) {
+                let dest = matching(ctx).join(entry.file_name());
+                match dest.exists() {
+                    true  => append_unmatched(&entry.path(), &dest)?,
+                    false => rename(entry.path(), dest)?,
+                }
+            }
+        }
+
+        // Only removed if it's now empty.
+        let _ = fs::remove_dir(&folder);
+    }
+
     // Move waiting files to the matching folder.
     for entry in (waiting(ctx).read_dir()?).flatten() {
         let pb = entry.path();
@@ -105,11 +128,17 @@ pub fn progress_to_matching(ctx: &Context) -> Result<(), MatcherError> {
 ///
 /// Move any matching files to the archive folder, remove derived data and old unmatched data.
 ///
-pub fn progress_to_archive(ctx: &Context, mut grid: Grid) -> Result<(), MatcherError> {
+/// Returns the archived filename of each data file moved to the archive folder.
+///
+pub fn progress_to_archive(ctx: &Context, mut grid: Grid) -> Result<Vec<String>, MatcherError> {
     for entry in (matching(ctx).read_dir()?).flatten() {
         let pb = entry.path();
 
-        if is_unmatched_data_file(&pb) || is_derived_file(&pb) {
+        if is_derived_file(&pb) && ctx.charter().keep_derived() {
+            // Keep .derived files for debugging, but not in the archive.
+            rename(entry.path(), debug_path(ctx).join(entry.file_name()))?;
+
+        } else if is_unmatched_data_file(&pb) || is_derived_file(&pb) {
             // Delete .unmatched files don't move them to archive. At the end of a match job,
             // their still-unmatched contents will have been written to a new unmatched file in
             // the unmatched folder.
@@ -127,7 +156,7 @@ pub fn progress_to_archive(ctx: &Context, mut grid: Grid) -> Result<(), MatcherE
         }
     }
 
-    Ok(())
+    Ok(grid.schema().files().iter().filter_map(|df| df.archived_filename().clone()).sorted().collect())
 }
 
 
@@ -151,15 +180,20 @@ pub fn archive_data_file(ctx: &Context, file: &mut DataFile) -> Result<(), Match
 
     if file.archived_filename().is_none() {
         if ctx.charter().archive_files() {
+            let suffix = if ctx.charter().archive_compress() { GZ } else { "" };
             let mut counter = 0;
-            let mut dest = archive(ctx).join(file.filename());
+            let mut dest = archive(ctx).join(format!("{}{}", file.filename(), suffix));
 
             while dest.exists() {
                 counter += 1;
-                dest = archive(ctx).join(format!("{}_{:02}", file.filename(), counter));
+                dest = archive(ctx).join(format!("{}_{:02}{}", file.filename(), counter, suffix));
+            }
+
+            match ctx.charter().archive_compress() {
+                true  => compress(file.path(), &dest)?,
+                false => rename(file.path(), &dest)?,
             }
 
-            rename(file.path(), &dest)?;
             file.set_archived_filename(dest.file_name().expect("no archive filename").to_string_lossy().into());
 
         } else {
@@ -170,6 +204,129 @@ pub fn archive_data_file(ctx: &Context, file: &mut DataFile) -> Result<(), Match
     Ok(())
 }
 
+///
+/// Delete any completed matched job files (the matched JSON, CSV, diagnostics and manifest files) with a timestamp prefix more
+/// than retention_days before this job's. In-progress files are never removed.
+///
+/// Returns the number of files removed.
+///
+pub fn remove_expired_matched(ctx: &Context, retention_days: u64) -> Result<usize, MatcherError> {
+    let now = unix_timestamp(ctx.ts()).ok_or_else(|| MatcherError::InvalidTimestampPrefix { filename: ctx.ts().into() })?;
+    let cut_off = now - (retention_days * MILLIS_IN_A_DAY) as i64;
+    let mut removed = 0;
+
+    for entry in (matched(ctx).read_dir()?).flatten() {
+        let filename = entry.file_name().to_string_lossy().to_string();
+
+        let expired = MATCHED_REGEX.captures(&filename)
+            .and_then(|captures| unix_timestamp(&captures[1]))
+            .map(|ts| ts < cut_off)
+            .unwrap_or_default();
+
+        if expired && entry.path().is_file() {
+            remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+///
+/// Gzip the file to the destination and remove the original.
+///
+fn compress(from: &Path, to: &Path) -> Result<(), MatcherError> {
+    log::debug!("Compressing {} -> {}", from.to_canoncial_string(), to.to_canoncial_string());
+
+    let mut reader = File::open(from)
+        .with_context(|| format!("Cannot open {}{}", from.to_canoncial_string(), here!()))?;
+
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    io::copy(&mut reader, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|mut writer| writer.flush())
+        .with_context(|| format!("Cannot compress {} to {}{}", from.to_canoncial_string(), to.to_canoncial_string(), here!()))?;
+
+    remove_file(from)
+}
+
+///
+/// The regular expression selecting a source file's data files in the matching folder.
+///
+/// A glob pattern is matched against the whole filename, allowing for the timestamp prefix and any .unmatched suffix
+/// the file has been given, so 0?-invoices.csv selects 20211219_082900000_01-invoices.unmatched.csv.
+///
+pub fn source_file_regex(source_file: &MatchingSourceFile) -> Result<String, MatcherError> {
+    if !source_file.glob() {
+        return Ok(source_file.pattern().to_string())
+    }
+
+    let pattern = source_file.pattern();
+    match pattern.strip_suffix(".csv") {
+        Some(stem) => Ok(format!(r"^\d{{8}}_\d{{9}}_{}(\.unmatched)?\.csv$", glob_to_regex(stem, pattern)?)),
+        None => Ok(format!(r"^\d{{8}}_\d{{9}}_{}$", glob_to_regex(pattern, pattern)?)),
+    }
+}
+
+///
+/// Translate a glob into the equivalent regex, without anchors. Supports * (any characters), ? (any one character),
+/// [abc], [a-z] and [!abc] character classes, {a,b} alternatives (which may be nested) and \ to escape a character.
+///
+fn glob_to_regex(glob: &str, pattern: &str) -> Result<String, MatcherError> {
+    let invalid = |reason: &str| MatcherError::InvalidSourceFileGlob { pattern: pattern.into(), reason: reason.into() };
+
+    let mut regex = String::new();
+    let mut braces = 0;
+    let mut chars = glob.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '\\' => {
+                let escaped = chars.next().ok_or_else(|| invalid("it ends with an escape character"))?;
+                regex.push_str(&regex::escape(&escaped.to_string()));
+            },
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+
+                for (idx, ch) in chars.by_ref().enumerate() {
+                    match ch {
+                        ']' if idx > 0 => { closed = true; break },
+                        '!' | '^' if idx == 0 => class.push('^'),
+                        '\\' | '[' | '&' | '~' => { class.push('\\'); class.push(ch) },
+                        _ => class.push(ch),
+                    }
+                }
+
+                if !closed {
+                    return Err(invalid("a [ character class isn't closed with ]"))
+                }
+                regex.push_str(&format!("[{}]", class));
+            },
+            '{' => {
+                braces += 1;
+                regex.push_str("(?:");
+            },
+            '}' => {
+                if braces == 0 {
+                    return Err(invalid("a } has no opening {"))
+                }
+                braces -= 1;
+                regex.push(')');
+            },
+            ',' if braces > 0 => regex.push('|'),
+            _ => regex.push_str(&regex::escape(&ch.to_string())),
+        }
+    }
+
+    match braces {
+        0 => Ok(regex),
+        _ => Err(invalid("a { isn't closed with }")),
+    }
+}
+
 ///
 /// Return all the files in the matching folder which match the filename (wildcard) specified.
 ///
@@ -201,7 +358,7 @@ pub fn changesets_in_matching(ctx: &Context) -> Result<Vec<DirEntry>, MatcherErr
 ///
 pub fn rollback_any_incomplete(ctx: &Context) -> Result<(), MatcherError> {
 
-    for folder in vec!(matched(ctx), unmatched(ctx)) {
+    for folder in vec!(matched(ctx), unmatched(ctx)).into_iter().chain(unmatched_subfolders(ctx)?) {
         for entry in (folder.read_dir()?).flatten() {
             if entry.file_name().to_string_lossy().ends_with(IN_PROGRESS) {
                 log::warn!("Rolling back file {}", entry.path().to_canoncial_string());
@@ -274,6 +431,22 @@ pub fn unmatched(ctx: &Context) -> PathBuf {
     Path::new(ctx.base_dir()).join("unmatched/")
 }
 
+///
+/// The sub-folders of the unmatched folder - each holds the records left unmatched by a group instruction, if the
+/// charter splits unmatched records by instruction.
+///
+pub fn unmatched_subfolders(ctx: &Context) -> Result<Vec<PathBuf>, MatcherError> {
+    Ok((unmatched(ctx).read_dir()?).flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .sorted()
+        .collect())
+}
+
+pub fn duplicates(ctx: &Context) -> PathBuf {
+    Path::new(ctx.base_dir()).join("duplicates/")
+}
+
 pub fn archive(ctx: &Context) -> PathBuf {
     Path::new(ctx.base_dir()).join("archive/celerity")
 }
@@ -282,6 +455,34 @@ pub fn lookups(ctx: &Context) -> PathBuf {
     Path::new(ctx.base_dir()).join("lookups/")
 }
 
+pub fn job_status(ctx: &Context) -> PathBuf {
+    Path::new(ctx.base_dir()).join("job.status")
+}
+
+///
+/// Write the job's current phase to the job.status file in the base dir, or remove it if the job is complete.
+///
+pub fn write_job_status(ctx: &Context) -> Result<(), MatcherError> {
+    let path = job_status(ctx);
+
+    if let Phase::Complete = ctx.phase() {
+        return match path.exists() {
+            true  => remove_file(&path),
+            false => Ok(()),
+        }
+    }
+
+    let status = serde_json::json!({
+        "job_id": ctx.job_id().to_hyphenated().to_string(),
+        "phase": format!("{:?}", ctx.phase()),
+        "ordinal": ctx.phase().ordinal(),
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+
+    Ok(fs::write(&path, status.to_string())
+        .with_context(|| format!("Unable to write {}{}", path.to_canoncial_string(), here!()))?)
+}
+
 pub fn debug_path(ctx: &Context) -> PathBuf {
     Path::new(ctx.base_dir()).join("debug/")
 }
@@ -290,11 +491,75 @@ pub fn new_matched_file(ctx: &Context) -> PathBuf {
     matched(ctx).join(format!("{}_matched.json{}", new_timestamp(), IN_PROGRESS))
 }
 
+///
+/// The CSV variant of the matched file, e.g. 20201118_053000000_matched.csv.inprogress
+///
+pub fn matched_csv_file(matched_file: &Path) -> PathBuf {
+    let filename = filename(matched_file).replacen("_matched.json", "_matched.csv", 1);
+    matched_file.with_file_name(filename)
+}
+
+///
+/// The diagnostics for the matched file, e.g. 20201118_053000000_diagnostics.json.inprogress
+///
+pub fn diagnostics_file(matched_file: &Path) -> PathBuf {
+    let filename = filename(matched_file).replacen("_matched.json", "_diagnostics.json", 1);
+    matched_file.with_file_name(filename)
+}
+
+///
+/// The manifest of the job's output files for the matched file, e.g. 20201118_053000000_manifest.json.inprogress
+///
+pub fn manifest_file(matched_file: &Path) -> PathBuf {
+    let filename = filename(matched_file).replacen("_matched.json", "_manifest.json", 1);
+    matched_file.with_file_name(filename)
+}
+
+///
+/// The SQLite database match job results are appended to, if configured in the charter.
+///
+pub fn results_database(ctx: &Context) -> PathBuf {
+    Path::new(ctx.base_dir()).join("results.db")
+}
+
 ///
 /// e.g. 20201118_053000000_invoices.unmatched.csv.inprogress
 ///
-pub fn new_unmatched_file(ctx: &Context, file: &DataFile) -> PathBuf {
-    unmatched(ctx).join(format!("{}_{}{}{}", file.timestamp(), file.shortname(), UNMATCHED, IN_PROGRESS))
+/// If an instruction is given, the file is in that instruction's sub-folder, e.g. unmatched/2/.
+///
+pub fn new_unmatched_file(ctx: &Context, file: &DataFile, instruction: Option<usize>) -> PathBuf {
+    let folder = match instruction {
+        Some(instruction) => unmatched(ctx).join(instruction.to_string()),
+        None => unmatched(ctx),
+    };
+    folder.join(format!("{}_{}{}{}", file.timestamp(), file.shortname(), UNMATCHED, IN_PROGRESS))
+}
+
+///
+/// Append the records of one unmatched file (skipping its header and schema rows) to another from the same data
+/// file, then delete it.
+///
+fn append_unmatched(from: &Path, to: &Path) -> Result<(), MatcherError> {
+    let mut reader = BufReader::new(File::open(from)
+        .with_context(|| format!("Unable to open {}{}", from.to_canoncial_string(), here!()))?);
+
+    let mut skipped = vec!();
+    for _ in 0..2 {
+        reader.read_until(b'\n', &mut skipped)?;
+    }
+
+    let mut writer = OpenOptions::new().append(true).open(to)
+        .with_context(|| format!("Unable to append to {}{}", to.to_canoncial_string(), here!()))?;
+
+    io::copy(&mut reader, &mut writer)?;
+    remove_file(from)
+}
+
+///
+/// Return the path to record the duplicates removed from the data file by this job.
+///
+pub fn new_duplicates_file(ctx: &Context, file: &DataFile) -> PathBuf {
+    duplicates(ctx).join(format!("{}_{}{}", ctx.ts(), file.shortname(), DUPLICATES))
 }
 
 ///
@@ -317,6 +582,10 @@ pub fn unsorted_index(ctx: &Context) -> PathBuf {
     matching(ctx).join("index.unsorted.csv")
 }
 
+pub fn unmatched_index(ctx: &Context) -> PathBuf {
+    matching(ctx).join("unmatched.unsorted.csv")
+}
+
 ///
 /// Returns true if the file starts with a datetime prefix in the form 'YYYYMMDD_HHmmSSsss_' and ends with
 /// a '.csv' suffix.
@@ -0,0 +1,43 @@
+use std::time::Instant;
+use core::{blue, formatted_duration_rate, lua::init_context};
+use crate::{error::MatcherError, folders, lua, matching::matched::MatchedHandler, model::grid::Grid, Context};
+
+///
+/// Evaluate the Lua script against every unmatched record in the grid. Records the script returns true for are
+/// marked as filtered, so they won't be grouped by subsequent instructions or written to an unmatched file.
+///
+/// Returns the number of records filtered.
+///
+pub fn filter_records(ctx: &Context, script: &str, grid: &Grid, matched: &mut MatchedHandler) -> Result<usize, MatcherError> {
+
+    log::info!("Filtering records where {}", script);
+
+    let start = Instant::now();
+    let mut count = 0;
+    let mut filtered = 0;
+
+    ctx.lua().context(|lua_ctx| {
+        init_context(&lua_ctx, ctx.charter().global_lua(), &folders::lookups(ctx))?;
+
+        for record in grid.iter(ctx) {
+            count += 1;
+
+            if !lua::lua_filter(&[&record], script, &lua_ctx, grid.schema())?.is_empty() {
+                matched.set_filtered_status(&[&record])?;
+                filtered += 1;
+            }
+        }
+
+        Ok(())
+    })
+    .map_err(|source| MatcherError::FilterError { source })?;
+
+    let (duration, rate) = formatted_duration_rate(count, start.elapsed());
+    log::info!("Filtered {} out of {} records in {} ({}/row)",
+        blue(&format!("{}", filtered)),
+        blue(&format!("{}", count)),
+        blue(&duration),
+        rate);
+
+    Ok(filtered)
+}
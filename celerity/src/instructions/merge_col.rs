@@ -2,9 +2,21 @@ use core::data_type::DataType;
 use crate::{error::MatcherError, model::grid::Grid};
 
 ///
-/// Ensure each source column exists in the grid and has the same datatype.
+/// Ensure each source column exists in the grid and has the same datatype - or, if coerce_to is specified, a datatype
+/// which can be coerced to it.
 ///
-pub fn validate(source: &[String], grid: &mut Grid) -> Result<DataType, MatcherError> {
+pub fn validate(source: &[String], coerce_to: Option<DataType>, grid: &mut Grid) -> Result<DataType, MatcherError> {
+
+    if let Some(coerce_to) = coerce_to {
+        for header in source {
+            match grid.schema().data_type(header) {
+                Some(dt) if can_coerce(*dt, coerce_to) => {},
+                Some(dt) => return Err(MatcherError::CannotCoerceSourceDataType { header: header.into(), this_type: *dt, coerce_to }),
+                None => continue, // As below, the source column may not be present.
+            }
+        }
+        return Ok(coerce_to)
+    }
 
     let mut data_type = DataType::Unknown;
 
@@ -32,4 +44,11 @@ pub fn validate(source: &[String], grid: &mut Grid) -> Result<DataType, MatcherE
     }
 
     Ok(data_type)
-}
\ No newline at end of file
+}
+
+///
+/// Only numeric widening is supported, i.e. an Integer can be merged into a Decimal column.
+///
+pub fn can_coerce(from: DataType, to: DataType) -> bool {
+    from == to || (from == DataType::Integer && to == DataType::Decimal)
+}
@@ -1,2 +1,54 @@
+pub mod filter;
 pub mod merge_col;
-pub mod project_col;
\ No newline at end of file
+pub mod project_col;
+pub mod sort;
+
+use itertools::Itertools;
+use core::charter::{Charter, Instruction, DATE_ONLY};
+use crate::{error::MatcherError, model::schema::GridSchema};
+
+///
+/// Ensure no enabled instruction relies on a column which would have been derived by a disabled instruction.
+///
+pub fn validate_disabled(charter: &Charter) -> Result<(), MatcherError> {
+    let mut disabled = vec!();
+
+    for (idx, inst) in charter.instructions().iter().enumerate() {
+        match inst {
+            Instruction::Project { column, enabled: false, .. } => disabled.push(column.as_str()),
+            Instruction::Merge { into, enabled: false, .. } => disabled.push(into.as_str()),
+            Instruction::Rename { to, enabled: false, .. } => disabled.push(to.as_str()),
+            inst if inst.enabled() => {
+                if let Some(column) = inst.referenced_columns().into_iter().find(|header| disabled.contains(&header.as_str())) {
+                    return Err(MatcherError::DisabledColumnReferenced { column, instruction: idx })
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Ensure every enabled group instruction's group-by columns exist before any records are grouped. Otherwise a
+/// misspelt column would only be found when the first record's key is built.
+///
+pub fn validate_group_by(charter: &Charter, schema: &GridSchema) -> Result<(), MatcherError> {
+    for (idx, inst) in charter.instructions().iter().enumerate() {
+        if let Instruction::Group { by, enabled: true, .. } = inst {
+            for header in by {
+                let column = header.strip_suffix(DATE_ONLY).unwrap_or(header);
+
+                if !schema.headers().iter().any(|existing| existing == column) {
+                    return Err(MatcherError::UnknownGroupByColumn {
+                        column: column.to_string(),
+                        instruction: idx,
+                        headers: schema.headers().iter().join(", ") })
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,23 +1,40 @@
+use bytes::Bytes;
 use itertools::Itertools;
 use core::{data_type::DataType, lua::{LuaDecimal, eval}};
-use crate::{error::MatcherError, model::{schema::{Column, GridSchema}, record::Record}, lua};
+use crate::{error::MatcherError, model::{schema::{Column, GridSchema}, record::Record}, lua, utils::convert};
 
+///
+/// Evaluate the projection's Lua script and append the result to the record's derived data.
+///
+/// If the projection is a constant, the literal value is appended instead and no Lua is evaluated (other than the
+/// optional when script).
+///
+/// The global Lua record table must already have been populated for this record (see derive_file) - it's built once
+/// per row and shared by every projection rather than being rebuilt for each one.
+///
+/// The script may also assign to columns derived earlier in the row, e.g. record["Total"] = record["Total"] * 2, in
+/// which case the assigned values replace the record's existing derived values.
+///
 pub fn project_column(
     data_type: DataType,
-    lua: &str,
+    from: &Option<String>,
+    constant: &Option<String>,
     when: &Option<String>,
+    assigned: &[Column],
     record: &mut Record,
-    avail_cols: &[Column],
     lua_ctx: &rlua::Context) -> Result<(), MatcherError> {
 
-    let globals = lua_ctx.globals();
-
-    let lua_record = lua::lua_record(record, avail_cols, lua_ctx)?;
-    globals.set("record", lua_record)?;
-
     // Evalute the WHEN script to see if we should even evaluate the EVAL script. This allows us to skip
     // attempting to calulate values that are not relevant to the record without having to write verbose scripts.
     if when.is_none() || eval(lua_ctx, when.as_ref().expect("weird"))? {
+        // A constant has been validated against the data type already, so can be used as-is.
+        if let Some(constant) = constant {
+            record.append_string(constant);
+            return Ok(())
+        }
+
+        let lua = from.as_deref().unwrap_or_default();
+
         // Now calculate the column value and append it to the underlying ByteRecord.
         match data_type {
             DataType::Unknown  => {},
@@ -28,6 +45,14 @@ pub fn project_column(
             DataType::String   => record.append_string(&eval::<String>(lua_ctx, lua)?),
             DataType::Uuid     => record.append_uuid(eval::<String>(lua_ctx, lua).map(|s|s.parse().expect("Lua returned an invalid uuid"))?),
         };
+
+        // Copy any values the script assigned to earlier derived columns back into the record.
+        if !assigned.is_empty() {
+            let lua_record: rlua::Table = lua_ctx.globals().get("record")?;
+            for col in assigned {
+                record.update_derived(col.header(), &lua::get_lua_field(col, &lua_record)?)?;
+            }
+        }
     } else {
         // Put a blank value in the projected column if we're not evaluating it.
         record.append_string("");
@@ -36,15 +61,50 @@ pub fn project_column(
     Ok(())
 }
 
+///
+/// Ensure a constant projection's value can be parsed as the column's data type.
+///
+pub fn validate_constant(column: &str, data_type: DataType, value: &str) -> Result<(), MatcherError> {
+    let bytes = Bytes::copy_from_slice(value.as_bytes());
+
+    let valid = match data_type {
+        DataType::Unknown  => false,
+        DataType::Boolean  => convert::csv_bytes_to_bool(bytes).is_ok(),
+        DataType::Datetime => convert::csv_bytes_to_datetime(bytes).is_ok(),
+        DataType::Decimal  => convert::csv_bytes_to_decimal(bytes).is_ok(),
+        DataType::Integer  => convert::csv_bytes_to_int(bytes).is_ok(),
+        DataType::String   => true,
+        DataType::Uuid     => convert::csv_bytes_to_uuid(bytes).is_ok(),
+    };
+
+    match valid {
+        true  => Ok(()),
+        false => Err(MatcherError::InvalidConstant { column: column.into(), value: value.into(), data_type: data_type.as_str().into() }),
+    }
+}
+
+///
+/// Return the derived columns the projection's script assigns to. Only columns derived by an earlier projection or
+/// merge can be assigned to - sourced data can't be modified by a projection.
+///
+pub fn assigned_cols(column: &str, from: Option<&str>, schema: &GridSchema) -> Result<Vec<Column>, MatcherError> {
+    from.map(lua::assigned_headers)
+        .unwrap_or_default()
+        .iter()
+        .map(|header| match schema.derived_columns().into_iter().find(|col| col.header() == header && header != column) {
+            Some(col) => Ok(col.clone()),
+            None => Err(MatcherError::CannotAssignColumn { column: column.into(), header: header.clone() }),
+        })
+        .collect()
+}
+
 ///
 /// Return the columns involved in any Lua script for this projection.
 ///
-pub fn referenced_cols(eval: &str, when: Option<&str>, schema: &GridSchema) -> Vec<Column> {
-    match when {
-        Some(when) => vec!(lua::referenced_columns(eval, schema), lua::referenced_columns(when, schema)),
-        None => vec!(lua::referenced_columns(eval, schema)),
-    }.concat()
-        .into_iter()
+pub fn referenced_cols(eval: Option<&str>, when: Option<&str>, schema: &GridSchema) -> Vec<Column> {
+    eval.into_iter()
+        .chain(when)
+        .flat_map(|script| lua::referenced_columns(script, schema))
         .unique()
         .collect::<Vec<Column>>()
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+use rust_decimal::Decimal;
+use core::data_type::DataType;
+use crate::{error::MatcherError, model::{record::Record, schema::GridSchema}};
+
+///
+/// The order the members of a matched group are written to the matched job file.
+///
+#[derive(Clone, Debug)]
+pub struct GroupOrder {
+    by: Vec<String>,
+    descending: bool,
+}
+
+///
+/// A typed value to sort on. Values of the same column always share a variant, an absent value sorts first.
+///
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Boolean(bool),
+    Datetime(u64),
+    Decimal(Decimal),
+    Integer(i64),
+    String(String),
+    Uuid(uuid::Uuid),
+}
+
+impl GroupOrder {
+    ///
+    /// Every sort column must be in the grid by the time the instruction is reached.
+    ///
+    pub fn new(by: &[String], descending: bool, schema: &GridSchema) -> Result<Self, MatcherError> {
+        if let Some(column) = by.iter().find(|column| schema.data_type(column).is_none()) {
+            return Err(MatcherError::SortColumnMissing { column: column.clone() })
+        }
+
+        Ok(Self { by: by.to_vec(), descending })
+    }
+
+    ///
+    /// Order the records by each sort column in turn, comparing values by the column's data type. The sort is
+    /// stable, records with equal values keep their existing relative order.
+    ///
+    pub fn sort<'a>(&self, records: &[&'a Record]) -> Result<Vec<&'a Record>, MatcherError> {
+        let mut keyed = records.iter()
+            .map(|record| Ok((self.key(record)?, *record)))
+            .collect::<Result<Vec<(Vec<Option<SortValue>>, &Record)>, MatcherError>>()?;
+
+        keyed.sort_by(|(k1, _), (k2, _)| match self.descending {
+            true  => k2.cmp(k1),
+            false => k1.cmp(k2),
+        });
+
+        Ok(keyed.into_iter().map(|(_, record)| record).collect())
+    }
+
+    fn key(&self, record: &Record) -> Result<Vec<Option<SortValue>>, MatcherError> {
+        self.by.iter()
+            .map(|header| Ok(match record.schema().data_type(header) {
+                Some(DataType::Boolean)  => record.get_bool(header)?.map(SortValue::Boolean),
+                Some(DataType::Datetime) => record.get_datetime(header)?.map(SortValue::Datetime),
+                Some(DataType::Decimal)  => record.get_decimal(header)?.map(SortValue::Decimal),
+                Some(DataType::Integer)  => record.get_int(header)?.map(SortValue::Integer),
+                Some(DataType::String)   => record.get_string(header)?.map(SortValue::String),
+                Some(DataType::Uuid)     => record.get_uuid(header)?.map(SortValue::Uuid),
+                Some(DataType::Unknown)  => return Err(MatcherError::UnknownDataTypeForHeader { header: header.clone() }),
+                None                     => None,
+            }))
+            .collect()
+    }
+}
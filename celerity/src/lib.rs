@@ -2,23 +2,27 @@ mod lua;
 mod utils;
 mod error;
 mod model;
+mod shadow;
 mod folders;
 mod matching;
 mod changeset;
+mod manifest;
 mod instructions;
 
 use uuid::Uuid;
+use ubyte::ToByteUnit;
 use error::MatcherError;
 use itertools::Itertools;
 use changeset::ChangeSet;
+use instructions::sort::GroupOrder;
 use utils::csv::CsvWriters;
 use model::schema::GridSchema;
 use folders::ToCanoncialString;
 use anyhow::{Result, Context as ErrContext};
 use rayon::iter::{IntoParallelRefMutIterator, IndexedParallelIterator, ParallelIterator};
-use core::{charter::{Charter, Instruction}, blue, formatted_duration_rate, lua::init_context};
-use std::{time::{Instant, Duration}, collections::HashMap, cell::Cell, path::{PathBuf, Path}, str::FromStr, sync::Arc};
-use crate::{model::{grid::Grid, schema::Column, record::Record}, instructions::{project_col::{project_column, referenced_cols}, merge_col}, matching::matched::MatchedHandler, matching::unmatched::UnmatchedHandler, utils::csv::{CsvReader, CsvWriter}};
+use core::{charter::{Charter, Instruction}, lock::JobLock, blue, formatted_duration_rate, lua::{init_context, set_timeout, validate_global_lua}};
+use std::{time::{Instant, Duration}, collections::HashMap, cell::Cell, path::{PathBuf, Path}, str::FromStr, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+use crate::{model::{grid::Grid, schema::Column, record::Record}, instructions::{project_col::{self, project_column, referenced_cols}, merge_col}, matching::{Grouping, matched::{MatchedHandler, MatchedGroup}}, matching::unmatched::UnmatchedHandler, utils::csv::{CsvReader, CsvWriter}};
 
 ///
 /// These are the linear state transitions of a match Job.
@@ -63,6 +67,8 @@ pub struct Context {
     timestamp: String,     // A unique timestamp to prefix any generated files with for this job.
     lua: rlua::Lua,        // Lua engine state.
     phase: Cell<Phase>,    // The current point in the linear state transition of the job.
+    capture_groups: bool,  // Keep matched group members in memory (for shadow runs).
+    cancel: Arc<AtomicBool>, // Set by the caller to stop the job early.
 }
 
 impl Context {
@@ -72,6 +78,12 @@ impl Context {
             Err(_) => uuid::Uuid::new_v4(),
         };
 
+        core::logging::set_field("job_id", &job_id.to_hyphenated().to_string());
+        core::logging::set_field("phase", &format!("{:?}", Phase::FolderInitialisation));
+
+        let lua = rlua::Lua::new();
+        set_timeout(&lua, charter.lua_timeout_ms());
+
         Self {
             started: Instant::now(),
             job_id,
@@ -79,8 +91,10 @@ impl Context {
             charter_path,
             base_dir,
             timestamp: folders::new_timestamp(),
-            lua: rlua::Lua::new(),
+            lua,
             phase: Cell::new(Phase::FolderInitialisation),
+            capture_groups: false,
+            cancel: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -116,8 +130,40 @@ impl Context {
         self.phase.get()
     }
 
-    pub fn set_phase(&self, phase: Phase) {
+    ///
+    /// Move the job to the next phase and record it in the job.status file, so a job which fails or crashes leaves
+    /// a trace of where it got to. The file is removed when the job completes.
+    ///
+    pub fn set_phase(&self, phase: Phase) -> Result<(), MatcherError> {
+        if !matches!(phase, Phase::Complete) {
+            self.check_cancelled()?;
+        }
+        core::logging::set_field("phase", &format!("{:?}", phase));
         self.phase.set(phase);
+        folders::write_job_status(self)
+    }
+
+    pub fn capture_groups(&self) -> bool {
+        self.capture_groups
+    }
+
+    pub fn set_capture_groups(&mut self, capture_groups: bool) {
+        self.capture_groups = capture_groups;
+    }
+
+    pub fn set_cancel(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = cancel;
+    }
+
+    pub fn cancel(&self) -> &AtomicBool {
+        &self.cancel
+    }
+
+    ///
+    /// Fail with a JobCancelled error if the job's cancellation flag has been set.
+    ///
+    pub fn check_cancelled(&self) -> Result<(), MatcherError> {
+        check_cancelled(&self.cancel, self.phase())
     }
 }
 
@@ -125,49 +171,136 @@ impl Context {
 ///
 /// Create a new match job and run the charter.
 ///
-/// If this library is used as part of a wider solution, care must be taken to synchronise these match jobs
-/// so only one can exclusively run against a given charter/folder of data at any one time.
+/// The base dir is locked for the duration of the job, if another job already holds the lock this one will fail.
 ///
 pub fn run_charter<P: AsRef<Path>>(charter: P, base_dir: P) -> Result<()> {
 
     let ctx = init_job(charter, base_dir)?;
+    let _lock = lock_base_dir(&ctx)?;
+    run_job(&ctx, None)?;
+    Ok(())
+}
 
-    ctx.set_phase(Phase::FolderInitialisation);
-    init_folders(&ctx)?;
-
-    ctx.set_phase(Phase::ApplyChangeSets);
-    let (mut grid, changesets) = apply_changesets(&ctx/* , grid */)?;
+///
+/// Run the charter as normal, but stop the job early if the cancel flag is set (e.g. from another thread). The flag is
+/// checked between phases and between records when deriving and matching data.
+///
+/// A cancelled job rolls back any in-progress files and fails with a JobCancelled error. The data files are left in
+/// the matching folder for the next job to pick up.
+///
+pub fn run_charter_cancellable<P: AsRef<Path>>(charter: P, base_dir: P, cancel: Arc<AtomicBool>) -> Result<()> {
 
-    ctx.set_phase(Phase::DeriveSchema);
-    let (projection_cols, writers) = create_derived_schema(&ctx, &mut grid)?;
+    let mut ctx = init_job(charter, base_dir)?;
+    ctx.set_cancel(cancel);
+    let _lock = lock_base_dir(&ctx)?;
 
-    ctx.set_phase(Phase::DeriveData);
-    derive_data(&ctx, &grid, projection_cols, writers)?;
+    if let Err(err) = run_job(&ctx, None) {
+        if let Some(MatcherError::JobCancelled { .. }) = err.downcast_ref::<MatcherError>() {
+            log::warn!("Match job {} was cancelled, rolling back", ctx.job_id());
+            folders::rollback_any_incomplete(&ctx)?;
+        }
+        return Err(err)
+    }
 
-    ctx.set_phase(Phase::MatchAndGroup);
-    let (matched, unmatched) = match_and_group(&ctx, &mut grid)?;
+    Ok(())
+}
 
-    ctx.set_phase(Phase::ComleteAndArchive);
-    complete_and_archive(&ctx, grid, matched, unmatched, changesets)?;
+///
+/// Run a charter which has already been loaded, e.g. with Charter::from_str. The label stands in for the charter's
+/// path in the logs and the matched job file.
+///
+pub fn run_loaded_charter<P: AsRef<Path>>(charter: Charter, label: &str, base_dir: P) -> Result<()> {
 
-    ctx.set_phase(Phase::Complete);
+    let base_dir_pb = base_dir.as_ref().to_path_buf().canonicalize().with_context(|| format!("base dir {:?}", base_dir.as_ref()))?;
+    let ctx = new_job(charter, PathBuf::from(label), base_dir_pb);
+    let _lock = lock_base_dir(&ctx)?;
+    run_job(&ctx, None)?;
     Ok(())
 }
 
+///
+/// Check the charter without running it or touching any data folders. Every problem found is returned, including Lua
+/// scripts which don't compile, an empty list means the charter is valid.
+///
+pub fn validate_charter<P: AsRef<Path>>(charter: P) -> Result<Vec<String>> {
+    let file = std::fs::File::open(charter.as_ref()).with_context(|| format!("charter path {:?}", charter.as_ref()))?;
+    Ok(Charter::validate(std::io::BufReader::new(file), &charter.as_ref().to_string_lossy()))
+}
+
+///
+/// Run the charter as normal, but first match a candidate charter against the same loaded and derived data. The
+/// matched groups from both are compared and the differences written to a shadow_diff.json file in the matched folder.
+///
+/// Only the primary charter's results are kept, the candidate's groups are only used for the comparison.
+///
+pub fn run_shadow<P: AsRef<Path>>(charter: P, candidate: P, base_dir: P) -> Result<()> {
+    shadow::run(charter.as_ref(), candidate.as_ref(), base_dir.as_ref())
+}
+
+///
+/// Run each phase of the job in turn. Returns any matched groups captured by the context and, if a shadow candidate
+/// charter was given and ran successfully, the groups it matched.
+///
+fn run_job(ctx: &Context, candidate: Option<&Context>) -> Result<(Vec<MatchedGroup>, Option<Vec<MatchedGroup>>)> {
+
+    // Report any error in the global Lua now, rather than part way through deriving or matching the data.
+    validate_global_lua(ctx.charter().global_lua(), &folders::lookups(ctx))
+        .map_err(|reason| MatcherError::InvalidGlobalLua { reason })?;
+
+    ctx.set_phase(Phase::FolderInitialisation)?;
+    init_folders(ctx)?;
+
+    ctx.set_phase(Phase::ApplyChangeSets)?;
+    let (mut grid, changesets) = apply_changesets(ctx/* , grid */)?;
+
+    ctx.set_phase(Phase::DeriveSchema)?;
+    let (projection_cols, writers) = create_derived_schema(ctx, &mut grid)?;
+
+    ctx.set_phase(Phase::DeriveData)?;
+    derive_data(ctx, &grid, projection_cols, writers)?;
+
+    ctx.set_phase(Phase::MatchAndGroup)?;
+    let candidate_groups = match candidate {
+        Some(candidate) => shadow::match_candidate(ctx, candidate, &mut grid)?,
+        None => None,
+    };
+    let (matched, unmatched) = match_and_group(ctx, &mut grid)?;
+
+    ctx.set_phase(Phase::ComleteAndArchive)?;
+    let captured = complete_and_archive(ctx, grid, matched, unmatched, changesets)?;
+
+    ctx.set_phase(Phase::Complete)?;
+    Ok((captured, candidate_groups))
+}
+
 ///
 /// Parse and load the charter configuration, return a job Context.
 ///
 fn init_job<P: AsRef<Path>>(charter: P, base_dir: P) -> Result<Context, MatcherError> {
     let charter_pb = charter.as_ref().to_path_buf().canonicalize().with_context(|| format!("charter path {:?}", charter.as_ref()))?;
     let base_dir_pb = base_dir.as_ref().to_path_buf().canonicalize().with_context(|| format!("base dir {:?}", base_dir.as_ref()))?;
-    let ctx = Context::new(Charter::load(&charter_pb)?, charter_pb, base_dir_pb);
+    Ok(new_job(Charter::load(&charter_pb)?, charter_pb, base_dir_pb))
+}
+
+///
+/// Create the Context for a new job and log it's details.
+///
+fn new_job(charter: Charter, charter_path: PathBuf, base_dir: PathBuf) -> Context {
+    let ctx = Context::new(charter, charter_path, base_dir);
 
     log::info!("Starting match job:");
     log::info!("    Job ID: {}", ctx.job_id());
     log::info!("   Charter: {} (v{})", ctx.charter().name(), ctx.charter().version());
     log::info!("  Base dir: {}", ctx.base_dir().to_canoncial_string());
 
-    Ok(ctx)
+    ctx
+}
+
+///
+/// Take an exclusive lock on the base dir, released when the returned lock is dropped.
+///
+fn lock_base_dir(ctx: &Context) -> Result<JobLock, MatcherError> {
+    JobLock::acquire(ctx.base_dir(), ctx.charter().stale_lock()).map_err(MatcherError::JobLockError)
 }
 
 ///
@@ -205,25 +338,47 @@ fn create_derived_schema(ctx: &Context, grid: &mut Grid) -> Result<(HashMap<usiz
     // Debug the grid before the new columns are added.
     grid.debug_grid(ctx, 1);
 
+    // Fail now, rather than mid-job, if a disabled instruction would leave a later instruction short of a column.
+    instructions::validate_disabled(ctx.charter())?;
+
     let mut projection_cols = HashMap::new();
 
     for (idx, inst) in ctx.charter().instructions().iter().enumerate() {
         let schema = grid.schema().clone();
         match inst {
-            Instruction::Project { column, as_a, from, when } => {
-                projection_cols.insert(idx, referenced_cols(from, when.as_ref().map(String::as_ref), &schema));
+            Instruction::Project { column, enabled: false, .. } => log::info!("Skipping disabled projection of column {}", column),
+            Instruction::Merge { into, enabled: false, .. } => log::info!("Skipping disabled merge into column {}", into),
+            Instruction::Rename { from, to, enabled: false } => log::info!("Skipping disabled rename of column {} to {}", from, to),
+            Instruction::Project { column, as_a, from, constant, when, .. } => {
+                if let Some(constant) = constant {
+                    project_col::validate_constant(column, *as_a, constant)?;
+                }
+                project_col::assigned_cols(column, from.as_deref(), &schema)?;
+                projection_cols.insert(idx, referenced_cols(from.as_deref(), when.as_deref(), &schema));
                 grid.schema_mut().add_projected_column(Column::new(column.into(), None, *as_a))?;
             },
-            Instruction::Merge { into, columns } => {
-                let data_type = merge_col::validate(columns, grid)?;
+            Instruction::Merge { into, columns, coerce_to, .. } => {
+                let data_type = merge_col::validate(columns, *coerce_to, grid)?;
                 grid.schema_mut().add_merged_column(Column::new(into.into(), None, data_type))?;
             },
+            Instruction::Rename { from, to, .. } => {
+                // As with merges, the column may not be present if there's no file for it in this job.
+                match grid.schema().headers().iter().any(|header| header == from) {
+                    true  => grid.schema_mut().add_alias(from, to)?,
+                    false => log::info!("Column {} isn't present and won't be renamed to {}", from, to),
+                }
+            },
             _ => { /* Ignore other instructions. */}
         }
     }
 
+    // Now the grid has all its columns, fail before reading any data if a group-by column doesn't exist.
+    if !grid.is_empty() {
+        instructions::validate_group_by(ctx.charter(), grid.schema())?;
+    }
+
     // Now we know what columns are derived, write their headers to the .derived files.
-    let mut writers = derived_writers(grid);
+    let mut writers = derived_writers(ctx, grid);
     write_derived_headers(grid.schema(), &mut writers)?;
 
     // Debug the grid after the columns are added (but before values are derived).
@@ -235,11 +390,11 @@ fn create_derived_schema(ctx: &Context, grid: &mut Grid) -> Result<(HashMap<usiz
 ///
 /// Create a csv::Writer<File> for every sourced data file - it should point to the derived csv file.
 ///
-fn derived_writers(grid: &Grid) -> CsvWriters {
+fn derived_writers(ctx: &Context, grid: &Grid) -> CsvWriters {
     grid.schema()
         .files()
         .iter()
-        .map(|f| utils::csv::writer(f.derived_path()))
+        .map(|f| utils::csv::output_writer(f.derived_path(), ctx.charter()))
         .collect::<CsvWriters>()
 }
 
@@ -265,20 +420,17 @@ fn write_derived_headers(schema: &GridSchema, writers: &mut CsvWriters) -> Resul
 /// file. Every corresponding row in the source files will have a row in the derived files which contains
 /// projected and merged column data.
 ///
-/// This implementation uses rayon to create a thread per file.
+/// This implementation uses rayon to create a thread per file (up to the charter's derive_threads). If derive_threads
+/// is 1, the files are derived one at a time, sharing a single Lua context, to use the least memory.
 ///
 fn derive_data(ctx: &Context, grid: &Grid, projection_cols: HashMap<usize, Vec<Column>>, writers: CsvWriters)
     -> Result<(), MatcherError> {
 
     log::info!("Deriving projected and merged data");
 
-    type Metrics = HashMap<usize, Duration>; // Accumulated duration per instruction.
+    let started = Instant::now();
 
-    // We need one thread per file.
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(std::cmp::min(grid.schema().files().len(), num_cpus::get()))
-        .build()
-        .expect("can't build rayon thread pool");
+    type Metrics = HashMap<usize, Duration>; // Accumulated duration per instruction.
 
     // Create a data reader per sourced file. Skip the schema rows.
     let readers: Vec<CsvReader> = grid.schema()
@@ -293,41 +445,82 @@ fn derive_data(ctx: &Context, grid: &Grid, projection_cols: HashMap<usize, Vec<C
     let schema = Arc::new(grid.schema().clone());
     let charter = Arc::new(ctx.charter());
     let lookup_path = folders::lookups(ctx);
-
-    pool.install::<_, Result<(), MatcherError>>(|| {
-        // Derive each file in a parallel iterator.
-        let results = zipped
-            .par_iter_mut()
-            .enumerate()
-            .map(|(file_idx, (reader, writer))| {
-                derive_file(file_idx, reader, writer, schema.clone(), &charter, projection_cols.clone(), &lookup_path)
-            })
-            .collect::<Result<Vec<Metrics>, MatcherError>>()?
-            .into_iter() // Result IntoIterator takes the R not the E
-            .collect::<Vec<Metrics>>();
-
-        // Accumulate all of the time spent per instruction across all derived files.
-        let mut total_metrics = Metrics::new();
-        results.into_iter()
-            .for_each(|metric| merge_metrics(metric, &mut total_metrics));
-
-        // Report the duration spent performing each projection and merge instruction.
-        for idx in total_metrics.keys().sorted_by(Ord::cmp) {
-            let (duration, rate) = formatted_duration_rate(grid.len(), *total_metrics.get(idx).expect("Duration metric missing"));
-
-            match &charter.instructions()[*idx] {
-                Instruction::Project { column, .. } => log::info!("Projecting Column {} took {} ({}/row)", column, blue(&duration), rate),
-                Instruction::Merge { into, .. } => log::info!("Merging Column {} took {} ({}/row)", into, blue(&duration), rate),
-                _ => {},
-            }
+    let cancel = ctx.cancel();
+
+    let results = match charter.derive_threads() {
+        // Derive each file in turn with a single Lua context, for the lowest memory usage.
+        Some(1) => {
+            log::info!("Deriving files serially");
+            let lua = rlua::Lua::new();
+            set_timeout(&lua, charter.lua_timeout_ms());
+            let mut eval_ctx = (0, 0, 0);
+
+            lua.context(|lua_ctx| {
+                init_context(&lua_ctx, charter.global_lua(), &lookup_path)?;
+                zipped.iter_mut()
+                    .enumerate()
+                    .map(|(file_idx, (reader, writer))| {
+                        derive_records(file_idx, reader, writer, schema.clone(), &charter, &projection_cols, &lua_ctx, cancel, &mut eval_ctx)
+                    })
+                    .collect::<Result<Vec<Metrics>, MatcherError>>()
+
+            }).map_err(|err| derive_error(&charter, &schema, eval_ctx, err))?
+        },
+
+        // We need one thread per file, up to the number of cores (or derive_threads). The pool is local to this job
+        // (rather than rayon's global pool, which can only be built once) so any number of charters can be run in the
+        // same process.
+        threads => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(std::cmp::min(grid.schema().files().len(), threads.unwrap_or_else(num_cpus::get)))
+                .build()
+                .expect("can't build rayon thread pool");
+
+            pool.install::<_, Result<Vec<Metrics>, MatcherError>>(|| {
+                // Derive each file in a parallel iterator.
+                zipped
+                    .par_iter_mut()
+                    .enumerate()
+                    .map(|(file_idx, (reader, writer))| {
+                        derive_file(file_idx, reader, writer, schema.clone(), &charter, &projection_cols, &lookup_path, cancel)
+                    })
+                    .collect::<Result<Vec<Metrics>, MatcherError>>()
+            })?
+        },
+    };
+
+    // Accumulate all of the time spent per instruction across all derived files.
+    let mut total_metrics = Metrics::new();
+    results.into_iter()
+        .for_each(|metric| merge_metrics(metric, &mut total_metrics));
+
+    // Report the duration spent performing each projection and merge instruction.
+    for idx in total_metrics.keys().sorted_by(Ord::cmp) {
+        let (duration, rate) = formatted_duration_rate(grid.len(), *total_metrics.get(idx).expect("Duration metric missing"));
+
+        match &charter.instructions()[*idx] {
+            Instruction::Project { column, .. } => log::info!("Projecting Column {} took {} ({}/row)", column, blue(&duration), rate),
+            Instruction::Merge { into, .. } => log::info!("Merging Column {} took {} ({}/row)", into, blue(&duration), rate),
+            _ => {},
         }
+    }
 
-        Ok(())
-    })?;
+    let (duration, rate) = formatted_duration_rate(grid.len(), started.elapsed());
+    log::info!("Derived data for {} rows in {} ({}/row)", grid.len(), blue(&duration), rate);
 
     Ok(())
 }
 
+///
+/// Fail with a JobCancelled error if the cancel flag has been set.
+///
+fn check_cancelled(cancel: &AtomicBool, phase: Phase) -> Result<(), MatcherError> {
+    match cancel.load(Ordering::Relaxed) {
+        true  => Err(MatcherError::JobCancelled { phase: format!("{:?}", phase) }),
+        false => Ok(()),
+    }
+}
+
 fn merge_metrics(merge: HashMap<usize, Duration>, into: &mut HashMap<usize, Duration>) {
     for (km, vm) in merge {
         *into.entry(km).or_insert(Duration::ZERO) += vm;
@@ -335,62 +528,139 @@ fn merge_metrics(merge: HashMap<usize, Duration>, into: &mut HashMap<usize, Dura
 }
 
 ///
-/// Derive all the data in a single file.
+/// Derive all the data in a single file with its own Lua context.
 ///
+#[allow(clippy::too_many_arguments)]
 fn derive_file(
     file_idx: usize,
     reader: &mut CsvReader,
     writer: &mut CsvWriter,
     schema: Arc<GridSchema>,
     charter: &Charter,
-    avail_cols: HashMap<usize, Vec<Column>>,
-    lookup_path: &Path) -> Result<HashMap<usize, Duration>, MatcherError> {
-
-    // Track accumulated time in each project and merge instruction.
-    let mut metrics: HashMap<usize, Duration> = HashMap::new();
+    avail_cols: &HashMap<usize, Vec<Column>>,
+    lookup_path: &Path,
+    cancel: &AtomicBool) -> Result<HashMap<usize, Duration>, MatcherError> {
 
     // Track the record and instruction being processed. Used in logs should an error occur.
     let mut eval_ctx = (file_idx /* file */, 0 /* row */, 0 /* instruction */);
 
     let lua = rlua::Lua::new();
+    set_timeout(&lua, charter.lua_timeout_ms());
 
     lua.context(|lua_ctx| {
         init_context(&lua_ctx, charter.global_lua(), lookup_path)?;
-        for csv_record in reader.byte_records() {
-            let mut record = Record::new(file_idx, schema.clone(), csv_record?, csv::ByteRecord::new());
-
-            for (i_idx, inst) in charter.instructions().iter().enumerate() {
-                let started = Instant::now();
-                eval_ctx = (file_idx, record.row(), i_idx);
-
-                match inst {
-                    Instruction::Project { column: _, as_a, from, when } => {
-                        let avail_cols = avail_cols.get(&i_idx).ok_or(MatcherError::MissingScriptCols { instruction: i_idx })?;
-                        project_column(*as_a, from, &when, &mut record, avail_cols, &lua_ctx)?;
-                        record_duration(i_idx, &mut metrics, started.elapsed());
-                    },
-
-                    Instruction::Merge { into: _, columns } => {
-                        record.merge_col_from(columns)?;
-                        record_duration(i_idx, &mut metrics, started.elapsed());
-                    },
-
-                    _ => {}, // Ignore other instructions in this phase.
-                };
+        derive_records(file_idx, reader, writer, schema.clone(), charter, avail_cols, &lua_ctx, cancel, &mut eval_ctx)
+
+    }).map_err(|err| derive_error(charter, &schema, eval_ctx, err))
+}
+
+///
+/// Derive all the data in a single file using the Lua context given, tracking the file, row and instruction being
+/// evaluated in eval_ctx.
+///
+#[allow(clippy::too_many_arguments)]
+fn derive_records(
+    file_idx: usize,
+    reader: &mut CsvReader,
+    writer: &mut CsvWriter,
+    schema: Arc<GridSchema>,
+    charter: &Charter,
+    avail_cols: &HashMap<usize, Vec<Column>>,
+    lua_ctx: &rlua::Context,
+    cancel: &AtomicBool,
+    eval_ctx: &mut (usize, usize, usize)) -> Result<HashMap<usize, Duration>, MatcherError> {
+
+    // Track accumulated time in each project and merge instruction.
+    let mut metrics: HashMap<usize, Duration> = HashMap::new();
+
+    // Every column any projection uses. The Lua record is built from these once per row and shared by all projections.
+    let lua_cols: Vec<Column> = avail_cols.values().flatten().unique().cloned().collect();
+    let has_projections = charter.instructions().iter().any(|inst| inst.enabled() && matches!(inst, Instruction::Project { from: Some(_), .. } | Instruction::Project { when: Some(_), .. }));
+
+    // The earlier derived columns each projection assigns to (validated when the derived schema was created).
+    let assigned = charter.instructions()
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, inst)| match inst {
+            Instruction::Project { column, from, enabled: true, .. } => Some(project_col::assigned_cols(column, from.as_deref(), &schema).map(|cols| (idx, cols))),
+            _ => None,
+        })
+        .collect::<Result<HashMap<usize, Vec<Column>>, MatcherError>>()?;
+
+    *eval_ctx = (file_idx, 0, 0);
+
+    for csv_record in reader.byte_records() {
+        check_cancelled(cancel, Phase::DeriveData)?;
+        let mut record = Record::new(file_idx, schema.clone(), csv_record?, csv::ByteRecord::new());
+
+        let lua_record = match has_projections {
+            true => {
+                let lua_record = lua::lua_record(&record, &lua_cols, lua_ctx)?;
+                lua_ctx.globals().set("record", lua_record.clone())?;
+                Some(lua_record)
+            },
+            false => None,
+        };
+
+        for (i_idx, inst) in charter.instructions().iter().enumerate() {
+            if !inst.enabled() {
+                continue;
             }
 
-            // Flush the current record's buffer to the appropriate derived file.
-            writer.write_byte_record(&record.flush()).map_err(MatcherError::CSVError)?;
+            let started = Instant::now();
+            *eval_ctx = (file_idx, record.row(), i_idx);
+
+            match inst {
+                Instruction::Project { column, as_a, from, constant, when, .. } => {
+                    project_column(*as_a, from, constant, when, &assigned[&i_idx], &mut record, lua_ctx)?;
+                    update_lua_record(&record, column, &lua_cols, &lua_record)?;
+                    record_duration(i_idx, &mut metrics, started.elapsed());
+                },
+
+                Instruction::Merge { into, columns, coerce_to, .. } => {
+                    record.merge_col_from(columns, *coerce_to)?;
+                    update_lua_record(&record, into, &lua_cols, &lua_record)?;
+                    record_duration(i_idx, &mut metrics, started.elapsed());
+                },
+
+                _ => {}, // Ignore other instructions in this phase.
+            };
         }
 
-        Ok(metrics)
+        // Flush the current record's buffer to the appropriate derived file.
+        writer.write_byte_record(&record.flush()).map_err(MatcherError::CSVError)?;
+    }
+
+    Ok(metrics)
+}
+
+///
+/// Wrap an error deriving data with the file, row and instruction being evaluated.
+///
+fn derive_error(charter: &Charter, schema: &GridSchema, eval_ctx: (usize, usize, usize), err: MatcherError) -> MatcherError {
+    if let MatcherError::JobCancelled { .. } = err {
+        return err
+    }
 
-    }).map_err(|err: MatcherError| MatcherError::DeriveDataError {
+    MatcherError::DeriveDataError {
         instruction: format!("{:?}", charter.instructions()[eval_ctx.2]),
         row: eval_ctx.1,
         file: schema.files()[eval_ctx.0].filename().into(),
-        err: err.to_string()
-    })
+        // Include the cause, i.e. errors raised by Rust functions called from Lua are otherwise hidden by the traceback.
+        err: std::iter::successors(Some(&err as &dyn std::error::Error), |err| err.source()).join(" : ")
+    }
+}
+
+///
+/// If a later projection uses the newly derived column, add its value to the row's Lua record.
+///
+fn update_lua_record(record: &Record, header: &str, lua_cols: &[Column], lua_record: &Option<rlua::Table>) -> Result<(), MatcherError> {
+    if let Some(lua_record) = lua_record {
+        if let Some(col) = lua_cols.iter().find(|col| col.header() == header) {
+            lua::set_lua_field(record, col, lua_record)?;
+        }
+    }
+    Ok(())
 }
 
 ///
@@ -414,26 +684,78 @@ fn match_and_group(ctx: &Context, grid: &mut Grid) -> Result<(MatchedHandler, Un
     let mut matched = MatchedHandler::new(ctx, grid)?;
 
     // Create unmatched files for each sourced file.
-    let unmatched = UnmatchedHandler::new(ctx, grid)?;
+    let mut unmatched = UnmatchedHandler::new(ctx, grid)?;
+
+    match_instructions(ctx, grid, &mut matched, &mut unmatched, false)?;
+
+    Ok((matched, unmatched))
+}
+
+///
+/// Run the filter, distinct, group and sort instructions against the grid. A shadow charter's duplicates and unmatched
+/// records aren't written.
+///
+fn match_instructions(
+    ctx: &Context,
+    grid: &mut Grid,
+    matched: &mut MatchedHandler,
+    unmatched: &mut UnmatchedHandler,
+    shadow: bool) -> Result<(), MatcherError> {
+
+    // Once the final grouping/filtering instruction has run nothing else can match a record, so if that's a group
+    // instruction, it indexes unmatched records as it goes and re-reads just those, in their original file order, at the
+    // end rather than re-scanning the grid.
+    let last_idx = match shadow {
+        true  => None,
+        false => ctx.charter().instructions().iter()
+            .rposition(|inst| inst.enabled() && matches!(inst, Instruction::Filter { .. } | Instruction::Distinct { .. } | Instruction::Group { .. })),
+    };
 
     // Debug the grid after each group instruction.
     grid.debug_grid(ctx, 0);
 
     for (idx, inst) in ctx.charter().instructions().iter().enumerate() {
-        if let Instruction::Group { by, match_when } = inst {
-            matching::match_groups(
-                ctx,
-                by,
-                match_when,
-                grid,
-                &mut matched)?;
-
-            // Debug the grid after each group instruction.
-            grid.debug_grid(ctx, idx);
+        match inst {
+            Instruction::Filter { lua, enabled: false } => log::info!("Skipping disabled filter {}", lua),
+            Instruction::Distinct { by, enabled: false, .. } => log::info!("Skipping disabled distinct by {}", by.iter().join(", ")),
+            Instruction::Group { by, enabled: false, .. } => log::info!("Skipping disabled group by {}", by.iter().join(", ")),
+            Instruction::Sort { by, enabled: false, .. } => log::info!("Skipping disabled sort by {}", by.iter().join(", ")),
+
+            Instruction::Filter { lua, .. } => {
+                let filtered = instructions::filter::filter_records(ctx, lua, grid, matched)?;
+                grid.remove_records(filtered);
+            },
+
+            Instruction::Distinct { by, write_duplicates, .. } => {
+                let removed = matching::distinct::remove_duplicates(ctx, by, *write_duplicates && !shadow, grid, matched)?;
+                grid.remove_records(removed);
+            },
+
+            Instruction::Group { by, match_when, normalise_keys, date_tolerance, max_group_size, aggregates, .. } => {
+                let grouping = Grouping {
+                    instruction: idx,
+                    by,
+                    normalise_keys: *normalise_keys,
+                    date_tolerance: date_tolerance.as_ref(),
+                    max_group_size: *max_group_size,
+                    aggregates: aggregates.as_ref(),
+                };
+
+                matching::match_groups(ctx, &grouping, match_when, grid, matched, unmatched, last_idx == Some(idx))?;
+
+                // Debug the grid after each group instruction.
+                grid.debug_grid(ctx, idx);
+            },
+
+            Instruction::Sort { by, descending, .. } => {
+                matched.set_order(GroupOrder::new(by, *descending, grid.schema())?);
+            },
+
+            _ => { /* Projections, merges and renames have already been applied. */ },
         }
     }
 
-    Ok((matched, unmatched))
+    Ok(())
 }
 
 ///
@@ -446,21 +768,35 @@ fn complete_and_archive(
     grid: Grid,
     mut matched: MatchedHandler,
     mut unmatched: UnmatchedHandler,
-    changesets: Vec<ChangeSet>) -> Result<(), MatcherError> {
+    changesets: Vec<ChangeSet>) -> Result<Vec<MatchedGroup>, MatcherError> {
 
-    // Write all unmatched records now.
+    // Write any unmatched records which weren't streamed out by the final group instruction.
     unmatched.write_records(ctx, &grid)?;
 
     let duration = ctx.started().elapsed();
+    let stats = job_stats(&grid, duration);
 
     // Complete the matched JSON file.
-    matched.complete_files(&unmatched, changesets, duration)?;
+    let matched_files = matched.complete_files(&unmatched, &changesets, stats, duration)?;
 
     // Debug the final grid now.
     grid.debug_grid(ctx, 1);
 
     // Move matching files to the archive.
-    folders::progress_to_archive(ctx, grid)?;
+    let archived = folders::progress_to_archive(ctx, grid)?;
+
+    // Index everything the job produced, archived or applied.
+    if ctx.charter().write_manifest() {
+        manifest::write(ctx, &matched_files, &unmatched, &archived, &changesets)?;
+    }
+
+    // Remove matched job files which are older than the retention period.
+    if let Some(days) = ctx.charter().matched_retention_days() {
+        let removed = folders::remove_expired_matched(ctx, days)?;
+        if removed > 0 {
+            log::info!("Removed {} matched file(s) older than {} day(s)", removed, days);
+        }
+    }
 
     // Log a warning for any file left in matching at the end of a job.
     let left_overs = folders::matching(ctx).read_dir()?
@@ -473,5 +809,26 @@ fn complete_and_archive(
 
     log::info!("Completed match job {} in {}", ctx.job_id(), blue(&formatted_duration_rate(1, duration).0));
 
-    Ok(())
+    Ok(matched.take_captured())
+}
+
+///
+/// Summerise the volume of data sourced into the job and the rate it was processed at.
+///
+fn job_stats(grid: &Grid, duration: Duration) -> serde_json::Value {
+    let (_duration, rate) = formatted_duration_rate(grid.loaded().max(1), duration);
+
+    log::info!("Processed {} record(s) ({}) at {}/row",
+        blue(&format!("{}", grid.loaded())),
+        grid.data_size().bytes(),
+        blue(&rate));
+
+    serde_json::json!({
+        "records": grid.loaded(),
+        "bytes": grid.data_size(),
+        "rate": rate,
+        "files": grid.schema().files().iter()
+            .map(|file| serde_json::json!({ "file": file.filename(), "rows": file.rows() }))
+            .collect::<Vec<serde_json::Value>>()
+    })
 }
@@ -1,12 +1,16 @@
 use regex::Regex;
-use rust_decimal::Decimal;
+use itertools::Itertools;
+use std::{cmp::Ordering, collections::HashSet};
+use rust_decimal::{Decimal, MathematicalOps};
 use rlua::{Context, Table};
 use lazy_static::lazy_static;
 use core::{data_type::DataType, lua::{LuaDecimal, eval}};
-use crate::{model::{record::Record, schema::{Column, GridSchema}}, error::MatcherError, folders};
+pub use core::lua::referenced_headers;
+use crate::{model::{record::Record, schema::{Column, GridSchema}}, error::MatcherError, folders, utils::convert};
 
 lazy_static! {
     static ref HEADER_REGEX: Regex = Regex::new(r#"record\["(.*?)"\]"#).expect("bad regex for HEADER_REGEX");
+    static ref ASSIGN_REGEX: Regex = Regex::new(r#"record\["(.*?)"\]\s*=[^=]"#).expect("bad regex for ASSIGN_REGEX");
 }
 
 ///
@@ -30,6 +34,24 @@ pub fn create_aggregate_fns(lua_ctx: &rlua::Context) -> Result<(), rlua::Error>
         Ok(count)
     })?;
 
+    // Provide a count_distinct("field", filter) function to the custom Lua script. Empty (nil) values aren't counted.
+    let count_distinct = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
+        let mut distinct = HashSet::new();
+        let data: rlua::Table = context.globals().get("records")?;
+
+        for idx in 1..=data.len()? {
+            let record: rlua::Table = data.get(idx)?;
+
+            if filter.call::<_, bool>(record.clone())? {
+                if let Some(bytes) = value_bytes(record.get::<String, rlua::Value>(field.clone())?)? {
+                    distinct.insert(bytes);
+                }
+            }
+        }
+
+        Ok(distinct.len())
+    })?;
+
     // Provide a sum("field", filter) function to the custom Lua script.
     let sum = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
         let mut sum = Decimal::ZERO;
@@ -143,16 +165,184 @@ pub fn create_aggregate_fns(lua_ctx: &rlua::Context) -> Result<(), rlua::Error>
         Ok(min)
     })?;
 
+    // Provide an avg("field", filter) function to the custom Lua script. Returns nil if no records match the filter.
+    let avg = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
+        let mut sum = Decimal::ZERO;
+        let mut count = 0;
+        let data: rlua::Table = context.globals().get("records")?;
+
+        for idx in 1..=data.len()? {
+            let record: rlua::Table = data.get(idx)?;
+
+            if filter.call::<_, bool>(record.clone())? {
+                sum += record.get::<String, LuaDecimal>(field.clone())
+                    .map_err(|source| MatcherError::CustomConstraintError { reason: format!("Field {} not found in record or not a DECIMAL. If you are trying to avg an INTEGER use avg_int() instead", field), source })?
+                    .0;
+                count += 1;
+            }
+        }
+
+        match count {
+            0 => Ok(None),
+            _ => Ok(Some(LuaDecimal(sum / Decimal::from(count)))),
+        }
+    })?;
+
+    // Provide an avg_int("field", filter) function to the custom Lua script. The mean is floored and nil is returned
+    // if no records match the filter.
+    let avg_int = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
+        let mut sum = 0u64;
+        let mut count = 0u64;
+        let data: rlua::Table = context.globals().get("records")?;
+
+        for idx in 1..=data.len()? {
+            let record: rlua::Table = data.get(idx)?;
+
+            if filter.call::<_, bool>(record.clone())? {
+                sum += record.get::<String, u64>(field.clone())
+                    .map_err(|source| MatcherError::CustomConstraintError { reason: format!("Field {} not found in record or not an INTEGER.", field), source })?;
+                count += 1;
+            }
+        }
+
+        match count {
+            0 => Ok(None),
+            _ => Ok(Some(sum / count)),
+        }
+    })?;
+
+    // Provide a variance("field", filter) function to the custom Lua script. Returns the sample variance, or nil if
+    // fewer than two records match the filter.
+    let variance = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
+        Ok(sample_variance(&filtered_decimals(context, &field, filter, "variance")?).map(LuaDecimal))
+    })?;
+
+    // Provide a stdev("field", filter) function to the custom Lua script. Returns the sample standard deviation, or nil
+    // if fewer than two records match the filter.
+    let stdev = lua_ctx.create_function(|context, (field, filter): (String, rlua::Function)| {
+        Ok(sample_variance(&filtered_decimals(context, &field, filter, "stdev")?)
+            .and_then(|variance| variance.sqrt())
+            .map(LuaDecimal))
+    })?;
+
+    // Provide a first("field", "sort field", filter) function to the custom Lua script. Returns the field from the
+    // matching record with the lowest sort field, or nil if no records match the filter.
+    let first = lua_ctx.create_function(|context, (field, sort_field, filter): (String, String, rlua::Function)| {
+        ordered_value(context, &field, &sort_field, filter, Ordering::Less)
+    })?;
+
+    // Provide a last("field", "sort field", filter) function to the custom Lua script. Returns the field from the
+    // matching record with the highest sort field, or nil if no records match the filter.
+    let last = lua_ctx.create_function(|context, (field, sort_field, filter): (String, String, rlua::Function)| {
+        ordered_value(context, &field, &sort_field, filter, Ordering::Greater)
+    })?;
+
     globals.set("count", count)?;
+    globals.set("count_distinct", count_distinct)?;
     globals.set("sum", sum)?;
     globals.set("sum_int", sum_int)?;
     globals.set("max", max)?;
     globals.set("max_int", max_int)?;
     globals.set("min", min)?;
     globals.set("min_int", min_int)?;
+    globals.set("avg", avg)?;
+    globals.set("avg_int", avg_int)?;
+    globals.set("variance", variance)?;
+    globals.set("stdev", stdev)?;
+    globals.set("first", first)?;
+    globals.set("last", last)?;
     Ok(())
 }
 
+///
+/// Get the decimal field from each record in the group which matches the filter.
+///
+fn filtered_decimals<'lua>(context: Context<'lua>, field: &str, filter: rlua::Function<'lua>, fn_name: &str) -> rlua::Result<Vec<Decimal>> {
+    let mut values = Vec::new();
+    let data: rlua::Table = context.globals().get("records")?;
+
+    for idx in 1..=data.len()? {
+        let record: rlua::Table = data.get(idx)?;
+
+        if filter.call::<_, bool>(record.clone())? {
+            values.push(record.get::<String, LuaDecimal>(field.to_string())
+                .map_err(|source| MatcherError::CustomConstraintError { reason: format!("Field {} not found in record or not a DECIMAL. {}() only supports DECIMAL fields", field, fn_name), source })?
+                .0);
+        }
+    }
+
+    Ok(values)
+}
+
+///
+/// Get the field from the filtered record which sorts first (Less) or last (Greater) by the sort field. Records
+/// without a sort value are ignored and ties resolve by the order of the records in the group - as if the records were
+/// stable-sorted and the first or last one taken.
+///
+fn ordered_value<'lua>(context: Context<'lua>, field: &str, sort_field: &str, filter: rlua::Function<'lua>, wanted: Ordering)
+    -> rlua::Result<rlua::Value<'lua>> {
+
+    let mut chosen: Option<(rlua::Value, rlua::Value)> = None; // (sort value, field value).
+    let data: rlua::Table = context.globals().get("records")?;
+
+    for idx in 1..=data.len()? {
+        let record: rlua::Table = data.get(idx)?;
+
+        if filter.call::<_, bool>(record.clone())? {
+            let sort_value = record.get::<&str, rlua::Value>(sort_field)?;
+            if let rlua::Value::Nil = sort_value {
+                continue
+            }
+
+            let replace = match &chosen {
+                None => true,
+                Some((current, _)) => match compare_values(&sort_value, current)? {
+                    Ordering::Equal => wanted == Ordering::Greater,
+                    ordering => ordering == wanted,
+                },
+            };
+
+            if replace {
+                chosen = Some((sort_value, record.get::<&str, rlua::Value>(field)?));
+            }
+        }
+    }
+
+    Ok(chosen.map(|(_, value)| value).unwrap_or(rlua::Value::Nil))
+}
+
+///
+/// Compare two (non-nil) Lua record field values of the same data type.
+///
+fn compare_values(lhs: &rlua::Value, rhs: &rlua::Value) -> rlua::Result<Ordering> {
+    use rlua::Value::*;
+
+    match (lhs, rhs) {
+        (Boolean(l),  Boolean(r))  => Ok(l.cmp(r)),
+        (Integer(l),  Integer(r))  => Ok(l.cmp(r)),
+        (Integer(l),  Number(r))   => Ok((*l as f64).total_cmp(r)),
+        (Number(l),   Integer(r))  => Ok(l.total_cmp(&(*r as f64))),
+        (Number(l),   Number(r))   => Ok(l.total_cmp(r)),
+        (String(l),   String(r))   => Ok(l.as_bytes().cmp(r.as_bytes())),
+        (UserData(l), UserData(r)) => Ok(l.borrow::<LuaDecimal>()?.0.cmp(&r.borrow::<LuaDecimal>()?.0)),
+        _ => Err(rlua::Error::FromLuaConversionError { from: "value", to: "sort value", message: Some("only record fields of the same type can be sorted".into()) }),
+    }
+}
+
+///
+/// The sample variance (n - 1) of the values, or None if there are fewer than two values.
+///
+fn sample_variance(values: &[Decimal]) -> Option<Decimal> {
+    if values.len() < 2 {
+        return None
+    }
+
+    let mean = values.iter().sum::<Decimal>() / Decimal::from(values.len());
+    let squares = values.iter().map(|value| (value - mean) * (value - mean)).sum::<Decimal>();
+
+    Some(squares / Decimal::from(values.len() - 1))
+}
+
 ///
 /// Return all the columns referenced in the script specified.
 ///
@@ -179,15 +369,7 @@ pub fn lua_record<'a>(
     let lua_record = lua_ctx.create_table()?;
 
     for col in avail_cols {
-        match col.data_type() {
-            DataType::Unknown  => {},
-            DataType::Boolean  => lua_record.set(col.header(), record.get_bool(col.header())?)?,
-            DataType::Datetime => lua_record.set(col.header(), record.get_datetime(col.header())?)?,
-            DataType::Decimal  => lua_record.set(col.header(), record.get_decimal(col.header())?.map(LuaDecimal))?,
-            DataType::Integer  => lua_record.set(col.header(), record.get_int(col.header())?)?,
-            DataType::String   => lua_record.set(col.header(), record.get_string(col.header())?)?,
-            DataType::Uuid     => lua_record.set(col.header(), record.get_uuid(col.header())?.map(|i|i.to_hyphenated().to_string()))?,
-        }
+        set_lua_field(record, col, &lua_record)?;
     }
 
     append_meta(record, &lua_record)?;
@@ -195,6 +377,76 @@ pub fn lua_record<'a>(
     Ok(lua_record)
 }
 
+///
+/// The raw bytes of a Lua record field used to compare values, Lua strings aren't assumed to be valid UTF-8.
+///
+fn value_bytes(value: rlua::Value) -> Result<Option<Vec<u8>>, rlua::Error> {
+    match value {
+        rlua::Value::Nil         => Ok(None),
+        rlua::Value::Boolean(b)  => Ok(Some(b.to_string().into_bytes())),
+        rlua::Value::Integer(i)  => Ok(Some(i.to_string().into_bytes())),
+        rlua::Value::Number(n)   => Ok(Some(n.to_string().into_bytes())),
+        rlua::Value::String(s)   => Ok(Some(s.as_bytes().to_vec())),
+        rlua::Value::UserData(d) => Ok(Some(d.borrow::<LuaDecimal>()?.0.to_string().into_bytes())),
+        _ => Err(rlua::Error::FromLuaConversionError { from: "value", to: "bytes", message: Some("only record fields can be counted".into()) }),
+    }
+}
+
+///
+/// Copy the record's current value for the column into the Lua record table.
+///
+pub fn set_lua_field(record: &Record, col: &Column, lua_record: &Table) -> Result<(), MatcherError> {
+    match col.data_type() {
+        DataType::Unknown  => {},
+        DataType::Boolean  => lua_record.set(col.header(), record.get_bool(col.header())?)?,
+        DataType::Datetime => lua_record.set(col.header(), record.get_datetime(col.header())?)?,
+        DataType::Decimal  => lua_record.set(col.header(), record.get_decimal(col.header())?.map(LuaDecimal))?,
+        DataType::Integer  => lua_record.set(col.header(), record.get_int(col.header())?)?,
+        DataType::String   => lua_record.set(col.header(), record.get_string(col.header())?)?,
+        DataType::Uuid     => lua_record.set(col.header(), record.get_uuid(col.header())?.map(|i|i.to_hyphenated().to_string()))?,
+    }
+
+    Ok(())
+}
+
+///
+/// Read the column's value back from the Lua record table in its csv form, i.e. after a script has assigned to it.
+///
+pub fn get_lua_field(col: &Column, lua_record: &Table) -> Result<String, MatcherError> {
+    let value = match col.data_type() {
+        DataType::Unknown  => None,
+        DataType::Boolean  => lua_record.get::<_, Option<bool>>(col.header())?.map(convert::bool_to_string),
+        DataType::Datetime => lua_record.get::<_, Option<u64>>(col.header())?.map(convert::datetime_to_string),
+        DataType::Decimal  => lua_record.get::<_, Option<LuaDecimal>>(col.header())?.map(|d| convert::decimal_to_string(d.0)),
+        DataType::Integer  => lua_record.get::<_, Option<i64>>(col.header())?.map(convert::int_to_string),
+        DataType::String   => lua_record.get::<_, Option<String>>(col.header())?,
+        DataType::Uuid     => lua_record.get::<_, Option<String>>(col.header())?
+            .map(|s| convert::uuid_to_string(s.parse().expect("Lua assigned an invalid uuid"))),
+    };
+
+    Ok(value.unwrap_or_default())
+}
+
+///
+/// The header names the script assigns a value to, e.g. record["Amount"] = 0 -> Amount.
+///
+pub fn assigned_headers(script: &str) -> Vec<String> {
+    ASSIGN_REGEX.captures_iter(script)
+        .map(|cap| cap[1].to_string())
+        .unique()
+        .collect()
+}
+
+///
+/// Replace each record["header"] reference in the script with the header returned from the resolver.
+///
+pub fn replace_headers<F>(script: &str, resolve: F) -> String
+where
+    F: Fn(&str) -> String
+{
+    HEADER_REGEX.replace_all(script, |cap: &regex::Captures| format!(r#"record["{}"]"#, resolve(&cap[1]))).to_string()
+}
+
 ///
 /// Create some contextural information regarding the file that loaded a record.
 ///
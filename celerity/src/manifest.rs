@@ -0,0 +1,35 @@
+use serde_json::json;
+use std::{fs::File, io::{BufWriter, Write}};
+use crate::{Context, changeset::ChangeSet, error::MatcherError, folders::{self, IN_PROGRESS, ToCanoncialString}, matching::{matched::{summerise_changesets, summerise_unmatched}, unmatched::UnmatchedHandler}};
+
+///
+/// Write a manifest.json file alongside the matched file, listing everything the job produced, archived or applied.
+/// Only used if the charter asks for a manifest.
+///
+/// The file is in the form: -
+/// {"job_id": "...", "matched": ["20211201_053700000_matched.json"], "unmatched": [{"file": "20211201_053700000_invoices.unmatched.csv", "rows": 1}],
+///  "archived": ["20211201_053700000_invoices.csv"], "changesets": [{"file": "20211201_053000000_changeset.json", "updated": 1, ...}]}
+///
+pub fn write(ctx: &Context, matched: &[String], unmatched: &UnmatchedHandler, archived: &[String], changesets: &[ChangeSet])
+    -> Result<(), MatcherError> {
+
+    let manifest = json!({
+        "job_id": ctx.job_id().to_hyphenated().to_string(),
+        "matched": matched,
+        "unmatched": summerise_unmatched(unmatched),
+        "archived": archived,
+        "changesets": summerise_changesets(changesets),
+    });
+
+    let matched_file = folders::matched(ctx).join(format!("{}{}", matched[0], IN_PROGRESS));
+    let path = folders::manifest_file(&matched_file).to_canoncial_string();
+    let mut writer = BufWriter::new(File::create(&path)?);
+
+    writeln!(&mut writer, "{:#}", manifest)
+        .and_then(|_| writer.flush())
+        .map_err(|source| MatcherError::CannotWriteThing { thing: "manifest".into(), filename: path.clone(), source })?;
+
+    let completed = folders::complete_file(&path)?;
+    log::debug!("Created manifest {}", completed.to_canoncial_string());
+    Ok(())
+}
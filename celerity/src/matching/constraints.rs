@@ -1,7 +1,9 @@
 use rlua::Context;
 use rust_decimal::Decimal;
-use core::{data_type::DataType, charter::{Constraint, ToleranceType}, lua::eval};
-use crate::{model::{record::Record, schema::{Column, GridSchema}}, error::MatcherError, lua};
+use serde_json::{json, Value};
+use core::{data_type::DataType, charter::{CompareOp, Constraint, FilterCount, GroupAggregates, ToleranceType}, lua::eval};
+use crate::{model::{record::Record, schema::{Column, GridSchema}}, error::MatcherError, lua, utils::convert};
+use super::MILLIS_IN_A_DAY;
 
 pub fn passes(
     constraint: &Constraint,
@@ -18,6 +20,14 @@ pub fn passes(
             }
         },
 
+        Constraint::NetsToN { column, lhs, rhs, target } => {
+            match schema.data_type(column).unwrap_or(&DataType::Unknown) {
+                DataType::Decimal => nets_to_n(column, lhs, rhs, *target, records, schema, lua_ctx),
+                DataType::Integer => nets_to_n(column, lhs, rhs, *target, records, schema, lua_ctx),
+                col_type => Err(MatcherError::CannotUseTypeForContstraint{ column: column.into(), col_type: format!("{:?}", col_type)})
+            }
+        },
+
         Constraint::NetsWithTolerance {column, lhs, rhs, tol_type, tolerance } => {
             match schema.data_type(column).unwrap_or(&DataType::Unknown) {
                 DataType::Decimal => nets_with_tolerance(column, lhs, rhs, tol_type, *tolerance, records, schema, lua_ctx),
@@ -26,10 +36,74 @@ pub fn passes(
             }
         },
 
+        Constraint::NetsWithTolerances { lhs, rhs, tolerances } => {
+            for tolerance in tolerances {
+                let column = tolerance.column();
+                match schema.data_type(column).unwrap_or(&DataType::Unknown) {
+                    DataType::Decimal | DataType::Integer => {},
+                    col_type => return Err(MatcherError::CannotUseTypeForContstraint{ column: column.into(), col_type: format!("{:?}", col_type)})
+                }
+
+                // Every column must net within its tolerance for the group to match.
+                if !nets_with_tolerance(column, lhs, rhs, tolerance.tol_type(), tolerance.tolerance(), records, schema, lua_ctx)? {
+                    return Ok(false)
+                }
+            }
+            Ok(true)
+        },
+
+        Constraint::CountInRange { filter, min, max } => count_in_range(filter, *min, *max, records, schema, lua_ctx),
+
+        Constraint::NoneMatch { filter } => none_match(filter, records, schema, lua_ctx),
+
+        Constraint::Cardinality { groups } => cardinality(groups, records, schema, lua_ctx),
+
+        Constraint::AllEqual { column } => all_equal(column, records),
+
+        Constraint::SumCompare { column, filter, op, value } => {
+            match schema.data_type(column).unwrap_or(&DataType::Unknown) {
+                DataType::Decimal => sum_compare(column, filter, *op, *value, records, schema, lua_ctx),
+                DataType::Integer => sum_compare(column, filter, *op, *value, records, schema, lua_ctx),
+                col_type => Err(MatcherError::CannotUseTypeForContstraint{ column: column.into(), col_type: format!("{:?}", col_type)})
+            }
+        },
+
         Constraint::Custom { script, available_fields } => custom_constraint(script, available_fields, records, schema, lua_ctx),
     }
 }
 
+///
+/// Check the earliest and latest dates of the column across the group are no more than the given number of days apart.
+///
+/// Records without a date are ignored.
+///
+pub fn within_date_tolerance(
+    column: &str,
+    days: u64,
+    records: &[&Record],
+    schema: &GridSchema) -> Result<bool, MatcherError> {
+
+    if schema.data_type(column) != Some(&DataType::Datetime) {
+        return Err(MatcherError::DateToleranceNotDatetime { column: column.into() })
+    }
+
+    let mut dates = vec!();
+    for record in records {
+        if let Some(date) = record.get_datetime(column)? {
+            dates.push(date);
+        }
+    }
+
+    let spread = match (dates.iter().min(), dates.iter().max()) {
+        (Some(min), Some(max)) => max - min,
+        _ => 0,
+    };
+
+    log::trace!("Date spread of {} is {}ms, tolerance is {} days", column, spread, days);
+
+    Ok(spread <= days * MILLIS_IN_A_DAY)
+}
+
 ///
 /// NETting takes two sets of records and SUMs a column from both. Then subtracts the SUM of the first list from the second
 /// and, if the result is zero (or within a tolerance) it returns true. There must be at least one record in each subset as well.
@@ -58,8 +132,8 @@ fn net_decimal<F>(
     let rhs_recs = lua::lua_filter(records, rhs, lua_ctx, schema)?;
 
     // Sum the NETting column for records on both sides.
-    let lhs_sum: Decimal = lhs_recs.iter().map(|r| r.get_decimal(column).unwrap_or(Some(Decimal::ZERO)).unwrap_or(Decimal::ZERO)).sum();
-    let rhs_sum: Decimal = rhs_recs.iter().map(|r| r.get_decimal(column).unwrap_or(Some(Decimal::ZERO)).unwrap_or(Decimal::ZERO)).sum();
+    let lhs_sum = sum(column, &lhs_recs);
+    let rhs_sum = sum(column, &rhs_recs);
 
     // The constraint passes if the sides net to zero AND there is at least one record from each side.
     let net = sum_checker(lhs_sum, rhs_sum) && (!lhs_recs.is_empty() && !rhs_recs.is_empty());
@@ -70,6 +144,155 @@ fn net_decimal<F>(
     Ok(net)
 }
 
+///
+/// Sum the column for the records, values which are missing or can't be read count as zero.
+///
+fn sum(column: &str, records: &[&Record]) -> Decimal {
+    records.iter().map(|r| r.get_decimal(column).unwrap_or(Some(Decimal::ZERO)).unwrap_or(Decimal::ZERO)).sum()
+}
+
+///
+/// Total the column for the records on each side of the group and the difference between them (after netting the
+/// absolute totals, as the nets_xxx constraints do), e.g. {"lhs": "100.00", "rhs": "-99.50", "difference": "0.50"}.
+///
+pub fn aggregates(
+    aggregates: &GroupAggregates,
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<Value, MatcherError> {
+
+    let column = aggregates.column();
+    match schema.data_type(column) {
+        Some(DataType::Decimal) | Some(DataType::Integer) => {},
+        None => return Err(MatcherError::ConstraintColumnMissing{ column: column.into() }),
+        Some(col_type) => return Err(MatcherError::CannotUseTypeForContstraint{ column: column.into(), col_type: format!("{:?}", col_type)}),
+    }
+
+    let lhs_sum = sum(column, &lua::lua_filter(records, aggregates.lhs(), lua_ctx, schema)?);
+    let rhs_sum = sum(column, &lua::lua_filter(records, aggregates.rhs(), lua_ctx, schema)?);
+
+    Ok(json!({
+        "lhs": convert::decimal_to_string(lhs_sum),
+        "rhs": convert::decimal_to_string(rhs_sum),
+        "difference": convert::decimal_to_string(lhs_sum.abs() - rhs_sum.abs()),
+    }))
+}
+
+///
+/// Sum the column for the records in the group which pass the Lua filter (or all the records if there's no filter)
+/// and compare the total to the value.
+///
+fn sum_compare(
+    column: &str,
+    filter: &Option<String>,
+    op: CompareOp,
+    value: Decimal,
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<bool, MatcherError> {
+
+    if !schema.headers().contains(&column.to_string()) {
+        return Err(MatcherError::ConstraintColumnMissing{ column: column.into() })
+    }
+
+    let total = match filter {
+        Some(filter) => sum(column, &lua::lua_filter(records, filter, lua_ctx, schema)?),
+        None => sum(column, records),
+    };
+
+    let result = match op {
+        CompareOp::LessThan           => total < value,
+        CompareOp::LessThanOrEqual    => total <= value,
+        CompareOp::GreaterThan        => total > value,
+        CompareOp::GreaterThanOrEqual => total >= value,
+        CompareOp::Equal              => total == value,
+    };
+
+    log::trace!("sum({}) {:?} {} : {} {:?} {} = {}", column, op, value, total, op, value, result);
+
+    Ok(result)
+}
+
+///
+/// Count the records in the group which pass the Lua filter and check the count is between min and max (inclusive).
+///
+fn count_in_range(
+    filter: &str,
+    min: usize,
+    max: usize,
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<bool, MatcherError> {
+
+    let count = lua::lua_filter(records, filter, lua_ctx, schema)?.len();
+    let result = (min..=max).contains(&count);
+
+    log::trace!("{} <= count({}) <= {} : {} <= {} <= {} = {}", min, filter, max, min, count, max, result);
+
+    Ok(result)
+}
+
+///
+/// Check no record in the group passes the Lua filter.
+///
+fn none_match(
+    filter: &str,
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<bool, MatcherError> {
+
+    let count = lua::lua_filter(records, filter, lua_ctx, schema)?.len();
+
+    log::trace!("count({}) == 0 : {} == 0 = {}", filter, count, count == 0);
+
+    Ok(count == 0)
+}
+
+///
+/// Check each filter is passed by exactly it's count of the records in the group.
+///
+fn cardinality(
+    groups: &[FilterCount],
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<bool, MatcherError> {
+
+    for group in groups {
+        let count = lua::lua_filter(records, group.filter(), lua_ctx, schema)?.len();
+
+        log::trace!("count({}) == {} : {} == {} = {}", group.filter(), group.count(), count, group.count(), count == group.count());
+
+        if count != group.count() {
+            return Ok(false)
+        }
+    }
+
+    Ok(true)
+}
+
+///
+/// Check every record in the group has exactly the same value in the column.
+///
+fn all_equal(column: &str, records: &[&Record]) -> Result<bool, MatcherError> {
+    let mut first = None;
+
+    for record in records {
+        let value = record.get_as_bytes(column)?
+            .ok_or_else(|| MatcherError::ConstraintColumnMissing { column: column.into() })?;
+
+        match &first {
+            None => first = Some(value),
+            Some(first) if *first != value => {
+                log::trace!("all_equal({}) : {:?} != {:?}", column, first, value);
+                return Ok(false)
+            },
+            Some(_) => {},
+        }
+    }
+
+    Ok(true)
+}
+
 ///
 /// Allow entirely custom Lua script to be evaluated for a group constraint.
 ///
@@ -125,6 +348,25 @@ fn net_to_zero(
 }
 
 
+fn nets_to_n(
+    column: &str,
+    lhs: &str,
+    rhs: &str,
+    target: Decimal,
+    records: &[&Record],
+    schema: &GridSchema,
+    lua_ctx: &Context) -> Result<bool, MatcherError>
+{
+    // Create a closure to check the difference between the lhs and rhs sums is exactly the target.
+    let sum_checker = |lhs_sum: Decimal, rhs_sum: Decimal| {
+        let result = lhs_sum.abs() - rhs_sum.abs() == target;
+        log::trace!("lhs_sum.abs() - rhs_sum.abs() == target : {}.abs() - {}.abs() == {} = {}", lhs_sum, rhs_sum, target, result);
+        result
+    };
+    net_decimal(column, lhs, rhs, sum_checker, records, schema, lua_ctx)
+}
+
+
 fn nets_with_tolerance(
     column: &str,
     lhs: &str,
@@ -149,6 +391,13 @@ fn nets_with_tolerance(
                 log::trace!("(lhs_sum.abs() - rhs_sum.abs()).abs() <= percent_tol : ({}.abs() - {}.abs()).abs() <= {} = {}", lhs_sum, rhs_sum, percent_tol, result);
                 result
             }),
+
+        ToleranceType::PercentOfLarger => Box::new(|lhs_sum: Decimal, rhs_sum: Decimal| {
+                let percent_tol = lhs_sum.abs().max(rhs_sum.abs()) * tolerance / Decimal::ONE_HUNDRED;
+                let result = (lhs_sum.abs() - rhs_sum.abs()).abs() <= percent_tol;
+                log::trace!("(lhs_sum.abs() - rhs_sum.abs()).abs() <= percent_tol : ({}.abs() - {}.abs()).abs() <= {} = {}", lhs_sum, rhs_sum, percent_tol, result);
+                result
+            }),
     };
 
     net_decimal(column, lhs, rhs, sum_checker, records, schema, lua_ctx)
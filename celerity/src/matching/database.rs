@@ -0,0 +1,121 @@
+use rusqlite::{Connection, params};
+use super::unmatched::UnmatchedHandler;
+use crate::{error::MatcherError, folders::{self, ToCanoncialString}, model::{grid::Grid, record::Record}, Context};
+
+///
+/// Writes the job details, matched group coordinates and unmatched row references to a SQLite database in the
+/// base dir. Every job appends to the same database and the job's rows are only committed when the job completes.
+///
+pub struct ResultsDatabase {
+    conn: Connection,
+    path: String,
+    job_id: String,
+    charter_name: String,
+    charter_version: u64,
+    charter_file: String,
+    files: Vec<String>, // The (archived) filename of each file in the grid.
+}
+
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS jobs (
+        job_id            TEXT PRIMARY KEY,
+        charter_name      TEXT NOT NULL,
+        charter_version   INTEGER NOT NULL,
+        charter_file      TEXT NOT NULL,
+        matched_file      TEXT NOT NULL,
+        matched_groups    INTEGER NOT NULL,
+        matched_records   INTEGER NOT NULL,
+        unmatched_records INTEGER NOT NULL,
+        duration_ms       INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS matched (
+        job_id     TEXT NOT NULL,
+        group_id   INTEGER NOT NULL,
+        file_index INTEGER NOT NULL,
+        file       TEXT NOT NULL,
+        row        INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS unmatched (
+        job_id TEXT NOT NULL,
+        file   TEXT NOT NULL,
+        row    INTEGER NOT NULL
+    );"#;
+
+impl ResultsDatabase {
+    ///
+    /// Open (or create) the results database and start a transaction for this job.
+    ///
+    pub fn new(ctx: &Context, grid: &Grid) -> Result<Self, MatcherError> {
+        let path = folders::results_database(ctx).to_canoncial_string();
+        let db_err = |source| MatcherError::CannotWriteDatabase { path: path.clone(), source };
+
+        let conn = Connection::open(&path).map_err(db_err)?;
+        conn.execute_batch(SCHEMA).map_err(db_err)?;
+        conn.execute_batch("BEGIN").map_err(db_err)?;
+
+        Ok(Self {
+            conn,
+            path: path.clone(),
+            job_id: ctx.job_id().to_hyphenated().to_string(),
+            charter_name: ctx.charter().name().to_string(),
+            charter_version: ctx.charter().version(),
+            charter_file: ctx.charter_path().to_canoncial_string(),
+            files: grid.schema().files()
+                .iter()
+                .map(|f| f.archived_filename().as_deref().unwrap_or_else(|| f.filename()).to_string())
+                .collect(),
+        })
+    }
+
+    ///
+    /// Record the file coordinates of each record in a matched group.
+    ///
+    pub fn append_group(&mut self, group_id: usize, records: &[&Record]) -> Result<(), MatcherError> {
+        let mut stmt = self.conn
+            .prepare_cached("INSERT INTO matched (job_id, group_id, file_index, file, row) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .map_err(|source| MatcherError::CannotWriteDatabase { path: self.path.clone(), source })?;
+
+        for record in records {
+            stmt.execute(params![self.job_id, group_id, record.file_idx(), self.files[record.file_idx()], record.row()])
+                .map_err(|source| MatcherError::CannotWriteDatabase { path: self.path.clone(), source })?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Record the job and a reference to each row in the unmatched files, then commit the job's results.
+    ///
+    /// Unmatched rows are referenced by their row in the unmatched file (including the header rows, so the first
+    /// record is on row 3).
+    ///
+    pub fn complete(&mut self, matched_file: &str, matched_groups: usize, matched_records: usize, unmatched: &UnmatchedHandler, duration_ms: u64)
+        -> Result<(), MatcherError> {
+
+        let db_err = |source| MatcherError::CannotWriteDatabase { path: self.path.clone(), source };
+
+        let unmatched_files = unmatched.unmatched_files();
+        let unmatched_records = unmatched_files.iter().map(|f| f.rows()).sum::<usize>();
+
+        self.conn.execute(
+            "INSERT INTO jobs (job_id, charter_name, charter_version, charter_file, matched_file, matched_groups, matched_records, unmatched_records, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![self.job_id, self.charter_name, self.charter_version, self.charter_file, matched_file,
+                matched_groups, matched_records, unmatched_records, duration_ms])
+            .map_err(db_err)?;
+
+        {
+            let mut stmt = self.conn
+                .prepare_cached("INSERT INTO unmatched (job_id, file, row) VALUES (?1, ?2, ?3)")
+                .map_err(db_err)?;
+
+            for file in unmatched_files.iter().filter(|f| f.rows() > 0) {
+                for row in 3..file.rows() + 3 {
+                    stmt.execute(params![self.job_id, file.filename(), row]).map_err(db_err)?;
+                }
+            }
+        }
+
+        self.conn.execute_batch("COMMIT").map_err(db_err)
+    }
+}
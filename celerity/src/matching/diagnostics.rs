@@ -0,0 +1,59 @@
+use serde_json::json;
+use std::{fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}};
+use crate::{error::MatcherError, folders::{self, ToCanoncialString}, model::record::Record};
+
+///
+/// Writes each group which failed to match, and the constraints it failed, to a diagnostics.json file alongside the
+/// matched file. Only used if the charter asks to explain unmatched data.
+///
+/// The file is an array of entries in the form: -
+/// {"instruction": 2, "key": "REF1", "records": [["20211219_082900000_invoices.csv", 3]], "failed": ["NetsToZero { .. }"]}
+///
+pub struct Diagnostics {
+    path: String,
+    writer: BufWriter<File>,
+    entries: usize,
+}
+
+impl Diagnostics {
+    pub fn new(matched_file: &Path) -> Result<Self, MatcherError> {
+        let path = folders::diagnostics_file(matched_file).to_canoncial_string();
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        write!(&mut writer, "[")
+            .map_err(|source| MatcherError::CannotWriteThing { thing: "diagnostics header".into(), filename: path.clone(), source })?;
+
+        Ok(Self { path, writer, entries: 0 })
+    }
+
+    ///
+    /// Record the group from the group instruction which didn't match, with the constraints which failed.
+    ///
+    pub fn append_group(&mut self, instruction: usize, key: &str, records: &[&Record], failed: Vec<String>) -> Result<(), MatcherError> {
+        let entry = json!({
+            "instruction": instruction,
+            "key": key,
+            "records": records.iter()
+                .map(|record| json!([record.schema().files()[record.file_idx()].filename(), record.row()]))
+                .collect::<Vec<_>>(),
+            "failed": failed,
+        });
+
+        let separator = if self.entries == 0 { "\n  " } else { ",\n  " };
+        self.entries += 1;
+
+        write!(&mut self.writer, "{}{}", separator, entry)
+            .map_err(|source| MatcherError::CannotWriteThing { thing: "diagnostics entry".into(), filename: self.path.clone(), source })
+    }
+
+    ///
+    /// Terminate the diagnostics array and remove the .inprogress suffix from the file, returning its completed path.
+    ///
+    pub fn complete(mut self) -> Result<PathBuf, MatcherError> {
+        writeln!(&mut self.writer, "\n]")
+            .and_then(|_| self.writer.flush())
+            .map_err(|source| MatcherError::CannotWriteThing { thing: "diagnostics terminator".into(), filename: self.path.clone(), source })?;
+
+        folders::complete_file(&self.path)
+    }
+}
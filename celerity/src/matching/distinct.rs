@@ -0,0 +1,107 @@
+use std::{collections::HashMap, fs, time::Instant};
+use anyhow::Context as ErrContext;
+use itertools::Itertools;
+use crate::{blue, error::{MatcherError, here}, folders::{self, ToCanoncialString}, formatted_duration_rate, model::{grid::Grid, record::Record}, utils::{self, csv::CsvWriter}};
+use super::{clean_up_indexes, group_iter::GroupIterator, matched::MatchedHandler, sort_index};
+
+///
+/// Remove all but the earliest record from each set of records sharing the same values in the by columns.
+///
+/// Records are sorted into groups in exactly the same way the group instruction does it. The record from the
+/// chronologically earliest file (and earliest row within that file) is kept, the remainder are marked as duplicates
+/// so they take no further part in the job. If write_duplicates is set, the removed records are also copied to a
+/// file in the duplicates folder.
+///
+/// Returns the number of duplicates removed.
+///
+pub fn remove_duplicates(
+    ctx: &crate::Context,
+    by: &[String],
+    write_duplicates: bool,
+    grid: &Grid,
+    matched: &mut MatchedHandler) -> Result<usize, MatcherError> {
+
+    if grid.is_empty() {
+        return Ok(0)
+    }
+
+    log::info!("Removing duplicates by {}", by.iter().join(", "));
+
+    let start = Instant::now();
+    let mut removed = 0;
+    let mut writers: HashMap<String /* shortname */, CsvWriter> = HashMap::new();
+
+    let file_count = sort_index(ctx, by, true, grid)?;
+
+    for group in GroupIterator::new(ctx, grid.schema(), None) {
+        let group = group?;
+
+        if group.len() < 2 {
+            continue
+        }
+
+        // Files are timestamped, so ordering by filename keeps the earliest record first.
+        let mut records: Vec<&Record> = group.iter()
+            .sorted_by_key(|r| (grid.schema().files()[r.file_idx()].filename(), r.row()))
+            .collect();
+
+        let duplicates = records.split_off(1);
+
+        if write_duplicates {
+            write_records(ctx, grid, &duplicates, &mut writers)?;
+        }
+
+        matched.set_duplicate_status(&duplicates)?;
+        removed += duplicates.len();
+    }
+
+    clean_up_indexes(ctx, file_count)?;
+
+    for writer in writers.values_mut() {
+        writer.flush()?;
+    }
+
+    let (duration, _rate) = formatted_duration_rate(grid.len(), start.elapsed());
+    log::info!("Removed {} duplicate record(s) in {}", blue(&format!("{}", removed)), blue(&duration));
+
+    Ok(removed)
+}
+
+///
+/// Copy the original data of each duplicate to a file named after its source data file in the duplicates folder.
+///
+fn write_records(ctx: &crate::Context, grid: &Grid, records: &[&Record], writers: &mut HashMap<String, CsvWriter>)
+    -> Result<(), MatcherError> {
+
+    for record in records {
+        let file = &grid.schema().files()[record.file_idx()];
+
+        if !writers.contains_key(file.shortname()) {
+            let folder = folders::duplicates(ctx);
+            fs::create_dir_all(&folder)
+                .with_context(|| format!("Unable to create directory {}{}", folder.to_canoncial_string(), here!()))?;
+
+            let path = folders::new_duplicates_file(ctx, file);
+            let mut writer = utils::csv::writer(&path);
+            let schema = &grid.schema().file_schemas()[file.schema_idx()];
+
+            writer.write_record(schema.columns().iter().map(|c| c.header_no_prefix()).collect::<Vec<&str>>())
+                .map_err(|source| MatcherError::CannotWriteHeaders{ filename: folders::filename(&path), source })?;
+
+            writer.write_record(schema.columns().iter().map(|c| c.data_type().as_str()).collect::<Vec<&str>>())
+                .map_err(|source| MatcherError::CannotWriteSchema{ filename: folders::filename(&path), source })?;
+
+            log::debug!("Created file {}", path.to_canoncial_string());
+            writers.insert(file.shortname().to_string(), writer);
+        }
+
+        // Any unmatched age column isn't in the duplicates file's headers.
+        let data = record.data().iter().take(record.data().len() - file.aged() as usize).collect::<csv::ByteRecord>();
+
+        let writer = writers.get_mut(file.shortname()).expect("no duplicates writer");
+        writer.write_byte_record(&data)
+            .map_err(|source| MatcherError::CannotWriteDuplicateRecord { filename: file.filename().into(), row: record.row(), source })?;
+    }
+
+    Ok(())
+}
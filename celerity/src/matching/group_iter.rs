@@ -12,10 +12,11 @@ pub struct GroupIterator {
     derived_rdrs: CsvReaders,
     current: Option<csv::ByteRecord>,
     limit: usize,
+    max_group_size: Option<usize>, // If exceeded, the iterator returns an error rather than the group.
 }
 
 impl GroupIterator {
-    pub fn new(ctx: &crate::Context, schema: &GridSchema) -> Self {
+    pub fn new(ctx: &crate::Context, schema: &GridSchema, max_group_size: Option<usize>) -> Self {
         Self {
             schema: Arc::new(schema.clone()),
             index_rdr: utils::csv::index_reader(folders::matching(ctx).join("index.sorted.csv")),
@@ -28,7 +29,8 @@ impl GroupIterator {
                 .map(|file| utils::csv::reader(file.derived_path(), true))
                 .collect(),
             current: None,
-            limit: ctx.charter().group_size_limit()
+            limit: ctx.charter().group_size_limit(),
+            max_group_size,
         }
     }
 
@@ -120,6 +122,15 @@ impl Iterator for GroupIterator {
                             Err(err) => return Some(Err(err)),
                         }
 
+                        if let Some(max_group_size) = self.max_group_size {
+                            if group.len() > max_group_size {
+                                return Some(Err(MatcherError::MaxGroupSizeExceeded {
+                                    max_group_size,
+                                    key: String::from_utf8_lossy(csv_record.get(COL_MERGE_KEY).expect("no merge key")).into()
+                                }))
+                            }
+                        }
+
                         if group.len() > self.limit {
                             log::error!("The current configuration and data would result in a group exceeding the maximum number of records ({}). This will have memory resource implications if allowed. If you still want to proceed, specify the group_size_limit property on the charter to be the maximum number of allowed records in a single group.", self.limit);
                             panic!("group size exceeds limit")
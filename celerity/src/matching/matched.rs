@@ -2,9 +2,9 @@ use itertools::Itertools;
 use positioned_io::WriteAt;
 use serde_json::{json, Value};
 use anyhow::Context as ErrContext;
-use super::unmatched::UnmatchedHandler;
-use std::{fs::{File, OpenOptions}, io::{BufWriter, Write}, time::Duration};
-use crate::{error::{MatcherError, here}, folders::{self, ToCanoncialString}, model::{grid::Grid, record::Record}, Context, changeset::{ChangeSet, Change}};
+use super::{database::ResultsDatabase, diagnostics::Diagnostics, unmatched::UnmatchedHandler};
+use std::{fs::{File, OpenOptions}, io::{BufWriter, Write}, path::Path, time::Duration};
+use crate::{instructions::sort::GroupOrder, error::{MatcherError, here}, folders::{self, ToCanoncialString}, model::{grid::Grid, record::Record}, utils::{self, convert, csv::CsvWriter}, Context, changeset::{ChangeSet, Change}};
 
 ///
 /// Manages the matched job file and appends matched groups to it.
@@ -12,12 +12,26 @@ use crate::{error::{MatcherError, here}, folders::{self, ToCanoncialString}, mod
 pub struct MatchedHandler {
     groups: usize,
     records: usize,
+    filtered: usize,
+    duplicates: usize,
     data_size: usize,
     path: String,
-    writer: BufWriter<File>, // For the matched.json file.
+    job_id: String,
+    writer: Option<BufWriter<File>>, // For the matched.json file, a shadow handler has none.
+    csv: Option<(String, CsvWriter)>, // For the optional matched.csv file.
+    database: Option<ResultsDatabase>, // For the optional SQLite results database.
+    diagnostics: Option<Diagnostics>, // For the optional diagnostics.json file explaining unmatched groups.
     data_writers: Vec<File>, // To update the status byte for matched records.
+    captured: Option<Vec<MatchedGroup>>, // Group members by filename, only kept for shadow runs.
+    changed: Option<Vec<(usize, u64)>>, // The file and data byte of each status a shadow handler changed.
+    order: Option<GroupOrder>, // The order group members are written in, set by a sort instruction.
 }
 
+///
+/// The filename and line number of each record in a matched group.
+///
+pub type MatchedGroup = Vec<(String, usize)>;
+
 impl MatchedHandler {
     ///
     /// Open a matched output file to write Json groups to. We'll add job details to the top of the file.
@@ -58,22 +72,64 @@ impl MatchedHandler {
 
         write!(&mut writer, ",\n{{\n  \"groups\": [\n    ")?;
 
+        let csv = match ctx.charter().matched_csv() {
+            true  => Some(new_csv(ctx, &path)?),
+            false => None,
+        };
+
+        let database = match ctx.charter().matched_sqlite() {
+            true  => Some(ResultsDatabase::new(ctx, grid)?),
+            false => None,
+        };
+
+        let diagnostics = match ctx.charter().explain_unmatched() {
+            true  => Some(Diagnostics::new(&path)?),
+            false => None,
+        };
+
         Ok(Self {
             groups: 0,
             records: 0,
+            filtered: 0,
+            duplicates: 0,
             data_size: grid.data_size(),
-            writer,
+            captured: if ctx.capture_groups() { Some(vec!()) } else { None },
+            changed: None,
+            order: None,
+            writer: Some(writer),
+            csv,
+            database,
+            diagnostics,
+            job_id: ctx.job_id().to_hyphenated().to_string(),
             path: path.to_canoncial_string(),
-            data_writers: grid.schema().files()
-                .iter()
-                .map(|df| OpenOptions::new()
-                    .write(true)
-                    .open(df.path())
-                    .unwrap_or_else(|_| panic!("unable to open {} to update status", df.path().to_canoncial_string())))
-                .collect()
+            data_writers: data_writers(grid),
         })
     }
 
+    ///
+    /// A handler for a shadow charter's groups. Nothing is written except the records' statuses, which are tracked so
+    /// they can be put back with restore_statuses once the shadow charter has finished.
+    ///
+    pub fn shadow(ctx: &Context, grid: &Grid) -> Self {
+        Self {
+            groups: 0,
+            records: 0,
+            filtered: 0,
+            duplicates: 0,
+            data_size: grid.data_size(),
+            captured: Some(vec!()),
+            changed: Some(vec!()),
+            order: None,
+            writer: None,
+            csv: None,
+            database: None,
+            diagnostics: None,
+            job_id: ctx.job_id().to_hyphenated().to_string(),
+            path: String::default(),
+            data_writers: data_writers(grid),
+        }
+    }
+
     ///
     /// Append the records in this group to the matched group file.
     ///
@@ -83,19 +139,53 @@ impl MatchedHandler {
     /// When n is a file index in the grid and y is the line number in the file for the record. Line numbers include
     /// the header rows (so the first line of data will start at 3).
     ///
-    pub fn append_group(&mut self, records: &[&Record]) -> Result<(), MatcherError> {
+    /// If a sort instruction has been applied, the records are written in that order.
+    ///
+    /// If the group instruction calculated aggregates for the group, the group is written as an object instead: -
+    /// {"records": [[n1,y1], [n2,y2]], "aggregates": {"lhs": "100.00", "rhs": "-99.50", "difference": "0.50"}}
+    ///
+    pub fn append_group(&mut self, records: &[&Record], aggregates: Option<Value>) -> Result<(), MatcherError> {
         // Mark all records as matched in thier source files.
         self.set_matched_status(records)?;
 
+        let records = match &self.order {
+            Some(order) => order.sort(records)?,
+            None => records.to_vec(),
+        };
+
         // Update the matched.json file.
-        if self.groups !=  0 {
-            write!(&mut self.writer, ",\n    ")
-                .map_err(|source| MatcherError::CannotWriteThing { thing: "matched padding".into(), filename: self.path.clone(), source })?;
+        let path = &self.path;
+        if let Some(writer) = &mut self.writer {
+            if self.groups !=  0 {
+                write!(writer, ",\n    ")
+                    .map_err(|source| MatcherError::CannotWriteThing { thing: "matched padding".into(), filename: path.clone(), source })?;
+            }
+
+            let mut json = json!(records.iter().map(|r| json!(vec!(r.file_idx(), r.row()))).collect::<Vec<serde_json::Value>>());
+            if let Some(aggregates) = aggregates {
+                json = json!({ "records": json, "aggregates": aggregates });
+            }
+
+            serde_json::to_writer(writer, &json)
+                .map_err(|source| MatcherError::CannotWriteMatchedRecord{ filename: path.clone(), source })?;
+        }
+
+        if let Some((_path, csv)) = &mut self.csv {
+            for record in &records {
+                csv.write_record([self.job_id.as_str(), &self.groups.to_string(), &record.file_idx().to_string(), &record.row().to_string()])?;
+            }
         }
 
-        let json = records.iter().map(|r| json!(vec!(r.file_idx(), r.row()))).collect::<Vec<serde_json::Value>>();
-        serde_json::to_writer(&mut self.writer, &json)
-            .map_err(|source| MatcherError::CannotWriteMatchedRecord{ filename: self.path.clone(), source })?;
+        if let Some(database) = &mut self.database {
+            database.append_group(self.groups, &records)?;
+        }
+
+        if let Some(captured) = &mut self.captured {
+            captured.push(records.iter()
+                .map(|r| (r.schema().files()[r.file_idx()].filename().to_string(), r.row()))
+                .sorted()
+                .collect());
+        }
 
         self.groups += 1;
         self.records += records.len();
@@ -106,14 +196,19 @@ impl MatchedHandler {
     ///
     /// Terminate the matched file to make it's contents valid JSON.
     ///
-    pub fn complete_files(&mut self, unmatched: &UnmatchedHandler, changesets: Vec<ChangeSet>, duration: Duration)
-        -> Result<(), MatcherError> {
+    /// Returns the filenames of the completed matched file and any CSV or diagnostics file written alongside it.
+    ///
+    pub fn complete_files(&mut self, unmatched: &UnmatchedHandler, changesets: &[ChangeSet], stats: Value, duration: Duration)
+        -> Result<Vec<String>, MatcherError> {
+
+        let path = self.path.clone();
+        let writer = self.writer.as_mut().expect("a shadow handler has no matched file to complete");
 
         // Terminate the groups object.
-        write!(&mut self.writer, "]\n}},\n")
-            .map_err(|source| MatcherError::CannotWriteThing { thing: "matched groups terminator".into(), filename: self.path.clone(), source })?;
+        write!(writer, "]\n}},\n")
+            .map_err(|source| MatcherError::CannotWriteThing { thing: "matched groups terminator".into(), filename: path.clone(), source })?;
 
-        let footer = json!(
+        let mut footer = json!(
         {
             "unmatched": summerise_unmatched(unmatched),
             "changesets": summerise_changesets(changesets),
@@ -122,42 +217,168 @@ impl MatchedHandler {
             "matched_groups": self.groups,
             "duration_ms": (duration.as_secs() * 1000) + duration.subsec_millis() as u64,
             "data_size_bytes": self.data_size,
+            "stats": stats,
         });
 
+        if self.filtered > 0 {
+            footer["filtered_records"] = json!(self.filtered);
+        }
+
+        if self.duplicates > 0 {
+            footer["duplicate_records"] = json!(self.duplicates);
+        }
+
+        if let Some(totals) = unmatched.totals() {
+            footer["unmatched_totals_by_currency"] = totals
+                .iter()
+                .map(|(currency, total)| (currency.clone(), json!(convert::decimal_to_string(*total))))
+                .collect::<serde_json::Map<_, _>>()
+                .into();
+        }
+
         // Write the unmatched count and changeset metrics.
-        serde_json::to_writer_pretty(&mut self.writer, &footer)
-            .map_err(|source| MatcherError::CannotWriteFooter { filename: self.path.clone(), source })?;
+        serde_json::to_writer_pretty(&mut *writer, &footer)
+            .map_err(|source| MatcherError::CannotWriteFooter { filename: path.clone(), source })?;
 
         // Terminate the root array.
-        writeln!(&mut self.writer, "]")
-            .map_err(|source| MatcherError::CannotWriteThing { thing: "matched file terminator".into(), filename: self.path.clone(), source })?;
+        writeln!(writer, "]")
+            .map_err(|source| MatcherError::CannotWriteThing { thing: "matched file terminator".into(), filename: path.clone(), source })?;
 
         // Remove the .inprogress suffix
-        folders::complete_file(&self.path)?;
+        let completed = folders::complete_file(&self.path)?;
+        let mut filenames = vec!(folders::filename(&completed));
 
-        Ok(())
+        if let Some(mut database) = self.database.take() {
+            database.complete(&folders::filename(&completed), self.groups, self.records, unmatched, footer["duration_ms"].as_u64().unwrap_or_default())?;
+        }
+
+        if let Some((path, mut csv)) = self.csv.take() {
+            csv.flush()?;
+            filenames.push(folders::filename(&folders::complete_file(&path)?));
+        }
+
+        if let Some(diagnostics) = self.diagnostics.take() {
+            filenames.push(folders::filename(&diagnostics.complete()?));
+        }
+
+        Ok(filenames)
+    }
+
+    ///
+    /// True if groups which don't match should be explained in the diagnostics file.
+    ///
+    pub fn explaining(&self) -> bool {
+        self.diagnostics.is_some()
+    }
+
+    ///
+    /// Record the constraints a group failed in the diagnostics file, if the charter asks for them.
+    ///
+    pub fn explain_group(&mut self, instruction: usize, key: &str, records: &[&Record], failed: Vec<String>) -> Result<(), MatcherError> {
+        match &mut self.diagnostics {
+            Some(diagnostics) => diagnostics.append_group(instruction, key, records, failed),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Write the members of any subsequently matched groups in this order.
+    ///
+    pub fn set_order(&mut self, order: GroupOrder) {
+        self.order = Some(order);
+    }
+
+    ///
+    /// Take the matched groups captured for a shadow run (empty if not capturing).
+    ///
+    pub fn take_captured(&mut self) -> Vec<MatchedGroup> {
+        self.captured.take().unwrap_or_default()
     }
 
     ///
     /// Writer a '1' to the first column of each matched record.
     ///
     pub fn set_matched_status(&mut self, records: &[&Record]) -> Result<(), MatcherError> {
-        let buf = vec!(0x31); // = 1 = Matched
+        self.set_status(records, 0x31) // = 1 = Matched
+    }
+
+    ///
+    /// Writer a '2' to the first column of each record removed by a filter instruction. Filtered records are
+    /// never grouped or written to an unmatched file.
+    ///
+    pub fn set_filtered_status(&mut self, records: &[&Record]) -> Result<(), MatcherError> {
+        self.filtered += records.len();
+        self.set_status(records, 0x32) // = 2 = Filtered
+    }
+
+    ///
+    /// Writer a '3' to the first column of each record removed by a distinct instruction.
+    ///
+    pub fn set_duplicate_status(&mut self, records: &[&Record]) -> Result<(), MatcherError> {
+        self.duplicates += records.len();
+        self.set_status(records, 0x33) // = 3 = Duplicate
+    }
+
+    fn set_status(&mut self, records: &[&Record], status: u8) -> Result<(), MatcherError> {
+        let buf = vec!(status);
 
         for record in records {
             let file = &mut self.data_writers[record.file_idx()];
             file.write_all_at(record.data_position().byte() +/* Skip double-quotes */ 1, &buf)
                 .with_context(|| format!("Unable to update status for record {} in {}{}", record.row(), record.file_idx(), here!()))?;
+
+            if let Some(changed) = &mut self.changed {
+                changed.push((record.file_idx(), record.data_position().byte()));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Write a '0' back to the first column of every record a shadow handler changed the status of, so the records
+    /// are unmatched again.
+    ///
+    pub fn restore_statuses(&mut self) -> Result<(), MatcherError> {
+        for (file_idx, byte) in self.changed.take().unwrap_or_default() {
+            self.data_writers[file_idx].write_all_at(byte + /* Skip double-quotes */ 1, &[0x30]) // = 0 = Unmatched
+                .with_context(|| format!("Unable to restore status at byte {} in {}{}", byte, file_idx, here!()))?;
         }
 
         Ok(())
     }
 }
 
+///
+/// Open each data file in the grid so the status of it's records can be updated.
+///
+fn data_writers(grid: &Grid) -> Vec<File> {
+    grid.schema().files()
+        .iter()
+        .map(|df| OpenOptions::new()
+            .write(true)
+            .open(df.path())
+            .unwrap_or_else(|_| panic!("unable to open {} to update status", df.path().to_canoncial_string())))
+        .collect()
+}
+
+///
+/// Create the matched.csv file alongside the matched.json file and write it's header row.
+///
+fn new_csv(ctx: &Context, json_path: &Path) -> Result<(String, CsvWriter), MatcherError> {
+    let path = folders::matched_csv_file(json_path);
+    let mut writer = utils::csv::output_writer(&path, ctx.charter());
+
+    writer.write_record(["job_id", "group_id", "file_index", "row"])
+        .map_err(|source| MatcherError::CannotWriteHeaders { filename: folders::filename(&path), source })?;
+
+    Ok((path.to_canoncial_string(), writer))
+}
+
 ///
 /// List each remaining unmatched file and how many records it contains.
 ///
-fn summerise_unmatched(unmatched: &UnmatchedHandler) -> Vec<Value> {
+pub fn summerise_unmatched(unmatched: &UnmatchedHandler) -> Vec<Value> {
     unmatched.unmatched_files()
         .iter()
         .filter(|uf| uf.rows() > 0)
@@ -173,7 +394,7 @@ fn summerise_unmatched(unmatched: &UnmatchedHandler) -> Vec<Value> {
 /// List each changeset file that was present for the match job and summerise the count of effected records
 /// for each file.
 ///
-fn summerise_changesets(changesets: Vec<ChangeSet>) -> Vec<Value> {
+pub fn summerise_changesets(changesets: &[ChangeSet]) -> Vec<Value> {
 
     let mut json = vec!();
 
@@ -182,20 +403,30 @@ fn summerise_changesets(changesets: Vec<ChangeSet>) -> Vec<Value> {
         .sorted_by(|cs1, cs2| Ord::cmp(cs1.filename(), cs2.filename()))
         .group_by(|cs| cs.filename().to_string() ) {
 
-        let (updated, ignored): (Vec<&ChangeSet>, Vec<&ChangeSet>) = group.1.partition(|cs| {
-            match cs.change() {
-                Change::UpdateFields { .. }  => true,
-                Change::IgnoreRecords { .. } => false,
-                Change::DeleteFile { .. } => false,
-            }
-        });
+        let changesets = group.1.collect::<Vec<&ChangeSet>>();
+        let effected = |f: fn(&Change) -> bool| changesets.iter()
+            .filter(|cs| f(cs.change()))
+            .map(|cs| cs.effected())
+            .sum::<usize>();
 
-        json.push(json!(
+        let mut summary = json!(
         {
             "file": &group.0,
-            "updated": updated.iter().map(|cs| cs.effected()).sum::<usize>(),
-            "ignored": ignored.iter().map(|cs| cs.effected()).sum::<usize>()
-        }));
+            "updated": effected(|change| matches!(change, Change::UpdateFields { .. } | Change::ClearFields { .. })),
+            "ignored": effected(|change| matches!(change, Change::IgnoreRecords { .. })),
+            "added": effected(|change| matches!(change, Change::AddRecords { .. }))
+        });
+
+        // Only reported for changeset files which can un-ignore records.
+        if changesets.iter().any(|cs| matches!(cs.change(), Change::UnignoreRecords { .. })) {
+            summary["unignored"] = json!(effected(|change| matches!(change, Change::UnignoreRecords { .. })));
+        }
+
+        if changesets.iter().any(|cs| cs.dry_run()) {
+            summary["dry_run"] = json!(true);
+        }
+
+        json.push(summary);
     }
 
     json
@@ -1,17 +1,21 @@
 mod group_iter;
 mod constraints;
+mod database;
+mod diagnostics;
+pub mod distinct;
 pub mod matched;
 pub mod unmatched;
 
 use rlua::Context;
 use ubyte::ToByteUnit;
 use itertools::Itertools;
-use core::{charter::Constraint, lua::init_context};
+use core::{charter::{Constraint, DateTolerance, GroupAggregates, DATE_ONLY}, data_type::DataType, lua::{init_context, set_timeout}};
 use anyhow::Context as ErrContext;
 use bytes::{BufMut, Bytes, BytesMut};
-use std::{cell::Cell, time::{Duration, Instant}, fs::File, path::Path};
-use self::{prelude::*, group_iter::GroupIterator, matched::MatchedHandler};
-use crate::{error::{MatcherError, here}, formatted_duration_rate, model::{grid::Grid, record::Record, schema::GridSchema}, blue, folders::{self, ToCanoncialString}, lua, utils::{self, convert, csv::CsvWriter}};
+use rayon::{iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator}, slice::ParallelSlice};
+use std::{cell::Cell, iter::Peekable, time::{Duration, Instant}, fs::File, path::{Path, PathBuf}};
+use self::{prelude::*, group_iter::GroupIterator, matched::MatchedHandler, unmatched::UnmatchedHandler};
+use crate::{error::{MatcherError, here}, formatted_duration_rate, model::{grid::Grid, record::Record, schema::GridSchema}, blue, folders::{self, ToCanoncialString}, lua, utils::{self, convert}};
 
 // The column position in our index records for the merge_key used to sort index records.
 mod prelude {
@@ -23,13 +27,39 @@ mod prelude {
     pub const COL_MERGE_KEY: usize = 5;
 }
 
+pub(crate) const MILLIS_IN_A_DAY: u64 = 86_400_000;
+
+///
+/// How a group instruction brings records together before the constraint rules are evaluated.
+///
+pub struct Grouping<'a> {
+    pub instruction: usize, // The position of the group instruction in the charter.
+    pub by: &'a [String],
+    pub normalise_keys: bool,
+    pub date_tolerance: Option<&'a DateTolerance>,
+    pub max_group_size: Option<usize>,
+    pub aggregates: Option<&'a GroupAggregates>,
+}
+
 ///
 /// Derive a value ('match key') to group this record with others.
 ///
-fn match_key(record: &Record, headers: &[String]) -> Result<Bytes, MatcherError> {
+/// If normalise is set, decimal and datetime values are converted to a canonical form first so that
+/// equal values with different representations (e.g. 100 and 100.00) produce the same key.
+///
+/// A group-by column suffixed with :date_only (e.g. Date:date_only) has its time portion truncated to midnight.
+///
+fn match_key(record: &Record, headers: &[String], normalise: bool) -> Result<Bytes, MatcherError> {
     let mut buf = BytesMut::new();
     for header in headers {
+        let (header, date_only) = match header.strip_suffix(DATE_ONLY) {
+            Some(header) => (header, true),
+            None => (header.as_str(), false),
+        };
+
         match record.get_as_bytes(header).expect("Failed to read match ley") {
+            Some(bytes) if date_only => buf.put(date_only_key(record.schema().data_type(header), header, bytes, normalise)?),
+            Some(bytes) if normalise => buf.put(normalise_key(record.schema().data_type(header), bytes)?),
             Some(bytes) => buf.put(bytes),
             None => return Err(MatcherError::GroupByColumnMissing { column: header.to_string() }),
         }
@@ -38,27 +68,59 @@ fn match_key(record: &Record, headers: &[String]) -> Result<Bytes, MatcherError>
 }
 
 ///
-/// Evaluate the constraint rules against the grroup to see if they all pass.
+/// Truncate a datetime key field to midnight UTC. The result is in the same form as the column's other
+/// values would be, i.e. epoch millis if keys are normalised, otherwise an ISO8601 string.
 ///
-fn is_match(
+fn date_only_key(data_type: Option<&DataType>, header: &str, bytes: Bytes, normalise: bool) -> Result<Bytes, MatcherError> {
+    if data_type != Some(&DataType::Datetime) {
+        return Err(MatcherError::GroupByDateOnlyNotDatetime { column: header.to_string() })
+    }
+
+    let datetime = convert::csv_bytes_to_datetime(bytes)?;
+    let midnight = datetime - (datetime % MILLIS_IN_A_DAY);
+
+    match normalise {
+        true  => Ok(midnight.to_string().into()),
+        false => Ok(convert::datetime_to_string(midnight).into()),
+    }
+}
+
+///
+/// Convert the raw bytes of a key field into a canonical representation for the column's data-type.
+///
+/// Decimals are normalised to strip trailing zeros and datetimes become epoch millis.
+///
+fn normalise_key(data_type: Option<&DataType>, bytes: Bytes) -> Result<Bytes, MatcherError> {
+    match data_type {
+        Some(DataType::Decimal) => Ok(convert::decimal_to_string(convert::csv_bytes_to_decimal(bytes)?.normalize()).into()),
+        Some(DataType::Datetime) => Ok(convert::csv_bytes_to_datetime(bytes)?.to_string().into()),
+        _ => Ok(bytes),
+    }
+}
+
+///
+/// Evaluate the constraint rules against the group, returning the position of each constraint which didn't pass. The
+/// group is a match if none fail.
+///
+fn failed_constraints(
     group: &[&Record],
     constraints: &[Constraint],
     schema: &GridSchema,
     lua_ctx: &Context,
-    lua_time: &Cell<Duration>) -> Result<bool, MatcherError> {
+    lua_time: &Cell<Duration>) -> Result<Vec<usize>, MatcherError> {
 
     let mut failed = vec!();
     let start = Instant::now();
 
-    for (_index, constraint) in constraints.iter().enumerate() {
+    for (index, constraint) in constraints.iter().enumerate() {
         if !constraints::passes(constraint, group, schema, lua_ctx)? {
-            failed.push(constraint);
+            failed.push(index);
         }
     }
 
     lua_time.replace(lua_time.get() + start.elapsed());
 
-    Ok(failed.is_empty())
+    Ok(failed)
 }
 
 ///
@@ -85,35 +147,43 @@ fn is_match(
 /// This row is an index pointer to the real csv data and the derived csv data rows for the record. Note: both byte
 /// and line positions are required by the csv library to seek a row.
 ///
+/// Because the sort can only group records with identical keys, a date tolerance is applied afterwards. Each sorted
+/// group is split into overlapping windows of records dated within the tolerance of each other and it's these windows
+/// which have the constraint rules evaluated against them.
+///
+/// Matched records are removed from the grid, so any subsequent group instruction only groups the records left
+/// unmatched by this one. If stream is set, nothing else can match the unmatched records so they're handed straight
+/// to the unmatched handler.
+///
 pub fn match_groups(
     ctx: &crate::Context,
-    group_by: &[String],
+    grouping: &Grouping,
     constraints: &[Constraint],
-    grid: &Grid,
-    matched: &mut MatchedHandler) -> Result<(), MatcherError> {
+    grid: &mut Grid,
+    matched: &mut MatchedHandler,
+    unmatched: &mut UnmatchedHandler,
+    stream: bool) -> Result<(), MatcherError> {
 
     if grid.is_empty() {
         return Ok(())
     }
 
-    log::info!("Grouping by {}", group_by.iter().join(", "));
+    unmatched.evaluating(grouping.instruction);
 
-    let lua_time = Cell::new(Duration::from_millis(0));
+    log::info!("Grouping by {}", grouping.by.iter().join(", "));
 
-    // Build index.unsorted.csv. and calculate the approximate length of each index row.
-    create_unsorted(ctx, group_by, grid)?;
+    if let Some(tolerance) = grouping.date_tolerance {
+        log::info!("Allowing {} to vary by up to {} day(s)", tolerance.column(), tolerance.days());
+    }
 
-    // Use a buffer to sort chunks of data and write each sorted chunk to it's own file.
-    let file_count = split_and_sort(ctx, grid)?;
+    let lua_time = Cell::new(Duration::from_millis(0));
 
-    // Initialise input and output readers/writers - prior to merge sorting.
-    let (inputs, output) = initialise_buffers(ctx, file_count);
-
-    // Merge-sort all the chunks into a single index.sorted.csv file.
-    merge_sort(inputs, output);
+    // Sort the records into index.sorted.csv so groups are contiguous.
+    let file_count = sort_index(ctx, grouping.by, grouping.normalise_keys, grid)?;
 
     // Match groups which pass the constriant rules.
-    let (group_count, match_count) = eval_contraints(ctx, grid, constraints, matched, &lua_time)?;
+    let (group_count, match_count, record_count) = eval_contraints(ctx, grid, grouping, constraints, matched, unmatched, stream, &lua_time)?;
+    grid.remove_records(record_count);
 
     // Delete all index files, index.unsorted.csv, index.sorted.*
     clean_up_indexes(ctx, file_count)?;
@@ -128,15 +198,65 @@ pub fn match_groups(
     Ok(())
 }
 
+///
+/// Build index.sorted.csv, an index of every unmatched record in the grid ordered by its match key, so that records
+/// with the same key are contiguous and can be read with a GroupIterator.
+///
+/// Returns the number of chunked index files created, these must be removed with clean_up_indexes afterwards.
+///
+fn sort_index(ctx: &crate::Context, group_by: &[String], normalise_keys: bool, grid: &Grid) -> Result<usize, MatcherError> {
+    // Build index.unsorted.csv. and calculate the approximate length of each index row.
+    create_unsorted(ctx, group_by, normalise_keys, grid)?;
+
+    // Use a buffer to sort chunks of data and write each sorted chunk to it's own file.
+    let file_count = split_and_sort(ctx, grid)?;
+
+    // Initialise input and output readers/writers - prior to merge sorting.
+    let (inputs, output) = initialise_buffers(ctx, file_count);
+
+    // Merge-sort all the chunks into a single index.sorted.csv file.
+    merge_sort(inputs, output);
+
+    Ok(file_count)
+}
+
+///
+/// Sort the index of streamed unmatched records (unmatched.unsorted.csv) by file and position within the file, so they
+/// can be re-read from the source files in their original order.
+///
+/// Returns the path of the sorted index, the unsorted and chunked index files are removed.
+///
+fn sort_unmatched_index(ctx: &crate::Context, rows: usize) -> Result<PathBuf, MatcherError> {
+    let unsorted_path = folders::unmatched_index(ctx);
+    let sorted_path = folders::matching(ctx).join("unmatched.sorted.csv");
+    let chunk_path = |idx: usize| folders::matching(ctx).join(format!("unmatched.sorted.{}", idx));
+
+    let avg_len = estimated_index_size(&unsorted_path, rows)?;
+    let batch_size = batch_size(avg_len, ctx.charter().memory_limit());
+    let file_count = split_into_sorted(&unsorted_path, batch_size, chunk_path);
+
+    let inputs = (1..=file_count)
+        .map(|idx| utils::csv::index_reader(chunk_path(idx)))
+        .collect();
+    merge_sort(inputs, utils::csv::writer(&sorted_path));
+
+    folders::remove_file(&unsorted_path)?;
+    for idx in 1..=file_count {
+        folders::remove_file(chunk_path(idx))?;
+    }
+
+    Ok(sorted_path)
+}
+
 ///
 /// Estimate the size of each record index row.
 ///
-fn estimated_index_size(unsorted_path: &Path, grid: &Grid) -> Result<usize, MatcherError> {
+fn estimated_index_size(unsorted_path: &Path, rows: usize) -> Result<usize, MatcherError> {
     let f = File::open(&unsorted_path)
         .with_context(|| format!("Unable to open {}{}", unsorted_path.to_canoncial_string(), here!()))?;
 
     let f_len = f.metadata().expect("no metadata").len();
-    let mut avg_len = (f_len as f64 / grid.len() as f64) as usize;   // Average data length.
+    let mut avg_len = (f_len as f64 / rows.max(1) as f64) as usize;  // Average data length.
     avg_len += std::mem::size_of::<csv::ByteRecord>();               // Struct 8B.
     avg_len += std::mem::size_of::<usize>();                         // Pointer to struct 8B.
     avg_len += std::mem::size_of::<usize>() * 6;                     // 6 fields (in the bounds sub-struct)
@@ -146,14 +266,14 @@ fn estimated_index_size(unsorted_path: &Path, grid: &Grid) -> Result<usize, Matc
 ///
 /// Calculate how many index records form a batch that will fit in the memory bounds.
 ///
-fn batch_size(avg_len: usize, ctx: &crate::Context) -> usize {
-    (ctx.charter().memory_limit() as f64 / avg_len as f64) as usize
+fn batch_size(avg_len: usize, memory_limit: usize) -> usize {
+    std::cmp::max(1, (memory_limit as f64 / avg_len as f64) as usize)
 }
 
 ///
 /// Create a file index for every record in the grid, along with the merge-key we'll use to sort the records.
 ///
-fn create_unsorted(ctx: &crate::Context, group_by: &[String], grid: &Grid) -> Result<(), MatcherError> {
+fn create_unsorted(ctx: &crate::Context, group_by: &[String], normalise_keys: bool, grid: &Grid) -> Result<(), MatcherError> {
 
     let unsorted_path = folders::unsorted_index(ctx);
     let mut unsorted_writer = utils::csv::writer(&unsorted_path);
@@ -168,7 +288,7 @@ fn create_unsorted(ctx: &crate::Context, group_by: &[String], grid: &Grid) -> Re
         buffer.push_field(convert::int_to_string(record.data_position().line() as i64).as_bytes());
         buffer.push_field(convert::int_to_string(record.derived_position().byte() as i64).as_bytes());
         buffer.push_field(convert::int_to_string(record.derived_position().line() as i64).as_bytes());
-        buffer.push_field(&match_key(&record, group_by)?);
+        buffer.push_field(&match_key(&record, group_by, normalise_keys)?);
         unsorted_writer.write_byte_record(&buffer)?;
         buffer.clear();
     }
@@ -195,21 +315,34 @@ fn split_and_sort(ctx: &crate::Context, grid: &Grid) -> Result<usize, MatcherErr
 
     // Calculate the average index row length.
     let unsorted_path = folders::unsorted_index(ctx);
-    let avg_len = estimated_index_size(&unsorted_path, grid)?;
+    let avg_len = estimated_index_size(&unsorted_path, grid.len())?;
 
     log::debug!("Split-sorting with average index length {avg_len}", avg_len = avg_len.bytes());
 
-    // Create a reader and a buffer to sort batches of data.
-    let batch_size = batch_size(avg_len, ctx);
+    let batch_size = batch_size(avg_len, ctx.charter().memory_limit());
+
+    Ok(split_into_sorted(&unsorted_path, batch_size, |file_idx| sorted_path(ctx, file_idx)))
+}
+
+///
+/// Read batches of index records, sort each batch by merge key and write it to a new file at the path from new_path.
+///
+/// Returns the number of sorted files written.
+///
+fn split_into_sorted<F>(unsorted_path: &Path, batch_size: usize, mut new_path: F) -> usize
+where
+    F: FnMut(usize) -> PathBuf
+{
     let mut file_count = 0; // Number of split files containing the chunked, sorted data.
-    let mut reader = utils::csv::index_reader(&unsorted_path);
     let mut buffer: Vec<csv::ByteRecord> = Vec::with_capacity(batch_size);
+    let mut reader = utils::csv::index_reader(unsorted_path);
+    let mut records = reader.byte_records().peekable();
 
-    for result in reader.byte_records() {
-        let record = result.unwrap_or_else(|_| panic!("Unable to read record from {}", unsorted_path.to_canoncial_string()));
-        buffer.push(record);
+    while let Some(result) = records.next() {
+        buffer.push(result.unwrap_or_else(|_| panic!("Unable to read record from {}", unsorted_path.to_canoncial_string())));
 
-        if buffer.len() == batch_size {
+        // Sort and write each full batch, and the last (potentially partial) batch.
+        if buffer.len() == batch_size || records.peek().is_none() {
             // Sort by merge key.
             buffer.sort_unstable_by(|r1, r2| r1.get(COL_MERGE_KEY).expect("no merge key")
                 .cmp(r2.get(COL_MERGE_KEY).expect("no merge key")) );
@@ -218,33 +351,21 @@ fn split_and_sort(ctx: &crate::Context, grid: &Grid) -> Result<usize, MatcherErr
             file_count += 1;
 
             // Write the sorted data to a new split file.
-            let mut writer = sorted_writer(ctx, file_count);
-            buffer.iter().for_each(|record| writer.write_byte_record(record).expect("unable to write sorted index"));
+            let sorted_path = new_path(file_count);
+            let mut writer = utils::csv::writer(&sorted_path);
+            buffer.iter().for_each(|record| writer.write_byte_record(record)
+                .unwrap_or_else(|_| panic!("Unable to write sorted index {}", sorted_path.to_canoncial_string())));
 
             // Clear the buffer.
             buffer.clear();
         }
     }
 
-    // Sort and write the last batch.
-    if !buffer.is_empty() {
-        // Sort by merge key.
-        buffer.sort_unstable_by(|r1, r2| r1.get(COL_MERGE_KEY).expect("no merge key").cmp(r2.get(COL_MERGE_KEY).expect("no merge key")) );
-
-        // Increment the count of split sorted files.
-        file_count += 1;
-
-        // Write the sorted data to a new split file.
-        let mut writer = sorted_writer(ctx, file_count);
-        buffer.iter().for_each(|record| writer.write_byte_record(record).expect("unable to write sorted index"));
-    }
-
-    Ok(file_count)
+    file_count
 }
 
-fn sorted_writer(ctx: &crate::Context, file_idx: usize) -> CsvWriter {
-    let sorted_path = folders::matching(ctx).join(format!("index.sorted.{}", file_idx));
-    utils::csv::writer(&sorted_path)
+fn sorted_path(ctx: &crate::Context, file_idx: usize) -> PathBuf {
+    folders::matching(ctx).join(format!("index.sorted.{}", file_idx))
 }
 
 
@@ -322,46 +443,297 @@ fn kway_sort(registers: &[Option<csv::ByteRecord>]) -> usize {
 /// Iterate all of the sorted indexes as groups and evaluate the Lua constraint rules against each group.
 /// If the group is a match, pass it to the match handler.
 ///
+/// Groups are read in batches, each holding as many groups as fit in the charter's memory_limit, and each batch is split
+/// between a pool of workers, each with it's own Lua state. The outcomes are then applied in the order the groups were
+/// read, so the results are the same however many threads (the charter's match_threads) are used.
+///
+/// Returns the number of groups evaluated, the number of groups matched and the number of records matched.
+///
+#[allow(clippy::too_many_arguments)]
 fn eval_contraints(
     ctx: &crate::Context,
     grid: &Grid,
+    grouping: &Grouping,
     constraints: &[Constraint],
     matched: &mut MatchedHandler,
-    lua_time: &Cell<Duration>) -> Result<(usize, usize), MatcherError> {
+    unmatched: &mut UnmatchedHandler,
+    stream: bool,
+    lua_time: &Cell<Duration>) -> Result<(usize, usize, usize), MatcherError> {
 
     let mut group_count = 0;
     let mut match_count = 0;
+    let mut record_count = 0;
 
-    log::info!("Evaluating constraints on groups");
+    let threads = ctx.charter().match_threads().unwrap_or_else(num_cpus::get);
+    log::info!("Evaluating constraints on groups ({} thread(s))", threads);
 
-    // Create a Lua context to evaluate Constraint rules in.
-    ctx.lua().context(|lua_ctx| {
-        init_context(&lua_ctx, ctx.charter().global_lua(), &folders::lookups(ctx))?;
-        lua::create_aggregate_fns(&lua_ctx)?;
+    // Create a Lua state per worker to evaluate Constraint rules in.
+    let mut luas = (0..threads)
+        .map(|_| constraint_lua(ctx))
+        .collect::<Result<Vec<rlua::Lua>, MatcherError>>()?;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("can't build rayon thread pool");
+
+    // Iterate groups a batch at a time, loading all the batch's records into memory.
+    let mut groups = GroupIterator::new(ctx, grid.schema(), grouping.max_group_size).peekable();
 
-        // Iterate groups one at a time, loading all the group's records into memory.
-        for group in GroupIterator::new(ctx, grid.schema()) {
-            let group = group?;
-            group_count += 1;
+    loop {
+        ctx.check_cancelled()?;
+
+        let batch = next_batch(&mut groups, ctx.charter().memory_limit(), |group| group.iter().map(record_size).sum())
+            .map_err(|err| MatcherError::MatchGroupError { source: err.into() })?;
+
+        if batch.is_empty() {
+            break
+        }
 
-            let records: Vec<&Record> = group.iter().collect();
+        group_count += batch.len();
 
-            if is_match(&records, constraints, grid.schema(), &lua_ctx, lua_time)? {
-                matched.append_group(&records)?;
+        let (outcomes, batch_time) = pool.install(|| eval_batch(&batch, &mut luas, grouping, constraints, grid.schema()))?;
+        lua_time.replace(lua_time.get() + batch_time);
+
+        for (group, outcome) in batch.iter().zip(outcomes) {
+            for (members, aggregates) in outcome.matched {
+                let records: Vec<&Record> = members.into_iter().map(|idx| &group[idx]).collect();
+                matched.append_group(&records, aggregates)?;
                 match_count += 1;
+                record_count += records.len();
+            }
+
+            if matched.explaining() && !outcome.unmatched.is_empty() {
+                let key = match_key(&group[outcome.unmatched[0]], grouping.by, grouping.normalise_keys)?;
+                let records: Vec<&Record> = outcome.unmatched.iter().map(|idx| &group[*idx]).collect();
+                let failed = outcome.failed.iter().map(|idx| format!("{:?}", constraints[*idx])).collect();
+                matched.explain_group(grouping.instruction, &String::from_utf8_lossy(&key), &records, failed)?;
+            }
+
+            for idx in outcome.unevaluated {
+                unmatched.not_evaluated(grouping.instruction, &group[idx]);
+            }
+
+            // If this is the last group instruction, nothing else can match these records - write them out now.
+            if stream {
+                for idx in outcome.unmatched {
+                    unmatched.write_record(ctx, &group[idx])?;
+                }
+            }
+        }
+    }
 
-            // } else if group_count <= 0 /* Useful but grid debugging might mean this isn't required. */{
-            //     log::info!("Unmatched group:-\n{:?}{}",
-            //         grid.schema().headers(),
-            //         records.iter().map(|r| format!("\n{:?}", r.as_strings())).collect::<String>());
+    Ok((group_count, match_count, record_count))
+}
+
+///
+/// Take groups until the next would take the batch over max_bytes. A batch always has at least one group, so a group
+/// larger than max_bytes is evaluated on its own.
+///
+fn next_batch<T, I, F>(groups: &mut Peekable<I>, max_bytes: usize, size: F) -> Result<Vec<T>, MatcherError>
+where
+    I: Iterator<Item = Result<T, MatcherError>>,
+    F: Fn(&T) -> usize
+{
+    let mut batch = vec!();
+    let mut bytes = 0;
+
+    while let Some(next) = groups.peek() {
+        if let Ok(group) = next {
+            bytes += size(group);
+            if !batch.is_empty() && bytes > max_bytes {
+                break
             }
         }
 
-        Ok(())
+        batch.push(groups.next().expect("peeked group")?);
+    }
+
+    Ok(batch)
+}
+
+///
+/// The approximate memory used by a record loaded into a group - its source and derived data and field positions.
+///
+fn record_size(record: &Record) -> usize {
+    std::mem::size_of::<Record>()
+        + record.data().as_slice().len()
+        + record.derived().as_slice().len()
+        + (record.data().len() + record.derived().len()) * std::mem::size_of::<usize>()
+}
+
+///
+/// The records (by their position in the group) which matched, and those which didn't, when a group was evaluated.
+///
+struct GroupOutcome {
+    matched: Vec<(Vec<usize>, Option<serde_json::Value>)>, // Each matched set of records and any aggregates for it.
+    unmatched: Vec<usize>,
+    unevaluated: Vec<usize>, // The unmatched records the constraints couldn't be evaluated against.
+    failed: Vec<usize>, // The position of each constraint which failed when the unmatched records were evaluated.
+}
+
+///
+/// Create a Lua state with the global Lua, lookups and aggregate functions needed for constraint rules.
+///
+fn constraint_lua(ctx: &crate::Context) -> Result<rlua::Lua, MatcherError> {
+    let lua = rlua::Lua::new();
+    set_timeout(&lua, ctx.charter().lua_timeout_ms());
+
+    lua.context(|lua_ctx| {
+        init_context(&lua_ctx, ctx.charter().global_lua(), &folders::lookups(ctx))?;
+        lua::create_aggregate_fns(&lua_ctx)
     })
     .map_err(|source| MatcherError::MatchGroupError { source })?;
 
-    Ok((group_count, match_count))
+    Ok(lua)
+}
+
+///
+/// Split the batch of groups into a contiguous chunk per worker and evaluate each chunk with the worker's Lua state.
+///
+/// The outcomes are returned in the same order as the groups, along with the total time spent evaluating them.
+///
+fn eval_batch(
+    batch: &[Vec<Record>],
+    luas: &mut [rlua::Lua],
+    grouping: &Grouping,
+    constraints: &[Constraint],
+    schema: &GridSchema) -> Result<(Vec<GroupOutcome>, Duration), MatcherError> {
+
+    let chunk_size = batch.len().div_ceil(luas.len());
+
+    let chunks = batch
+        .par_chunks(chunk_size)
+        .zip(luas.par_iter_mut())
+        .map(|(groups, lua)| {
+            let worker_time = Cell::new(Duration::ZERO);
+
+            let outcomes = lua.context(|lua_ctx| {
+                groups.iter()
+                    .map(|group| eval_group(group, grouping, constraints, schema, &lua_ctx, &worker_time))
+                    .collect::<Result<Vec<GroupOutcome>, MatcherError>>()
+                    .map_err(rlua::Error::from)
+            })
+            .map_err(|source| MatcherError::MatchGroupError { source })?;
+
+            Ok((outcomes, worker_time.get()))
+        })
+        .collect::<Result<Vec<(Vec<GroupOutcome>, Duration)>, MatcherError>>()?;
+
+    let mut outcomes = Vec::with_capacity(batch.len());
+    let mut lua_time = Duration::ZERO;
+
+    for (chunk, worker_time) in chunks {
+        lua_time += worker_time;
+        outcomes.extend(chunk);
+    }
+
+    Ok((outcomes, lua_time))
+}
+
+///
+/// Evaluate the constraint rules against a group of records - or windows of the group if there's a date tolerance.
+///
+fn eval_group(
+    group: &[Record],
+    grouping: &Grouping,
+    constraints: &[Constraint],
+    schema: &GridSchema,
+    lua_ctx: &Context,
+    lua_time: &Cell<Duration>) -> Result<GroupOutcome, MatcherError> {
+
+    let records: Vec<&Record> = group.iter().collect();
+
+    if let Some(tolerance) = grouping.date_tolerance {
+        return eval_date_windows(&records, grouping, tolerance, constraints, schema, lua_ctx, lua_time)
+    }
+
+    let failed = failed_constraints(&records, constraints, schema, lua_ctx, lua_time)?;
+
+    match failed.is_empty() {
+        true => {
+            let aggregates = group_aggregates(grouping, &records, schema, lua_ctx)?;
+            Ok(GroupOutcome { matched: vec!(((0..records.len()).collect(), aggregates)), unmatched: vec!(), unevaluated: vec!(), failed })
+        },
+        false => Ok(GroupOutcome { matched: vec!(), unmatched: (0..records.len()).collect(), unevaluated: vec!(), failed }),
+    }
+}
+
+///
+/// Evaluate the constraint rules against windows of the group's records, rather than the group as a whole.
+///
+/// Records are ordered by date and each window starts at the earliest record still available and includes every
+/// record dated within the tolerance of it. If the window matches, its records are removed from the group, otherwise
+/// the next window starts from the following record - so windows overlap. Undated records are never matched.
+///
+/// Returns the windows which matched and the records left unmatched - the undated of which were never evaluated.
+///
+fn eval_date_windows(
+    records: &[&Record],
+    grouping: &Grouping,
+    tolerance: &DateTolerance,
+    constraints: &[Constraint],
+    schema: &GridSchema,
+    lua_ctx: &Context,
+    lua_time: &Cell<Duration>) -> Result<GroupOutcome, MatcherError> {
+
+    let max_spread = tolerance.days() * MILLIS_IN_A_DAY;
+    let mut dated = vec!();
+    let mut undated = vec!();
+
+    for (idx, record) in records.iter().enumerate() {
+        match record.get_datetime(tolerance.column())? {
+            Some(date) => dated.push((date, idx)),
+            None => undated.push(idx),
+        }
+    }
+
+    dated.sort_by_key(|(date, _idx)| *date);
+
+    let mut matched = vec!();
+    let mut failed = vec!();
+    let mut start = 0;
+
+    while start < dated.len() {
+        let from = dated[start].0;
+        let window: Vec<usize> = dated[start..]
+            .iter()
+            .take_while(|(date, _idx)| date - from <= max_spread)
+            .map(|(_date, idx)| *idx)
+            .collect();
+
+        let window_records: Vec<&Record> = window.iter().map(|idx| records[*idx]).collect();
+
+        let within = constraints::within_date_tolerance(tolerance.column(), tolerance.days(), &window_records, schema)?;
+        let window_failed = match within {
+            true  => failed_constraints(&window_records, constraints, schema, lua_ctx, lua_time)?,
+            false => vec!(),
+        };
+
+        if within && window_failed.is_empty() {
+            let aggregates = group_aggregates(grouping, &window_records, schema, lua_ctx)?;
+            dated.drain(start..start + window.len());
+            matched.push((window, aggregates));
+        } else {
+            failed.extend(window_failed);
+            start += 1;
+        }
+    }
+
+    let unmatched = undated.iter().copied().chain(dated.into_iter().map(|(_date, idx)| idx)).collect();
+    Ok(GroupOutcome { matched, unmatched, unevaluated: undated, failed: failed.into_iter().sorted().dedup().collect() })
+}
+
+///
+/// If the group instruction asks for them, total each side of a matched group and the difference between them.
+///
+fn group_aggregates(grouping: &Grouping, records: &[&Record], schema: &GridSchema, lua_ctx: &Context)
+    -> Result<Option<serde_json::Value>, MatcherError> {
+
+    match grouping.aggregates {
+        Some(aggregates) => Ok(Some(constraints::aggregates(aggregates, records, schema, lua_ctx)?)),
+        None => Ok(None),
+    }
 }
 
 ///
@@ -402,3 +774,61 @@ impl RecordProvider for csv::Reader<File> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiny_batches_still_merge_sort() {
+        let dir = std::env::temp_dir().join(format!("openrec_merge_sort_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Write an unsorted index with the merge keys out of order.
+        let mut unsorted = utils::csv::writer(dir.join("index.unsorted.csv"));
+        for idx in 0..100 {
+            let key = format!("{:03}", (idx * 37) % 100);
+            unsorted.write_record(["0", "0", "0", "0", "0", &key]).unwrap();
+        }
+        unsorted.flush().unwrap();
+
+        // An average index length close to the memory limit gives a batch of a few records.
+        let batch_size = batch_size(1024 * 1024 * 3, 16 * 1024 * 1024);
+        assert_eq!(batch_size, 5);
+
+        let file_count = split_into_sorted(&dir.join("index.unsorted.csv"), batch_size, |idx| dir.join(format!("index.sorted.{}", idx)));
+        assert_eq!(file_count, 20);
+
+        let inputs = (1..=file_count)
+            .map(|idx| utils::csv::index_reader(dir.join(format!("index.sorted.{}", idx))))
+            .collect();
+        merge_sort(inputs, utils::csv::writer(dir.join("index.sorted.csv")));
+
+        let keys: Vec<String> = utils::csv::index_reader(dir.join("index.sorted.csv"))
+            .records()
+            .map(|r| r.unwrap()[COL_MERGE_KEY].to_string())
+            .collect();
+
+        let expected: Vec<String> = (0..100).map(|idx| format!("{:03}", idx)).collect();
+        assert_eq!(keys, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_batches_are_bounded_by_bytes() {
+        let mut groups = [40, 50, 20, 150, 10].iter().copied().map(Ok::<usize, MatcherError>).peekable();
+        let mut batches = vec!();
+
+        loop {
+            let batch = next_batch(&mut groups, 100, |group| *group).unwrap();
+            if batch.is_empty() {
+                break
+            }
+            batches.push(batch);
+        }
+
+        // A group larger than the limit is a batch on it's own.
+        assert_eq!(batches, vec!(vec!(40, 50), vec!(20), vec!(150), vec!(10)));
+    }
+}
@@ -1,12 +1,21 @@
 use csv::Writer;
-use std::{collections::HashMap, fs::File, path::PathBuf};
-use crate::{error::MatcherError, folders::{self, ToCanoncialString}, model::grid::Grid, Context, utils};
+use rust_decimal::Decimal;
+use std::{collections::{BTreeMap, HashMap, HashSet, hash_map::Entry}, fs::{self, File}, path::PathBuf};
+use super::{group_iter::csv_to_u64, prelude::*, MILLIS_IN_A_DAY};
+use crate::{error::MatcherError, folders::{self, ToCanoncialString}, model::{datafile::DataFile, grid::Grid, record::Record, schema::AGE}, Context, utils::{self, csv::{CsvReaders, CsvWriter}}};
 
 ///
 /// Manages the unmatched files for the current job.
 ///
 pub struct UnmatchedHandler {
-    files: HashMap<String /* ORIGINAL filename, e.g. 20211126_072400000_invoices.csv. */, UnmatchedFile>,
+    files: HashMap<(Option<usize> /* instruction sub-folder */, String /* ORIGINAL filename, e.g. 20211126_072400000_invoices.csv. */), UnmatchedFile>,
+    totals: Option<BTreeMap<String /* currency */, Decimal>>, // Unmatched amounts, if configured in the charter.
+    streamed: bool, // True if records were indexed as the final group instruction evaluated them.
+    index: Option<CsvWriter>, // The positions of streamed records, to write them in their original file order.
+    indexed: usize,
+    by_instruction: bool, // Split unmatched files into a sub-folder per group instruction.
+    stages: Vec<usize>, // The group instructions which have evaluated the grid, in order.
+    skipped: HashMap<(usize /* file_idx */, u64 /* data byte */), HashSet<usize>>, // Stages which couldn't evaluate a record.
 }
 
 ///
@@ -15,8 +24,11 @@ pub struct UnmatchedHandler {
 pub struct UnmatchedFile {
     rows: usize,
     path: PathBuf,
-    full_filename: String, // CURRENT filename, e.g. 20211126_072400000_invoices.unmatched.csv.
+    full_filename: String, // CURRENT filename, e.g. 20211126_072400000_invoices.unmatched.csv or 2/20211126_072400000_invoices.unmatched.csv.
+    instruction: Option<usize>, // The group instruction sub-folder the file is in, if any.
     writer: Writer<File>,
+    aged: bool,            // The sourced file's records already end with an age.
+    age: Option<String>,   // The days since the records were first seen, if the charter ages unmatched records.
 }
 
 impl UnmatchedFile {
@@ -37,58 +49,205 @@ impl UnmatchedHandler {
     /// if there are any files that didn't have data appended, they are deleted.
     ///
     pub fn new(ctx: &Context, grid: &Grid) -> Result<Self, MatcherError> {
-        let mut files: HashMap<String, UnmatchedFile> = HashMap::new();
+        let mut files = HashMap::new();
 
         // Create an unmatched file for each original sourced data file (i.e. there may be )
         for file in grid.schema().files() {
-            if !files.contains_key(file.filename()) {
-                // Create an new unmatched file.
-                let output_path = folders::new_unmatched_file(ctx, file); // $REC_HOME/unmatched/timestamp_invoices.unmatched.csv
-                let full_filename = folders::filename(&output_path); // timestamp_invoices.unmatched.csv
-                let mut writer = utils::csv::writer(&output_path);
+            if let Entry::Vacant(entry) = files.entry((None, file.filename().to_string())) {
+                entry.insert(create_file(ctx, grid, file, None)?);
+            }
+        }
 
-                // Add the column header and schema rows.
-                let schema = &grid.schema().file_schemas()[file.schema_idx()];
+        let totals = ctx.charter().unmatched_totals().as_ref().map(|_| BTreeMap::new());
 
-                writer.write_record(schema.columns().iter().map(|c| c.header_no_prefix()).collect::<Vec<&str>>())
-                    .map_err(|source| MatcherError::CannotWriteHeaders{ filename: file.filename().into(), source })?;
+        Ok(Self {
+            files,
+            totals,
+            streamed: false,
+            index: None,
+            indexed: 0,
+            by_instruction: ctx.charter().unmatched_by_instruction(),
+            stages: vec!(),
+            skipped: HashMap::new(),
+        })
+    }
 
-                writer.write_record(schema.columns().iter().map(|c| c.data_type().as_str()).collect::<Vec<&str>>())
-                    .map_err(|source| MatcherError::CannotWriteSchema{ filename: file.filename().into(), source })?;
+    ///
+    /// A handler for a shadow charter, it has no unmatched files as only the shadow charter's groups are kept.
+    ///
+    pub fn shadow() -> Self {
+        Self {
+            files: HashMap::new(),
+            totals: None,
+            streamed: false,
+            index: None,
+            indexed: 0,
+            by_instruction: false,
+            stages: vec!(),
+            skipped: HashMap::new(),
+        }
+    }
 
-                files.insert(file.filename().into(), UnmatchedFile{ full_filename, path: output_path.clone(), rows: 0, writer });
+    ///
+    /// Note the group instruction about to evaluate the grid. Records left unmatched are written to the sub-folder
+    /// of the last instruction to evaluate them, if the charter splits unmatched records by instruction.
+    ///
+    pub fn evaluating(&mut self, instruction: usize) {
+        if self.by_instruction {
+            self.stages.push(instruction);
+        }
+    }
 
-                log::debug!("Created file {}", output_path.to_canoncial_string());
-            }
+    ///
+    /// Note a record the group instruction couldn't evaluate the constraints for (i.e. it had no date to apply a
+    /// date tolerance to), so it isn't attributed to that instruction.
+    ///
+    pub fn not_evaluated(&mut self, instruction: usize, record: &Record) {
+        if self.by_instruction {
+            self.skipped.entry((record.file_idx(), record.data_position().byte()))
+                .or_default()
+                .insert(instruction);
         }
+    }
 
-        Ok(Self { files })
+    ///
+    /// The group instruction sub-folder for an unmatched record, if any instruction evaluated it.
+    ///
+    fn instruction(&self, file_idx: usize, data_byte: u64) -> Option<usize> {
+        let skipped = self.skipped.get(&(file_idx, data_byte));
+        self.stages.iter()
+            .rfind(|stage| skipped.is_none_or(|skipped| !skipped.contains(stage)))
+            .copied()
     }
 
+    ///
+    /// Write any remaining unmatched records in the grid and complete the unmatched files.
+    ///
+    /// If the records were already streamed out by the final group instruction, the grid isn't re-scanned. Instead
+    /// the streamed records' index is sorted and each record is re-read from its file, so either way the unmatched
+    /// files keep the order of the original files.
+    ///
     pub fn write_records(&mut self, ctx: &Context, grid: &Grid) -> Result<(), MatcherError> {
-        for record in grid.iter(ctx) {
-            // Get the unmatched-file for this record.
-            let filename = grid.schema().files().get(record.file_idx())
-                .ok_or(MatcherError::UnmatchedFileNotInGrid { file_idx: record.file_idx() })?
-                .filename();
-
-            let mut unmatched = self.files.get_mut(filename)
-                .ok_or(MatcherError::UnmatchedFileNotInHandler { filename: filename.to_string() })?;
-
-            // Track how many records are written to each unmatched file.
-            unmatched.rows += 1;
-
-            // Copy the original CSV record to the unmatched file.
-            unmatched.writer.write_byte_record(record.data())
-                .map_err(|source| MatcherError::CannotWriteUnmatchedRecord {
-                    filename: unmatched.full_filename.clone(),
-                    row: record.row(), source
-                })?;
+        if !self.streamed {
+            for record in grid.iter(ctx) {
+                self.total(ctx, &record)?;
+                let instruction = self.instruction(record.file_idx(), record.data_position().byte());
+                self.append(ctx, grid, record.file_idx(), record.data(), instruction)?;
+            }
+        }
+
+        if let Some(mut index) = self.index.take() {
+            index.flush()?;
+            self.write_indexed(ctx, grid)?;
         }
 
         self.complete_files(ctx)
     }
 
+    ///
+    /// Track a record which can no longer be matched by this job, it's written to its unmatched file at the end.
+    ///
+    pub fn write_record(&mut self, ctx: &Context, record: &Record) -> Result<(), MatcherError> {
+        self.streamed = true;
+        self.total(ctx, record)?;
+
+        // The merge key orders the index by file, then the record's position in that file.
+        let merge_key = format!("{:010}{:020}", record.file_idx(), record.data_position().byte());
+
+        let index = self.index.get_or_insert_with(|| utils::csv::writer(folders::unmatched_index(ctx)));
+        index.write_record([
+            record.file_idx().to_string(),
+            record.data_position().byte().to_string(),
+            record.data_position().line().to_string(),
+            record.derived_position().byte().to_string(),
+            record.derived_position().line().to_string(),
+            merge_key])?;
+
+        self.indexed += 1;
+        Ok(())
+    }
+
+    ///
+    /// Sort the index of streamed records and copy each record from its data file into its unmatched file.
+    ///
+    fn write_indexed(&mut self, ctx: &Context, grid: &Grid) -> Result<(), MatcherError> {
+        let sorted_path = super::sort_unmatched_index(ctx, self.indexed)?;
+        let mut index_rdr = utils::csv::index_reader(&sorted_path);
+
+        let mut data_rdrs: CsvReaders = grid.schema().files()
+            .iter()
+            .map(|file| utils::csv::reader(file.path(), true))
+            .collect();
+
+        let mut index = csv::ByteRecord::new();
+        let mut data = csv::ByteRecord::new();
+
+        while index_rdr.read_byte_record(&mut index)? {
+            let file_idx = csv_to_u64(index.get(COL_FILE_IDX)) as usize;
+
+            let mut data_pos = csv::Position::new();
+            data_pos.set_byte(csv_to_u64(index.get(COL_DATA_BYTE)));
+            data_pos.set_line(csv_to_u64(index.get(COL_DATA_LINE)));
+
+            data_rdrs[file_idx].seek(data_pos.clone())?;
+            data_rdrs[file_idx].read_byte_record(&mut data)?;
+
+            let instruction = self.instruction(file_idx, data_pos.byte());
+            self.append(ctx, grid, file_idx, &data, instruction)?;
+        }
+
+        folders::remove_file(&sorted_path)
+    }
+
+    ///
+    /// Accumulate the unmatched amount for the record's currency.
+    ///
+    fn total(&mut self, ctx: &Context, record: &Record) -> Result<(), MatcherError> {
+        if let (Some(config), Some(totals)) = (ctx.charter().unmatched_totals(), &mut self.totals) {
+            if let Some(amount) = record.get_decimal(config.amount())? {
+                *totals.entry(record.get_as_string(config.currency())?).or_insert(Decimal::ZERO) += amount;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, ctx: &Context, grid: &Grid, file_idx: usize, data: &csv::ByteRecord, instruction: Option<usize>)
+        -> Result<(), MatcherError> {
+
+        // Get the unmatched-file for this record.
+        let file = grid.schema().files().get(file_idx)
+            .ok_or(MatcherError::UnmatchedFileNotInGrid { file_idx })?;
+
+        let key = (instruction, file.filename().to_string());
+
+        // Instruction sub-folder files are only created once they have a record to write.
+        let unmatched = match self.files.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if instruction.is_some() => entry.insert(create_file(ctx, grid, file, instruction)?),
+            Entry::Vacant(_) => return Err(MatcherError::UnmatchedFileNotInHandler { filename: file.filename().to_string() }),
+        };
+
+        // Track how many records are written to each unmatched file.
+        unmatched.rows += 1;
+
+        // Copy the original CSV record to the unmatched file, replacing any previous age with the current one.
+        let result = match (&unmatched.age, unmatched.aged) {
+            (None, false) => unmatched.writer.write_byte_record(data),
+            (age, aged) => {
+                let mut record = data.iter().take(data.len() - aged as usize).collect::<csv::ByteRecord>();
+                if let Some(age) = age {
+                    record.push_field(age.as_bytes());
+                }
+                unmatched.writer.write_byte_record(&record)
+            },
+        };
+
+        result.map_err(|source| MatcherError::CannotWriteUnmatchedRecord {
+            filename: unmatched.full_filename.clone(),
+            row: data.position().expect("no row position").line() as usize, source
+        })
+    }
+
     fn complete_files(&mut self, ctx: &Context) -> Result<(), MatcherError> {
         // Delete any unmatched files we didn't write records to.
         for (_filename, mut unmatched) in self.files.iter_mut() {
@@ -97,7 +256,7 @@ impl UnmatchedHandler {
             } else {
                 // Rename any remaining .inprogress files.
                 let path = folders::complete_file(&unmatched.path.to_canoncial_string())?;
-                unmatched.full_filename = folders::filename(&path);
+                unmatched.full_filename = relative_filename(&path, unmatched.instruction);
                 log::debug!("Created unmatched file {}", path.to_canoncial_string());
             }
         }
@@ -105,7 +264,73 @@ impl UnmatchedHandler {
         Ok(())
     }
 
+    ///
+    /// The sum of the unmatched amounts for each currency (only populated if configured in the charter).
+    ///
+    pub fn totals(&self) -> &Option<BTreeMap<String, Decimal>> {
+        &self.totals
+    }
+
     pub fn unmatched_files(&self) -> Vec<&UnmatchedFile> {
         self.files.values().collect()
     }
-}
\ No newline at end of file
+}
+
+///
+/// Create an unmatched file for the data file, with the column header and schema rows ready for records to be appended.
+///
+fn create_file(ctx: &Context, grid: &Grid, file: &DataFile, instruction: Option<usize>) -> Result<UnmatchedFile, MatcherError> {
+    let output_path = folders::new_unmatched_file(ctx, file, instruction); // $REC_HOME/unmatched/timestamp_invoices.unmatched.csv
+    let full_filename = relative_filename(&output_path, instruction); // timestamp_invoices.unmatched.csv
+
+    if let Some(folder) = output_path.parent() {
+        fs::create_dir_all(folder)?;
+    }
+
+    let mut writer = utils::csv::output_writer(&output_path, ctx.charter());
+
+    // Add the column header and schema rows.
+    let schema = &grid.schema().file_schemas()[file.schema_idx()];
+
+    let age = match ctx.charter().age_unmatched() {
+        true  => Some(days_since(ctx, file)?.to_string()),
+        false => None,
+    };
+
+    let mut headers = schema.columns().iter().map(|c| c.header_no_prefix()).collect::<Vec<&str>>();
+    let mut types = schema.columns().iter().map(|c| c.data_type().as_str()).collect::<Vec<&str>>();
+
+    if age.is_some() {
+        headers.push(AGE);
+        types.push("IN");
+    }
+
+    writer.write_record(headers)
+        .map_err(|source| MatcherError::CannotWriteHeaders{ filename: file.filename().into(), source })?;
+
+    writer.write_record(types)
+        .map_err(|source| MatcherError::CannotWriteSchema{ filename: file.filename().into(), source })?;
+
+    log::debug!("Created file {}", output_path.to_canoncial_string());
+
+    Ok(UnmatchedFile{ full_filename, instruction, path: output_path, rows: 0, writer, aged: file.aged(), age })
+}
+
+///
+/// The unmatched file's name relative to the unmatched folder, e.g. 2/timestamp_invoices.unmatched.csv
+///
+fn relative_filename(path: &std::path::Path, instruction: Option<usize>) -> String {
+    match instruction {
+        Some(instruction) => format!("{}/{}", instruction, folders::filename(path)),
+        None => folders::filename(path),
+    }
+}
+
+///
+/// The number of whole days between the data file's timestamp, when it's records were first seen, and this job.
+///
+fn days_since(ctx: &Context, file: &DataFile) -> Result<i64, MatcherError> {
+    let now = folders::unix_timestamp(ctx.ts()).ok_or_else(|| MatcherError::InvalidTimestampPrefix { filename: ctx.ts().into() })?;
+    let first_seen = folders::unix_timestamp(file.timestamp()).ok_or_else(|| MatcherError::InvalidTimestampPrefix { filename: file.filename().into() })?;
+    Ok(((now - first_seen) / MILLIS_IN_A_DAY as i64).max(0))
+}
@@ -19,10 +19,12 @@ pub struct DataFile {
     derived_filename: String,
     archived_filename: Option<String>,
     schema_idx: usize,
+    rows: usize,
+    aged: bool,
 }
 
 impl DataFile {
-    pub fn new(entry: &DirEntry, schema_idx: usize) -> Self {
+    pub fn new(entry: &DirEntry, schema_idx: usize, rows: usize, aged: bool) -> Self {
         let pb = entry.path();
         let derived_path = folders::derived(&pb);
 
@@ -35,9 +37,25 @@ impl DataFile {
             archived_filename: None,
             derived_path,
             schema_idx,
+            rows,
+            aged,
         }
     }
 
+    ///
+    /// The number of records in the file when it was sourced into the grid.
+    ///
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    ///
+    /// True if each record in the file has an OpenRecAge value after the columns in it's schema.
+    ///
+    pub fn aged(&self) -> bool {
+        self.aged
+    }
+
     ///
     /// This index of the FileSchema in the grid that this file uses.
     ///
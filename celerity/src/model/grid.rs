@@ -2,7 +2,8 @@ use ubyte::ToByteUnit;
 use anyhow::Context as ErrContext;
 use super::grid_iter::GridIterator;
 use core::charter::MatchingSourceFile;
-use std::{fs::{DirEntry, self}, time::Instant};
+use serde_json::json;
+use std::{fs::{DirEntry, self}, io::{BufWriter, Write}, path::PathBuf, time::Instant};
 use crate::{error::{MatcherError, here}, folders::{self, ToCanoncialString}, model::{datafile::DataFile, schema::{FileSchema, GridSchema}}, Context, blue, formatted_duration_rate, utils};
 
 ///
@@ -29,8 +30,12 @@ use crate::{error::{MatcherError, here}, folders::{self, ToCanoncialString}, mod
 ///
 /// Note: No memory is allocted for the empty cells shown above.
 ///
+/// Records which are matched, filtered or removed as duplicates are skipped when the grid is iterated, so each group
+/// instruction only sees the records earlier instructions left behind. The grid's len reflects this.
+///
 pub struct Grid {
-    count: usize,
+    count: usize,               // The number of records still unmatched.
+    loaded: usize,              // The number of records loaded into the grid.
     data_size: usize,
     schema: GridSchema,         // Represents the column structure of the grid and maps headers to the underlying record columns.
 }
@@ -44,6 +49,24 @@ impl Grid {
         self.count == 0
     }
 
+    pub fn loaded(&self) -> usize {
+        self.loaded
+    }
+
+    ///
+    /// Reduce the grid's len when records are matched, filtered or removed so they're no longer iterated.
+    ///
+    pub fn remove_records(&mut self, count: usize) {
+        self.count = self.count.saturating_sub(count);
+    }
+
+    ///
+    /// Put back records which were removed, i.e. once a shadow charter's statuses have been reset.
+    ///
+    pub fn restore_records(&mut self, count: usize) {
+        self.count = std::cmp::min(self.count + count, self.loaded);
+    }
+
     pub fn data_size(&self) -> usize {
         self.data_size
     }
@@ -77,7 +100,7 @@ impl Grid {
             // Because all files of the same record type will need the same schema for any single match run.
             let mut last_schema_idx = None;
 
-            for file in folders::files_in_matching(ctx, source_file.pattern())? {
+            for file in folders::files_in_matching(ctx, &folders::source_file_regex(source_file)?)? {
                 let (count, file_size, last) = load_file(ctx, &file, source_file, &mut grid_schema, last_schema_idx)?;
                 last_schema_idx = last;
                 total_count += count;
@@ -89,6 +112,7 @@ impl Grid {
 
         Ok(Grid {
             count: total_count,
+            loaded: total_count,
             data_size,
             schema: grid_schema
         })
@@ -99,13 +123,7 @@ impl Grid {
     ///
     pub fn debug_grid(&self, ctx: &Context, sequence: usize) {
         if ctx.charter().debug() {
-            let output_path = folders::debug_path(ctx)
-                .join(format!("{timestamp}_{phase_num}_{phase_name:?}_{sequence}.debug.csv",
-                phase_num = ctx.phase().ordinal(),
-                phase_name = ctx.phase(),
-                sequence = sequence,
-                timestamp = ctx.ts()
-            ));
+            let output_path = debug_file(ctx, sequence, "csv");
 
             log::debug!("Creating grid debug file {}...", output_path.to_canoncial_string());
 
@@ -120,10 +138,75 @@ impl Grid {
 
             writer.flush().expect("Unable to flush the debug file");
             log::debug!("...{} rows written to {}", count, output_path.to_canoncial_string());
+
+            self.debug_grid_json(ctx, sequence);
+        }
+    }
+
+    ///
+    /// Writes all the grid's data to a JSON file at this point, for tooling. The columns (with their data types and
+    /// prefixes) and files are listed, then each row is an object of typed fields, e.g.
+    ///
+    /// {"file_idx": 0, "row": 3, "fields": {"INV.Ref": "ABC", "INV.Amount": "10.99", "INV.Count": 2}}
+    ///
+    pub fn debug_grid_json(&self, ctx: &Context, sequence: usize) {
+        if ctx.charter().debug() {
+            let output_path = debug_file(ctx, sequence, "json");
+
+            log::debug!("Creating grid debug file {}...", output_path.to_canoncial_string());
+
+            let columns = self.schema().headers().iter()
+                .filter_map(|header| self.schema().column(header))
+                .map(|column| json!({
+                    "header": column.header(),
+                    "prefix": column.prefix(),
+                    "data_type": format!("{:?}", column.data_type()),
+                }))
+                .collect::<Vec<_>>();
+
+            let files = self.schema().files().iter()
+                .enumerate()
+                .map(|(file_idx, file)| json!({
+                    "file_idx": file_idx,
+                    "filename": file.filename(),
+                    "prefix": self.schema().file_schemas()[file.schema_idx()].prefix(),
+                }))
+                .collect::<Vec<_>>();
+
+            let mut rows = vec!();
+            for record in self.iter(ctx) {
+                let fields = self.schema().headers().iter()
+                    .map(|header| (header.clone(), record.get_as_json(header).unwrap_or_default()))
+                    .collect::<serde_json::Map<_, _>>();
+
+                rows.push(json!({ "file_idx": record.file_idx(), "row": record.row(), "fields": fields }));
+            }
+
+            let count = rows.len();
+            let snapshot = json!({ "columns": columns, "files": files, "rows": rows });
+
+            let mut writer = BufWriter::new(fs::File::create(&output_path).expect("Unable to create the debug file"));
+            serde_json::to_writer_pretty(&mut writer, &snapshot).expect("Unable to write the debug file");
+            writer.flush().expect("Unable to flush the debug file");
+            log::debug!("...{} rows written to {}", count, output_path.to_canoncial_string());
         }
     }
 }
 
+///
+/// The path of a grid debug file for the current phase.
+///
+fn debug_file(ctx: &Context, sequence: usize, extension: &str) -> PathBuf {
+    folders::debug_path(ctx)
+        .join(format!("{timestamp}_{phase_num}_{phase_name:?}_{sequence}.debug.{extension}",
+        phase_num = ctx.phase().ordinal(),
+        phase_name = ctx.phase(),
+        sequence = sequence,
+        timestamp = ctx.ts(),
+        extension = extension
+    ))
+}
+
 ///
 /// Parse each csv row in the file to ensure it's parseable. Count the rows and ensure no two files loaded from the same pattern,
 /// have different column schemas.
@@ -147,6 +230,9 @@ fn load_file(ctx: &Context, file: &DirEntry, source_file: &MatchingSourceFile, g
     let schema = FileSchema::new(source_file.field_prefix(), &mut rdr)
         .map_err(|source| MatcherError::BadSourceFile { path: file.path().to_canoncial_string(), description: source.to_string() })?;
 
+    // Unmatched files may have an age column after the schema's columns.
+    let aged = rdr.headers().map_err(|source| MatcherError::CannotReadHeaders { source })?.len() > schema.columns().len();
+
     // Validate each record can be parsed okay.
     for result in rdr.byte_records() {
         let _csv_record = result // Ensure we can read the record - but ignore it at this point.
@@ -168,7 +254,7 @@ fn load_file(ctx: &Context, file: &DirEntry, source_file: &MatchingSourceFile, g
         let last_schema_idx = validate_schema(grid_schema, schema_idx, &last_schema_idx, &schema, source_file.pattern())?;
 
         // Register the data file with the grid.
-        let _file_idx = grid_schema.add_file(DataFile::new(file, schema_idx));
+        let _file_idx = grid_schema.add_file(DataFile::new(file, schema_idx, count, aged));
         last_schema_idx
     };
 
@@ -1,4 +1,5 @@
 use uuid::Uuid;
+use serde_json::json;
 use std::sync::Arc;
 use rust_decimal::Decimal;
 use super::schema::GridSchema;
@@ -45,6 +46,10 @@ impl Record {
         &self.data
     }
 
+    pub fn derived(&self) -> &csv::ByteRecord {
+        &self.derived
+    }
+
     pub fn schema(&self) -> Arc<GridSchema> {
         self.schema.clone()
     }
@@ -145,6 +150,25 @@ impl Record {
         }
     }
 
+    ///
+    /// Get the value in the column as a typed JSON value - booleans and integers are JSON booleans and numbers, other types
+    /// are strings (decimals keep their precision). If no value is present null is returned.
+    ///
+    pub fn get_as_json(&self, header: &str) -> Result<serde_json::Value, MatcherError> {
+        match self.schema.data_type(header) {
+            Some(data_type) => match data_type {
+                DataType::Unknown => Err(MatcherError::UnknownDataTypeForHeader { header: header.into() }),
+                DataType::Boolean => Ok(json!(self.get_bool(header)?)),
+                DataType::Datetime => Ok(json!(self.get_datetime(header)?.map(convert::datetime_to_string))),
+                DataType::Decimal => Ok(json!(self.get_decimal(header)?.map(convert::decimal_to_string))),
+                DataType::Integer => Ok(json!(self.get_int(header)?)),
+                DataType::String => Ok(json!(self.get_string(header)?)),
+                DataType::Uuid => Ok(json!(self.get_uuid(header)?.map(convert::uuid_to_string))),
+            },
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
     ///
     /// Initialise the buffer if this is the first update. Populate it with all the data-fields.
     ///
@@ -187,6 +211,28 @@ impl Record {
         Ok(())
     }
 
+    ///
+    /// Replace a derived value already added to the buffer - as part of a later projection assigning to it.
+    ///
+    pub fn update_derived(&mut self, header: &str, value: &str) -> Result<(), MatcherError> {
+        let file = &self.schema.files()[self.file_idx()];
+
+        // Derived columns have negative positions. -1 -> 0, -2 -> 1, etc.
+        let pos = match self.schema.position_in_record(header, self) {
+            Some(pos) if *pos < 0 => (pos.abs() - 1) as usize,
+            Some(_) |
+            None => return Err(MatcherError::MissingColumn { column: header.into(), file: file.filename().into() }),
+        };
+
+        self.buffer[pos] = Bytes::copy_from_slice(value.as_bytes());
+        self.derived = self.derived.iter()
+            .enumerate()
+            .map(|(idx, field)| if idx == pos { value.as_bytes() } else { field })
+            .collect();
+
+        Ok(())
+    }
+
     ///
     /// Add a derived boolean value to the buffer. Use flush to retrieve the buffer for writing.
     ///
@@ -274,9 +320,10 @@ impl Record {
     }
 
     ///
-    /// Merge the first non-None value from the source columns into a new column.
+    /// Merge the first non-None value from the source columns into a new column, coercing Integer values if the merged
+    /// column is a Decimal.
     ///
-    pub fn merge_col_from(&mut self, source: &[String]) -> Result<(), MatcherError> {
+    pub fn merge_col_from(&mut self, source: &[String], coerce_to: Option<DataType>) -> Result<(), MatcherError> {
 
         for header in source {
             let data_type = match self.schema.data_type(header) {
@@ -306,6 +353,7 @@ impl Record {
                 },
                 DataType::Integer => {
                     match self.get_int(header)? {
+                        Some(value) if coerce_to == Some(DataType::Decimal) => convert::decimal_to_string(Decimal::from(value)),
                         Some(value) => convert::int_to_string(value),
                         None => continue,
                     }
@@ -4,7 +4,9 @@ use super::{datafile::DataFile};
 use std::{collections::HashMap, fs, slice::IterMut};
 use crate::{model::record::Record, error::MatcherError};
 
-const STATUS: &str = "OpenRecStatus";
+pub const STATUS: &str = "OpenRecStatus";
+pub const ID: &str = "OpenRecId";
+pub const AGE: &str = "OpenRecAge";
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Column {
@@ -59,6 +61,9 @@ pub struct GridSchema {
 
     // Columns created from projection and merge instructions.
     derived_cols: Vec<Column>,
+
+    // Additional names for existing columns, from rename instructions.
+    aliases: Vec<(String /* from */, String /* to */)>,
 }
 
 impl Column {
@@ -81,6 +86,10 @@ impl Column {
         &self.header_no_prefix
     }
 
+    pub fn prefix(&self) -> Option<&str> {
+        self.header.strip_suffix(&self.header_no_prefix).and_then(|prefix| prefix.strip_suffix('.'))
+    }
+
     pub fn data_type(&self) -> &DataType {
         &self.data_type
     }
@@ -148,6 +157,32 @@ impl GridSchema {
         Ok(self.derived_cols.len() - 1)
     }
 
+    ///
+    /// Allow an existing column to also be referred to by another name. The same name can be given to columns from
+    /// different file schemas (with the same data-type) so they can be used as one column. The name must not already
+    /// be used by any other column.
+    ///
+    pub fn add_alias(&mut self, from: &str, to: &str) -> Result<(), MatcherError> {
+        let this_type = *self.data_type(from).ok_or_else(|| MatcherError::MissingSourceColumn { header: from.into() })?;
+
+        let is_alias = self.aliases.iter().any(|(_from, alias)| alias == to);
+        let clashes = self.position_map.values().any(|positions| positions.contains_key(from) && positions.contains_key(to));
+
+        if clashes || (self.col_map.contains_key(to) && !is_alias) {
+            return Err(MatcherError::RenameColumnExists { from: from.into(), to: to.into() })
+        }
+
+        if let Some(other_type) = self.data_type(to) {
+            if *other_type != this_type {
+                return Err(MatcherError::RenameDataTypeMismatch { from: from.into(), to: to.into(), this_type, other_type: *other_type })
+            }
+        }
+
+        self.aliases.push((from.into(), to.into()));
+        self.rebuild_cache();
+        Ok(())
+    }
+
     pub fn files(&self) -> &[DataFile] {
         &self.files
     }
@@ -237,6 +272,25 @@ impl GridSchema {
                     } );
             } );
 
+        // Resolve each alias to the same position as the column it renames.
+        for (from, to) in &self.aliases {
+            let column = match col_map.get(from) {
+                Some(column) => Column::new(to.clone(), None, *column.data_type()),
+                None => continue,
+            };
+
+            for positions in position_map.values_mut() {
+                if let Some(position) = positions.get(from).copied() {
+                    positions.insert(to.clone(), position);
+                }
+            }
+
+            if !headers.contains(to) {
+                headers.push(to.clone());
+            }
+            col_map.insert(to.clone(), column);
+        }
+
         self.headers = headers;
         self.col_map = col_map;
         self.position_map = position_map;
@@ -265,6 +319,12 @@ impl FileSchema {
         }
 
         for (idx, hdr) in hdrs.iter().enumerate() {
+            // An age column added to unmatched records isn't part of the schema, so carried-forward unmatched files
+            // have the same schema as new data files.
+            if hdr == AGE && idx == hdrs.len() - 1 {
+                break
+            }
+
             let data_type = match type_record.get(idx) {
                 Some(raw_type) => raw_type.into(),
                 None => return Err(MatcherError::NoSchemaTypeForColumn { column: idx }),
@@ -390,4 +450,36 @@ mod tests {
             },
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_aliases_cannot_clash() {
+        let fs_1 = FileSchema {
+            prefix: Some("FS1".into()),
+            columns: vec!(
+                Column { header: "FS1.COLA".into(), header_no_prefix: "COLA".into(), data_type: DataType::String },
+                Column { header: "FS1.COLB".into(), header_no_prefix: "COLB".into(), data_type: DataType::String })
+        };
+
+        let fs_2 = FileSchema {
+            prefix: Some("FS2".into()),
+            columns: vec!(
+                Column { header: "FS2.COLA".into(), header_no_prefix: "COLA".into(), data_type: DataType::String },
+                Column { header: "FS2.COLB".into(), header_no_prefix: "COLB".into(), data_type: DataType::Boolean })
+        };
+
+        let mut gs = GridSchema::default();
+        gs.add_file_schema(fs_1).unwrap();
+        gs.add_file_schema(fs_2).unwrap();
+
+        // Columns from different files can share an alias.
+        gs.add_alias("FS1.COLA", "REF").unwrap();
+        gs.add_alias("FS2.COLA", "REF").unwrap();
+        assert_eq!(5, gs.headers().len());
+
+        // But not two columns from the same file, an existing column or columns of a different type.
+        assert!(matches!(gs.add_alias("FS1.COLB", "REF"), Err(MatcherError::RenameColumnExists { .. })));
+        assert!(matches!(gs.add_alias("FS1.COLB", "FS2.COLA"), Err(MatcherError::RenameColumnExists { .. })));
+        assert!(matches!(gs.add_alias("FS2.COLB", "FLAG"), Ok(())));
+        assert!(matches!(gs.add_alias("FS1.COLB", "FLAG"), Err(MatcherError::RenameDataTypeMismatch { .. })));
+    }
+}
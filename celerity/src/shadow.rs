@@ -0,0 +1,133 @@
+use anyhow::Result;
+use itertools::Itertools;
+use serde_json::json;
+use core::charter::{Charter, Instruction};
+use std::{collections::BTreeSet, fs::File, io::BufWriter, path::Path};
+use crate::{Context, error::MatcherError, init_job, instructions, lock_base_dir, match_instructions, run_job, folders::{self, ToCanoncialString}, matching::{matched::{MatchedGroup, MatchedHandler}, unmatched::UnmatchedHandler}, model::grid::Grid};
+
+/*
+    A shadow run lets a candidate charter be trialled against live data without it effecting the control.
+
+    The primary charter's job loads and derives the data as normal. Before the primary's matching instructions run,
+    the candidate's filter, distinct, group and sort instructions are run against the same grid. The statuses of any
+    records the candidate matched, filtered or removed as duplicates are then put back so the primary charter sees the
+    data untouched. Nothing is ingested twice and the candidate writes no files of it's own.
+
+    As the candidate shares the primary's derived data, it must project, merge and rename the same columns - only it's
+    matching instructions may differ. It's source_files, changesets and other job settings are not used.
+
+    Both charters capture the members of each group they match, by filename and line, and any groups which were only
+    matched by one of the charters are written to a shadow_diff.json report alongside the primary's match report.
+*/
+
+///
+/// Run the primary charter for real with the candidate charter matched against the same data, and report any
+/// differences in the groups they matched.
+///
+pub fn run(charter: &Path, candidate: &Path, base_dir: &Path) -> Result<()> {
+
+    // The primary job is created last, so the logs carry it's job id.
+    let candidate = init_job(candidate, base_dir)?;
+    let mut primary = init_job(charter, base_dir)?;
+    primary.set_capture_groups(true);
+
+    let _lock = lock_base_dir(&primary)?;
+    let (primary_groups, candidate_groups) = run_job(&primary, Some(&candidate))?;
+
+    // A broken candidate doesn't stop the primary charter, it's reported when it's matched and no diff is written.
+    if let Some(candidate_groups) = candidate_groups {
+        write_diff(&primary, candidate.charter_path(), primary_groups, candidate_groups)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Run the candidate's matching instructions against the primary's grid, then restore the grid. Returns the groups
+/// the candidate matched, or None if the candidate failed.
+///
+/// An error is only returned if the grid couldn't be restored for the primary charter.
+///
+pub fn match_candidate(primary: &Context, candidate: &Context, grid: &mut Grid) -> Result<Option<Vec<MatchedGroup>>, MatcherError> {
+
+    log::info!("Matching shadow charter {}", candidate.charter_path().to_canoncial_string());
+
+    // Read the derived data as the primary does, without writing a job.status file for the candidate.
+    candidate.phase.set(primary.phase());
+
+    let count = grid.len();
+    let mut matched = MatchedHandler::shadow(candidate, grid);
+    let mut unmatched = UnmatchedHandler::shadow();
+
+    let result = validate_candidate(primary, candidate, grid)
+        .and_then(|_| match_instructions(candidate, grid, &mut matched, &mut unmatched, true));
+
+    // Put the records back as the primary charter found them.
+    matched.restore_statuses()?;
+    grid.restore_records(count - grid.len());
+
+    match result {
+        Ok(()) => Ok(Some(matched.take_captured())),
+        Err(err) => {
+            log::error!("Shadow charter {} failed, no diff will be written : {:?}", candidate.charter_path().to_canoncial_string(), err);
+            Ok(None)
+        },
+    }
+}
+
+///
+/// The candidate is matched against the primary's derived data, so it can't derive anything differently.
+///
+fn validate_candidate(primary: &Context, candidate: &Context, grid: &Grid) -> Result<(), MatcherError> {
+    if derived_instructions(primary.charter()) != derived_instructions(candidate.charter()) {
+        return Err(MatcherError::ShadowDerivesDiffer { candidate: candidate.charter_path().to_canoncial_string() })
+    }
+
+    if !grid.is_empty() {
+        instructions::validate_group_by(candidate.charter(), grid.schema())?;
+    }
+
+    Ok(())
+}
+
+fn derived_instructions(charter: &Charter) -> String {
+    charter.instructions()
+        .iter()
+        .filter(|inst| matches!(inst, Instruction::Project { .. } | Instruction::Merge { .. } | Instruction::Rename { .. }))
+        .map(|inst| format!("{:?}", inst))
+        .join("\n")
+}
+
+///
+/// Write the groups only matched by one or other charter to matched/<timestamp>_shadow_diff.json.
+///
+fn write_diff(ctx: &Context, candidate: &Path, primary_groups: Vec<MatchedGroup>, candidate_groups: Vec<MatchedGroup>)
+    -> Result<()> {
+
+    let primary_groups: BTreeSet<MatchedGroup> = primary_groups.into_iter().collect();
+    let candidate_groups: BTreeSet<MatchedGroup> = candidate_groups.into_iter().collect();
+
+    let only_primary = primary_groups.difference(&candidate_groups).collect::<Vec<&MatchedGroup>>();
+    let only_candidate = candidate_groups.difference(&primary_groups).collect::<Vec<&MatchedGroup>>();
+
+    let diff = json!(
+    {
+        "primary": ctx.charter_path(),
+        "candidate": candidate.to_canoncial_string(),
+        "primary_groups": primary_groups.len(),
+        "candidate_groups": candidate_groups.len(),
+        "common_groups": primary_groups.intersection(&candidate_groups).count(),
+        "only_primary": only_primary,
+        "only_candidate": only_candidate,
+    });
+
+    let path = folders::matched(ctx).join(format!("{}_shadow_diff.json", ctx.ts()));
+    serde_json::to_writer_pretty(BufWriter::new(File::create(&path)?), &diff)?;
+
+    log::info!("Shadow charter matched {} group(s) the primary didn't and missed {}. See {}",
+        only_candidate.len(),
+        only_primary.len(),
+        path.to_canoncial_string());
+
+    Ok(())
+}
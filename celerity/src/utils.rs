@@ -2,6 +2,7 @@
 
 pub mod csv {
     use std::{fs::File, path::Path};
+    use core::charter::{Charter, CsvLineTerminator, CsvQuoteStyle};
     use crate::folders::ToCanoncialString;
 
     pub type CsvReader = csv::Reader<File>;
@@ -49,6 +50,35 @@ pub mod csv {
             })
     }
 
+    ///
+    /// Create a CSV writer for a derived, matched or unmatched file using the quote style and line
+    /// terminator from the charter.
+    ///
+    pub fn output_writer<P>(path: P, charter: &Charter) -> CsvWriter
+    where
+        P: AsRef<Path>
+    {
+        let quote_style = match charter.csv_quote_style() {
+            CsvQuoteStyle::Always    => csv::QuoteStyle::Always,
+            CsvQuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            CsvQuoteStyle::Never     => csv::QuoteStyle::Never,
+        };
+
+        let terminator = match charter.csv_line_terminator() {
+            CsvLineTerminator::Lf   => csv::Terminator::Any(b'\n'),
+            CsvLineTerminator::Crlf => csv::Terminator::CRLF,
+        };
+
+        csv::WriterBuilder::new()
+            .quote_style(quote_style)
+            .terminator(terminator)
+            .from_path(&path)
+            .unwrap_or_else(|err| {
+                let path: &Path = path.as_ref();
+                panic!("Failed to open {} : {}", path.to_canoncial_string(), err)
+            })
+    }
+
     ///
     /// Create a CSV reader for an index.xxxx.xxx file. These have no schema row or headers.
     ///
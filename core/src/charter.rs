@@ -1,7 +1,17 @@
 use serde::Deserialize;
 use rust_decimal::Decimal;
-use std::{io::BufReader, path::Path};
-use crate::{data_type::DataType, error::Error};
+use std::{collections::HashMap, io::{BufReader, Read}, path::Path, str::FromStr};
+use crate::{data_type::DataType, error::Error, lua};
+
+// The group-by column modifier used to ignore the time portion of a datetime.
+pub const DATE_ONLY: &str = ":date_only";
+
+const MEMORY_LIMIT_ENV: &str = "OPENREC_MEMORY_LIMIT";
+const CHANGESET_DRY_RUN_ENV: &str = "OPENREC_CHANGESET_DRY_RUN";
+const DERIVE_THREADS_ENV: &str = "OPENREC_DERIVE_THREADS";
+const MATCH_THREADS_ENV: &str = "OPENREC_MATCH_THREADS";
+const EXPLAIN_UNMATCHED_ENV: &str = "OPENREC_EXPLAIN_UNMATCHED";
+const MIN_MEMORY_LIMIT: usize = 16777216; // 16MB, 16 * 1048576
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -10,6 +20,10 @@ pub struct Charter {
     description: Option<String>,
     version: u64,
     debug: Option<bool>,
+
+    #[serde(default)]
+    keep_derived: bool, // Move the job's derived files to the debug folder rather than deleting them.
+
     matching: Matching,
     jetwash: Option<Jetwash>,
     global_lua: Option<String>,
@@ -19,6 +33,28 @@ pub struct Charter {
 
     #[serde(default = "default_archive")]
     archive_files: bool,
+
+    #[serde(default)]
+    archive_compress: bool, // Gzip data files as they're archived.
+
+    stale_lock: Option<StaleLock>, // Allow a .lock left behind by a crashed job to be replaced.
+
+    #[serde(default)]
+    changeset_dry_run: bool, // Report what changesets would do without modifying any data.
+
+    derive_threads: Option<usize>, // The most files to derive in parallel, 1 derives them one at a time.
+
+    match_threads: Option<usize>, // The number of threads evaluating group constraints, 1 evaluates them serially.
+
+    lua_timeout_ms: Option<u64>, // Abort any single Lua script which runs for longer than this.
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaleLock {
+    #[serde(default)]
+    check_pid: bool,
+    max_age: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,12 +66,48 @@ pub struct Matching {
 
     #[serde(default = "default_group_limit")]
     group_size_limit: usize, // The maximum number of records in a single group.
+
+    unmatched_totals: Option<UnmatchedTotals>, // Report the unmatched amount per currency.
+
+    #[serde(default)]
+    matched_csv: bool, // Also write the matched groups as a flat CSV file.
+
+    #[serde(default)]
+    matched_sqlite: bool, // Also write the job, matched groups and unmatched rows to a SQLite database.
+
+    #[serde(default)]
+    explain_unmatched: bool, // Write the constraints each unmatched group failed to a diagnostics file.
+
+    #[serde(default)]
+    write_manifest: bool, // Write a manifest listing every file the job produced, archived or applied.
+
+    matched_retention_days: Option<u64>, // Remove matched job files older than this when a job completes.
+
+    #[serde(default)]
+    age_unmatched: bool, // Stamp unmatched records with the days since they were first seen.
+
+    #[serde(default)]
+    unmatched_by_instruction: bool, // Split unmatched files into a sub-folder per group instruction which last evaluated them.
+
+    csv_quote_style: Option<CsvQuoteStyle>,         // How fields are quoted in derived, matched and unmatched csv files.
+    csv_line_terminator: Option<CsvLineTerminator>, // How records are terminated in derived, matched and unmatched csv files.
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnmatchedTotals {
+    amount: String,   // The decimal or integer column to total.
+    currency: String, // The column to group the totals by.
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename = "SourceFile")]
 pub struct MatchingSourceFile {
     pattern: String,
+
+    #[serde(default)]
+    glob: bool, // Interpret the pattern as a filesystem glob, e.g. *.csv, rather than a regex.
+
     field_prefix: Option<String> // TODO: Prevent duplicate aliases.
 }
 
@@ -53,6 +125,13 @@ pub struct JetwashSourceFile {
     quote: Option<String>,
     delimiter: Option<String>,
     headers: Option<Vec<String>>,
+    encoding: Option<String>,
+    strict_encoding: Option<bool>,
+    skip_header_lines: Option<usize>,
+    skip_footer_lines: Option<usize>,
+    ragged_tolerance: Option<usize>, // How many trailing fields a row may be missing (or have spare, if blank).
+    trim_all: Option<bool>, // Trim whitespace from every field before any column mappings.
+    column_types: Option<HashMap<String, DataType>>, // Declared column data-types which aren't analysed.
     column_mappings: Option<Vec<ColumnMapping>>,
     new_columns: Option<Vec<NewColumn>>,
 }
@@ -69,38 +148,186 @@ pub struct NewColumn {
 #[serde(rename_all = "snake_case")]
 pub enum ColumnMapping {
     Map { column: String, as_a: DataType, from: String  }, // Lua script transforming an existing column with Lua script.
-    Dmy ( String /* column */ ),  // Parse a day/month/year into a UTC Datetime
-    Mdy ( String /* column */ ),  // Parse a month/day/year into a UTC Datetime
-    Ymd ( String /* column */ ),  // Parse a year/month/day into a UTC Datetime
+    Dmy ( DateColumn ),  // Parse a day/month/year into a UTC Datetime
+    Mdy ( DateColumn ),  // Parse a month/day/year into a UTC Datetime
+    Ymd ( DateColumn ),  // Parse a year/month/day into a UTC Datetime
     Trim ( String /* column */ ), // Trim whitespace from the value.
+    Regex { column: String, pattern: String, replace: String }, // Replace every match of the pattern in the value.
+    Split { column: String, delimiter: String, into: Vec<String> }, // Split the value into new columns.
+    Hash { column: String, algorithm: HashAlgorithm }, // Replace the value with its hex digest.
+    Boolean { column: String, true_values: Vec<String>, false_values: Vec<String> }, // Map recognised tokens to 1/0.
+    Number { column: String, as_a: DataType }, // Clean an accounting formatted amount into a Decimal or Integer.
     AsBoolean ( String /* column */ ),  // Column data-type hint.
     AsDatetime ( String /* column */ ), // Column data-type hint.
     AsDecimal ( String /* column */ ),  // Column data-type hint.
     AsInteger ( String /* column */ ),  // Column data-type hint.
 }
 
+///
+/// The column a date mapping parses, either just the column name or the column and the timezone its dates are local to,
+/// e.g. { column: TradeDate, timezone: Europe/London }. Dates without a timezone are UTC.
+///
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum DateColumn {
+    Column ( String ),
+    Zoned { column: String, timezone: String },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub enum Instruction {
-    Project { column: String, as_a: DataType, from: String, when: Option<String> }, // Create a derived column from one or more other columns.
-    Merge { into: String, columns: Vec<String> }, // Merge the contents of columns together.
-    Group { by: Vec<String>, match_when: Vec<Constraint> }, // Group the data by one or more columns (header-names)
+    Project { // Create a derived column from one or more other columns.
+        column: String,
+        as_a: DataType,
+        from: Option<String>,     // A Lua script to calculate the value.
+        constant: Option<String>, // Or a literal value, written as-is without evaluating any Lua.
+        when: Option<String>,
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+    Rename { // Give an existing column another name, e.g. to group columns from different files with one name.
+        from: String,
+        to: String,
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+    Merge { // Merge the contents of columns together.
+        into: String,
+        columns: Vec<String>,
+        coerce_to: Option<DataType>, // Allow columns of a compatible type (i.e. Integer into Decimal) to be merged.
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+    Filter { // Remove records from the job where the Lua script evaluates to true.
+        lua: String,
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+    Distinct { // Remove all but the earliest record of any sharing the same values in the by columns.
+        by: Vec<String>,
+
+        #[serde(default)]
+        write_duplicates: bool, // Copy removed records to the duplicates folder for audit.
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+    Group { // Group the data by one or more columns (header-names)
+        by: Vec<String>,
+        match_when: Vec<Constraint>,
+
+        #[serde(default = "default_enabled")]
+        enabled: bool, // Set to false to skip the instruction without removing it from the charter.
+
+        #[serde(default = "default_normalise_keys")]
+        normalise_keys: bool, // Compare typed key columns by value (100 == 100.00) rather than raw bytes.
+
+        #[serde(default)]
+        date_tolerance: Option<DateTolerance>, // Allow records within N days of each other to form a group.
+
+        #[serde(default)]
+        max_group_size: Option<usize>, // Fail the job if any group has more records than this.
+
+        #[serde(default)]
+        aggregates: Option<GroupAggregates>, // Record each side's total and the difference in the matched groups.
+    },
+    Sort { // Order the members of groups matched by subsequent group instructions.
+        by: Vec<String>,
+
+        #[serde(default)]
+        descending: bool,
+
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DateTolerance {
+    column: String, // The datetime column to compare.
+    days: u64,      // The maximum number of days between the earliest and latest record in a group.
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnTolerance {
+    column: String,
+    tol_type: ToleranceType,
+    tolerance: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterCount {
+    filter: String,
+    count: usize,
 }
 
 #[derive(Debug, Deserialize)]
 pub enum ToleranceType {
     Amount,
-    Percent
+    Percent,        // A percentage of the lhs sum.
+    PercentOfLarger // A percentage of whichever side's sum is larger.
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub enum Constraint {
     NetsToZero { column: String, lhs: String, rhs: String },
+    NetsToN { column: String, lhs: String, rhs: String, target: Decimal },
     NetsWithTolerance { column: String, lhs: String, rhs: String, tol_type: ToleranceType, tolerance: Decimal },
+    NetsWithTolerances { lhs: String, rhs: String, tolerances: Vec<ColumnTolerance> }, // Every column must net within its tolerance.
+    CountInRange { filter: String, min: usize, max: usize },
+    NoneMatch { filter: String }, // No record in the group may pass the filter, e.g. a cancellation.
+    Cardinality { groups: Vec<FilterCount> }, // Each filter must be passed by exactly it's count of records.
+    AllEqual { column: String },
+    SumCompare { column: String, filter: Option<String>, op: CompareOp, value: Decimal },
     Custom { script: String, available_fields: Option<Vec<String>> }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum CompareOp {
+    #[serde(rename = "<")]
+    LessThan,
+    #[serde(rename = "<=")]
+    LessThanOrEqual,
+    #[serde(rename = ">")]
+    GreaterThan,
+    #[serde(rename = ">=")]
+    GreaterThanOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvQuoteStyle {
+    Always,    // Quote every field (the default).
+    Necessary, // Only quote fields containing a delimiter, quote or line terminator.
+    Never,     // Never quote fields - even if the output becomes invalid csv.
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum CsvLineTerminator {
+    #[serde(rename = "\n")]
+    Lf, // The default.
+    #[serde(rename = "\r\n")]
+    Crlf,
+}
+
 impl Jetwash {
     pub fn source_files(&self) -> &[JetwashSourceFile] {
         &self.source_files
@@ -128,6 +355,34 @@ impl JetwashSourceFile {
         &self.headers
     }
 
+    pub fn encoding(&self) -> &Option<String> {
+        &self.encoding
+    }
+
+    pub fn strict_encoding(&self) -> bool {
+        self.strict_encoding.unwrap_or(false)
+    }
+
+    pub fn skip_header_lines(&self) -> usize {
+        self.skip_header_lines.unwrap_or_default()
+    }
+
+    pub fn skip_footer_lines(&self) -> usize {
+        self.skip_footer_lines.unwrap_or_default()
+    }
+
+    pub fn ragged_tolerance(&self) -> usize {
+        self.ragged_tolerance.unwrap_or_default()
+    }
+
+    pub fn trim_all(&self) -> bool {
+        self.trim_all.unwrap_or_default()
+    }
+
+    pub fn column_types(&self) -> &Option<HashMap<String, DataType>> {
+        &self.column_types
+    }
+
     pub fn column_mappings(&self) -> &Option<Vec<ColumnMapping>> {
         &self.column_mappings
     }
@@ -137,6 +392,19 @@ impl JetwashSourceFile {
     }
 }
 
+impl StaleLock {
+    pub fn check_pid(&self) -> bool {
+        self.check_pid
+    }
+
+    ///
+    /// The max_age is validated when the charter is loaded, so can be parsed here without failing.
+    ///
+    pub fn max_age(&self) -> Option<std::time::Duration> {
+        self.max_age.as_ref().and_then(|max_age| humantime::parse_duration(max_age).ok())
+    }
+}
+
 impl MatchingSourceFile {
     pub fn pattern(&self) -> &str {
         &self.pattern
@@ -145,6 +413,191 @@ impl MatchingSourceFile {
     pub fn field_prefix(&self) -> &Option<String> {
         &self.field_prefix
     }
+
+    pub fn glob(&self) -> bool {
+        self.glob
+    }
+}
+
+impl Instruction {
+    ///
+    /// Disabled instructions remain in the charter but are skipped by the match job.
+    ///
+    pub fn enabled(&self) -> bool {
+        match self {
+            Instruction::Project { enabled, .. }
+            | Instruction::Merge { enabled, .. }
+            | Instruction::Rename { enabled, .. }
+            | Instruction::Filter { enabled, .. }
+            | Instruction::Distinct { enabled, .. }
+            | Instruction::Group { enabled, .. }
+            | Instruction::Sort { enabled, .. } => *enabled,
+        }
+    }
+}
+
+impl Instruction {
+    ///
+    /// The column this instruction derives, if it's a projection, merge or rename.
+    ///
+    fn derives(&self) -> Option<&str> {
+        match self {
+            Instruction::Project { column, .. } => Some(column),
+            Instruction::Merge { into, .. } => Some(into),
+            Instruction::Rename { to, .. } => Some(to),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The columns this instruction reads from, either by name or from within a Lua script.
+    ///
+    pub fn referenced_columns(&self) -> Vec<String> {
+        let mut columns: Vec<String> = match self {
+            Instruction::Merge { columns, .. } => columns.clone(),
+            Instruction::Rename { from, .. } => vec!(from.clone()),
+            Instruction::Distinct { by, .. } => by.iter().map(|header| without_date_only(header).to_string()).collect(),
+            Instruction::Sort { by, .. } => by.clone(),
+            Instruction::Group { by, match_when, date_tolerance, aggregates, .. } => {
+                let mut columns: Vec<String> = by.iter().map(|header| without_date_only(header).to_string()).collect();
+                columns.extend(date_tolerance.iter().map(|tolerance| tolerance.column.clone()));
+
+                if let Some(aggregates) = aggregates {
+                    columns.push(aggregates.column().to_string());
+                    columns.extend(lua::referenced_headers(aggregates.lhs()));
+                    columns.extend(lua::referenced_headers(aggregates.rhs()));
+                }
+
+                for constraint in match_when {
+                    match constraint {
+                        Constraint::NetsToZero { column, .. }
+                        | Constraint::NetsToN { column, .. }
+                        | Constraint::NetsWithTolerance { column, .. }
+                        | Constraint::AllEqual { column }
+                        | Constraint::SumCompare { column, .. } => columns.push(column.clone()),
+                        Constraint::NetsWithTolerances { tolerances, .. } => columns.extend(tolerances.iter().map(|tolerance| tolerance.column.clone())),
+                        Constraint::Custom { available_fields, .. } => columns.extend(available_fields.iter().flatten().cloned()),
+                        Constraint::CountInRange { .. }
+                        | Constraint::NoneMatch { .. }
+                        | Constraint::Cardinality { .. } => {},
+                    }
+                }
+                columns
+            },
+            Instruction::Project { .. } | Instruction::Filter { .. } => vec!(),
+        };
+
+        columns.extend(self.scripts().into_iter().flat_map(|(_, script)| lua::referenced_headers(script)));
+        columns
+    }
+
+    ///
+    /// The Lua scripts in this instruction, with a description of where each is used.
+    ///
+    fn scripts(&self) -> Vec<(String, &str)> {
+        match self {
+            Instruction::Project { column, from, when, .. } => {
+                let mut scripts = vec!();
+                scripts.extend(from.iter().map(|from| (format!("projection of {}", column), from.as_str())));
+                scripts.extend(when.iter().map(|when| (format!("projection of {} when", column), when.as_str())));
+                scripts
+            },
+            Instruction::Filter { lua, .. } => vec!(("filter".into(), lua)),
+            Instruction::Group { match_when, .. } => match_when.iter()
+                .enumerate()
+                .flat_map(|(c_idx, constraint)| constraint.scripts()
+                    .into_iter()
+                    .map(move |(what, script)| (format!("constraint {} {}", c_idx, what), script)))
+                .collect(),
+            _ => vec!(),
+        }
+    }
+}
+
+impl Constraint {
+    ///
+    /// The Lua scripts in this constraint, with the name of the field each is in.
+    ///
+    fn scripts(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            Constraint::NetsToZero { lhs, rhs, .. }
+            | Constraint::NetsToN { lhs, rhs, .. }
+            | Constraint::NetsWithTolerance { lhs, rhs, .. }
+            | Constraint::NetsWithTolerances { lhs, rhs, .. } => vec!(("lhs", lhs), ("rhs", rhs)),
+            Constraint::CountInRange { filter, .. }
+            | Constraint::NoneMatch { filter } => vec!(("filter", filter)),
+            Constraint::Cardinality { groups } => groups.iter().map(|group| ("filter", group.filter())).collect(),
+            Constraint::SumCompare { filter, .. } => filter.iter().map(|filter| ("filter", filter.as_str())).collect(),
+            Constraint::Custom { script, .. } => vec!(("script", script)),
+            Constraint::AllEqual { .. } => vec!(),
+        }
+    }
+}
+
+impl FilterCount {
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl ColumnTolerance {
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn tol_type(&self) -> &ToleranceType {
+        &self.tol_type
+    }
+
+    pub fn tolerance(&self) -> Decimal {
+        self.tolerance
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupAggregates {
+    column: String, // The decimal or integer column to total.
+    lhs: String,    // Lua filter for the first side's records.
+    rhs: String,    // Lua filter for the second side's records.
+}
+
+impl GroupAggregates {
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn lhs(&self) -> &str {
+        &self.lhs
+    }
+
+    pub fn rhs(&self) -> &str {
+        &self.rhs
+    }
+}
+
+impl DateTolerance {
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn days(&self) -> u64 {
+        self.days
+    }
+}
+
+impl UnmatchedTotals {
+    pub fn amount(&self) -> &str {
+        &self.amount
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
 }
 
 impl NewColumn {
@@ -161,14 +614,35 @@ impl NewColumn {
     }
 }
 
+impl DateColumn {
+    pub fn column(&self) -> &str {
+        match self {
+            DateColumn::Column( column )     => column,
+            DateColumn::Zoned { column, .. } => column,
+        }
+    }
+
+    pub fn timezone(&self) -> Option<&str> {
+        match self {
+            DateColumn::Column( _ )            => None,
+            DateColumn::Zoned { timezone, .. } => Some(timezone),
+        }
+    }
+}
+
 impl ColumnMapping {
     pub fn column(&self) -> &str {
         match self {
             ColumnMapping::Map { column, .. } => column,
-            ColumnMapping::Dmy( column )      => column,
-            ColumnMapping::Mdy( column )      => column,
-            ColumnMapping::Ymd( column )      => column,
+            ColumnMapping::Dmy( column )      => column.column(),
+            ColumnMapping::Mdy( column )      => column.column(),
+            ColumnMapping::Ymd( column )      => column.column(),
             ColumnMapping::Trim( column )     => column,
+            ColumnMapping::Regex { column, .. } => column,
+            ColumnMapping::Split { column, .. } => column,
+            ColumnMapping::Hash { column, .. } => column,
+            ColumnMapping::Boolean { column, .. } => column,
+            ColumnMapping::Number { column, .. } => column,
             ColumnMapping::AsBoolean( column )  => column,
             ColumnMapping::AsDatetime( column ) => column,
             ColumnMapping::AsDecimal( column )  => column,
@@ -190,6 +664,10 @@ impl Charter {
         self.debug.unwrap_or(false)
     }
 
+    pub fn keep_derived(&self) -> bool {
+        self.keep_derived
+    }
+
     pub fn version(&self) -> u64 {
         self.version
     }
@@ -202,14 +680,78 @@ impl Charter {
         self.matching.group_size_limit
     }
 
+    pub fn unmatched_totals(&self) -> &Option<UnmatchedTotals> {
+        &self.matching.unmatched_totals
+    }
+
     pub fn archive_files(&self) -> bool {
         self.archive_files
     }
 
+    pub fn archive_compress(&self) -> bool {
+        self.archive_compress
+    }
+
+    pub fn changeset_dry_run(&self) -> bool {
+        self.changeset_dry_run
+    }
+
+    pub fn derive_threads(&self) -> Option<usize> {
+        self.derive_threads
+    }
+
+    pub fn match_threads(&self) -> Option<usize> {
+        self.match_threads
+    }
+
+    pub fn lua_timeout_ms(&self) -> Option<u64> {
+        self.lua_timeout_ms
+    }
+
+    pub fn stale_lock(&self) -> Option<&StaleLock> {
+        self.stale_lock.as_ref()
+    }
+
     pub fn source_files(&self) -> &[MatchingSourceFile] {
         &self.matching.source_files
     }
 
+    pub fn matched_csv(&self) -> bool {
+        self.matching.matched_csv
+    }
+
+    pub fn matched_sqlite(&self) -> bool {
+        self.matching.matched_sqlite
+    }
+
+    pub fn explain_unmatched(&self) -> bool {
+        self.matching.explain_unmatched
+    }
+
+    pub fn write_manifest(&self) -> bool {
+        self.matching.write_manifest
+    }
+
+    pub fn matched_retention_days(&self) -> Option<u64> {
+        self.matching.matched_retention_days
+    }
+
+    pub fn age_unmatched(&self) -> bool {
+        self.matching.age_unmatched
+    }
+
+    pub fn unmatched_by_instruction(&self) -> bool {
+        self.matching.unmatched_by_instruction
+    }
+
+    pub fn csv_quote_style(&self) -> CsvQuoteStyle {
+        self.matching.csv_quote_style.unwrap_or(CsvQuoteStyle::Always)
+    }
+
+    pub fn csv_line_terminator(&self) -> CsvLineTerminator {
+        self.matching.csv_line_terminator.unwrap_or(CsvLineTerminator::Lf)
+    }
+
     pub fn use_field_prefixes(&self) -> bool {
         self.matching.use_field_prefixes.unwrap_or(true)
     }
@@ -229,24 +771,240 @@ impl Charter {
         &self.jetwash
     }
 
+    ///
+    /// Load and validate the charter yaml file.
+    ///
     pub fn load(path: &Path) -> Result<Self, Error> {
         let rdr = BufReader::new(std::fs::File::open(&path)
             .map_err(|source| Error::CharterFileNotFound { path: path.to_string_lossy().into(), source })?);
 
+        Self::from_reader(rdr, &path.to_string_lossy())
+    }
 
+    ///
+    /// Read and validate charter yaml from any source, e.g. a database. The label identifies the charter in any error.
+    ///
+    pub fn from_reader<R: Read>(rdr: R, label: &str) -> Result<Self, Error> {
         let charter: Self = serde_yaml::from_reader(rdr)
-            .map_err(|source| Error::InvalidCharter { path: path.to_string_lossy().into(), source })?;
+            .map_err(|source| Error::InvalidCharter { path: label.into(), source })?;
 
-        // If field_aliases are defined, there should be one for every file_pattern.
-        let count_aliases = charter.source_files().iter().filter(|df| df.field_prefix.is_some() ).count();
-        if count_aliases > 0 && count_aliases != charter.source_files().len() {
-            return Err(Error::CharterValidationError { reason: "If field_aliases are defined, there must be one for each defined file_pattern".into() })
+        if let Some(reason) = charter.problems().into_iter().next() {
+            return Err(Error::CharterValidationError { reason })
         }
 
         // TODO 'META' is a reserved word and can't be an alias.
 
+        let mut charter = charter;
+        charter.memory_limit = effective_memory_limit(charter.memory_limit, std::env::var(MEMORY_LIMIT_ENV).ok());
+        charter.changeset_dry_run |= matches!(std::env::var(CHANGESET_DRY_RUN_ENV).as_deref(), Ok("true") | Ok("1"));
+        charter.matching.explain_unmatched |= matches!(std::env::var(EXPLAIN_UNMATCHED_ENV).as_deref(), Ok("true") | Ok("1"));
+        if let Some(threads) = env_threads(DERIVE_THREADS_ENV) {
+            charter.derive_threads = Some(threads);
+        }
+        if let Some(threads) = env_threads(MATCH_THREADS_ENV) {
+            charter.match_threads = Some(threads);
+        }
+
         Ok(charter)
     }
+
+    ///
+    /// Check charter yaml without running it, returning every problem found rather than failing on the first. As well
+    /// as the checks made when a charter is loaded, every Lua script is compiled (but not run).
+    ///
+    pub fn validate<R: Read>(rdr: R, label: &str) -> Vec<String> {
+        let charter: Self = match serde_yaml::from_reader(rdr) {
+            Ok(charter) => charter,
+            Err(source) => return vec!(Error::InvalidCharter { path: label.into(), source }.to_string()),
+        };
+
+        let mut problems = charter.problems();
+        problems.extend(charter.lua_problems());
+        problems
+    }
+
+    ///
+    /// Everything wrong with the charter's configuration, in the order they're checked.
+    ///
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec!();
+
+        // If field_aliases are defined, there should be one for every file_pattern.
+        let count_aliases = self.source_files().iter().filter(|df| df.field_prefix.is_some() ).count();
+        if count_aliases > 0 && count_aliases != self.source_files().len() {
+            problems.push("If field_aliases are defined, there must be one for each defined file_pattern".into());
+        }
+
+        if let Some(max_age) = self.stale_lock().and_then(|stale| stale.max_age.as_ref()) {
+            if let Err(err) = humantime::parse_duration(max_age) {
+                problems.push(format!("stale_lock max_age {} is invalid - {}", max_age, err));
+            }
+        }
+
+        for inst in self.instructions() {
+            if let Instruction::Project { column, from, constant, .. } = inst {
+                if from.is_some() == constant.is_some() {
+                    problems.push(format!("The projection of column {} must have either a from script or a constant value", column));
+                }
+            }
+        }
+
+        if let Some(jetwash) = self.jetwash() {
+            for mapping in jetwash.source_files().iter().filter_map(|sf| sf.column_mappings().as_ref()).flatten() {
+                if let ColumnMapping::Number { column, as_a } = mapping {
+                    if !matches!(as_a, DataType::Decimal | DataType::Integer) {
+                        problems.push(format!("The number mapping of column {} must be a Decimal or Integer", column));
+                    }
+                }
+            }
+        }
+
+        if let Err(Error::CharterValidationError { reason }) = validate_column_references(self.instructions()) {
+            problems.push(reason);
+        }
+
+        if self.derive_threads == Some(0) {
+            problems.push("derive_threads must be at least 1".into());
+        }
+
+        if self.match_threads == Some(0) {
+            problems.push("match_threads must be at least 1".into());
+        }
+
+        if self.matching.matched_retention_days == Some(0) {
+            problems.push("matched_retention_days must be at least 1".into());
+        }
+
+        if self.lua_timeout_ms == Some(0) {
+            problems.push("lua_timeout_ms must be at least 1".into());
+        }
+
+        problems
+    }
+
+    ///
+    /// Compile every Lua script in the charter, returning a problem for each one which doesn't compile.
+    ///
+    fn lua_problems(&self) -> Vec<String> {
+        let mut scripts: Vec<(String, &str)> = vec!();
+
+        if let Some(global_lua) = &self.global_lua {
+            scripts.push(("global_lua".into(), global_lua));
+        }
+
+        for (idx, inst) in self.instructions().iter().enumerate() {
+            scripts.extend(inst.scripts().into_iter().map(|(what, script)| (format!("instruction {} {}", idx, what), script)));
+        }
+
+        if let Some(jetwash) = self.jetwash() {
+            for source_file in jetwash.source_files() {
+                for mapping in source_file.column_mappings().iter().flatten() {
+                    if let ColumnMapping::Map { column, from, .. } = mapping {
+                        scripts.push((format!("jetwash mapping of {}", column), from));
+                    }
+                }
+
+                for new_column in source_file.new_columns().iter().flatten() {
+                    scripts.push((format!("jetwash new column {}", new_column.column), &new_column.from));
+                }
+            }
+        }
+
+        rlua::Lua::new().context(|lua_ctx| {
+            scripts.iter()
+                .filter_map(|(what, script)| lua::compile(&lua_ctx, script)
+                    .err()
+                    .map(|err| format!("The {} script has a Lua error, {}", what, err)))
+                .collect()
+        })
+    }
+}
+
+impl FromStr for Charter {
+    type Err = Error;
+
+    ///
+    /// Parse and validate charter yaml held in memory.
+    ///
+    fn from_str(yaml: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(yaml.as_bytes(), "<string>")
+    }
+}
+
+///
+/// A thread count from the environment variable, if it's set to a positive number.
+///
+fn env_threads(env_var: &str) -> Option<usize> {
+    std::env::var(env_var).ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|threads| *threads > 0)
+}
+
+///
+/// A group-by or distinct column without any :date_only modifier.
+///
+fn without_date_only(header: &str) -> &str {
+    header.strip_suffix(DATE_ONLY).unwrap_or(header)
+}
+
+///
+/// The source columns aren't known until data is loaded, so check what can be - that no instruction refers to a column
+/// before the projection or merge which derives it, or to a column which only differs in case from a derived column.
+///
+/// All the unknown references are reported together.
+///
+fn validate_column_references(instructions: &[Instruction]) -> Result<(), Error> {
+    let derived: Vec<(usize, &str)> = instructions.iter()
+        .enumerate()
+        .filter_map(|(idx, inst)| inst.derives().map(|column| (idx, column)))
+        .collect();
+
+    let mut unknown = vec!();
+
+    for (idx, inst) in instructions.iter().enumerate() {
+        for column in inst.referenced_columns() {
+            let column = column.as_str();
+            if derived.iter().any(|(derived_idx, derived)| *derived == column && *derived_idx < idx) {
+                continue
+            }
+
+            if let Some((derived_idx, _)) = derived.iter().find(|(_, derived)| *derived == column) {
+                unknown.push(format!("{} in instruction {} isn't derived until instruction {}", column, idx, derived_idx));
+
+            } else if let Some((_, derived)) = derived.iter().find(|(_, derived)| derived.eq_ignore_ascii_case(column)) {
+                unknown.push(format!("{} in instruction {} (did you mean {}?)", column, idx, derived));
+            }
+        }
+    }
+
+    match unknown.is_empty() {
+        true  => Ok(()),
+        false => Err(Error::CharterValidationError { reason: format!("Unknown column references: {}", unknown.join(", ")) }),
+    }
+}
+
+///
+/// The environment variable, if set to a valid number of bytes, overrides the charter's memory_limit. Either way the
+/// limit can't go below MIN_MEMORY_LIMIT - too small a limit would create a huge number of files when sorting.
+///
+fn effective_memory_limit(configured: usize, env_limit: Option<String>) -> usize {
+    let limit = match env_limit {
+        Some(value) => match value.trim().parse::<usize>() {
+            Ok(limit) => limit,
+            Err(_) => {
+                log::warn!("Ignoring invalid {} value [{}], using {} bytes", MEMORY_LIMIT_ENV, value, configured);
+                configured
+            },
+        },
+        None => configured,
+    };
+
+    if limit < MIN_MEMORY_LIMIT {
+        log::warn!("A memory_limit of {} bytes was requested, the minimum of {} bytes will be used instead", limit, MIN_MEMORY_LIMIT);
+        return MIN_MEMORY_LIMIT
+    }
+
+    limit
 }
 
 fn default_group_limit() -> usize {
@@ -259,4 +1017,108 @@ fn default_memory_limit() -> usize {
 
 fn default_archive() -> bool {
     true
-}
\ No newline at end of file
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_normalise_keys() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_limit_env_override_and_floor() {
+        assert_eq!(effective_memory_limit(default_memory_limit(), None), default_memory_limit());
+        assert_eq!(effective_memory_limit(default_memory_limit(), Some("104857600".into())), 104857600);
+        assert_eq!(effective_memory_limit(default_memory_limit(), Some("lots".into())), default_memory_limit());
+        assert_eq!(effective_memory_limit(1024, None), MIN_MEMORY_LIMIT);
+        assert_eq!(effective_memory_limit(default_memory_limit(), Some("1024".into())), MIN_MEMORY_LIMIT);
+    }
+
+    #[test]
+    fn test_charter_from_string() {
+        let charter = Charter::from_str(r#"
+name: in memory
+version: 3
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#).unwrap();
+
+        assert_eq!(charter.name(), "in memory");
+        assert_eq!(charter.version(), 3);
+        assert_eq!(charter.instructions().len(), 1);
+
+        // Invalid yaml is reported against the label given.
+        let err = Charter::from_reader("name: [".as_bytes(), "charters table, id 7").unwrap_err();
+        assert!(err.to_string().ends_with(": charters table, id 7"));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let problems = Charter::validate(r#"
+name: broken
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Category']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+    - project:
+        column: Category
+        as_a: String
+        from: |
+          if record["Type"] == "INV" then
+            return "invoice"
+"#.as_bytes(), "<string>");
+
+        assert_eq!(problems, vec!(
+            "Unknown column references: Category in instruction 0 isn't derived until instruction 1",
+            "The instruction 1 projection of Category script has a Lua error, line 3: 'end' expected (to close 'if' at line 1) near <eof>"));
+
+        assert!(Charter::validate("name: [".as_bytes(), "<string>")[0].ends_with(": <string>"));
+    }
+
+    #[test]
+    fn test_referenced_columns_include_lua_references() {
+        let charter = Charter::from_str(r#"
+name: lua references
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - filter:
+        lua: record["Status"] == "OPEN" and record["META.filename"] ~= nil
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#).unwrap();
+
+        assert_eq!(charter.instructions()[0].referenced_columns(), vec!("Status"));
+        assert_eq!(charter.instructions()[1].referenced_columns(), vec!("Ref", "Amount", "Type", "Type"));
+    }
+}
@@ -11,4 +11,13 @@ pub enum Error {
 
     #[error("Chart configuration is invalid - {reason}")]
     CharterValidationError { reason: String },
-}
\ No newline at end of file
+
+    #[error("The base dir is locked by another job (pid {owner}), remove {path} if no job is running")]
+    JobLocked { path: String, owner: String },
+
+    #[error("Unable to create lock {path}")]
+    CannotLock { path: String, source: std::io::Error },
+
+    #[error("The Lua script was aborted after running for longer than the lua_timeout_ms of {timeout_ms}ms")]
+    LuaTimeout { timeout_ms: u64 },
+}
@@ -3,6 +3,8 @@ use std::time::Duration;
 pub mod charter;
 pub mod data_type;
 pub mod error;
+pub mod lock;
+pub mod logging;
 pub mod lua;
 
 ///
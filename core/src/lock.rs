@@ -0,0 +1,157 @@
+use std::{fs::{self, OpenOptions}, io::{ErrorKind, Write}, path::{Path, PathBuf}, time::SystemTime};
+use crate::{charter::StaleLock, error::Error};
+
+pub const LOCK_FILENAME: &str = ".lock";
+
+///
+/// An exclusive lock on a base dir, held for the duration of a jetwash or celerity job.
+///
+/// The lock is a .lock file in the base dir containing the PID of the process holding it. It's created atomically
+/// so two jobs can't both believe they hold it, and it's removed when the JobLock is dropped.
+///
+#[derive(Debug)]
+pub struct JobLock {
+    path: PathBuf,
+}
+
+impl JobLock {
+    ///
+    /// Create the .lock file in the base dir or error if another job already holds it.
+    ///
+    /// If stale lock detection is configured, an existing lock from a process which is no longer running or which
+    /// is older than the max_age is logged and replaced.
+    ///
+    pub fn acquire(base_dir: &Path, stale: Option<&StaleLock>) -> Result<Self, Error> {
+        let path = base_dir.join(LOCK_FILENAME);
+
+        match create(&path) {
+            Err(Error::JobLocked { .. }) if is_stale(&path, stale) => {
+                log::warn!("Removing stale lock {}", path.to_string_lossy());
+                fs::remove_file(&path)
+                    .map_err(|source| Error::CannotLock { path: path.to_string_lossy().into(), source })?;
+                create(&path)
+            },
+            result => result,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::error!("Unable to remove lock {} : {}", self.path.to_string_lossy(), err);
+        }
+    }
+}
+
+fn create(path: &Path) -> Result<JobLock, Error> {
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            return Err(Error::JobLocked { path: path.to_string_lossy().into(), owner: read_pid(path).unwrap_or_default() })
+        },
+        Err(source) => return Err(Error::CannotLock { path: path.to_string_lossy().into(), source }),
+    };
+
+    writeln!(file, "{}", std::process::id())
+        .map_err(|source| Error::CannotLock { path: path.to_string_lossy().into(), source })?;
+
+    log::debug!("Acquired lock {}", path.to_string_lossy());
+    Ok(JobLock { path: path.to_path_buf() })
+}
+
+fn read_pid(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|content| content.trim().to_string())
+}
+
+///
+/// A lock is stale if it's older than the configured max_age or, when check_pid is set, the process which created
+/// it is no longer running.
+///
+fn is_stale(path: &Path, stale: Option<&StaleLock>) -> bool {
+    let stale = match stale {
+        Some(stale) => stale,
+        None => return false,
+    };
+
+    if let Some(max_age) = stale.max_age() {
+        let age = fs::metadata(path)
+            .and_then(|md| md.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+
+        if matches!(age, Some(age) if age >= max_age) {
+            return true
+        }
+    }
+
+    if stale.check_pid() {
+        if let Some(pid) = read_pid(path) {
+            return !process_running(&pid)
+        }
+    }
+
+    false
+}
+
+///
+/// Only platforms with a /proc filesystem can be checked, elsewhere the process is assumed to be running.
+///
+fn process_running(pid: &str) -> bool {
+    let proc = Path::new("/proc");
+    if !proc.is_dir() {
+        return true
+    }
+    pid.parse::<u32>().map(|pid| proc.join(pid.to_string()).exists()).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("openrec_lock_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lock_is_exclusive_and_released_on_drop() {
+        let dir = temp_dir("exclusive");
+
+        let lock = JobLock::acquire(&dir, None).unwrap();
+        assert!(lock.path().exists());
+        assert!(matches!(JobLock::acquire(&dir, None), Err(Error::JobLocked { .. })));
+
+        drop(lock);
+        assert!(!dir.join(LOCK_FILENAME).exists());
+        assert!(JobLock::acquire(&dir, None).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_locks_are_replaced() {
+        let dir = temp_dir("stale");
+
+        // A PID which can't be running.
+        fs::write(dir.join(LOCK_FILENAME), "4294967295").unwrap();
+        assert!(matches!(JobLock::acquire(&dir, None), Err(Error::JobLocked { .. })));
+
+        let check_pid: StaleLock = serde_yaml::from_str("check_pid: true").unwrap();
+        let lock = JobLock::acquire(&dir, Some(&check_pid)).unwrap();
+        assert_eq!(read_pid(lock.path()), Some(std::process::id().to_string()));
+
+        // Our own PID is running, so only an age check can replace it.
+        assert!(matches!(JobLock::acquire(&dir, Some(&check_pid)), Err(Error::JobLocked { .. })));
+        std::mem::forget(lock);
+
+        let max_age: StaleLock = serde_yaml::from_str("max_age: 0s").unwrap();
+        assert!(JobLock::acquire(&dir, Some(&max_age)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use chrono::{SecondsFormat, Utc};
+use log4rs::encode::{Encode, Write, pattern::PatternEncoder};
+
+///
+/// Set to 'json' to log structured JSON lines rather than text (the default).
+///
+pub const LOG_FORMAT: &str = "OPENREC_LOG_FORMAT";
+
+///
+/// Set by Steward when it launches a match job, so the job's log lines can include the control.
+///
+pub const CONTROL_ID: &str = "OPENREC_CONTROL_ID";
+
+///
+/// True if the OPENREC_LOG_FORMAT environment variable asks for JSON logging.
+///
+pub fn json_format() -> bool {
+    matches!(std::env::var(LOG_FORMAT), Ok(format) if format.eq_ignore_ascii_case("json"))
+}
+
+///
+/// Add a field (e.g. job_id or phase) to every subsequent JSON log line written by the current thread.
+///
+pub fn set_field(key: &str, value: &str) {
+    log_mdc::insert(key, value);
+}
+
+///
+/// The JSON encoder if configured, otherwise a text encoder for the pattern given.
+///
+pub fn encoder(pattern: &str) -> Box<dyn Encode> {
+    match json_format() {
+        true  => Box::new(JsonEncoder),
+        false => Box::new(PatternEncoder::new(pattern)),
+    }
+}
+
+///
+/// Format the log record as a single line of JSON, including any fields set on this thread.
+///
+pub fn json_line(record: &log::Record) -> String {
+    let mut line = serde_json::json!({
+        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+
+    log_mdc::iter(|key, value| line[key] = value.into());
+
+    if line.get("control_id").is_none() {
+        if let Ok(control_id) = std::env::var(CONTROL_ID) {
+            line["control_id"] = control_id.into();
+        }
+    }
+
+    line.to_string()
+}
+
+///
+/// A log4rs encoder which writes each log record as a line of JSON.
+///
+#[derive(Debug)]
+pub struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &log::Record) -> anyhow::Result<()> {
+        writeln!(w, "{}", json_line(record))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log4rs::encode::writer::simple::SimpleWriter;
+
+    #[test]
+    fn test_json_lines_include_thread_fields() {
+        set_field("job_id", "a1b2c3");
+        set_field("phase", "DeriveData");
+
+        let mut output = SimpleWriter(vec!());
+        for message in ["first", "second"] {
+            JsonEncoder.encode(&mut output, &log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(log::Level::Info)
+                .target("celerity")
+                .build()).unwrap();
+        }
+
+        let output = String::from_utf8(output.0).unwrap();
+        let lines = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "first");
+        assert_eq!(lines[1]["level"], "INFO");
+        assert!(lines.iter().all(|line| line["phase"] == "DeriveData" && line["job_id"] == "a1b2c3"));
+    }
+}
@@ -1,7 +1,31 @@
-use std::path::Path;
+use std::{cell::RefCell, collections::HashMap, path::{Path, PathBuf}};
+use csv::StringRecord;
 use chrono::{Utc, TimeZone};
-use rlua::{FromLuaMulti, Number};
-use rust_decimal::{Decimal, prelude::FromPrimitive};
+use rlua::{FromLuaMulti, HookTriggers, Number};
+use rust_decimal::{Decimal, RoundingStrategy, prelude::FromPrimitive};
+use regex::Regex;
+use lazy_static::lazy_static;
+use crate::error::Error;
+
+// The registry key of the time (epoch millis) the current script started, used to enforce any timeout.
+const SCRIPT_STARTED: &str = "openrec_script_started";
+
+// How often (in Lua VM instructions) a running script is checked against its timeout.
+const TIMEOUT_CHECK_INSTRUCTIONS: u32 = 10_000;
+
+lazy_static! {
+    static ref HEADER_REGEX: Regex = Regex::new(r#"record\["(.*?)"\]"#).expect("bad regex for HEADER_REGEX");
+}
+
+///
+/// The header names referenced in the script, e.g. record["Amount"] -> Amount. META fields are excluded.
+///
+pub fn referenced_headers(script: &str) -> Vec<String> {
+    HEADER_REGEX.captures_iter(script)
+        .map(|cap| cap[1].to_string())
+        .filter(|header| !header.starts_with("META."))
+        .collect()
+}
 
 ///
 /// Plug-in global Rust functions that can be called from Lua script.
@@ -23,6 +47,21 @@ pub fn init_context(lua_ctx: &rlua::Context, global_lua: &Option<String>, lookup
 
     globals.set("abs", abs)?;
 
+    // Create a neg() function to negate a Rust Decimal data-type.
+    let neg = lua_ctx.create_function(|_, value: LuaDecimal| {
+        Ok(LuaDecimal(-value.0))
+    })?;
+
+    globals.set("neg", neg)?;
+
+    // Create a round(value, scale) function to round a Rust Decimal data-type to a number of decimal places. Midpoints
+    // are rounded away from zero, e.g. round(decimal(2.345), 2) is 2.35.
+    let round = lua_ctx.create_function(|_, (value, scale): (LuaDecimal, u32)| {
+        Ok(LuaDecimal(value.0.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero)))
+    })?;
+
+    globals.set("round", round)?;
+
     // Create a midnight() function to remove the time portion of a datetime value.
     let midnight = lua_ctx.create_function(|_, value: String| {
         let ts = value.parse::<i64>().unwrap_or_else(|_| panic!("midnight called with a non-numeric: {}", value));
@@ -33,14 +72,15 @@ pub fn init_context(lua_ctx: &rlua::Context, global_lua: &Option<String>, lookup
     globals.set("midnight", midnight)?;
 
     // Create a lookup(field, filename, filter_field, filter_string) function to find a value from another csv.
-    let lookup_path = lookup_path.to_string_lossy().to_string();
+    // Each file is read once, on first use, and kept for the lifetime of the Lua context.
+    let cache = RefCell::new(LookupCache::new(lookup_path));
     let lookup = lua_ctx.create_function(move |_, search: (
         /* find_field: */ String,
         /* file_name:  */ String,
         /* where_field: */ String,
         /* where_value: */ String)| {
-        Ok(lookup(&search.0, &search.1, &search.2, &search.3, &lookup_path)
-            .map_err(|err| rlua::Error::external(format!("{}", err))))
+        cache.borrow_mut().lookup(&search.0, &search.1, &search.2, &search.3)
+            .map_err(rlua::Error::external)
     })?;
 
     globals.set("lookup", lookup)?;
@@ -53,6 +93,75 @@ pub fn init_context(lua_ctx: &rlua::Context, global_lua: &Option<String>, lookup
     Ok(())
 }
 
+///
+/// Run the charter's global Lua script once in a throw-away context, so any error in it can be reported before a job
+/// processes any data. The error describes the failing line, e.g. "line 2: '=' expected near 'end'".
+///
+pub fn validate_global_lua(global_lua: &Option<String>, lookup_path: &Path) -> Result<(), String> {
+    if global_lua.is_none() {
+        return Ok(())
+    }
+
+    rlua::Lua::new().context(|lua_ctx| init_context(&lua_ctx, global_lua, lookup_path))
+        .map_err(|err| describe_error(&err))
+}
+
+///
+/// Compile the script without running it. Like eval, the script can either be an expression or a chunk of statements.
+///
+pub fn compile(lua_ctx: &rlua::Context, script: &str) -> Result<(), String> {
+    if lua_ctx.load(&format!("return {}", script)).into_function().is_ok() {
+        return Ok(())
+    }
+
+    lua_ctx.load(script).into_function()
+        .map(|_| ())
+        .map_err(|err| describe_error(&err))
+}
+
+///
+/// The first line of a Lua error's message, with the chunk name replaced by the line number the error occurred on.
+///
+fn describe_error(err: &rlua::Error) -> String {
+    let message = match err {
+        rlua::Error::SyntaxError { message, .. } => message.clone(),
+        rlua::Error::RuntimeError(message) => message.clone(),
+        rlua::Error::CallbackError { cause, .. } => return describe_error(cause),
+        err => err.to_string(),
+    };
+
+    let message = message.lines().next().unwrap_or_default();
+
+    // Lua prefixes errors with the chunk, e.g. [string "..."]:2: the actual error.
+    if let Some((_chunk, located)) = message.split_once("]:") {
+        if let Some((line, reason)) = located.split_once(':') {
+            if line.parse::<usize>().is_ok() {
+                return format!("line {}: {}", line, reason.trim())
+            }
+        }
+    }
+
+    message.to_string()
+}
+
+///
+/// Abort any script run with eval() in the Lua state if it runs for longer than the timeout. The clock restarts for
+/// each script evaluated, so the timeout applies to a single projection or constraint rather than the whole job.
+///
+pub fn set_timeout(lua: &rlua::Lua, timeout_ms: Option<u64>) {
+    if let Some(timeout_ms) = timeout_ms {
+        let triggers = HookTriggers { every_nth_instruction: Some(TIMEOUT_CHECK_INSTRUCTIONS), ..Default::default() };
+
+        lua.set_hook(triggers, move |lua_ctx, _debug| {
+            match lua_ctx.named_registry_value::<_, Option<i64>>(SCRIPT_STARTED)? {
+                Some(started) if Utc::now().timestamp_millis() - started > timeout_ms as i64 =>
+                    Err(rlua::Error::external(Error::LuaTimeout { timeout_ms })),
+                _ => Ok(()),
+            }
+        });
+    }
+}
+
 ///
 /// Run the lua script provided. Reporting the failing script if it errors.
 ///
@@ -61,6 +170,9 @@ pub fn eval<'lua, R: FromLuaMulti<'lua>>(lua_ctx: &rlua::Context<'lua>, lua: &st
 
     log::trace!("Running: {:?}", lua);
 
+    // Start the clock for any timeout set on this Lua state.
+    lua_ctx.set_named_registry_value(SCRIPT_STARTED, Utc::now().timestamp_millis())?;
+
     match lua_ctx.load(lua).eval::<R>() {
         Ok(result) => Ok(result),
         Err(err) => {
@@ -93,52 +205,82 @@ impl rlua::UserData for LuaDecimal {
 }
 
 ///
-/// Find a value from another csv file - or empty string if no match.
+/// The lookup files used by a Lua context. Each file is indexed by a where field the first time it's searched by it.
 ///
-fn lookup(what_field: &str, file_name: &str, where_field: &str, is: &str, lookup_path: &str)
-    -> Result<String, csv::Error> {
+struct LookupCache {
+    lookup_path: PathBuf,
+    files: HashMap<String /* filename */, LookupFile>,
+}
 
-    let path = Path::new(lookup_path).join(file_name);
-    if !path.exists() {
-        panic!("Lookup file {} does not exist", path.to_string_lossy());
+struct LookupFile {
+    headers: StringRecord,
+    rows: Vec<StringRecord>,
+    indexes: HashMap<usize /* where column */, HashMap<String /* value */, usize /* row */>>,
+}
+
+impl LookupCache {
+    fn new(lookup_path: &Path) -> Self {
+        Self { lookup_path: lookup_path.to_path_buf(), files: HashMap::new() }
     }
 
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_path(path)
-        .unwrap_or_else(|err| panic!("Failed to open {} : {}", lookup_path, err));
+    ///
+    /// Find a value from another csv file - or None if no row has the where value.
+    ///
+    fn lookup(&mut self, what_field: &str, file_name: &str, where_field: &str, is: &str) -> Result<Option<String>, String> {
+        if !self.files.contains_key(file_name) {
+            let file = LookupFile::load(&self.lookup_path.join(file_name))?;
+            self.files.insert(file_name.to_string(), file);
+        }
 
-    // Get the column position of the where_field header.
-    let where_col = match reader.headers()?.iter().position(|h| h == where_field) {
-        Some(col) => col,
-        None => panic!("Lookup 'where' field {} was not in the file {}", where_field, file_name),
-    };
+        let file = self.files.get_mut(file_name).expect("lookup file not cached");
 
-    // Get the column position of the what_field header.
-    let what_col = match reader.headers()?.iter().position(|h| h == what_field) {
-        Some(col) => col,
-        None => panic!("Lookup 'what' field {} was not in the file {}", what_field, file_name),
-    };
+        let where_col = file.position(where_field)
+            .ok_or_else(|| format!("Lookup 'where' field {} was not in the file {}", where_field, file_name))?;
 
-    for record in reader.records() {
-        let record = record?;
-
-        // Find a record where the where_field value == the is clause.
-        match record.get(where_col) {
-            Some(value) => {
-                if value == is {
-                    // Return the what_field.
-                    match record.get(what_col) {
-                        Some(what) => return Ok(what.to_string()),
-                        None => return Ok(String::default()),
-                    }
+        let what_col = file.position(what_field)
+            .ok_or_else(|| format!("Lookup 'what' field {} was not in the file {}", what_field, file_name))?;
+
+        // The first row with the value wins.
+        let rows = &file.rows;
+        let index = file.indexes.entry(where_col).or_insert_with(|| {
+            let mut index = HashMap::new();
+            for (row, record) in rows.iter().enumerate() {
+                if let Some(value) = record.get(where_col) {
+                    index.entry(value.to_string()).or_insert(row);
                 }
-            },
-            None => continue,
+            }
+            index
+        });
+
+        Ok(index.get(is).map(|row| file.rows[*row].get(what_col).unwrap_or_default().to_string()))
+    }
+}
+
+impl LookupFile {
+    fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Err(format!("Lookup file {} does not exist", path.to_string_lossy()))
         }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .from_path(path)
+            .map_err(|err| format!("Failed to open lookup file {} : {}", path.to_string_lossy(), err))?;
+
+        let headers = reader.headers()
+            .map_err(|err| format!("Failed to read headers from lookup file {} : {}", path.to_string_lossy(), err))?
+            .clone();
+
+        let rows = reader.records()
+            .collect::<Result<Vec<StringRecord>, csv::Error>>()
+            .map_err(|err| format!("Failed to read lookup file {} : {}", path.to_string_lossy(), err))?;
+
+        Ok(Self { headers, rows, indexes: HashMap::new() })
     }
 
-    Ok(String::default())
+    fn position(&self, header: &str) -> Option<usize> {
+        self.headers.iter().position(|h| h == header)
+    }
 }
 
 
@@ -168,4 +310,29 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_global_lua_errors_name_the_line() {
+        assert_eq!(validate_global_lua(&None, Path::new("/tmp")), Ok(()));
+        assert_eq!(validate_global_lua(&Some("x = 1\ny = decimal(2)".into()), Path::new("/tmp")), Ok(()));
+        assert_eq!(validate_global_lua(&Some("x = 1\ny = = 2".into()), Path::new("/tmp")),
+            Err("line 2: unexpected symbol near '='".into()));
+        assert_eq!(validate_global_lua(&Some("x = 1\n\nerror(\"bad\")".into()), Path::new("/tmp")),
+            Err("line 3: bad".into()));
+    }
+
+    #[test]
+    fn test_decimal_round_and_neg() {
+        let lua = rlua::Lua::new();
+
+        lua.context(|lua_ctx| {
+            init_context(&lua_ctx, &None, Path::new("/tmp")).expect("init_context failed");
+            let ld: LuaDecimal = lua_ctx.load("round(decimal(\"2.345\"), 2)").eval().expect("lua failed");
+            assert_eq!(ld.0, Decimal::new(235, 2));
+            let ld: LuaDecimal = lua_ctx.load("round(neg(decimal(\"2.345\")), 1)").eval().expect("lua failed");
+            assert_eq!(ld.0, Decimal::new(-23, 1));
+            let ld: LuaDecimal = lua_ctx.load("abs(neg(decimal(12)))").eval().expect("lua failed");
+            assert_eq!(ld.0, Decimal::new(12, 0));
+        });
+    }
+
 }
\ No newline at end of file
@@ -73,24 +73,24 @@ impl ColumnMeta {
         }
     }
 
-    pub fn new_currency(code: Option<String>) -> Self {
+    pub fn new_currency(code: Option<String>, currencies: &[&str]) -> Self {
         Self {
             segments: None,
-            currency: Some(CurrencyMeta::new(code)),
+            currency: Some(CurrencyMeta::new(code, currencies)),
             integer: None,
             decimal: None,
         }
     }
 
     ///
-    /// Generate a random set of column properties based on the data-type.
+    /// Generate a random set of column properties based on the data-type. Currency columns only use the currencies given.
     ///
-    pub fn generate(data_type: DataType, rng: &mut StdRng) -> Self {
+    pub fn generate(data_type: DataType, rng: &mut StdRng, currencies: &[&str]) -> Self {
         match data_type {
             DataType::STRING => {
                 match rng.gen_range(1..=100) {
                     1..=60 => ColumnMeta { segments: Some(SegmentMeta::generate(None, rng)), ..Default::default() }, // 60% of string columns are a reference code.
-                    _      => ColumnMeta { currency: Some(CurrencyMeta::generate(rng)), ..Default::default() },      // 40% of string columns are an ISO currency code.
+                    _      => ColumnMeta { currency: Some(CurrencyMeta::generate(rng, currencies)), ..Default::default() },      // 40% of string columns are an ISO currency code.
                 }
             },
             DataType::INTEGER => ColumnMeta { integer: Some(IntegerMeta::new(rng)), ..Default::default() },
@@ -197,26 +197,31 @@ impl Default for SegmentMeta {
 ///
 #[derive(Debug)]
 pub struct CurrencyMeta {
-    code: Option<String>, // All values will use this currency, otherwise a weighted random currency will be used.
+    code: Option<String>,    // All values will use this currency, otherwise a weighted random currency will be used.
+    currencies: Vec<String>, // The currencies a random currency is selected from.
 }
 
 impl CurrencyMeta {
-    pub fn new(code: Option<String>) -> Self {
-        Self { code }
+    pub fn new(code: Option<String>, currencies: &[&str]) -> Self {
+        Self { code, currencies: currencies.iter().map(|c| c.to_string()).collect() }
     }
 
-    fn generate(rng: &mut StdRng) -> Self {
+    fn generate(rng: &mut StdRng, currencies: &[&str]) -> Self {
         let currency = match rng.gen_range(1..=100) {
-            1..=70 => Some(generator::rand_currency()), // All values in this column will use this randomly selected currency.
+            1..=70 => Some(generator::rand_currency(rng, currencies)), // All values in this column will use this randomly selected currency.
             _      => None,                  // Each value in this column will be a random currency.
         };
 
-        Self { code: currency }
+        Self::new(currency, currencies)
     }
 
     pub fn code(&self) -> &Option<String> {
         &self.code
     }
+
+    pub fn currencies(&self) -> &[String] {
+        &self.currencies
+    }
 }
 
 
@@ -1,7 +1,8 @@
-use uuid::Uuid;
+use uuid::{Builder, Variant, Version};
 use csv::QuoteStyle;
 use self::prelude::*;
-use std::time::Instant;
+use std::{fs::{self, File, OpenOptions}, io, ops::RangeInclusive, path::{Path, PathBuf}, time::Instant};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rust_decimal::prelude::*;
 use humantime::format_duration;
 use num_format::{Locale, ToFormattedString};
@@ -12,6 +13,7 @@ use crate::{column::*, data_type::DataType, group::Group, schema::Schema};
 pub mod prelude {
     // Snaphot of ISO currency codes.
     pub const CURRENCIES: [&str; 162] = ["AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT", "BGN", "BHD", "BIF", "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF", "CHF", "CLP", "CNY", "COP", "CRC", "CUC", "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GGP", "GHS", "GIP", "GMD", "GNF", "GTQ", "GYD", "HKD", "HNL", "HRK", "HTG", "HUF", "IDR", "ILS", "IMP", "INR", "IQD", "IRR", "ISK", "JEP", "JMD", "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLL", "SOS", "SPL", "SRD", "STN", "SVC", "SYP", "SZL", "THB", "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TVD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS", "VEF", "VND", "VUV", "WST", "XAF", "XCD", "XDR", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWD"];
+    pub const DEFAULT_CURRENCY: &str = "GBP";
     pub const RANDOM_ALPHANUMERIC: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
     pub const RANDOM_ALPHA: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
     pub const RANDOM_NUMERIC: &str = "0123456789";
@@ -22,7 +24,8 @@ pub mod prelude {
     pub const CURRENCY: &str = "Currency";
     pub const FX_RATE: &str = "FXRate";
     pub const INVOICE_REF: &str = "InvoiceRef";
-    // pub const OPENREC_STATUS: &str = "OpenRecStatus";
+    pub const OPENREC_ID: &str = "OpenRecId";
+    pub const OPENREC_STATUS: &str = "OpenRecStatus";
     pub const PAYMENT_DATE: &str = "PaymentDate";
     pub const PAYMENT_REF: &str = "PaymentRef";
     pub const RECEIPT_DATE: &str = "ReceiptDate";
@@ -43,7 +46,12 @@ pub struct Options {
     pub rec_columns: Option<usize>,
     pub pay_columns: Option<usize>,
     pub rows: Option<u64>,
-    pub rnd_seed: Option<u64>
+    pub rnd_seed: Option<u64>,
+    pub celerity_format: bool, // Include the OpenRec columns and schema row so files can skip jetwash.
+    pub unmatched_ratio: Option<f64>, // The fraction of groups to deliberately unbalance.
+    pub threads: Option<usize>, // The maximum number of threads generating groups, defaults to the number of CPUs.
+    pub currencies: Option<Vec<String>>, // Restrict generated currencies to these ISO codes, otherwise any are used.
+    pub payment_files: Option<usize>, // Split the payments across this many files.
 }
 
 ///
@@ -60,51 +68,164 @@ pub fn generate(options: Options) -> Result<(), csv::Error> {
     let pay_schema = column_schema(&options.pay_schema, &options.pay_columns, &mut rng);
     let rec_schema = column_schema(&options.rec_schema, &options.rec_columns, &mut rng);
 
-    // Turn the ID,ST,DT,DE type strings into real schemas with some randomness to field lengths.
-    let inv_schema = Schema::new(&inv_schema, &mut rng, &mut fixed_inv_columns());
-    let pay_schema = Schema::new(&pay_schema, &mut rng, &mut fixed_pay_columns());
-    let rec_schema = Schema::new(&rec_schema, &mut rng, &mut fixed_rec_columns());
+    let currencies = match &options.currencies {
+        Some(currencies) => currencies.iter().map(String::as_str).collect(),
+        None => CURRENCIES.to_vec(),
+    };
 
-    let prefix = "";//Utc::now().format("%Y%m%d_%H%M%S%3f_").to_string();
-    let inv_path = format!("{}/{}invoices.csv", output, prefix);
-    let pay_path = format!("{}/{}payments.csv", output, prefix);
-    let rec_path = format!("{}/{}receipts.csv", output, prefix);
+    // Turn the ID,ST,DT,DE type strings into real schemas with some randomness to field lengths.
+    let inv_schema = Schema::new(&inv_schema, &mut rng, &mut fixed_columns(options.celerity_format, fixed_inv_columns(&currencies)), &currencies);
+    let pay_schema = Schema::new(&pay_schema, &mut rng, &mut fixed_columns(options.celerity_format, fixed_pay_columns(&currencies)), &currencies);
+    let rec_schema = Schema::new(&rec_schema, &mut rng, &mut fixed_columns(options.celerity_format, fixed_rec_columns(&currencies)), &currencies);
 
-    let inv_path = std::path::Path::new(&inv_path);
-    let pay_path = std::path::Path::new(&pay_path);
-    let rec_path = std::path::Path::new(&rec_path);
+    let files = OutputFiles::new(&output, options.payment_files.unwrap_or(1));
 
     // Create parent dirs if required.
-    let parent = inv_path.parent().unwrap();
+    let parent = files.invoices.parent().unwrap();
     std::fs::create_dir_all(parent).unwrap();
 
-    // Output the column headers to both files.
-    let mut inv_wtr = csv::WriterBuilder::new().quote_style(QuoteStyle::Always).from_path(inv_path)?;
-    inv_wtr.write_record(inv_schema.header_vec())?;
-    // inv_wtr.write_record(inv_schema.schema_vec())?;
+    // Output the column headers to each file.
+    write_headers(&files.invoices, &inv_schema, options.celerity_format)?;
+    for pay_path in &files.payments {
+        write_headers(pay_path, &pay_schema, options.celerity_format)?;
+    }
+    write_headers(&files.receipts, &rec_schema, options.celerity_format)?;
+
+    // Split the groups into a contiguous range for each worker.
+    let rows = options.rows.unwrap_or(10);
+    let threads = options.threads.unwrap_or_else(num_cpus::get).clamp(1, rows.max(1) as usize);
+    let chunk = (rows as f64 / threads as f64).ceil() as u64;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("can't build rayon thread pool");
+
+    let config = GroupConfig { inv_schema: &inv_schema, pay_schema: &pay_schema, rec_schema: &rec_schema, rnd_seed, unmatched_ratio: options.unmatched_ratio };
+
+    // Each worker writes its groups to its own temporary files.
+    let counts = pool.install(|| (0..threads)
+        .into_par_iter()
+        .map(|worker| {
+            let groups = (worker as u64 * chunk + 1)..=((worker as u64 + 1) * chunk).min(rows);
+            generate_groups(&config, groups, &files.for_worker(worker))
+        })
+        .collect::<Result<Vec<(usize, usize, usize)>, csv::Error>>())?;
 
-    let mut pay_wtr = csv::WriterBuilder::new().quote_style(QuoteStyle::Always).from_path(pay_path)?;
-    pay_wtr.write_record(pay_schema.header_vec())?;
-    // pay_wtr.write_record(pay_schema.schema_vec())?;
+    // Append the worker files in order, so groups are written in the same order whatever the number of threads.
+    for worker in 0..threads {
+        for (path, worker_path) in files.all().zip(files.for_worker(worker).all()) {
+            append_file(worker_path, path)?;
+        }
+    }
 
-    let mut rec_wtr = csv::WriterBuilder::new().quote_style(QuoteStyle::Always).from_path(rec_path)?;
-    rec_wtr.write_record(rec_schema.header_vec())?;
-    // rec_wtr.write_record(rec_schema.schema_vec())?;
+    // Total the records each worker wrote.
+    let (invoices, payments, receipts) = counts.iter()
+        .fold((0, 0, 0), |acc, count| (acc.0 + count.0, acc.1 + count.1, acc.2 + count.2));
 
-    // Initialise some counters.
-    let (mut invoices, mut receipts, mut payments) = (0, 0, 0);
+    println!("Generated data in {dur} using seed {seed}\n  {inv} invoices exported to {inv_p}\n  {pay} payments exported to {pay_p}\n  {rec} receipts exported to {rec_p}",
+        inv = invoices.to_formatted_string(&Locale::en),
+        pay = payments.to_formatted_string(&Locale::en),
+        rec = receipts.to_formatted_string(&Locale::en),
+        inv_p = files.invoices.canonicalize().unwrap().into_os_string().into_string().unwrap(),
+        pay_p = files.payments.iter().map(|path| path.canonicalize().unwrap().into_os_string().into_string().unwrap()).collect::<Vec<String>>().join(", "),
+        rec_p = files.receipts.canonicalize().unwrap().into_os_string().into_string().unwrap(),
+        dur = format_duration(start.elapsed()),
+        seed = rnd_seed
+    );
+
+    Ok(())
+}
+
+///
+/// The files generated data is written to.
+///
+struct OutputFiles {
+    invoices: PathBuf,
+    payments: Vec<PathBuf>, // payments.csv, or payments-1.csv, payments-2.csv, etc. if payments are split.
+    receipts: PathBuf,
+}
+
+impl OutputFiles {
+    fn new(output: &str, payment_files: usize) -> Self {
+        let payments = match payment_files {
+            0 | 1 => vec!(PathBuf::from(format!("{}/payments.csv", output))),
+            count => (1..=count).map(|idx| PathBuf::from(format!("{}/payments-{}.csv", output, idx))).collect(),
+        };
+
+        Self {
+            invoices: PathBuf::from(format!("{}/invoices.csv", output)),
+            payments,
+            receipts: PathBuf::from(format!("{}/receipts.csv", output)),
+        }
+    }
+
+    ///
+    /// The temporary files a worker writes it's groups to.
+    ///
+    fn for_worker(&self, worker: usize) -> Self {
+        Self {
+            invoices: worker_path(&self.invoices, worker),
+            payments: self.payments.iter().map(|path| worker_path(path, worker)).collect(),
+            receipts: worker_path(&self.receipts, worker),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.invoices)
+            .chain(self.payments.iter())
+            .chain(std::iter::once(&self.receipts))
+    }
+}
+
+///
+/// The schemas and settings shared by all the workers generating groups.
+///
+struct GroupConfig<'a> {
+    inv_schema: &'a Schema,
+    pay_schema: &'a Schema,
+    rec_schema: &'a Schema,
+    rnd_seed: u64,
+    unmatched_ratio: Option<f64>,
+}
+
+///
+/// Generate the range of groups into the invoice, payment and receipt files (without headers).
+///
+/// Each group has it's own rng, seeded from the master seed and the group's position, so a group is the same
+/// regardless of which worker generates it. If there are several payment files, a group's payments are dealt across
+/// them in turn, starting from a different file for each group. Returns the number of invoices, payments and receipts
+/// written.
+///
+fn generate_groups(config: &GroupConfig, groups: RangeInclusive<u64>, files: &OutputFiles) -> Result<(usize, usize, usize), csv::Error> {
+    let writer = |path: &PathBuf| csv::WriterBuilder::new().quote_style(QuoteStyle::Always).from_path(path);
+    let mut inv_wtr = writer(&files.invoices)?;
+    let mut pay_wtrs = files.payments.iter().map(writer).collect::<Result<Vec<_>, csv::Error>>()?;
+    let mut rec_wtr = writer(&files.receipts)?;
+
+    let (mut invoices, mut payments, mut receipts) = (0, 0, 0);
 
     // Generate some random CSV rows.
-    for _row in 1..=options.rows.unwrap_or(10) {
+    for row in groups {
+        let mut rng = StdRng::seed_from_u64(group_seed(config.rnd_seed, row));
+
         // Generate number of records which should match into a group.
-        let group = Group::new(&inv_schema, &pay_schema, &rec_schema, &mut rng);
+        let mut group = Group::new(config.inv_schema, config.pay_schema, config.rec_schema, &mut rng);
+
+        // Only consult the rng if a ratio was given, so existing seeds generate the same data.
+        if let Some(ratio) = config.unmatched_ratio {
+            if rng.gen_bool(ratio) {
+                group.unbalance(config.inv_schema, &mut rng);
+            }
+        }
 
         // Write the group to the approriate file.
         inv_wtr.write_record(group.invoice())?;
         invoices +=1;
 
-        for payment in group.payments() {
-            pay_wtr.write_record(payment)?;
+        for (idx, payment) in group.payments().iter().enumerate() {
+            let file = (row as usize - 1 + idx) % pay_wtrs.len();
+            pay_wtrs[file].write_record(payment)?;
             payments += 1
         }
 
@@ -114,37 +235,79 @@ pub fn generate(options: Options) -> Result<(), csv::Error> {
         }
     }
 
-    println!("Generated data in {dur} using seed {seed}\n  {inv} invoices exported to {inv_p}\n  {pay} payments exported to {pay_p}\n  {rec} receipts exported to {rec_p}",
-        inv = invoices.to_formatted_string(&Locale::en),
-        pay = payments.to_formatted_string(&Locale::en),
-        rec = receipts.to_formatted_string(&Locale::en),
-        inv_p = inv_path.canonicalize().unwrap().into_os_string().into_string().unwrap(),
-        pay_p = pay_path.canonicalize().unwrap().into_os_string().into_string().unwrap(),
-        rec_p = rec_path.canonicalize().unwrap().into_os_string().into_string().unwrap(),
-        dur = format_duration(start.elapsed()),
-        seed = rnd_seed
-    );
-
-    // Generate a 1-to-n pair of output files (invoice and receiptes).
     inv_wtr.flush()?;
-    pay_wtr.flush()?;
+    for pay_wtr in &mut pay_wtrs {
+        pay_wtr.flush()?;
+    }
     rec_wtr.flush()?;
+    Ok((invoices, payments, receipts))
+}
+
+///
+/// Derive a group's seed from the master seed. This is the SplitMix64 finaliser, so neighbouring groups (and
+/// neighbouring master seeds) get unrelated sequences.
+///
+fn group_seed(rnd_seed: u64, row: u64) -> u64 {
+    let mut z = rnd_seed ^ row.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+///
+/// Create the file with the column headers and, if for celerity, the schema row.
+///
+fn write_headers(path: &Path, schema: &Schema, celerity_format: bool) -> Result<(), csv::Error> {
+    let mut wtr = csv::WriterBuilder::new().quote_style(QuoteStyle::Always).from_path(path)?;
+    wtr.write_record(schema.header_vec())?;
+    if celerity_format {
+        wtr.write_record(schema.schema_vec())?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+///
+/// The temporary file a worker writes it's groups to, eg. invoices.csv.3.tmp
+///
+fn worker_path(path: &Path, worker: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}.tmp", path.to_string_lossy(), worker))
+}
+
+///
+/// Append the worker's temporary file to the output file and remove it.
+///
+fn append_file(from: &Path, to: &Path) -> Result<(), csv::Error> {
+    let mut reader = File::open(from)?;
+    let mut writer = OpenOptions::new().append(true).open(to)?;
+    io::copy(&mut reader, &mut writer)?;
+    fs::remove_file(from)?;
     Ok(())
 }
 
+///
+/// Celerity expects the OpenRecStatus and OpenRecId columns (usually added by jetwash) to lead every record.
+///
+fn fixed_columns(celerity_format: bool, mut columns: Vec<Column>) -> Vec<Column> {
+    if celerity_format {
+        columns.insert(0, Column::new(DataType::INTEGER, OPENREC_STATUS.into(), ColumnMeta::default()));
+        columns.insert(1, Column::new(DataType::UUID, OPENREC_ID.into(), ColumnMeta::default()));
+    }
+    columns
+}
+
 ///
 /// Add some fixed columns which are always present regardless of other random junk.
 ///
-fn fixed_inv_columns() -> Vec<Column> {
+fn fixed_inv_columns(currencies: &[&str]) -> Vec<Column> {
     vec!(
-        // Column::new(DataType::INTEGER, OPENREC_STATUS.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, RECORD_TYPE.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, REFERENCE.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::STRING, INVOICE_REF.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::DATETIME, TRADE_DATE.into(), ColumnMeta::default()),
         Column::new(DataType::DATETIME, SETTLEMENT_DATE.into(), ColumnMeta::default()),
         Column::new(DataType::DECIMAL, TOTAL_AMOUNT.into(), ColumnMeta::new_decimal(12, 6)),
-        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some("GBP".into()))),
+        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some(default_currency(currencies)), currencies)),
         Column::new(DataType::DECIMAL, FX_RATE.into(), ColumnMeta::new_decimal(12, 6)),
     )
 }
@@ -152,15 +315,14 @@ fn fixed_inv_columns() -> Vec<Column> {
 ///
 /// Add some fixed columns which are always present regardless of other random junk.
 ///
-fn fixed_pay_columns() -> Vec<Column> {
+fn fixed_pay_columns(currencies: &[&str]) -> Vec<Column> {
     vec!(
-        // Column::new(DataType::INTEGER, OPENREC_STATUS.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, RECORD_TYPE.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, REFERENCE.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::STRING, PAYMENT_REF.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::DATETIME, PAYMENT_DATE.into(), ColumnMeta::default()),
         Column::new(DataType::DECIMAL, AMOUNT.into(), ColumnMeta::new_decimal(12, 6)),
-        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some("GBP".into()))),
+        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some(default_currency(currencies)), currencies)),
         Column::new(DataType::DECIMAL, FX_RATE.into(), ColumnMeta::new_decimal(12, 6)),
     )
 }
@@ -168,16 +330,15 @@ fn fixed_pay_columns() -> Vec<Column> {
 ///
 /// Add some fixed columns which are always present regardless of other random junk.
 ///
-fn fixed_rec_columns() -> Vec<Column> {
+fn fixed_rec_columns(currencies: &[&str]) -> Vec<Column> {
     vec!(
-        // Column::new(DataType::INTEGER, OPENREC_STATUS.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, RECORD_TYPE.into(), ColumnMeta::default()),
         Column::new(DataType::STRING, REFERENCE.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::STRING, RECEIPT_REF.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
         Column::new(DataType::DATETIME, RECEIPT_DATE.into(), ColumnMeta::default()),
         Column::new(DataType::DECIMAL, AMOUNT.into(), ColumnMeta::new_decimal(12, 6)),
         Column::new(DataType::STRING, PAYMENT_REF.into(), ColumnMeta::new_reference(vec!((3, SegmentType::ALPHA), (5, SegmentType::NUMERIC)))),
-        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some("GBP".into()))),
+        Column::new(DataType::STRING, CURRENCY.into(), ColumnMeta::new_currency(Some(default_currency(currencies)), currencies)),
         Column::new(DataType::DECIMAL, FX_RATE.into(), ColumnMeta::new_decimal(12, 6)),
     )
 }
@@ -252,7 +413,7 @@ pub fn generate_row(schema: &Schema, foreign_key: &str, record_type: &str, rng:
             match col.header() {
                 RECORD_TYPE    => record_type.to_string(),
                 REFERENCE      => foreign_key.to_string(),
-                // OPENREC_STATUS => "0".into(),
+                OPENREC_STATUS => "0".into(),
                 _ => match col.data_type() {
                     DataType::UNKNOWN  => panic!("Unknown data type encountered for column {}", col.header()),
                     DataType::BOOLEAN  => generate_boolean(rng),
@@ -260,7 +421,7 @@ pub fn generate_row(schema: &Schema, foreign_key: &str, record_type: &str, rng:
                     DataType::DECIMAL  => generate_decimal(rng, col.meta()),
                     DataType::INTEGER  => generate_integer(rng, col.meta()),
                     DataType::STRING   => generate_string(rng, col.meta()),
-                    DataType::UUID     => generate_uuid(),
+                    DataType::UUID     => generate_uuid(rng),
                 }
             }
         })
@@ -279,7 +440,7 @@ fn generate_string(rng: &mut StdRng, meta: &ColumnMeta) -> String {
     if let Some(cur_meta) = &meta.currency() {
         match &cur_meta.code() {
             Some(code) => return code.clone(),
-            None => return rand_currency(),
+            None => return rand_currency(rng, cur_meta.currencies()),
         }
     }
 
@@ -288,10 +449,15 @@ fn generate_string(rng: &mut StdRng, meta: &ColumnMeta) -> String {
 }
 
 ///
-/// Generate a v4 UUID hyphenated string.
+/// Generate a v4 UUID hyphenated string from the rng, so seeded output is reproducible.
 ///
-fn generate_uuid() -> String {
-    Uuid::new_v4().to_hyphenated().to_string()
+fn generate_uuid(rng: &mut StdRng) -> String {
+    Builder::from_bytes(rng.gen())
+        .set_variant(Variant::RFC4122)
+        .set_version(Version::Random)
+        .build()
+        .to_hyphenated()
+        .to_string()
 }
 
 ///
@@ -364,10 +530,20 @@ fn generate_boolean(rng: &mut StdRng) -> String {
 }
 
 ///
-/// Select a random currency code.
+/// Select a random currency code from those allowed.
 ///
-pub fn rand_currency() -> String {
-    CURRENCIES.choose(&mut rand::thread_rng()).unwrap().to_string()
+pub fn rand_currency<S: AsRef<str>>(rng: &mut StdRng, currencies: &[S]) -> String {
+    currencies.choose(rng).unwrap().as_ref().to_string()
+}
+
+///
+/// The currency for the fixed currency columns, GBP unless the allowed currencies exclude it.
+///
+fn default_currency(currencies: &[&str]) -> String {
+    match currencies.contains(&DEFAULT_CURRENCY) {
+        true  => DEFAULT_CURRENCY.into(),
+        false => currencies[0].into(),
+    }
 }
 
 ///
@@ -392,3 +568,280 @@ fn rand_chars(amount: usize, slice: &str, rng: &mut StdRng) -> String {
         .collect()
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn generate_invoices(name: &str, celerity_format: bool) -> Vec<csv::StringRecord> {
+        let output = std::env::temp_dir().join(format!("generator_{}", name));
+        generate(Options {
+            output: Some(output.to_string_lossy().into()),
+            inv_schema: Some("ST,DT".into()),
+            rec_schema: Some("ST".into()),
+            pay_schema: Some("ST".into()),
+            inv_columns: None,
+            rec_columns: None,
+            pay_columns: None,
+            rows: Some(2),
+            rnd_seed: None,
+            celerity_format,
+            unmatched_ratio: None,
+            threads: None,
+            currencies: None,
+            payment_files: None,
+        }).unwrap();
+
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(output.join("invoices.csv"))
+            .unwrap()
+            .records()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_celerity_format_adds_openrec_columns() {
+        let records = generate_invoices("celerity", true);
+
+        assert_eq!(&records[0].iter().take(3).collect::<Vec<&str>>(), &[OPENREC_STATUS, OPENREC_ID, RECORD_TYPE]);
+        assert_eq!(&records[1].iter().take(3).collect::<Vec<&str>>(), &["IN", "ID", "ST"]);
+        assert_eq!(records[1].len(), records[0].len());
+
+        // Two invoices, all unmatched with a uuid.
+        assert_eq!(records.len(), 4);
+        for record in &records[2..] {
+            assert_eq!(&record[0], "0");
+            assert!(Uuid::parse_str(&record[1]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_default_format_has_no_openrec_columns() {
+        let records = generate_invoices("default", false);
+
+        assert_eq!(&records[0][0], RECORD_TYPE);
+        assert_eq!(records.len(), 3);
+    }
+
+    #[test]
+    fn test_output_directory_is_created() {
+        let output = std::env::temp_dir().join("generator_output").join("nested");
+        let _ = std::fs::remove_dir_all(output.parent().unwrap());
+        let default_invoices = std::path::Path::new("./tmp/invoices.csv");
+        let default_modified = std::fs::metadata(default_invoices).and_then(|md| md.modified()).ok();
+
+        generate(Options {
+            output: Some(output.to_string_lossy().into()),
+            inv_schema: Some("ST".into()),
+            rec_schema: Some("ST".into()),
+            pay_schema: Some("ST".into()),
+            inv_columns: None,
+            rec_columns: None,
+            pay_columns: None,
+            rows: Some(1),
+            rnd_seed: None,
+            celerity_format: false,
+            unmatched_ratio: None,
+            threads: None,
+            currencies: None,
+            payment_files: None,
+        }).unwrap();
+
+        for file in ["invoices.csv", "payments.csv", "receipts.csv"] {
+            assert!(output.join(file).is_file(), "{} not generated", file);
+        }
+
+        // Nothing is written to the default folder.
+        assert_eq!(std::fs::metadata(default_invoices).and_then(|md| md.modified()).ok(), default_modified);
+    }
+
+    #[test]
+    fn test_same_seed_same_data_regardless_of_threads() {
+        let generate_with = |threads: usize| {
+            let output = std::env::temp_dir().join(format!("generator_threads_{}", threads));
+            let _ = std::fs::remove_dir_all(&output);
+
+            generate(Options {
+                output: Some(output.to_string_lossy().into()),
+                inv_schema: None,
+                rec_schema: None,
+                pay_schema: None,
+                inv_columns: Some(5),
+                rec_columns: Some(5),
+                pay_columns: Some(5),
+                rows: Some(50),
+                rnd_seed: Some(42),
+                celerity_format: true,
+                unmatched_ratio: Some(0.2),
+                threads: Some(threads),
+                currencies: None,
+                payment_files: None,
+            }).unwrap();
+
+            ["invoices.csv", "payments.csv", "receipts.csv"]
+                .map(|file| std::fs::read_to_string(output.join(file)).unwrap())
+        };
+
+        let serial = generate_with(1);
+        assert_eq!(serial[0].lines().count(), 52);
+        assert_eq!(generate_with(4), serial);
+        assert_eq!(generate_with(7), serial);
+
+        // No worker files are left behind.
+        let leftovers = std::fs::read_dir(std::env::temp_dir().join("generator_threads_4")).unwrap().count();
+        assert_eq!(leftovers, 3);
+    }
+
+    #[test]
+    fn test_only_restricted_currencies_generated() {
+        let output = std::env::temp_dir().join("generator_currencies");
+        let _ = std::fs::remove_dir_all(&output);
+
+        generate(Options {
+            output: Some(output.to_string_lossy().into()),
+            inv_schema: Some("ST,ST,ST,ST,ST,ST,ST,ST".into()),
+            rec_schema: Some("ST,ST,ST,ST,ST,ST,ST,ST".into()),
+            pay_schema: Some("ST,ST,ST,ST,ST,ST,ST,ST".into()),
+            inv_columns: None,
+            rec_columns: None,
+            pay_columns: None,
+            rows: Some(20),
+            rnd_seed: None,
+            celerity_format: false,
+            unmatched_ratio: None,
+            threads: None,
+            currencies: Some(vec!("USD".into(), "EUR".into())),
+            payment_files: None,
+        }).unwrap();
+
+        let mut generated = std::collections::BTreeSet::new();
+        for file in ["invoices.csv", "payments.csv", "receipts.csv"] {
+            let mut rdr = csv::Reader::from_path(output.join(file)).unwrap();
+            let currency_col = rdr.headers().unwrap().iter().position(|hdr| hdr == CURRENCY).unwrap();
+
+            for record in rdr.records().map(Result::unwrap) {
+                // GBP isn't allowed so the fixed currency column uses the first currency.
+                assert_eq!(&record[currency_col], "USD");
+                generated.extend(record.iter().filter(|field| CURRENCIES.contains(field)).map(str::to_string));
+            }
+        }
+
+        assert_eq!(generated.into_iter().collect::<Vec<String>>(), vec!("EUR", "USD"));
+    }
+
+    ///
+    /// Generate celerity-format files, run them through a charter grouping by Reference and return the number of
+    /// unmatched invoices.
+    ///
+    fn unmatched_invoices(name: &str, unmatched_ratio: Option<f64>, payment_files: Option<usize>) -> usize {
+        let base_dir = std::env::temp_dir().join(format!("generator_{}", name));
+        let _ = std::fs::remove_dir_all(&base_dir);
+        let output = base_dir.join("generated");
+
+        generate(Options {
+            output: Some(output.to_string_lossy().into()),
+            inv_schema: None,
+            rec_schema: None,
+            pay_schema: None,
+            inv_columns: Some(3),
+            rec_columns: Some(3),
+            pay_columns: Some(3),
+            rows: Some(200),
+            rnd_seed: Some(1234567890),
+            celerity_format: true,
+            unmatched_ratio,
+            threads: None,
+            currencies: None,
+            payment_files,
+        }).unwrap();
+
+        // Queue the files for celerity with a timestamp prefix.
+        let waiting = base_dir.join("waiting");
+        std::fs::create_dir_all(&waiting).unwrap();
+        for file in std::fs::read_dir(&output).unwrap().map(Result::unwrap) {
+            std::fs::copy(file.path(), waiting.join(format!("20211201_053700000_{}", file.file_name().to_string_lossy()))).unwrap();
+        }
+
+        let charter = base_dir.join("charter.yaml");
+        std::fs::write(&charter, r#"name: Generated
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.*\.csv
+      field_prefix: INV
+    - pattern: .*payments.*\.csv
+      field_prefix: PAY
+    - pattern: .*receipts.*\.csv
+      field_prefix: REC
+  instructions:
+    - project:
+        column: PAYMENT_AMOUNT_BASE
+        as_a: Decimal
+        from: record["PAY.Amount"] * record["PAY.FXRate"]
+        when: record["META.prefix"] == "PAY"
+    - project:
+        column: RECEIPT_AMOUNT_BASE
+        as_a: Decimal
+        from: record["REC.Amount"] * record["REC.FXRate"]
+        when: record["META.prefix"] == "REC"
+    - merge:
+        columns: ['PAYMENT_AMOUNT_BASE', 'RECEIPT_AMOUNT_BASE', 'INV.TotalAmount']
+        into: AMOUNT_BASE
+    - merge:
+        columns: ['INV.Reference', 'PAY.Reference', 'REC.Reference']
+        into: REFERENCE
+    - group:
+        by: ['REFERENCE']
+        match_when:
+          - nets_to_zero:
+              column: AMOUNT_BASE
+              lhs: record["META.prefix"] == "PAY"
+              rhs: record["META.prefix"] == "INV"
+          - nets_to_zero:
+              column: AMOUNT_BASE
+              lhs: record["META.prefix"] == "REC"
+              rhs: record["META.prefix"] == "INV"
+"#).unwrap();
+
+        celerity::run_charter(&charter, &base_dir).unwrap();
+
+        // Skip the header and schema rows.
+        match std::fs::read_to_string(base_dir.join("unmatched/20211201_053700000_invoices.unmatched.csv")) {
+            Ok(contents) => contents.lines().count() - 2,
+            Err(_) => 0,
+        }
+    }
+
+    #[test]
+    fn test_generated_groups_net_to_zero() {
+        assert_eq!(unmatched_invoices("net_to_zero", None, None), 0, "generated groups didn't all match");
+    }
+
+    #[test]
+    fn test_unmatched_ratio() {
+        // Each unbalanced group leaves it's invoice unmatched.
+        let unmatched = unmatched_invoices("unmatched_ratio", Some(0.5), None);
+        assert!((70..=130).contains(&unmatched), "{} of 200 invoices unmatched", unmatched);
+
+        // The same seed unbalances the same groups.
+        assert_eq!(unmatched_invoices("unmatched_ratio_again", Some(0.5), None), unmatched);
+    }
+
+    #[test]
+    fn test_split_payments_still_match() {
+        assert_eq!(unmatched_invoices("payment_files", None, Some(2)), 0, "groups with split payments didn't all match");
+
+        // Both payment files were sourced and fully matched.
+        let base_dir = std::env::temp_dir().join("generator_payment_files");
+        assert!(!base_dir.join("generated/payments.csv").exists());
+
+        for file in ["payments-1", "payments-2"] {
+            let generated = std::fs::read_to_string(base_dir.join(format!("generated/{}.csv", file))).unwrap();
+            assert!(generated.lines().count() > 2, "{} has no payments", file);
+            assert!(!base_dir.join(format!("unmatched/20211201_053700000_{}.unmatched.csv", file)).exists());
+        }
+    }
+}
@@ -40,6 +40,20 @@ impl Group {
         Self { invoice, payments, receipts }
     }
 
+    ///
+    /// Stop the group matching, either by dropping one of the payments or by tweaking the invoice's total amount.
+    ///
+    pub fn unbalance(&mut self, inv_schema: &Schema, rng: &mut StdRng) {
+        match rng.gen_bool(0.5) {
+            true  => { self.payments.pop(); },
+            false => {
+                let tweak = Decimal::from(rng.gen_range(1..=100));
+                let total = get_decimal(TOTAL_AMOUNT, &self.invoice, inv_schema);
+                set_decimal(TOTAL_AMOUNT, total + tweak, &mut self.invoice, inv_schema);
+            },
+        }
+    }
+
     pub fn invoice(&self) -> &[String] {
         &self.invoice
     }
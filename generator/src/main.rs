@@ -1,5 +1,5 @@
 use clap::{App, Arg, ArgMatches};
-use generator::Options;
+use generator::{Options, prelude::CURRENCIES};
 
 mod data_type;
 mod column;
@@ -72,6 +72,31 @@ fn main() {
             .required(false)
             .long("rows")
             .takes_value(true))
+        .arg(Arg::with_name("CELERITY")
+            .help("Add the OpenRecStatus and OpenRecId columns and a schema row, so the files can be placed directly in a control's waiting folder rather than it's inbox.")
+            .required(false)
+            .long("celerity")
+            .takes_value(false))
+        .arg(Arg::with_name("UNMATCHED_RATIO")
+            .help("An (optional) fraction, between 0 and 1, of the groups to deliberately unbalance so they won't match.")
+            .required(false)
+            .long("unmatched-ratio")
+            .takes_value(true))
+        .arg(Arg::with_name("THREADS")
+            .help("An (optional) maximum number of threads to generate groups with. Defaults to the number of CPUs. The same seed generates the same data regardless of the number of threads.")
+            .required(false)
+            .long("threads")
+            .takes_value(true))
+        .arg(Arg::with_name("CURRENCIES")
+            .help("An (optional) comma-separated list of ISO currency codes, eg. GBP,USD,EUR. Only these currencies will be generated.")
+            .required(false)
+            .long("currencies")
+            .takes_value(true))
+        .arg(Arg::with_name("PAYMENT_FILES")
+            .help("An (optional) number of files to split the payments across, eg. payments-1.csv, payments-2.csv. Defaults to a single payments.csv.")
+            .required(false)
+            .long("payment-files")
+            .takes_value(true))
         .arg(Arg::with_name("SEED")
             .help("An (optional) unsigned long-integer used to seed the random number generator. Passing the same value should always yield repropduceable output")
             .required(false)
@@ -98,6 +123,28 @@ fn parse<T: std::str::FromStr>(value: Option<&str>, msg: &str) -> Option<T> {
     }
 }
 
+///
+/// Parse the unmatched-ratio (if specified), panic if it's not a fraction.
+///
+fn parse_ratio(value: Option<&str>) -> Option<f64> {
+    let ratio = parse::<f64>(value, "unmatched-ratio")?;
+    if !(0.0..=1.0).contains(&ratio) {
+        panic!("unmatched-ratio if specified, must be between 0 and 1");
+    }
+    Some(ratio)
+}
+
+///
+/// Parse the currencies (if specified), panic if any aren't ISO currency codes.
+///
+fn parse_currencies(value: Option<&str>) -> Option<Vec<String>> {
+    let currencies = value?.split(',').map(|code| code.trim().to_string()).collect::<Vec<String>>();
+    if let Some(code) = currencies.iter().find(|code| !CURRENCIES.contains(&code.as_str())) {
+        panic!("currencies if specified, must be ISO currency codes, '{}' isn't one", code);
+    }
+    Some(currencies)
+}
+
 impl From<ArgMatches<'static>> for Options {
     fn from(matches: ArgMatches<'static>) -> Self {
         Self {
@@ -110,6 +157,11 @@ impl From<ArgMatches<'static>> for Options {
             rec_columns: parse(matches.value_of("RECEIPT_COLUMNS"), "receipt-columns"),
             rows: parse(matches.value_of("ROWS"), "rows"),
             rnd_seed: parse(matches.value_of("SEED"), "seed"),
+            celerity_format: matches.is_present("CELERITY"),
+            unmatched_ratio: parse_ratio(matches.value_of("UNMATCHED_RATIO")),
+            threads: parse(matches.value_of("THREADS"), "threads"),
+            currencies: parse_currencies(matches.value_of("CURRENCIES")),
+            payment_files: parse(matches.value_of("PAYMENT_FILES"), "payment-files"),
         }
     }
 }
\ No newline at end of file
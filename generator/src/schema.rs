@@ -9,9 +9,9 @@ pub struct Schema {
 impl Schema {
     ///
     /// Parse a comma-separated string of data-type short-codes and turn into a schema with generated column header names
-    /// and randomly sized numbers.
+    /// and randomly sized numbers. Any currency columns only use the currencies given.
     ///
-    pub fn new(raw: &str, rng: &mut StdRng, additional: &mut Vec<Column>) -> Self {
+    pub fn new(raw: &str, rng: &mut StdRng, additional: &mut Vec<Column>, currencies: &[&str]) -> Self {
         let mut columns = vec!();
         let mut idx = 1u16;
 
@@ -29,7 +29,7 @@ impl Schema {
             columns.push(Column::new(
                 data_type,
                 format!("Column_{}", idx),
-                ColumnMeta::generate(data_type, rng)));
+                ColumnMeta::generate(data_type, rng, currencies)));
 
             idx += 1;
         }
@@ -48,25 +48,26 @@ impl Schema {
             .collect::<Vec<&str>>()
     }
 
-    // pub fn schema_vec(&self) -> Vec<&str> {
-    //     self.columns
-    //     .iter()
-    //     .map(|c| c.data_type().into())
-    //     .collect::<Vec<&str>>()
-    // }
+    pub fn schema_vec(&self) -> Vec<&str> {
+        self.columns
+            .iter()
+            .map(|c| c.data_type().into())
+            .collect::<Vec<&str>>()
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use rand::SeedableRng;
+    use crate::generator::prelude::CURRENCIES;
 
     use super::*;
 
     #[test]
     fn test_parse_ok() {
         let mut rng = StdRng::seed_from_u64(1234567890u64);
-        let schema = Schema::new("ID,BO,DT,DE,IN,ST,ID,BO", &mut rng, &mut vec!());
+        let schema = Schema::new("ID,BO,DT,DE,IN,ST,ID,BO", &mut rng, &mut vec!(), &CURRENCIES);
 
         // Parsed columns.
         for idx in 0..8 {
@@ -89,6 +90,6 @@ mod tests {
     #[should_panic(expected = "Unknown data type 'x'")]
     fn test_parse_err() {
         let mut rng = StdRng::seed_from_u64(1234567890u64);
-        let _schema = Schema::new("ID,BO,ST,x", &mut rng, &mut vec!());
+        let _schema = Schema::new("ID,BO,ST,x", &mut rng, &mut vec!(), &CURRENCIES);
     }
 }
\ No newline at end of file
@@ -146,7 +146,8 @@ r#"    [
                 {
                     "file": "20211220_061800000_changeset.json",
                     "updated": 1,
-                    "ignored": 0
+                    "ignored": 0,
+                    "added": 0
                 }
             ]
         }
@@ -268,7 +269,8 @@ matching:
                 {
                     "file": "20211220_061800000_changeset.json",
                     "updated": 2,
-                    "ignored": 0
+                    "ignored": 0,
+                    "added": 0
                 }
             ]
         }
@@ -401,12 +403,14 @@ r#"    [
                 {
                     "file": "20211220_061800000_changeset.json",
                     "updated": 2,
-                    "ignored": 0
+                    "ignored": 0,
+                    "added": 0
                 },
                 {
                     "file": "20211221_061800000_changeset.json",
                     "updated": 2,
-                    "ignored": 0
+                    "ignored": 0,
+                    "added": 0
                 }
             ]
         }
@@ -519,7 +523,8 @@ r#"    [
                 {
                     "file": "20211220_061800000_changeset.json",
                     "updated": 0,
-                    "ignored": 1
+                    "ignored": 1,
+                    "added": 0
                 }
             ]
         }
@@ -728,6 +733,7 @@ r#"[
             "changesets": [
             {
                 "file": "20211220_061800000_changeset.json",
+                "added": 0,
                 "ignored": 0,
                 "updated": 0
             }],
@@ -817,4 +823,498 @@ r#""Invoice No","Ref","Invoice Date","Amount","Thing"
             "unmatched_records": 0
         }
     ]));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_changesets_resolve_bare_columns_with_field_prefixes() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The payment amount is wrong, a changeset will correct it using un-prefixed column names.
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","INV0001","100.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_payments.csv",
+r#""OpenRecStatus","PaymentId","Ref","Amount"
+"IN","ST","ST","DE"
+"0","P1","INV0001","90.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "6b7c6e0c-6ce2-11ec-8f5d-00155ddc3e05",
+        "change": {
+            "type": "UpdateFields",
+            "updates": [ { "field": "Amount", "value": "100.00" } ],
+            "lua_filter": "record[\"PaymentId\"] == \"P1\""
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: changeset prefix test
+version: 1
+matching:
+  source_files:
+  - pattern: .*invoices.*\.csv$
+    field_prefix: INV
+  - pattern: .*payments.*\.csv$
+    field_prefix: PAY
+  instructions:
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref']
+        into: REF
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount']
+        into: AMOUNT
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY""#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_files_in_folders(&base_dir, vec!(
+        (0, "waiting"),
+        (0, "unmatched"),
+        (1, "matched")));
+}
+
+
+#[test]
+fn test_changesets_error_on_unknown_columns_with_field_prefixes() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","INV0001","100.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "6b7c6e0c-6ce2-11ec-8f5d-00155ddc3e05",
+        "change": {
+            "type": "IgnoreRecords",
+            "lua_filter": "record[\"InvoiceRef\"] == \"INV0001\""
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: changeset prefix test
+version: 1
+matching:
+  source_files:
+  - pattern: .*invoices.*\.csv$
+    field_prefix: INV
+  instructions:
+    - group:
+        by: ['INV.Ref']
+        match_when: []"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert_eq!(err.to_string(), "ChangeSet 6b7c6e0c-6ce2-11ec-8f5d-00155ddc3e05 references the column InvoiceRef which doesn't exist in any data file");
+}
+
+
+#[test]
+fn test_changesets_can_be_scoped_to_files() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Two feeds each with an identical record.
+    for shortname in ["feed-a", "feed-b"] {
+        common::write_file(&base_dir.join("waiting/"), &format!("20211201_053700000_{}.csv", shortname),
+r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"#);
+    }
+
+    // Ignore the record - but only from the first feed.
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "1b8ea2ea-6cf0-11ec-a1f6-00155ddc3e05",
+        "change": {
+            "type": "IgnoreRecords",
+            "lua_filter": "record[\"TransId\"] == 1"
+        },
+        "scope": { "files": [ "feed-a" ] },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: changeset scope test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['TransId']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Only the second feed's record remains unmatched.
+    assert_eq!(common::get_filenames(&base_dir.join("unmatched")), vec!("20211201_053700000_feed-b.unmatched.csv"));
+    common::assert_file_contents(&base_dir.join("unmatched/20211201_053700000_feed-b.unmatched.csv"),
+r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"#);
+}
+
+
+#[test]
+fn test_changesets_can_clear_fields() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"0","1","90.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: changeset clear test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['TransId']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    // The T2 amount is wrong so the group won't match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    common::assert_files_in_folders(&base_dir, vec!((1, "unmatched")));
+
+    // Blank the bad amount.
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "3d1f1f1c-6d76-11ec-9ea0-00155dd154c9",
+        "change": {
+            "type": "ClearFields",
+            "fields": [ "Amount" ],
+            "lua_filter": "record[\"Type\"] == \"T2\""
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_file_contents(&base_dir.join("unmatched/20211201_053700000_transactions.unmatched.csv"),
+r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"0","1","","T2"
+"#);
+
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    assert_eq!(footer["changesets"], json!([
+        {
+            "file": "20211220_061800000_changeset.json",
+            "updated": 1,
+            "ignored": 0,
+            "added": 0
+        }
+    ]));
+
+    // Now correct any record with a blank amount.
+    common::write_file(&base_dir.join("waiting/"), "20211221_061800000_changeset.json",
+r#"[
+    {
+        "id": "4a9e2b6e-6d76-11ec-9ea0-00155dd154c9",
+        "change": {
+            "type": "UpdateFields",
+            "updates": [ { "field": "Amount", "value": "100.00" } ],
+            "lua_filter": "record[\"Amount\"] == nil"
+        },
+        "timestamp": "2021-12-21T06:18:00.000Z"
+    }
+]"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_files_in_folders(&base_dir, vec!(
+        (0, "waiting"),
+        (0, "unmatched")));
+}
+
+#[test]
+fn test_changesets_can_add_records() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","OpenRecId","TransId","Amount","Type"
+"IN","ID","IN","DE","ST"
+"0","d9d8c3c6-6d76-11ec-9ea0-00155dd154c9","1","100.00","T1"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: test
+version: 1
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['TransId']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    // The T1 has no T2 to net against.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    common::assert_files_in_folders(&base_dir, vec!((1, "unmatched")));
+
+    // Inject the missing T2.
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "5c2b7a4e-6d76-11ec-9ea0-00155dd154c9",
+        "change": {
+            "type": "AddRecords",
+            "file": "transactions",
+            "records": [ { "TransId": "1", "Amount": "100.00", "Type": "T2" } ]
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_files_in_folders(&base_dir, vec!(
+        (0, "waiting"),
+        (0, "unmatched")));
+
+    let job = common::read_json_file(common::get_match_job_file(&base_dir));
+    assert_eq!(job[1]["groups"], json!([ [[0,3],[0,4]] ]));
+    assert_eq!(job[2]["changesets"], json!([
+        {
+            "file": "20211220_061800000_changeset.json",
+            "updated": 0,
+            "ignored": 0,
+            "added": 1
+        }
+    ]));
+}
+
+#[test]
+fn test_changesets_reject_added_values_of_the_wrong_type() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "5c2b7a4e-6d76-11ec-9ea0-00155dd154c9",
+        "change": {
+            "type": "AddRecords",
+            "file": "transactions",
+            "records": [ { "TransId": "1", "Amount": "one hundred", "Type": "T2" } ]
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: test
+version: 1
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['TransId']
+        match_when: []"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert_eq!(err.to_string(), "ChangeSet 5c2b7a4e-6d76-11ec-9ea0-00155dd154c9 adds the value one hundred to the column Amount which isn't a valid DE");
+}
+
+#[test]
+fn test_changesets_dry_run_leaves_data_unchanged() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    let transactions = r#""OpenRecStatus","TransId","Amount","Type"
+"IN","IN","DE","ST"
+"0","1","100.00","T1"
+"0","1","90.00","T2"
+"0","2","50.00","T1"
+"0","2","40.00","T2"
+"#;
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv", transactions);
+
+    // The amount correction would match the first group, if it were applied.
+    common::write_file(&base_dir.join("waiting/"), "20211220_061800000_changeset.json",
+r#"[
+    {
+        "id": "6e4a3b1c-6d76-11ec-9ea0-00155dd154c9",
+        "change": {
+            "type": "UpdateFields",
+            "updates": [ { "field": "Amount", "value": "100.00" } ],
+            "lua_filter": "record[\"Type\"] == \"T2\" and record[\"TransId\"] == 1"
+        },
+        "timestamp": "2021-12-20T06:18:00.000Z"
+    }
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: test
+version: 1
+changeset_dry_run: true
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['TransId']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Nothing matched and the unmatched data is exactly the data that was supplied. The changeset wasn't applied,
+    // so it's left in waiting rather than archived.
+    common::assert_files_in_folders(&base_dir, vec!(
+        (1, "waiting"),
+        (1, "unmatched")));
+    common::assert_file_contents(&base_dir.join("unmatched/20211201_053700000_transactions.unmatched.csv"), transactions);
+    assert!(base_dir.join("waiting/20211220_061800000_changeset.json").exists());
+
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    assert_eq!(footer["matched_groups"], 0);
+    assert_eq!(footer["changesets"], json!([
+        {
+            "file": "20211220_061800000_changeset.json",
+            "updated": 1,
+            "ignored": 0,
+            "added": 0,
+            "dry_run": true
+        }
+    ]));
+}
+
+
+#[test]
+fn test_changesets_can_unignore_records() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","B","50.00","PAY"
+"#);
+
+    // Ignore every payment then bring back the payment for A. The first UnignoreRecords is before the ignore so
+    // has no effect on B.
+    common::write_file(&base_dir.join("waiting/"), "20211201_053000000_changeset.json",
+r#"[
+{
+    "id": "1c6c2f6e-60a7-11ec-a5fb-00155ddc3c4d",
+    "change": {
+        "type": "UnignoreRecords",
+        "lua_filter": "record[\"Ref\"] == \"B\""
+    },
+    "timestamp": "2021-12-01T05:30:00.000Z"
+},
+{
+    "id": "2d8e4a7c-60a7-11ec-a5fb-00155ddc3c4d",
+    "change": {
+        "type": "IgnoreRecords",
+        "lua_filter": "record[\"Type\"] == \"PAY\""
+    },
+    "timestamp": "2021-12-01T05:30:00.000Z"
+},
+{
+    "id": "3e9f5b8d-60a7-11ec-a5fb-00155ddc3c4d",
+    "change": {
+        "type": "UnignoreRecords",
+        "lua_filter": "record[\"Ref\"] == \"A\""
+    },
+    "timestamp": "2021-12-01T05:30:00.000Z"
+}
+]"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: unignore test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The un-ignored payment matches its invoice and the ignored payment is released.
+    assert_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+    common::assert_files_in_folders(&base_dir, vec!((0, "unmatched")));
+
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    assert_eq!(footer["changesets"], json!([
+        {
+            "file": "20211201_053000000_changeset.json",
+            "updated": 0,
+            "ignored": 2,
+            "added": 0,
+            "unignored": 1
+        }
+    ]));
+}
@@ -46,6 +46,50 @@ matching:
 }
 
 
+#[test]
+fn test_decimal_net_to_n_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 3 transactions, with a 1:2 cardinality. Only the first group differs by the target.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","102.50","T1"
+"0","0002","2021-12-19T00:00:00.000Z","75.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25.00","T2"
+"0","0004","2021-01-20T00:00:00.000Z","102.49","T1"
+"0","0005","2021-01-20T00:00:00.000Z","75.00","T2"
+"0","0006","2021-01-20T00:00:00.000Z","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_n:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            target: 2.50
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
 #[test]
 fn test_decimal_net_with_tolerance_constraint() {
 
@@ -136,6 +180,51 @@ matching:
 }
 
 
+#[test]
+fn test_decimal_net_with_tolerance_percent_of_larger_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 3 transactions, with a 1:2 cardinality. The T2s are the larger side, 1% of which allows the first group.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","99.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","75.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25.00","T2"
+"0","0004","2021-01-20T00:00:00.000Z","98.99","T1"
+"0","0005","2021-01-20T00:00:00.000Z","75.00","T2"
+"0","0006","2021-01-20T00:00:00.000Z","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_with_tolerance:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            tol_type: PercentOfLarger
+            tolerance: 1.0
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
 #[test]
 fn test_integer_net_to_zero_constraint() {
 
@@ -179,6 +268,50 @@ matching:
 }
 
 
+#[test]
+fn test_integer_net_to_n_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 3 transactions, with a 1:2 cardinality. Only the first group differs by the target.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","IN","ST"
+"0","0001","2021-12-19T00:00:00.000Z","105","T1"
+"0","0002","2021-12-19T00:00:00.000Z","75","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25","T2"
+"0","0004","2021-01-20T00:00:00.000Z","104","T1"
+"0","0005","2021-01-20T00:00:00.000Z","75","T2"
+"0","0006","2021-01-20T00:00:00.000Z","25","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_n:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            target: 5
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
 #[test]
 fn test_integer_net_with_tolerance_constraint() {
 
@@ -267,6 +400,50 @@ matching:
     assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
 }
 
+#[test]
+fn test_integer_net_with_tolerance_percent_of_larger_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 3 transactions, with a 1:2 cardinality. The T2s are the larger side, 1% of which allows the first group.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","IN","ST"
+"0","0001","2021-12-19T00:00:00.000Z","99","T1"
+"0","0002","2021-12-19T00:00:00.000Z","75","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25","T2"
+"0","0004","2021-01-20T00:00:00.000Z","98","T1"
+"0","0005","2021-01-20T00:00:00.000Z","75","T2"
+"0","0006","2021-01-20T00:00:00.000Z","25","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_with_tolerance:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            tol_type: PercentOfLarger
+            tolerance: 1
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
 #[test]
 fn test_custom_constraint_with_count() {
 
@@ -439,4 +616,690 @@ matching:
 
     assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
     assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_custom_constraint_with_avg_and_avg_int() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 3 transactions, with a 1:2 cardinality.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type","IntAmount"
+"IN","IN","DT","DE","ST","IN"
+"0","0001","2021-12-19T08:29:00.000Z","100.00","T1","550"
+"0","0002","2021-12-19T08:29:00.000Z","75.00","T2","300"
+"0","0003","2021-12-19T08:29:00.000Z","25.00","T2","251"
+"#);
+
+    // Create a charter with a custom constraint.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: avg aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - custom:
+            script: |
+              local t1 = function (record) return record["Type"] == "T1" end
+              local t2 = function (record) return record["Type"] == "T2" end
+              local t3 = function (record) return record["Type"] == "T3" end
+
+              return avg("Amount", t1) == decimal(100.00)
+                and avg("Amount", t2) == decimal(50.00)
+                and avg_int("IntAmount", t2) == 275
+                and avg("Amount", t3) == nil
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+}
+
+#[test]
+fn test_custom_constraint_with_stdev_and_variance() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create two groups of transactions, the first have similar amounts, the second are widely spread.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T08:29:00.000Z","99.00","T1"
+"0","0002","2021-12-19T08:29:00.000Z","100.00","T1"
+"0","0003","2021-12-19T08:29:00.000Z","101.00","T2"
+"0","0004","2021-12-20T08:29:00.000Z","10.00","T1"
+"0","0005","2021-12-20T08:29:00.000Z","100.00","T1"
+"0","0006","2021-12-20T08:29:00.000Z","190.00","T2"
+"#);
+
+    // Create a charter with a custom constraint.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: stdev aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - custom:
+            script: |
+              local all = function (record) return true end
+              local t2 = function (record) return record["Type"] == "T2" end
+
+              return stdev("Amount", all) < decimal(5)
+                and variance("Amount", all) == decimal(1)
+                and stdev("Amount", t2) == nil
+                and variance("Amount", t2) == nil
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+#[test]
+fn test_custom_constraint_with_first_and_last() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 4 transactions out of date order, the two earliest share a date.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Reference","Date","Amount","Type"
+"IN","IN","ST","DT","DE","ST"
+"0","0001","REF1","2021-12-20T08:29:00.000Z","100.00","T1"
+"0","0002","REF1","2021-12-18T08:29:00.000Z","75.00","T2"
+"0","0003","REF1","2021-12-18T08:29:00.000Z","50.00","T2"
+"0","0004","REF1","2021-12-21T08:29:00.000Z","25.00","T2"
+"#);
+
+    // Create a charter with a custom constraint.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: first and last aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Reference']
+        match_when:
+        - custom:
+            script: |
+              local all = function (record) return true end
+              local t1 = function (record) return record["Type"] == "T1" end
+              local t3 = function (record) return record["Type"] == "T3" end
+
+              return first("Amount", "Date", all) == decimal(75.00)
+                and last("Amount", "Date", all) == decimal(25.00)
+                and first("TransId", "Date", all) == 2
+                and last("TransId", "Date", t1) == 1
+                and last("Amount", "Date", t3) == nil
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5], [0,6]] ]));
+}
+
+#[test]
+fn test_custom_constraint_with_abs_tolerance() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create two groups of transactions, the first nets to within 1.00, the second doesn't.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Reference","Amount"
+"IN","IN","ST","DE"
+"0","0001","REF1","100.00"
+"0","0002","REF1","-100.50"
+"0","0003","REF2","100.00"
+"0","0004","REF2","-105.00"
+"#);
+
+    // Create a charter with a custom constraint.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: abs tolerance test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Reference']
+        match_when:
+        - custom:
+            script: |
+              local all = function (record) return true end
+              return abs(sum("Amount", all)) <= decimal(1)
+                and round(neg(sum("Amount", all)), 0) == decimal(1)
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+#[test]
+fn test_custom_constraint_with_count_distinct() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Create 4 transactions, the T2s repeat a counterparty reference.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type","Counterparty"
+"IN","IN","DT","DE","ST","ST"
+"0","0001","2021-12-19T08:29:00.000Z","100.00","T1","ACME"
+"0","0002","2021-12-19T08:29:00.000Z","50.00","T2","ACME"
+"0","0003","2021-12-19T08:29:00.000Z","25.00","T2","ACME"
+"0","0004","2021-12-19T08:29:00.000Z","25.00","T2","GLOBEX"
+"#);
+
+    // Create a charter with a custom constraint.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count distinct aggregate test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - custom:
+            script: |
+              local all = function (record) return true end
+              local t2 = function (record) return record["Type"] == "T2" end
+
+              return count_distinct("Counterparty", all) == 2
+                and count_distinct("Counterparty", t2) == 2
+                and count_distinct("Amount", t2) == 2
+                and count_distinct("Type", all) == 2
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
+}
+
+#[test]
+fn test_count_in_range_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","50.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25.00","T2"
+"0","0004","2021-12-19T00:00:00.000Z","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count in range test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+        - count_in_range:
+            filter: record["Type"] == "T1"
+            min: 1
+            max: 1
+        - count_in_range:
+            filter: record["Type"] == "T2"
+            min: 1
+            max: 3
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5], [0,6]] ]));
+}
+
+
+#[test]
+fn test_count_out_of_range_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","50.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","25.00","T2"
+"0","0004","2021-12-19T00:00:00.000Z","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: count in range test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+        - count_in_range:
+            filter: record["Type"] == "T1"
+            min: 1
+            max: 1
+        - count_in_range:
+            filter: record["Type"] == "T2"
+            min: 1
+            max: 2
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([]));
+}
+
+
+#[test]
+fn test_none_match_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The second invoice has been cancelled.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","INV1","100.00","INV"
+"0","0002","INV1","100.00","PAY"
+"0","0003","INV2","50.00","INV"
+"0","0004","INV2","50.00","PAY"
+"0","0005","INV2","0.00","CAN"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: none match test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+        - none_match:
+            filter: record["Type"] == "CAN"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+}
+
+
+#[test]
+fn test_cardinality_constraint() {
+
+    let data_files = common::example_data_files(vec!("04-invoices.csv", "04-payments.csv", "04-receipts.csv"));
+    let base_dir = common::init_test_from_examples(&format!("tests/{}", function!()), &data_files);
+
+    // The 3-way match example, but only matching an invoice settled with exactly two payments and two receipts.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: cardinality test
+version: 1
+jetwash:
+  source_files:
+   - pattern: ^04-invoices\.csv$
+   - pattern: ^04-payments\.csv$
+   - pattern: ^04-receipts\.csv$
+matching:
+  source_files:
+    - pattern: .*04-invoices.*\.csv
+      field_prefix: INV
+    - pattern: .*04-payments.*\.csv
+      field_prefix: PAY
+    - pattern: .*04-receipts.*\.csv
+      field_prefix: REC
+  instructions:
+    - merge:
+        columns: ['INV.Reference', 'PAY.Reference', 'REC.Reference']
+        into: REFERENCE
+    - group:
+        by: ['REFERENCE']
+        match_when:
+          - cardinality:
+              groups:
+                - filter: record["META.prefix"] == "INV"
+                  count: 1
+                - filter: record["META.prefix"] == "PAY"
+                  count: 2
+                - filter: record["META.prefix"] == "REC"
+                  count: 2
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,4],[1,4],[1,5],[2,4],[2,5]] ]));
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 3);
+}
+
+
+#[test]
+fn test_all_equal_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The first group shares a currency, the second doesn't.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Currency","Type"
+"IN","IN","DT","DE","ST","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","GBP","T1"
+"0","0002","2021-12-19T00:00:00.000Z","100.00","GBP","T2"
+"0","0003","2021-12-20T00:00:00.000Z","100.00","GBP","T1"
+"0","0004","2021-12-20T00:00:00.000Z","100.00","USD","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: all equal test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+        - all_equal:
+            column: Currency
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_sum_compare_constraint_passes() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","60.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","40.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: sum compare test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+        - sum_compare:
+            column: Amount
+            filter: record["Type"] == "T1"
+            op: '<='
+            value: 100
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
+#[test]
+fn test_sum_compare_constraint_fails() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","60.00","T2"
+"0","0003","2021-12-19T00:00:00.000Z","40.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: sum compare test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+        - sum_compare:
+            column: Amount
+            filter: record["Type"] == "T1"
+            op: '<'
+            value: 100
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([]));
+}
+
+
+#[test]
+fn test_net_with_tolerances_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The principal of both groups nets within tolerance, the interest of the second group doesn't.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Principal","Interest","Type"
+"IN","IN","DT","DE","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","10.00","T1"
+"0","0002","2021-12-19T00:00:00.000Z","99.50","10.00","T2"
+"0","0003","2021-01-20T00:00:00.000Z","100.00","10.00","T1"
+"0","0004","2021-01-20T00:00:00.000Z","99.50","9.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: tolerances test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_with_tolerances:
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            tolerances:
+            - column: Principal
+              tol_type: Amount
+              tolerance: 1.00
+            - column: Interest
+              tol_type: Percent
+              tolerance: 5.0
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_group_aggregates_written_to_matched_groups() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","A","100.00","INV"
+"0","0002","A","-99.50","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: aggregates test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_with_tolerance:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+            tol_type: Amount
+            tolerance: 1.00
+        aggregates:
+          column: Amount
+          lhs: record["Type"] == "INV"
+          rhs: record["Type"] == "PAY"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([{
+        "records": [[0,3], [0,4]],
+        "aggregates": { "lhs": "100.00", "rhs": "-99.50", "difference": "0.50" }
+    }]));
+}
+
+#[test]
+fn test_explain_unmatched_names_failing_constraint() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // REF1 nets within the tolerance, REF2 doesn't.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Reference","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","REF1","99.00","T1"
+"0","0002","REF1","100.00","T2"
+"0","0003","REF2","90.00","T1"
+"0","0004","REF2","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: explain unmatched test
+version: 1
+matching:
+  use_field_prefixes: false
+  explain_unmatched: true
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Reference']
+        match_when:
+        - count_in_range:
+            filter: record["Type"] == "T1"
+            min: 1
+            max: 1
+        - nets_with_tolerance:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            tol_type: Amount
+            tolerance: 1.0
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    let diagnostics = get_dir_content(base_dir.join("matched")).unwrap().files
+        .into_iter()
+        .find(|file| file.ends_with("_diagnostics.json"))
+        .expect("no diagnostics file");
+
+    let diagnostics = common::read_json_file(diagnostics.into());
+    let entries = diagnostics.as_array().unwrap();
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["instruction"], 0);
+    assert_eq!(entries[0]["key"], "REF2");
+    assert_json_eq!(entries[0]["records"].clone(), json!([ ["20211219_082900000_transactions.csv", 5], ["20211219_082900000_transactions.csv", 6] ]));
+
+    let failed = entries[0]["failed"].as_array().unwrap();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].as_str().unwrap().starts_with("NetsWithTolerance"), "{}", failed[0]);
+}
@@ -453,6 +453,44 @@ fn test_07_unmatched_data_from_examples() {
 }
 
 
+#[test]
+fn test_07_unmatched_data_manifest() {
+
+    // Run the 07 example with a manifest.
+    let example = std::fs::read_to_string(common::example_charter("07-Unmatched-Data.yaml")).unwrap();
+    let data_files = common::example_data_files(vec!("07-invoices.csv", "07-payments-a.csv"));
+
+    let base_dir = common::init_test_from_examples("tests/examples/test_07_unmatched_data_manifest/", &data_files);
+    let charter = common::write_file(&base_dir, "charter.yaml", &example.replacen("matching:\n", "matching:\n  write_manifest: true\n", 1));
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_files_in_folders(&base_dir, vec!(
+        (2, "unmatched"),
+        (2, "matched")));
+
+    let manifest = common::read_json_file(base_dir.join("matched/20211201_053700000_manifest.json"));
+    assert_eq!(manifest, json!({
+        "job_id": FIXED_JOB_ID,
+        "matched": [ "20211201_053700000_matched.json" ],
+        "unmatched": [
+            { "file": "20211201_053700000_07-invoices.unmatched.csv", "rows": 1 },
+            { "file": "20211201_053700000_07-payments-a.unmatched.csv", "rows": 1 }
+        ],
+        "archived": [
+            "20211201_053700000_07-invoices.csv",
+            "20211201_053700000_07-payments-a.csv"
+        ],
+        "changesets": []
+    }));
+
+    // Every unmatched file in the manifest exists.
+    for unmatched in manifest["unmatched"].as_array().unwrap() {
+        assert!(base_dir.join("unmatched").join(unmatched["file"].as_str().unwrap()).exists());
+    }
+}
+
 #[test]
 fn test_08_advanced_lua_from_examples() {
 
@@ -638,6 +676,7 @@ fn test_09_changesets_from_examples() {
               "changesets": [
                 {
                   "file": "20220118_041500000_changeset.json",
+                  "added": 0,
                   "ignored": 1,
                   "updated": 1
                 }
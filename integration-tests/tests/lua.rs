@@ -1,3 +1,5 @@
+use serde_json::json;
+use assert_json_diff::assert_json_eq;
 use fs_extra::dir::get_dir_content;
 use crate::common::{function, self};
 
@@ -97,4 +99,193 @@ matching:
 
     assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
     assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_lookup_in_projections() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+    std::fs::create_dir_all(base_dir.join("lookups/")).expect("Cannot create a lookups folder");
+
+    common::write_file(&base_dir.join("lookups/"), "counterparties.csv",
+r#""Code","Name"
+"AC","ACME"
+"GX","GLOBEX"
+"AC","NOT THE FIRST ACME"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Counterparty","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","AC","100.00","T1"
+"0","0002","AC","100.00","T2"
+"0","0003","GX","50.00","T1"
+"0","0004","GX","50.00","T2"
+"0","0005","XX","10.00","T1"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: lookup projection test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: CounterpartyName
+        as_a: String
+        from: |
+            return lookup("Name", "counterparties.csv", "Code", record["Counterparty"]) or "UNKNOWN"
+    - group:
+        by: ['CounterpartyName']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+    - filter:
+        lua: record["CounterpartyName"] == "UNKNOWN"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The unknown counterparty is filtered, only if the lookup returned nil.
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]], [[0,5], [0,6]] ]));
+}
+
+
+#[test]
+fn test_lookup_file_missing() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Counterparty"
+"IN","IN","ST"
+"0","0001","AC"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: lookup missing file test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: CounterpartyName
+        as_a: String
+        from: |
+            return lookup("Name", "counterparties.csv", "Code", record["Counterparty"])
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("counterparties.csv does not exist"));
+}
+
+#[test]
+fn test_invalid_global_lua_fails_before_matching() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Type"
+"IN","IN","ST"
+"0","0001","T1"
+"#);
+
+    // The second line of the global Lua has a syntax error.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: invalid global lua test
+version: 1
+global_lua: |
+  only_t1s = function (record) return record["Type"] == "T1" end
+  only_t2s = function (record) return record["Type"] = "T2" end
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - filter:
+        lua: only_t1s(record)
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("Error in the charter's global_lua, line 2:"), "{:?}", err);
+
+    // The data shouldn't have been touched.
+    assert_eq!(get_dir_content(base_dir.join("waiting")).unwrap().files.len(), 1);
+    assert!(!base_dir.join("matching").exists() || get_dir_content(base_dir.join("matching")).unwrap().files.is_empty());
+}
+
+#[test]
+fn test_lua_timeout_aborts_endless_projection() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Type"
+"IN","IN","ST"
+"0","0001","T1"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: lua timeout test
+version: 1
+lua_timeout_ms: 100
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Forever
+        as_a: Integer
+        from: |
+            while true do end
+            return 1
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    let err = format!("{:?}", err);
+    assert!(err.contains("on record 3 from file 20211219_082900000_transactions.csv"), "{}", err);
+    assert!(err.contains("aborted after running for longer than the lua_timeout_ms of 100ms"), "{}", err);
+}
+
+#[test]
+fn test_lua_timeout_aborts_endless_mapping() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Ref"
+"0001","ABC"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: lua mapping timeout test
+version: 1
+lua_timeout_ms: 100
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - map:
+            column: Ref
+            as_a: String
+            from: |
+                while true do end
+                return value
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    let err = format!("{:?}", err);
+    assert!(err.contains("LuaTimeout { timeout_ms: 100 }"), "{}", err);
+}
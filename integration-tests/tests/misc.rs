@@ -1,4 +1,6 @@
+use std::{io::{Read, Write}, path::PathBuf, sync::{Arc, atomic::{AtomicBool, Ordering}}};
 use serde_json::json;
+use assert_json_diff::assert_json_eq;
 use fs_extra::dir::get_dir_content;
 use crate::common::{self, FIXED_JOB_ID, function};
 
@@ -451,4 +453,2889 @@ matching:
     celerity::run_charter(&charter, &base_dir).unwrap();
     assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
     assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_decimal_match_keys_are_normalised() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The group-by values are equal, just formatted with a different scale.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","DE","DE","ST"
+"0","0001","100","100.00","T1"
+"0","0002","100.00","75.00","T2"
+"0","0003","100.0","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: decimal key test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
+#[test]
+fn test_datetime_match_keys_are_normalised() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The group-by dates are the same instant, expressed with different offsets and precision.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T00:00:00.000Z","100.00","T1"
+"0","0002","2021-12-19T01:00:00+01:00","75.00","T2"
+"0","0003","2021-12-19T00:00:00Z","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: datetime key test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4], [0,5]] ]));
+}
+
+
+#[test]
+fn test_match_keys_can_use_raw_values() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","DE","DE","ST"
+"0","0001","100","100.00","T1"
+"0","0002","100.00","75.00","T2"
+"0","0003","100.00","25.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: raw key test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        normalise_keys: false
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([]));
+}
+
+
+#[test]
+fn test_unmatched_totals_by_currency() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Only the first pair of transactions will match.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Currency","Type"
+"IN","IN","ST","DE","ST","ST"
+"0","0001","A","100.00","GBP","T1"
+"0","0002","A","100.00","GBP","T2"
+"0","0003","B","10.50","GBP","T1"
+"0","0004","C","20.25","GBP","T1"
+"0","0005","D","99.99","USD","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: unmatched totals test
+version: 1
+matching:
+  use_field_prefixes: false
+  unmatched_totals:
+    amount: Amount
+    currency: Currency
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    let matched = common::get_match_job_file(&base_dir);
+    let footer = &common::read_json_file(matched)[2];
+    assert_eq!(footer["unmatched_records"], json!(3));
+    assert_json_eq!(footer["unmatched_totals_by_currency"].clone(), json!({ "GBP": "30.75", "USD": "99.99" }));
+}
+
+
+#[test]
+fn test_unmatched_records_are_aged() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The data file was received three days before the job.
+    common::write_file(&base_dir.join("waiting/"), "20211128_053700000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","B","50.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: ageing test
+version: 1
+matching:
+  use_field_prefixes: false
+  age_unmatched: true
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    common::assert_file_contents(&base_dir.join("unmatched/20211128_053700000_transactions.unmatched.csv"),
+r#""OpenRecStatus","Ref","Amount","Type","OpenRecAge"
+"IN","ST","DE","ST","IN"
+"0","A","100.00","INV","3"
+"0","B","50.00","INV","3"
+"#);
+
+    // Simulate the next job running two days later by back-dating the unmatched file.
+    std::fs::rename(base_dir.join("unmatched/20211128_053700000_transactions.unmatched.csv"),
+        base_dir.join("unmatched/20211126_053700000_transactions.unmatched.csv")).unwrap();
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","PAY"
+"0","C","10.00","INV"
+"#);
+
+    // The age must survive the carried-forward record being modified.
+    common::write_file(&base_dir.join("waiting/"), "20211201_053000000_changeset.json",
+r#"[
+{
+    "id": "0b7a6c9e-60a7-11ec-a5fb-00155ddc3c4d",
+    "change": {
+        "type": "UpdateFields",
+        "updates": [ { "field": "Amount", "value": "60.00" } ],
+        "lua_filter": "record[\"Ref\"] == \"B\""
+    },
+    "timestamp": "2021-12-01T05:30:00.000Z"
+}
+]"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [1,3]] ]));
+
+    common::assert_file_contents(&base_dir.join("unmatched/20211126_053700000_transactions.unmatched.csv"),
+r#""OpenRecStatus","Ref","Amount","Type","OpenRecAge"
+"IN","ST","DE","ST","IN"
+"0","B","60.00","INV","5"
+"#);
+
+    common::assert_file_contents(&base_dir.join("unmatched/20211201_053700000_transactions.unmatched.csv"),
+r#""OpenRecStatus","Ref","Amount","Type","OpenRecAge"
+"IN","ST","DE","ST","IN"
+"0","C","10.00","INV","0"
+"#);
+}
+
+
+#[test]
+fn test_unmatched_csv_quote_style_and_terminator() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","B,C","50.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: quote style test
+version: 1
+matching:
+  use_field_prefixes: false
+  csv_quote_style: necessary
+  csv_line_terminator: "\r\n"
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Numbers are left unquoted, only the value containing a delimiter is quoted.
+    common::assert_file_contents(&base_dir.join("unmatched/20211201_053700000_transactions.unmatched.csv"),
+        "OpenRecStatus,Ref,Amount,Type\r\nIN,ST,DE,ST\r\n0,A,100.00,INV\r\n0,\"B,C\",50.00,INV\r\n");
+}
+
+
+#[test]
+fn test_shadow_charter_reports_group_differences() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The second pair is out by 0.50 so will only match with a tolerance.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","A","100.00","T1"
+"0","0002","A","100.00","T2"
+"0","0003","B","50.00","T1"
+"0","0004","B","50.50","T2"
+"#);
+
+    let charter = r#"name: shadow test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - "#;
+
+    let primary = common::write_file(&base_dir, "charter.yaml", &format!("{}{}", charter,
+r#"nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#));
+
+    // The candidate's source_files match nothing, so any group it matches must come from the primary's loaded data.
+    let candidate = common::write_file(&base_dir, "candidate.yaml", &format!("{}{}", charter.replace(".*.csv", ".*.unused"),
+r#"nets_with_tolerance:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+            tol_type: Amount
+            tolerance: 1.00
+"#));
+
+    celerity::run_shadow(&primary, &candidate, &base_dir).unwrap();
+
+    // Only the primary charter's results are kept and the data was only ingested once.
+    assert!(!base_dir.join("shadow").exists());
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_eq!(common::get_filenames(&base_dir.join("archive")), vec!("20211219_082900000_transactions.csv"));
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    // The candidate's matches were put back before the primary charter matched.
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    assert_eq!(footer["matched_records"], json!(2));
+    assert_eq!(footer["unmatched_records"], json!(2));
+
+    // The diff shows the extra group the candidate would match.
+    let diff = common::read_json_file(base_dir.join("matched/20211201_053700000_shadow_diff.json"));
+    assert_eq!(diff["primary_groups"], json!(1));
+    assert_eq!(diff["candidate_groups"], json!(2));
+    assert_eq!(diff["common_groups"], json!(1));
+    assert_json_eq!(diff["only_primary"].clone(), json!([]));
+    assert_json_eq!(diff["only_candidate"].clone(), json!([
+        [["20211219_082900000_transactions.csv", 5], ["20211219_082900000_transactions.csv", 6]]
+    ]));
+}
+
+
+#[test]
+fn test_group_by_date_ignoring_time() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The records are on the same day, but at different times.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0","0002","2021-12-19T17:45:12.345Z","100.00","T2"
+"0","0003","2021-12-20T00:00:00.000Z","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: date only test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date:date_only']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_date_tolerance_within_days() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Date","Amount","Type"
+"IN","ST","DT","DE","ST"
+"0","INV0001","2021-12-19T08:29:00.000Z","100.00","INV"
+"0","INV0001","2021-12-21T08:29:00.000Z","100.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: date tolerance test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        date_tolerance:
+          column: Date
+          days: 2
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_date_tolerance_exceeded() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Date","Amount","Type"
+"IN","ST","DT","DE","ST"
+"0","INV0001","2021-12-19T08:29:00.000Z","100.00","INV"
+"0","INV0001","2021-12-21T08:29:00.000Z","100.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: date tolerance test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        date_tolerance:
+          column: Date
+          days: 1
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 1);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([]));
+}
+
+
+#[test]
+fn test_disabled_group_is_skipped() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","B","50.00","INV"
+"0","B","50.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: disabled group test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        enabled: false
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV" and record["Ref"] == "B"
+            rhs: record["Type"] == "PAY" and record["Ref"] == "B"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Only the second group instruction should have run.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,5], [0,6]] ]));
+}
+
+
+#[test]
+fn test_disabled_projection_still_referenced() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: disabled projection test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: AbsAmount
+        as_a: Decimal
+        from: math.abs(record["Amount"])
+        enabled: false
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: AbsAmount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    // The group relies on the disabled projection so the job should fail before matching anything.
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("Instruction 1 uses the column AbsAmount but the instruction which derives it is disabled"));
+}
+
+
+#[test]
+fn test_filtered_records_are_neither_matched_or_unmatched() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","A","0.00","ADJ"
+"0","B","50.00","INV"
+"0","C","0.00","ADJ"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: filter test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - filter:
+        lua: record["Type"] == "ADJ"
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The adjustment in group A mustn't stop the group matching.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    let matched = common::get_match_job_file(&base_dir);
+    let footer = &common::read_json_file(matched)[2];
+    assert_eq!(footer["filtered_records"], json!(2));
+    assert_eq!(footer["unmatched_records"], json!(1));
+
+    // Only the unmatched invoice should be in the unmatched file, not the adjustments.
+    common::assert_file_contents(&base_dir.join("unmatched/20211219_082900000_transactions.unmatched.csv"),
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","B","50.00","INV"
+"#);
+}
+
+
+#[test]
+fn test_second_group_stage_skips_records_matched_by_the_first() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","B","100.00","PAY"
+"0","C","100.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: staged test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+    - group:
+        by: ['Amount']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // All four records share an amount, but the second stage must only group the two the first stage left behind.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]], [[0,5], [0,6]] ]));
+
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    assert_eq!(footer["unmatched_records"], json!(0));
+    assert_eq!(footer["stats"]["records"], json!(4));
+}
+
+
+#[test]
+fn test_distinct_removes_duplicate_rows() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    let invoices = r#""OpenRecStatus","TransId","Ref","Amount"
+"IN","ST","ST","DE"
+"0","T1","A","100.00"
+"0","T2","B","50.00"
+"#;
+
+    // The invoice file has been delivered twice.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv", invoices);
+    common::write_file(&base_dir.join("waiting/"), "20211219_083000000_invoices.csv", invoices);
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv",
+r#""OpenRecStatus","TransId","Ref","Amount"
+"IN","ST","ST","DE"
+"0","P1","A","100.00"
+"0","P2","B","50.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: distinct test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - merge:
+        columns: ['INV.TransId', 'PAY.TransId']
+        into: TRANS_ID
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref']
+        into: REF
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount']
+        into: AMOUNT
+    - distinct:
+        by: ['TRANS_ID']
+        write_duplicates: true
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+"#);
+
+    // Run the match.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Only the first invoice file's records should have been matched, nothing is left unmatched.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [2,3]], [[0,4], [2,4]] ]));
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+
+    let matched = common::get_match_job_file(&base_dir);
+    let footer = &common::read_json_file(matched)[2];
+    assert_eq!(footer["duplicate_records"], json!(2));
+
+    // The duplicates from the second delivery are kept for audit.
+    common::assert_file_contents(&base_dir.join("duplicates/20211201_053700000_invoices.duplicates.csv"),
+r#""OpenRecStatus","TransId","Ref","Amount"
+"IN","ST","ST","DE"
+"0","T1","A","100.00"
+"0","T2","B","50.00"
+"#);
+}
+
+
+#[test]
+fn test_max_group_size_exceeded() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Every record has the same currency, so grouping by it gives a single group.
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Currency","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","GBP","100.00","T1"
+"0","0002","GBP","100.00","T2"
+"0","0003","GBP","50.00","T1"
+"0","0004","GBP","50.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: max group size test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Currency']
+        max_group_size: 2
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("A group with the key [GBP] has more than the max_group_size of 2 records"));
+}
+
+
+#[test]
+fn test_run_charter_loaded_from_string() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"#);
+
+    let charter = r#"name: in memory test
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#.parse().unwrap();
+
+    celerity::run_loaded_charter(charter, "charters/in-memory", &base_dir).unwrap();
+
+    let job = common::read_json_file(common::get_match_job_file(&base_dir));
+    assert_eq!(job[0]["charter"]["file"], json!("charters/in-memory"));
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+#[test]
+fn test_run_charter_twice_in_one_process() {
+
+    // Each job builds its own thread pool to derive data, so running charters back-to-back mustn't panic.
+    for run in 1..=2 {
+        let base_dir = common::init_test(format!("tests/{}_{}", function!(), run));
+
+        common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"#);
+
+        common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"#);
+
+        let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: twice test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref']
+        into: REF
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount']
+        into: AMOUNT
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+"#);
+
+        celerity::run_charter(&charter, &base_dir).unwrap();
+        assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [1,3]] ]));
+    }
+}
+
+
+#[test]
+fn test_serial_derive_same_as_parallel() {
+
+    // Derive the same files in parallel and then serially, both must produce the same derived data.
+    let mut outputs = vec!();
+
+    for derive_threads in ["", "derive_threads: 1"] {
+        let base_dir = common::init_test(format!("tests/{}_{}", function!(), outputs.len()));
+
+        for (file, amount) in [("invoices", "100.00"), ("payments", "-40.00"), ("receipts", "-60.00")] {
+            common::write_file(&base_dir.join("waiting/"), &format!("20211219_082900000_{}.csv", file), &format!(
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","{amount}"
+"0","B","{amount}"
+"#, amount = amount));
+        }
+
+        let charter = common::write_file(&base_dir, "charter.yaml", &format!(
+r#"name: derive threads test
+version: 1
+debug: true
+{derive_threads}
+global_lua: |
+    function label(prefix, ref)
+        return prefix .. "-" .. ref
+    end
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+    - pattern: .*receipts.csv
+      field_prefix: REC
+  instructions:
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref', 'REC.Ref']
+        into: REF
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount', 'REC.Amount']
+        into: AMOUNT
+    - project:
+        column: LABEL
+        as_a: String
+        from: return label(record["META.prefix"], record["REF"])
+    - project:
+        column: DOUBLED
+        as_a: Decimal
+        from: return record["AMOUNT"] + record["AMOUNT"]
+"#, derive_threads = derive_threads));
+
+        celerity::run_charter(&charter, &base_dir).unwrap();
+
+        let mut debug = get_dir_content(base_dir.join("debug")).unwrap().files;
+        debug.sort();
+        outputs.push(debug.iter().map(|file| std::fs::read_to_string(file).unwrap()).collect::<Vec<String>>());
+    }
+
+    assert!(outputs[0].iter().any(|debug| debug.contains("INV-A")));
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn test_debug_grid_json_has_typed_fields() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount","Qty","Paid","Date"
+"IN","ST","DE","IN","BO","DT"
+"0","A","100.50","2","1","2021-12-19T08:29:00.000Z"
+"0","B","20.00","","0","2021-12-19T08:29:00.000Z"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: debug json test
+version: 1
+debug: true
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+  instructions:
+    - project:
+        column: DOUBLED
+        as_a: Decimal
+        from: return record["INV.Amount"] + record["INV.Amount"]
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The last snapshot has the projected column.
+    let filename = common::get_filenames(&base_dir.join("debug")).into_iter()
+        .rfind(|filename| filename.ends_with(".debug.json"))
+        .expect("no debug json");
+
+    let snapshot = common::read_json_file(base_dir.join("debug").join(filename));
+
+    assert_eq!(snapshot["files"], json!([{ "file_idx": 0, "filename": "20211219_082900000_invoices.csv", "prefix": "INV" }]));
+    assert!(snapshot["columns"].as_array().unwrap().contains(&json!({ "header": "INV.Qty", "prefix": "INV", "data_type": "Integer" })));
+    assert!(snapshot["columns"].as_array().unwrap().contains(&json!({ "header": "DOUBLED", "prefix": null, "data_type": "Decimal" })));
+
+    let rows = snapshot["rows"].as_array().unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["file_idx"], 0);
+    assert_eq!(rows[0]["fields"]["INV.Ref"], "A");
+    assert_eq!(rows[0]["fields"]["INV.Amount"], "100.50");
+    assert_eq!(rows[0]["fields"]["INV.Qty"], 2);
+    assert_eq!(rows[0]["fields"]["INV.Paid"], true);
+    assert_eq!(rows[0]["fields"]["INV.Date"], "2021-12-19T08:29:00.000Z");
+    assert_eq!(rows[0]["fields"]["DOUBLED"], "201.00");
+    assert_eq!(rows[1]["fields"]["INV.Qty"], json!(null));
+    assert_eq!(rows[1]["fields"]["INV.Paid"], false);
+}
+
+#[test]
+fn test_parallel_constraints_same_as_serial() {
+
+    // Evaluate the same groups serially and then with several threads, both must write exactly the same output.
+    let mut outputs = vec!();
+
+    for match_threads in [1, 4] {
+        let base_dir = common::init_test(format!("tests/{}_{}", function!(), match_threads));
+
+        // Every third group doesn't net to zero. The padding takes the groups over the minimum memory_limit, so they're
+        // evaluated in more than one batch.
+        let padding = "X".repeat(8000);
+        let rows = (1..=1500)
+            .map(|idx| format!("\"0\",\"G{:04}\",\"{}.00\",\"INV\",\"{}\"\n\"0\",\"G{:04}\",\"-{}.00\",\"PAY\",\"{}\"\n",
+                idx, idx, padding, idx, if idx % 3 == 0 { idx + 1 } else { idx }, padding))
+            .collect::<String>();
+
+        common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+            &format!("\"OpenRecStatus\",\"Ref\",\"Amount\",\"Type\",\"Padding\"\n\"IN\",\"ST\",\"DE\",\"ST\",\"ST\"\n{}", rows));
+
+        let charter = common::write_file(&base_dir, "charter.yaml", &format!(
+r#"name: match threads test
+version: 1
+match_threads: {match_threads}
+memory_limit: 16777216
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - custom:
+            script: |
+                local total = decimal(0)
+                for _, record in ipairs(records) do
+                    total = total + record["Amount"]
+                end
+                return total == decimal(0)
+            available_fields: ['Amount']
+"#, match_threads = match_threads));
+
+        celerity::run_charter(&charter, &base_dir).unwrap();
+
+        // The matched job file's header and footer have the charter's path and the job's duration, so only the groups
+        // are compared.
+        let matched = std::fs::read_to_string(common::get_match_job_file(&base_dir)).unwrap();
+        let groups = matched[matched.find("\"groups\"").unwrap()..matched.find("\"changesets\"").unwrap()].to_string();
+
+        let unmatched = get_dir_content(base_dir.join("unmatched")).unwrap().files;
+        assert_eq!(unmatched.len(), 1);
+        outputs.push((groups, std::fs::read(&unmatched[0]).unwrap()));
+    }
+
+    assert_eq!(outputs[0].0.lines().filter(|line| line.contains("[[0,")).count(), 1000);
+    assert_eq!(outputs[0].1.iter().filter(|byte| **byte == b'\n').count(), 2 + 1000);
+    assert!(outputs[0] == outputs[1], "output differs from the serial run");
+}
+
+#[test]
+fn test_streamed_unmatched_same_as_buffered() {
+
+    // When the final instruction is a group, unmatched records are written as each group is evaluated. Adding a
+    // trailing no-op filter forces the old behaviour of writing them all at the end - both should agree.
+    let streamed = run_large_unmatched(&format!("tests/{}_streamed", function!()), "");
+    let buffered = run_large_unmatched(&format!("tests/{}_buffered", function!()), r#"
+    - filter:
+        lua: "false""#);
+
+    assert_eq!(streamed.0.iter().filter(|byte| **byte == b'\n').count(), 2002); // Header, schema and 2,000 unmatched rows.
+    assert!(streamed.0 == buffered.0, "streamed unmatched file differs from the buffered file");
+    assert_json_eq!(streamed.1.clone(), buffered.1);
+    assert_json_eq!(streamed.1, json!([
+        {
+            "file": "20211219_082900000_transactions.unmatched.csv",
+            "rows": 2000
+        }
+    ]));
+}
+
+///
+/// Run a charter over 3,000 transactions where a third of them match. Returns the contents of the unmatched file and
+/// the unmatched section of the job footer.
+///
+fn run_large_unmatched(folder: &str, trailing_instructions: &str) -> (Vec<u8>, serde_json::Value) {
+
+    let base_dir = common::init_test(folder);
+
+    let mut data = String::from("\"OpenRecStatus\",\"TransId\",\"Ref\",\"Amount\",\"Type\"\n\"IN\",\"IN\",\"ST\",\"DE\",\"ST\"\n");
+    for idx in 0..1500 {
+        // Every third pair nets to zero, the others don't.
+        let payment = if idx % 3 == 0 { "100.00" } else { "99.00" };
+        data.push_str(&format!("\"0\",\"{}\",\"R{:04}\",\"100.00\",\"T1\"\n", idx * 2, idx));
+        data.push_str(&format!("\"0\",\"{}\",\"R{:04}\",\"{}\",\"T2\"\n", idx * 2 + 1, idx, payment));
+    }
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv", &data);
+
+    let charter = common::write_file(&base_dir, "charter.yaml", &format!(
+r#"name: streamed unmatched test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"{}
+"#, trailing_instructions));
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    let unmatched = std::fs::read(base_dir.join("unmatched/20211219_082900000_transactions.unmatched.csv")).unwrap();
+    let footer = common::read_json_file(common::get_match_job_file(&base_dir))[2]["unmatched"].clone();
+    (unmatched, footer)
+}
+
+
+#[test]
+fn test_streamed_unmatched_keeps_file_order() {
+
+    // The refs descend through each file, so groups are evaluated in the reverse of the files' order.
+    let streamed = run_reversed_unmatched(&format!("tests/{}_streamed", function!()), "");
+    let buffered = run_reversed_unmatched(&format!("tests/{}_buffered", function!()), r#"
+    - filter:
+        lua: "false""#);
+
+    assert_eq!(streamed.len(), 2);
+    assert_eq!(streamed, buffered);
+
+    // Only the unmatched rows remain, in their original order.
+    let ids: Vec<usize> = streamed[0].lines()
+        .skip(2)
+        .map(|line| line.split(',').nth(1).unwrap().trim_matches('"').parse().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 1000);
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "invoices out of order");
+}
+
+///
+/// Match 1,500 invoices against 1,500 payments in a separate file, where a third of them match. Returns the contents of
+/// the unmatched invoice and payment files.
+///
+fn run_reversed_unmatched(folder: &str, trailing_instructions: &str) -> Vec<String> {
+
+    let base_dir = common::init_test(folder);
+
+    let mut invoices = String::from("\"OpenRecStatus\",\"InvId\",\"Ref\",\"Amount\"\n\"IN\",\"IN\",\"ST\",\"DE\"\n");
+    let mut payments = String::from("\"OpenRecStatus\",\"PayId\",\"Ref\",\"Amount\"\n\"IN\",\"IN\",\"ST\",\"DE\"\n");
+    for idx in 0..1500 {
+        let payment = if idx % 3 == 0 { "100.00" } else { "99.00" };
+        invoices.push_str(&format!("\"0\",\"{}\",\"R{:04}\",\"100.00\"\n", idx, 1500 - idx));
+        payments.push_str(&format!("\"0\",\"{}\",\"R{:04}\",\"{}\"\n", idx, 1500 - idx, payment));
+    }
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv", &invoices);
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv", &payments);
+
+    let charter = common::write_file(&base_dir, "charter.yaml", &format!(
+r#"name: reversed unmatched test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - merge:
+        into: REF
+        columns: ['INV.Ref', 'PAY.Ref']
+    - merge:
+        into: AMOUNT
+        columns: ['INV.Amount', 'PAY.Amount']
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"{}
+"#, trailing_instructions));
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    ["invoices", "payments"].iter()
+        .map(|file| std::fs::read_to_string(base_dir.join(format!("unmatched/20211219_082900000_{}.unmatched.csv", file))).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_regex_column_mapping() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Amounts with thousand separators and currency symbols wouldn't otherwise be decimals.
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Amount"
+"0001","1,234.50"
+"0002","£20.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: regex mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - regex:
+            column: Amount
+            pattern: '[^0-9.\-]'
+            replace: ''
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+
+    assert_eq!(lines[0], r#""OpenRecStatus","OpenRecId","TransId","Amount""#);
+    assert_eq!(lines[1], r#""IN","ID","IN","DE""#);
+    assert!(lines[2].ends_with(r#","0001","1234.50""#));
+    assert!(lines[3].ends_with(r#","0002","20.00""#));
+}
+
+
+#[test]
+fn test_trim_all_fields() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Ref","Amount"
+" 0001 ","  ABC ","  100.00"
+"0002","DEF  ","20.00 "
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: trim all test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       trim_all: true
+       column_mappings:
+        - map:
+            column: Ref
+            as_a: String
+            from: value:lower() .. ' '
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+
+    // Padded values are analysed as their trimmed types. The mapping is given the trimmed value and its result is kept.
+    assert_eq!(lines[1], r#""IN","ID","IN","ST","DE""#);
+    assert!(lines[2].ends_with(r#","0001","abc ","100.00""#));
+    assert!(lines[3].ends_with(r#","0002","def ","20.00""#));
+}
+
+
+#[test]
+fn test_split_column_mapping() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "customers.csv",
+r#""CustomerId","Name"
+"0001","Smith/John"
+"0002","Madonna"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: split mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^customers\.csv$
+       column_mappings:
+        - split:
+            column: Name
+            delimiter: /
+            into: ['Last', 'First']
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_customers.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+
+    // Missing segments are left empty.
+    assert_eq!(lines[0], r#""OpenRecStatus","OpenRecId","CustomerId","Name","Last","First""#);
+    assert_eq!(lines[1], r#""IN","ID","IN","ST","ST","ST""#);
+    assert!(lines[2].ends_with(r#","0001","Smith/John","Smith","John""#));
+    assert!(lines[3].ends_with(r#","0002","Madonna","Madonna","""#));
+}
+
+
+#[test]
+fn test_hash_column_mapping() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "accounts.csv",
+r#""AccountNumber","SortCode"
+"12345678","87654321"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: hash mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^accounts\.csv$
+       column_mappings:
+        - hash:
+            column: AccountNumber
+            algorithm: sha256
+        - hash:
+            column: SortCode
+            algorithm: md5
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_accounts.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+
+    assert_eq!(lines[1], r#""IN","ID","ST","ST""#);
+    assert!(lines[2].ends_with(r#","ef797c8118f02dfb649607dd5d3f8c7623048c9c063d532cc95c5ed7a898a64f","5e8667a439c68f5145dd2fcbecf02209""#));
+    assert!(!waiting.contains("12345678"));
+    assert!(!waiting.contains("87654321"));
+}
+
+
+#[test]
+fn test_non_csv_inbox_file_washed_to_csv() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "feed.txt",
+r#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: txt extension test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^feed\.txt$
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*feed.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+    assert!(base_dir.join("waiting/20211201_053700000_feed.csv").exists());
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_gzipped_inbox_file() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Compress the data into the inbox.
+    let file = std::fs::File::create(base_dir.join("inbox/feed.csv.gz")).unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    encoder.write_all(
+br#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#).unwrap();
+    encoder.finish().unwrap();
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: gzip test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^feed\.csv\.gz$
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*feed.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    // The compressed original is archived and the waiting file is plain csv.
+    assert_eq!(common::get_filenames(&base_dir.join("archive/jetwash")), vec!("20211201_053700000_feed.csv.gz"));
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_feed.csv")).unwrap();
+    assert!(waiting.starts_with(r#""OpenRecStatus","OpenRecId","TransId","Date","Amount","Type""#));
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+}
+
+
+#[test]
+fn test_archived_files_are_compressed() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    let original = r#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#;
+    common::write_file(&base_dir.join("inbox/"), "feed.csv", original);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: archive compress test
+version: 1
+archive_compress: true
+jetwash:
+    source_files:
+     - pattern: ^feed\.csv$
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*feed.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    // The original is gzipped into the jetwash archive.
+    assert_eq!(common::get_filenames(&base_dir.join("archive/jetwash")), vec!("20211201_053700000_feed.csv.gz"));
+    assert_eq!(gunzip(&base_dir.join("archive/jetwash/20211201_053700000_feed.csv.gz")), original);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    // The matched data file is gzipped into the celerity archive.
+    assert_eq!(common::get_filenames(&base_dir.join("archive/celerity")), vec!("20211201_053700000_feed.csv.gz"));
+    let archived = gunzip(&base_dir.join("archive/celerity/20211201_053700000_feed.csv.gz"));
+    assert!(archived.starts_with(r#""OpenRecStatus","OpenRecId","TransId","Date","Amount","Type""#));
+    assert_eq!(archived.lines().count(), 4);
+}
+
+fn gunzip(path: &std::path::Path) -> String {
+    let mut contents = String::new();
+    flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap()).read_to_string(&mut contents).unwrap();
+    contents
+}
+
+#[test]
+fn test_expired_matched_files_are_removed() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // The job is timestamped 20211201_053700000, so files from September have expired and November's haven't.
+    let matched = base_dir.join("matched/");
+    std::fs::create_dir_all(&matched).unwrap();
+    common::write_file(&matched, "20210901_000000000_matched.json", "[]");
+    common::write_file(&matched, "20210901_000000000_matched.csv", "");
+    common::write_file(&matched, "20211125_000000000_matched.json", "[]");
+
+    common::write_file(&base_dir.join("waiting/"), "20211201_053700000_transactions.csv",
+r#""OpenRecStatus","TransId","Date","Amount","Type"
+"IN","IN","DT","DE","ST"
+"0","0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0","0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: matched retention test
+version: 1
+matching:
+  use_field_prefixes: false
+  matched_retention_days: 30
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(common::get_filenames(&matched), vec!(
+        "20211125_000000000_matched.json",
+        "20211201_053700000_matched.json"));
+}
+
+#[test]
+fn test_latin1_inbox_file_transcoded_to_utf8() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // £ is 0xA3 and € is 0x80 in windows-1252, neither are valid UTF-8 bytes.
+    std::fs::write(base_dir.join("inbox/feed.csv"),
+        b"\"TransId\",\"Description\"\n\"0001\",\"\xA3100 fee\"\n\"0002\",\"\x80200 fee\"\n").unwrap();
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: encoding test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^feed\.csv$
+       encoding: latin1
+       strict_encoding: true
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions: []"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_feed.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+
+    assert!(lines[2].ends_with(r#","0001","£100 fee""#));
+    assert!(lines[3].ends_with(r#","0002","€200 fee""#));
+}
+
+
+#[test]
+fn test_skip_header_and_footer_lines() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "report.csv",
+r#"DAILY TRANSACTION REPORT
+Generated 2021-12-19 08:29
+"TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"TOTAL","200.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: skip lines test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^report\.csv$
+       skip_header_lines: 2
+       skip_footer_lines: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*report.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    // The banner and totals lines don't effect the analysed types.
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_report.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[1], r#""IN","ID","IN","DT","DE","ST""#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+}
+
+
+#[test]
+fn test_archiving_disabled() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: no archive test
+version: 1
+archive_files: false
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The originals are deleted rather than archived.
+    common::assert_files_in_folders(&base_dir, vec!(
+        (0, "inbox"),
+        (0, "waiting"),
+        (0, "matching"),
+        (0, "archive/jetwash"),
+        (0, "archive/celerity"),
+        (0, "unmatched"),
+        (1, "matched")));
+}
+
+#[test]
+fn test_locked_base_dir_refuses_jobs() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","2021-12-19T08:29:00.000Z","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: lock test
+version: 1
+stale_lock:
+  check_pid: true
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    // Simulate a job already running against the folder - this process is alive so the lock isn't stale.
+    let lock = common::write_file(&base_dir, ".lock", &format!("{}\n", std::process::id()));
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    assert!(err.to_string().contains("locked by another job"), "{}", err);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(err.to_string().contains("locked by another job"), "{}", err);
+
+    // Nothing should have been touched and the other job's lock remains.
+    assert!(lock.exists());
+    common::assert_files_in_folders(&base_dir, vec!((1, "inbox"), (0, "waiting")));
+    assert!(!base_dir.join("matched").exists());
+
+    // Once released, both jobs run and remove their own locks.
+    std::fs::remove_file(&lock).unwrap();
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert!(!lock.exists());
+    common::assert_files_in_folders(&base_dir, vec!((0, "inbox"), (1, "matched")));
+}
+
+#[test]
+fn test_stale_lock_is_replaced() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: stale lock test
+version: 1
+stale_lock:
+  max_age: 1s
+matching:
+  source_files:
+   - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Date']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2""#);
+
+    let lock = common::write_file(&base_dir, ".lock", "1\n");
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert!(!lock.exists());
+}
+
+
+#[test]
+fn test_job_stats_are_recorded() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"0","B","50.00"
+"0","C","25.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"0","B","50.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: stats test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref']
+        into: Ref
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount']
+        into: Amount
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    let footer = &common::read_json_file(common::get_match_job_file(&base_dir))[2];
+    let stats = &footer["stats"];
+    assert_eq!(stats["records"], json!(5));
+    assert_eq!(stats["bytes"], footer["data_size_bytes"]);
+    assert!(stats["rate"].as_str().unwrap().ends_with("ms"));
+    assert_eq!(stats["files"], json!([
+        { "file": "20211219_082900000_invoices.csv", "rows": 3 },
+        { "file": "20211219_082900000_payments.csv", "rows": 2 }
+    ]));
+}
+
+
+#[test]
+fn test_job_status_names_failing_phase() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Counterparty"
+"IN","IN","ST"
+"0","0001","AC"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: job status test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: CounterpartyName
+        as_a: String
+        from: |
+            return lookup("Name", "counterparties.csv", "Code", record["Counterparty"])
+"#);
+
+    // The lookup file is missing so the job fails deriving the projected column.
+    assert!(celerity::run_charter(&charter, &base_dir).is_err());
+
+    let status = common::read_json_file(base_dir.join("job.status"));
+    assert_eq!(status["job_id"], json!(FIXED_JOB_ID));
+    assert_eq!(status["phase"], json!("DeriveData"));
+    assert_eq!(status["ordinal"], json!(4));
+
+    // Once a job completes, the status file is removed.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: job status test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+    assert!(!base_dir.join("job.status").exists());
+}
+
+#[test]
+fn test_cancelled_job_stops_cleanly() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    let rows = (1..=200).map(|idx| format!("\"0\",\"R{}\",\"10.00\"\n", idx)).collect::<String>();
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+        &format!("\"OpenRecStatus\",\"Ref\",\"Amount\"\n\"IN\",\"ST\",\"DE\"\n{}", rows));
+
+    // A deliberately slow projection so the job is still deriving data when it's cancelled.
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: cancel test
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Slow
+        as_a: String
+        from: |
+            local total = 0
+            for i = 1, 200000 do total = total + i end
+            return record["Ref"]
+    - group:
+        by: ['Slow']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Amount"] > decimal(0)
+            rhs: record["Amount"] < decimal(0)
+"#);
+
+    // Cancel the job from another thread as soon as the job's status file shows it deriving data.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let cancel = cancel.clone();
+        let status = base_dir.join("job.status");
+        std::thread::spawn(move || {
+            for _attempt in 0..10000 {
+                if std::fs::read_to_string(&status).map(|status| status.contains("DeriveData")).unwrap_or(false) {
+                    cancel.store(true, Ordering::Relaxed);
+                    return
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        })
+    };
+
+    let err = celerity::run_charter_cancellable(&charter, &base_dir, cancel).unwrap_err();
+    watcher.join().unwrap();
+    assert!(format!("{:?}", err).contains("The match job was cancelled during the DeriveData phase"));
+
+    // No results were written and only the original data file is left to be picked-up by the next job.
+    assert_eq!(get_dir_content(base_dir.join("matched")).unwrap().files.len(), 0);
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    let matching = get_dir_content(base_dir.join("matching")).unwrap().files;
+    assert_eq!(matching.len(), 1);
+    assert!(matching[0].ends_with("20211219_082900000_transactions.csv"));
+}
+
+#[test]
+fn test_matched_groups_written_to_csv() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","B","50.00","INV"
+"0","B","20.00","PAY"
+"0","B","30.00","PAY"
+"0","C","10.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: csv report test
+version: 1
+matching:
+  matched_csv: true
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    let groups = common::get_matched_groups(&base_dir);
+    let coordinates = groups.as_array().unwrap().iter().map(|group| group.as_array().unwrap().len()).sum::<usize>();
+    assert_eq!(coordinates, 5);
+
+    let csv = std::fs::read_to_string(common::get_match_job_file(&base_dir).with_extension("csv")).unwrap();
+    let lines = csv.lines().collect::<Vec<&str>>();
+    assert_eq!(lines[0], r#""job_id","group_id","file_index","row""#);
+    assert_eq!(lines.len() - 1, coordinates);
+    assert_eq!(lines[1], format!(r#""{}","0","0","3""#, FIXED_JOB_ID));
+    assert!(lines[3..].iter().all(|line| line.starts_with(&format!(r#""{}","1","0","#, FIXED_JOB_ID))));
+}
+
+
+#[test]
+fn test_matched_groups_written_to_sqlite() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"0","B","50.00","INV"
+"0","B","20.00","PAY"
+"0","B","30.00","PAY"
+"0","C","10.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: sqlite report test
+version: 1
+matching:
+  matched_sqlite: true
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    let conn = rusqlite::Connection::open(base_dir.join("results.db")).unwrap();
+    let count = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+
+    assert_eq!(count("SELECT COUNT(*) FROM jobs"), 1);
+    assert_eq!(count("SELECT matched_groups FROM jobs"), 2);
+    assert_eq!(count("SELECT COUNT(DISTINCT group_id) FROM matched"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM matched"), 5);
+    assert_eq!(count("SELECT COUNT(*) FROM unmatched"), 1);
+
+    let job_id: String = conn.query_row("SELECT job_id FROM jobs", [], |row| row.get(0)).unwrap();
+    assert_eq!(job_id, FIXED_JOB_ID);
+}
+
+#[test]
+fn test_sort_orders_group_members() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","129.00","INV"
+"0","A","100.00","PAY"
+"0","A","9.00","PAY"
+"0","A","20.00","PAY"
+"0","B","5.00","INV"
+"0","B","5.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: sort test
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - sort:
+        by: ['Amount', 'Type']
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Amounts are compared as decimals (not strings) and equal amounts keep sorting by the next column.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([
+        [[0,5], [0,6], [0,4], [0,3]],
+        [[0,7], [0,8]]
+    ]));
+}
+
+
+#[test]
+fn test_constant_projection() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: constant test
+version: 1
+debug: true
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Feed
+        as_a: String
+        constant: bank
+    - group:
+        by: ['Ref']
+        match_when:
+        - count_in_range:
+            filter: record["Feed"] == "bank"
+            min: 2
+            max: 2
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // The group only matches if both records have the constant value.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    // The derived values are visible in the debug output of the grid.
+    let debug = common::get_filenames(&base_dir.join("debug")).into_iter()
+        .map(|filename| std::fs::read_to_string(base_dir.join("debug").join(filename)).unwrap())
+        .find(|content| content.contains(r#""bank""#))
+        .expect("no debug grid with the projected column");
+
+    assert_eq!(debug, r#""Feed","OpenRecStatus","Ref","Amount","Type"
+"bank","0","A","100.00","INV"
+"bank","0","A","100.00","PAY"
+"#);
+}
+
+
+#[test]
+fn test_keep_derived_moves_derived_files_to_debug() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","100.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: keep derived test
+version: 1
+keep_derived: true
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Feed
+        as_a: String
+        constant: bank
+    - group:
+        by: ['Ref']
+        match_when:
+        - count_in_range:
+            filter: record["Feed"] == "bank"
+            min: 2
+            max: 2
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    // Only the derived file is in the debug folder, the grid isn't dumped without the debug flag.
+    assert_eq!(common::get_filenames(&base_dir.join("debug")), vec!("20211219_082900000_transactions.derived.csv"));
+    assert_eq!(common::get_filenames(&base_dir.join("matching")), Vec::<String>::new());
+    assert_eq!(std::fs::read_to_string(base_dir.join("debug/20211219_082900000_transactions.derived.csv")).unwrap(),
+r#""Feed"
+"ST"
+"bank"
+"bank"
+"#);
+}
+
+#[test]
+fn test_unmatched_by_instruction_splits_unmatched_files() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Date","Amount","Type"
+"IN","ST","DT","DE","ST"
+"0","A","2021-12-19T08:29:00.000Z","100.00","INV"
+"0","A","2021-12-19T08:29:00.000Z","100.00","PAY"
+"0","B","2021-12-19T08:29:00.000Z","50.00","INV"
+"0","C","","70.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: unmatched by instruction test
+version: 1
+matching:
+  use_field_prefixes: false
+  unmatched_by_instruction: true
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+    - group:
+        by: ['Amount']
+        date_tolerance:
+          column: Date
+          days: 1
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "INV"
+            rhs: record["Type"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [0,4]] ]));
+
+    // The undated record can't be evaluated by the second group, so it's attributed to the first.
+    let assert_unmatched = |base_dir: &PathBuf| {
+        assert!(!base_dir.join("unmatched/20211219_082900000_transactions.unmatched.csv").exists());
+        assert_eq!(std::fs::read_to_string(base_dir.join("unmatched/0/20211219_082900000_transactions.unmatched.csv")).unwrap(),
+r#""OpenRecStatus","Ref","Date","Amount","Type"
+"IN","ST","DT","DE","ST"
+"0","C","","70.00","INV"
+"#);
+        assert_eq!(std::fs::read_to_string(base_dir.join("unmatched/1/20211219_082900000_transactions.unmatched.csv")).unwrap(),
+r#""OpenRecStatus","Ref","Date","Amount","Type"
+"IN","ST","DT","DE","ST"
+"0","B","2021-12-19T08:29:00.000Z","50.00","INV"
+"#);
+    };
+
+    assert_unmatched(&base_dir);
+
+    // The split files are merged back together and re-matched by the next job.
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_unmatched(&base_dir);
+    assert_eq!(common::get_filenames(&base_dir.join("matching")), Vec::<String>::new());
+}
+
+#[test]
+fn test_glob_source_file_pattern() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    for name in ["01-invoices.csv", "11-invoices.csv"] {
+        common::write_file(&base_dir.join("waiting/"), &format!("20211219_082900000_{}", name),
+r#""OpenRecStatus","Ref","Type"
+"IN","ST","ST"
+"0","A","INV"
+"#);
+    }
+
+    let write_charter = |pattern: &str| common::write_file(&base_dir, "charter.yaml", &format!(
+r#"name: glob test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: {pattern}
+      glob: true
+  instructions:
+    - group:
+        by: ['Ref']
+        match_when:
+        - count_in_range:
+            filter: record["Type"] == "INV"
+            min: 1
+            max: 1
+"#, pattern = pattern));
+
+    // As a regex, 0?-invoices.csv would match both files.
+    celerity::run_charter(&write_charter("0?-invoices.csv"), &base_dir).unwrap();
+
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3]] ]));
+
+    let err = celerity::run_charter(&write_charter("'[0-invoices.csv'"), &base_dir).unwrap_err();
+    assert_eq!(err.to_string(), "The source file glob [0-invoices.csv is invalid: a [ character class isn't closed with ]");
+}
+
+#[test]
+fn test_constant_projection_must_match_type() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: constant test
+version: 1
+matching:
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Batch
+        as_a: Integer
+        constant: ten
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("The constant ten projected into column Batch is not a valid IN"), "{:?}", err);
+}
+
+#[test]
+fn test_projection_builds_on_earlier_projection() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"0","A","50.00","PAY"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: chained projection test
+version: 1
+debug: true
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Net
+        as_a: Decimal
+        from: record["Amount"]
+    - project:
+        column: Label
+        as_a: String
+        from: |
+          record["Net"] = record["Net"] + record["Net"]
+          return record["Type"] .. " " .. tostring(record["Net"])
+    - project:
+        column: Doubled
+        as_a: Decimal
+        from: record["Net"]
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Label reads the earlier projection's value after assigning to it, and later projections see the assigned value.
+    let debug = common::get_filenames(&base_dir.join("debug")).into_iter()
+        .map(|filename| std::fs::read_to_string(base_dir.join("debug").join(filename)).unwrap())
+        .find(|content| content.contains(r#""INV 200.00""#))
+        .expect("no debug grid with the projected columns");
+
+    assert_eq!(debug, r#""Net","Label","Doubled","OpenRecStatus","Ref","Amount","Type"
+"200.00","INV 200.00","200.00","0","A","100.00","INV"
+"100.00","PAY 100.00","100.00","0","A","50.00","PAY"
+"#);
+}
+
+#[test]
+fn test_projection_cannot_assign_to_sourced_column() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","Ref","Amount","Type"
+"IN","ST","DE","ST"
+"0","A","100.00","INV"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: chained projection test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: Label
+        as_a: String
+        from: |
+          record["Amount"] = record["Amount"] + record["Amount"]
+          return record["Type"]
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("The projection of column Label assigns to Amount which isn't derived by an earlier projection or merge"), "{:?}", err);
+}
+
+#[test]
+fn test_merge_coerces_integer_into_decimal() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.50"
+"0","B","20.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","IN"
+"0","A","100"
+"0","B","20"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: coerce test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - merge:
+        columns: ['INV.Amount', 'PAY.Amount']
+        into: AMOUNT
+        coerce_to: Decimal
+    - merge:
+        columns: ['INV.Ref', 'PAY.Ref']
+        into: REF
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    // Only B nets to zero once the integer payment is merged as a decimal.
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,4], [1,4]] ]));
+
+    // Coercing a string into a decimal is still rejected.
+    common::write_file(&base_dir.join("waiting/"), "20211220_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","C","10.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: coerce test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+  instructions:
+    - merge:
+        columns: ['INV.Amount', 'INV.Ref']
+        into: AMOUNT
+        coerce_to: Decimal
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("The source column INV.Ref has type String which can't be coerced to Decimal"), "{:?}", err);
+}
+
+
+#[test]
+fn test_short_row_fails_inbox_file() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Date","Amount","Type"
+"0001","2021-12-19T08:29:00.000Z","100.00","T1"
+"0002","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: ragged test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    assert!(err.to_string().contains("errors in inbox files during data analysis"), "{}", err);
+
+    // Rather than shifting the row's fields into the wrong columns, nothing is washed.
+    assert_eq!(common::get_filenames(&base_dir.join("inbox")), vec!("transactions.csv.failed"));
+    assert!(!base_dir.join("waiting/20211201_053700000_transactions.csv").exists());
+}
+
+#[test]
+fn test_ragged_tolerance_pads_short_rows() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Amount","Type","Notes"
+"0001","100.00","T1","late"
+"0002","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: ragged test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       ragged_tolerance: 1
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert!(lines[3].ends_with(r#","0002","100.00","T2","""#), "{}", waiting);
+}
+
+
+#[test]
+fn test_boolean_mapping_groups_on_mapped_tokens() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Settled","Amount","Type"
+"0001","Y","100.00","T1"
+"0002","N","50.00","T1"
+"0003","Y","60.00","T2"
+"0004","N","50.00","T2"
+"0005","Y","40.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: boolean mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - boolean:
+            column: Settled
+            true_values: ['Y']
+            false_values: ['N']
+matching:
+  use_field_prefixes: false
+  source_files:
+   - pattern: .*transactions.csv
+  instructions:
+    - group:
+        by: ['Settled']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert!(lines[1].contains(r#""BO""#), "{}", waiting);
+    assert!(lines[2].contains(r#","0001","1","#), "{}", waiting);
+    assert!(lines[3].contains(r#","0002","0","#), "{}", waiting);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,4], [0,6]], [[0,3], [0,5], [0,7]] ]));
+}
+
+
+#[test]
+fn test_boolean_mapping_rejects_unrecognised_tokens() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Settled"
+"0001","Y"
+"0002","maybe"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: boolean mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - boolean:
+            column: Settled
+            true_values: ['Y']
+            false_values: ['N']
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    assert!(format!("{:?}", err).contains(r#"UnrecognisedBoolean { column: "Settled", value: "maybe" }"#), "{:?}", err);
+}
+
+
+#[test]
+fn test_number_mapping_cleans_accounting_formats() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Amount"
+"0001","$1,234.50"
+"0002","(100.00)"
+"0003","250.00 CR"
+"0004","75.25DR"
+"0005","£ 12"
+"0006","-3.5"
+"0007",""
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: number mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - number:
+            column: Amount
+            as_a: Decimal
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert!(lines[1].ends_with(r#","DE""#), "{}", waiting);
+
+    let amounts: Vec<&str> = lines[2..].iter().map(|line| line.rsplit(',').next().unwrap()).collect();
+    assert_eq!(amounts, vec!(r#""1234.50""#, r#""-100.00""#, r#""-250.00""#, r#""75.25""#, r#""12""#, r#""-3.5""#, r#""""#));
+}
+
+
+#[test]
+fn test_number_mapping_rejects_unparseable_values() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Amount"
+"0001","$1,234"
+"0002","n/a"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: number mapping test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - number:
+            column: Amount
+            as_a: Integer
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    assert!(format!("{:?}", err).contains(r#"SchemaViolation { column: "Amount", value: "n/a", data_type: "IN" }"#), "{:?}", err);
+}
+
+
+#[test]
+fn test_date_mappings_convert_local_dates_to_utc() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","TradeDate","SettleDate","ValueDate"
+"0001","01/12/2021","2021-07-01","2021-12-01"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: date timezone test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - dmy:
+            column: TradeDate
+            timezone: Australia/Sydney
+        - ymd:
+            column: SettleDate
+            timezone: America/New_York
+        - ymd: ValueDate
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    // Sydney is UTC+11 in December, New York is UTC-4 in July.
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert!(lines[2].ends_with(r#","2021-11-30T13:00:00.000Z","2021-07-01T04:00:00.000Z","2021-12-01T00:00:00.000Z""#), "{}", waiting);
+}
+
+
+#[test]
+fn test_date_mapping_rejects_nonexistent_local_dates() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    // Sao Paulo's clocks went from midnight to 1am when daylight saving started on this date.
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","TradeDate"
+"0001","2018-11-04"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: date timezone test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_mappings:
+        - ymd:
+            column: TradeDate
+            timezone: America/Sao_Paulo
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    let err = jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap_err();
+    assert!(format!("{:?}", err).contains(r#"NonexistentLocalDate { column: "TradeDate", value: "2018-11-04", timezone: "America/Sao_Paulo" }"#), "{:?}", err);
+}
+
+
+#[test]
+fn test_column_types_override_analysis() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("inbox/"), "transactions.csv",
+r#""TransId","Account","Amount"
+"0001","1001","100.00"
+"0002","1002","50.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: column types test
+version: 1
+jetwash:
+    source_files:
+     - pattern: ^transactions\.csv$
+       column_types:
+         Account: String
+matching:
+  source_files:
+   - pattern: .*transactions.csv
+  instructions: []
+"#);
+
+    jetwash::run_charter(&charter, &base_dir, Some(1)).unwrap();
+
+    // The unlisted columns are still analysed.
+    let waiting = std::fs::read_to_string(base_dir.join("waiting/20211201_053700000_transactions.csv")).unwrap();
+    let lines: Vec<&str> = waiting.lines().collect();
+    assert_eq!(lines[1], r#""IN","ID","IN","ST","DE""#, "{}", waiting);
+}
+
+
+#[test]
+fn test_misspelt_group_by_column_fails_early() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_transactions.csv",
+r#""OpenRecStatus","TransId","Ref","Amount","Type"
+"IN","IN","ST","DE","ST"
+"0","0001","A","100.00","T1"
+"0","0002","B","100.00","T2"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: group by column test
+version: 1
+matching:
+  use_field_prefixes: false
+  source_files:
+    - pattern: .*.csv
+  instructions:
+    - project:
+        column: AbsAmount
+        as_a: Decimal
+        from: math.abs(record["Amount"])
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: Amount
+            lhs: record["Type"] == "T1"
+            rhs: record["Type"] == "T2"
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("The column REF in group-by instruction 1 doesn't exist, the available columns are AbsAmount, OpenRecStatus, TransId, Ref, Amount, Type"), "{:?}", err);
+
+    // The failure is found before any data is derived.
+    assert!(get_dir_content(base_dir.join("matching")).unwrap().files.iter().all(|file| !file.ends_with(".derived.csv")));
+}
+
+
+#[test]
+fn test_charter_column_references_validated_on_load() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","Ref","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: column reference test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+  instructions:
+    - merge:
+        into: REF
+        columns: ['INV.Ref']
+    - group:
+        by: ['Ref']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+    - merge:
+        into: AMOUNT
+        columns: ['INV.Amount']
+"#);
+
+    let err = celerity::run_charter(&charter, &base_dir).unwrap_err();
+    assert!(format!("{:?}", err).contains("Unknown column references: Ref in instruction 1 (did you mean REF?), AMOUNT in instruction 1 isn't derived until instruction 2"), "{:?}", err);
+
+    // The charter was rejected before any data was loaded.
+    assert!(base_dir.join("waiting/20211219_082900000_invoices.csv").exists());
+}
+
+
+#[test]
+fn test_rename_columns_to_group_files() {
+
+    let base_dir = common::init_test(format!("tests/{}", function!()));
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_invoices.csv",
+r#""OpenRecStatus","InvoiceRef","Amount"
+"IN","ST","DE"
+"0","A","100.00"
+"0","B","50.00"
+"#);
+
+    common::write_file(&base_dir.join("waiting/"), "20211219_082900000_payments.csv",
+r#""OpenRecStatus","Invoice No","Amount"
+"IN","ST","DE"
+"0","B","50.00"
+"0","A","100.00"
+"#);
+
+    let charter = common::write_file(&base_dir, "charter.yaml",
+r#"name: rename test
+version: 1
+matching:
+  source_files:
+    - pattern: .*invoices.csv
+      field_prefix: INV
+    - pattern: .*payments.csv
+      field_prefix: PAY
+  instructions:
+    - rename:
+        from: INV.InvoiceRef
+        to: REF
+    - rename:
+        from: PAY.Invoice No
+        to: REF
+    - merge:
+        into: AMOUNT
+        columns: ['INV.Amount', 'PAY.Amount']
+    - group:
+        by: ['REF']
+        match_when:
+        - nets_to_zero:
+            column: AMOUNT
+            lhs: record["META.prefix"] == "INV"
+            rhs: record["META.prefix"] == "PAY"
+"#);
+
+    celerity::run_charter(&charter, &base_dir).unwrap();
+
+    assert_eq!(get_dir_content(base_dir.join("unmatched")).unwrap().files.len(), 0);
+    assert_json_eq!(common::get_matched_groups(&base_dir), json!([ [[0,3], [1,4]], [[0,4], [1,3]] ]));
+}
@@ -3,7 +3,7 @@ use chrono::DateTime;
 use ubyte::ToByteUnit;
 use lazy_static::lazy_static;
 use std::{collections::HashMap, path::PathBuf, time::Instant};
-use crate::{error::JetwashError, Context, folders, csv_reader};
+use crate::{error::JetwashError, Context, folders, csv_reader, fit_to_columns, header_record, mapping, source_columns, SkipFooter};
 use core::{data_type::DataType, charter::{JetwashSourceFile, Jetwash}, blue, formatted_duration_rate};
 
 ///
@@ -87,22 +87,49 @@ pub fn analyse_and_validate(ctx: &Context, jetwash: &Jetwash) -> Result<Analysis
             let row_offset = match source_file.headers().is_some() {
                 true  => 1,
                 false => 0,
-            };
+            } + source_file.skip_header_lines();
 
             let mut rdr = csv_reader(&file.path(), source_file)?;
+            let columns = source_columns(source_file, &mut rdr)?;
+
+            // Regex mappings can change a column's type (e.g. removing thousand separators) so analyse the replaced values.
+            let regexes = mapping::compile_regexes(source_file)?;
+            let header_record = match regexes.is_empty() && source_file.column_types().is_none() {
+                true  => None,
+                false => Some(header_record(source_file, &mut rdr)?),
+            };
+
+            // Any columns with a declared type aren't analysed.
+            let declared = declared_types(source_file, &header_record);
 
-            for result in rdr.byte_records() {
+            for result in SkipFooter::new(rdr.byte_records(), source_file.skip_footer_lines()) {
                 row_count += 1;
 
+                // Reject rows which don't have a field for each column header.
+                let result = result
+                    .map_err(JetwashError::from)
+                    .and_then(|csv_record| fit_to_columns(csv_record, columns, source_file.ragged_tolerance()));
+
                 match result {
                     Ok(csv_record) => {
+                        // Fields are trimmed before any mappings, so analyse the trimmed values.
+                        let csv_record = match source_file.trim_all() {
+                            true  => csv_record.iter().map(mapping::trim_field).collect(),
+                            false => csv_record,
+                        };
+
+                        let csv_record = match &header_record {
+                            Some(header_record) => mapping::replace_regex_fields(source_file, &regexes, header_record, &csv_record),
+                            None => csv_record,
+                        };
+
                         // If this is the first row, initialise all current data-types.
                         if col_count == 0 {
                             data_types = vec![DataType::Unknown; csv_record.len()];
                         }
 
                         // Analyse the row's actual data and narrow-down what the type is.
-                        if let Err(err) = analyse_types(&mut data_types, &csv_record) {
+                        if let Err(err) = analyse_types(&mut data_types, &declared, &csv_record) {
                             log::error!("{:?}:{} {}", file.path(), row_count + row_offset, err);
                             err_count += 1;
                         }
@@ -116,9 +143,12 @@ pub fn analyse_and_validate(ctx: &Context, jetwash: &Jetwash) -> Result<Analysis
                 }
             }
 
-			// Convert Unknowns to strings.
+			// Use the declared types and convert Unknowns to strings.
 			for col_idx in 0..data_types.len() {
-				if data_types[col_idx] == DataType::Unknown {
+				if let Some((_, data_type)) = declared.get(col_idx).and_then(|declared| declared.as_ref()) {
+					data_types[col_idx] = *data_type;
+
+				} else if data_types[col_idx] == DataType::Unknown {
 					data_types[col_idx] = DataType::String;
 				}
 			}
@@ -151,19 +181,40 @@ pub fn analyse_and_validate(ctx: &Context, jetwash: &Jetwash) -> Result<Analysis
     Ok(results)
 }
 
+///
+/// The declared column header and data-type (if any) for each column in the source file.
+///
+fn declared_types(source_file: &JetwashSourceFile, header_record: &Option<csv::ByteRecord>) -> Vec<Option<(String, DataType)>> {
+	match (source_file.column_types(), header_record) {
+		(Some(column_types), Some(header_record)) => header_record.iter()
+			.skip(2 /* hardcoded headers */)
+			.map(|header| {
+				let header = String::from_utf8_lossy(header).to_string();
+				column_types.get(&header).map(|data_type| (header, *data_type))
+			})
+			.collect(),
+		_ => vec!(),
+	}
+}
+
 ///
 /// Iterate each column and deduce the cell's type - track the data-type being used for each column.
 ///
 /// The data_types passed in are the current best-guesses for the column data-types. These will be refined if required
-/// based on the current record passed in.
+/// based on the current record passed in. Columns with a declared type are only checked to hold that type.
 ///
-fn analyse_types(data_types: &mut [DataType], csv_record: &csv::ByteRecord) -> Result<(), JetwashError> {
+fn analyse_types(data_types: &mut [DataType], declared: &[Option<(String, DataType)>], csv_record: &csv::ByteRecord) -> Result<(), JetwashError> {
 
 	for (col_idx, value) in csv_record.iter().enumerate() {
 		// Ensure the value is a valid UTF8.
 		let value = std::str::from_utf8(value)?;
 
-		if !value.is_empty() {
+		if let Some((column, data_type)) = declared.get(col_idx).and_then(|declared| declared.as_ref()) {
+			if !value.is_empty() && !is_type(value, *data_type) {
+				return Err(JetwashError::SchemaViolation { column: column.clone(), value: value.to_string(), data_type: data_type.as_str().to_string() })
+			}
+
+		} else if !value.is_empty() {
 			for data_type in SEQUENCE.iter().skip(type_position(data_types[col_idx])) {
 				if is_type(value, *data_type) {
 					if is_more_general(*data_type, data_types[col_idx]) {
@@ -237,6 +288,7 @@ fn is_datetime(value: &str) -> bool {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::fit_to_columns;
 
 	#[test]
 	fn test_analyse_all_types() {
@@ -254,7 +306,7 @@ mod tests {
 		));
 		let mut data_types = vec![DataType::Unknown; record.len()];
 
-		analyse_types(&mut data_types, &record).unwrap();
+		analyse_types(&mut data_types, &[], &record).unwrap();
 		assert_eq!(vec!(
 			DataType::String,
 			DataType::Boolean,
@@ -274,7 +326,7 @@ mod tests {
 			csv::ByteRecord::from(vec!( "0", "2021-12-29T03:39:00Z", "1234567", "test" )));
 		let mut data_types = vec![DataType::Unknown; records[0].len()];
 
-		analyse_types(&mut data_types, &records[0]).unwrap();
+		analyse_types(&mut data_types, &[], &records[0]).unwrap();
 		assert_eq!(vec!(
 			DataType::Boolean,
 			DataType::Datetime,
@@ -284,7 +336,7 @@ mod tests {
 		records.push(
 			csv::ByteRecord::from(vec!( "10", "wibble", "123.4567", "2021-12-29T03:39:00Z" )));
 
-		analyse_types(&mut data_types, &records[1]).unwrap();
+		analyse_types(&mut data_types, &[], &records[1]).unwrap();
 		assert_eq!(vec!(
 			DataType::Integer,
 			DataType::String,
@@ -298,7 +350,7 @@ mod tests {
 			csv::ByteRecord::from(vec!( "10", "wibble", "123.4567", "2021-12-29T03:39:00Z" )));
 		let mut data_types = vec![DataType::Unknown; records[0].len()];
 
-		analyse_types(&mut data_types, &records[0]).unwrap();
+		analyse_types(&mut data_types, &[], &records[0]).unwrap();
 		assert_eq!(vec!(
 			DataType::Integer,
 			DataType::String,
@@ -308,7 +360,7 @@ mod tests {
 		records.push(
 			csv::ByteRecord::from(vec!( "0", "2021-12-29T03:39:00Z", "1234567", "test" )));
 
-		analyse_types(&mut data_types, &records[1]).unwrap();
+		analyse_types(&mut data_types, &[], &records[1]).unwrap();
 		assert_eq!(vec!(
 			DataType::Integer,
 			DataType::String,
@@ -324,9 +376,9 @@ mod tests {
 			csv::ByteRecord::from(vec!( "1", "2021-12-29T03:39:00Z", "7654321", "another test" )));
 		let mut data_types = vec![DataType::Unknown; records[0].len()];
 
-		analyse_types(&mut data_types, &records[0]).unwrap();
-		analyse_types(&mut data_types, &records[1]).unwrap();
-		analyse_types(&mut data_types, &records[2]).unwrap();
+		analyse_types(&mut data_types, &[], &records[0]).unwrap();
+		analyse_types(&mut data_types, &[], &records[1]).unwrap();
+		analyse_types(&mut data_types, &[], &records[2]).unwrap();
 		assert_eq!(vec!(
 			DataType::Boolean,
 			DataType::Datetime,
@@ -339,14 +391,14 @@ mod tests {
 		let record = csv::ByteRecord::from(vec!( "0", "2021-12-29T03:39:00Z", "1234567", "test" ));
 		let mut data_types = vec![DataType::Unknown; record.len()];
 
-		analyse_types(&mut data_types, &record).unwrap();
+		analyse_types(&mut data_types, &[], &record).unwrap();
 		assert_eq!(vec!(
 			DataType::Boolean,
 			DataType::Datetime,
 			DataType::Integer,
 			DataType::String), data_types, "initial types incorrect");
 
-		analyse_types(&mut data_types, &record).unwrap();
+		analyse_types(&mut data_types, &[], &record).unwrap();
 		assert_eq!(vec!(
 			DataType::Boolean,
 			DataType::Datetime,
@@ -364,10 +416,35 @@ mod tests {
 
 		let mut data_types = vec![DataType::Unknown; record.len()];
 
-		let result = analyse_types(&mut data_types, &record);
+		let result = analyse_types(&mut data_types, &[], &record);
 		match result.unwrap_err() {
     		JetwashError::Utf8Error(_) => {},
 			_ => panic!("Wrong error - expected UTF8 error"),
 		}
 	}
+
+	#[test]
+	fn test_declared_types_are_not_analysed() {
+		let record = csv::ByteRecord::from(vec!( "0", "1234567", "test" ));
+		let declared = vec!(None, Some(("Account".to_string(), DataType::String)), Some(("Notes".to_string(), DataType::Integer)));
+		let mut data_types = vec![DataType::Unknown; record.len()];
+
+		let err = analyse_types(&mut data_types, &declared, &record).unwrap_err();
+		assert_eq!(err.to_string(), "Value 'test' in colume Notes can not be coerced into a IN");
+		assert_eq!(vec!(DataType::Boolean, DataType::Unknown, DataType::Unknown), data_types);
+	}
+
+	#[test]
+	fn test_rows_must_fit_the_columns() {
+		let short = csv::ByteRecord::from(vec!("a", "b"));
+		let err = fit_to_columns(short.clone(), 3, 0).unwrap_err();
+		assert_eq!(err.to_string(), "The row has 2 fields but there are 3 column headers");
+
+		// Within tolerance, missing fields are blank and spare blank fields are dropped.
+		assert_eq!(fit_to_columns(short, 3, 1).unwrap(), csv::ByteRecord::from(vec!("a", "b", "")));
+		assert_eq!(fit_to_columns(csv::ByteRecord::from(vec!("a", "b", "c", "")), 3, 1).unwrap(), csv::ByteRecord::from(vec!("a", "b", "c")));
+
+		// Spare fields with data are never dropped.
+		assert!(fit_to_columns(csv::ByteRecord::from(vec!("a", "b", "c", "d")), 3, 1).is_err());
+	}
 }
@@ -3,7 +3,8 @@ use anyhow::Result;
 use clap::{App, Arg};
 use log::LevelFilter;
 use std::{path::Path, fs};
-use log4rs::{append::{console::ConsoleAppender, file::FileAppender}, Config, config::{Appender, Root}, encode::pattern::PatternEncoder, Handle};
+use core::logging;
+use log4rs::{append::{console::ConsoleAppender, file::FileAppender}, Config, config::{Appender, Root}, Handle};
 
 // Ref: https://docs.rs/log4rs/latest/log4rs/encode/pattern/index.html
 const CONSOLE_PATTERN: &str = "[{d(%Y-%m-%d %H:%M:%S%.3f)} {h({l:<5})}] {m}{n}";
@@ -54,11 +55,11 @@ fn init_logging(base_path: &Path) -> Handle {
 
     // Initialise logging.
     let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(CONSOLE_PATTERN)))
+        .encoder(logging::encoder(CONSOLE_PATTERN))
         .build();
 
     let log_file = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(FILE_PATTERN)))
+        .encoder(logging::encoder(FILE_PATTERN))
         .build(&log_path.join(format!("{}_jetwash.log", Utc::now().format("%Y%m%d").to_string())))
         .unwrap_or_else(|_| panic!("cannot create log file appended to {}", log_path.to_string_lossy()));
 
@@ -0,0 +1,113 @@
+use std::io::{self, Read};
+use encoding_rs::{Decoder, DecoderResult, Encoding};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+///
+/// Convert a stream of encoded bytes (e.g. windows-1252) into UTF-8 as it's read.
+///
+/// Bytes which aren't valid in the encoding are replaced with the unicode replacement character, or if strict, cause
+/// the read to fail.
+///
+pub struct Utf8Transcoder<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    strict: bool,
+    input: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
+    output: Vec<u8>,
+    output_pos: usize,
+    output_len: usize,
+    eof: bool,  // The inner reader has no more bytes.
+    done: bool, // The decoder has been flushed.
+}
+
+impl<R: Read> Utf8Transcoder<R> {
+    pub fn new(inner: R, encoding: &'static Encoding, strict: bool) -> Self {
+        Self {
+            inner,
+            decoder: encoding.new_decoder(),
+            strict,
+            input: vec![0; BUFFER_SIZE],
+            input_pos: 0,
+            input_len: 0,
+            output: vec!(),
+            output_pos: 0,
+            output_len: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    ///
+    /// Decode the next chunk of input into the output buffer.
+    ///
+    fn decode(&mut self) -> io::Result<()> {
+        if self.input_pos == self.input_len && !self.eof {
+            self.input_len = self.inner.read(&mut self.input)?;
+            self.input_pos = 0;
+            self.eof = self.input_len == 0;
+        }
+
+        let input = &self.input[self.input_pos..self.input_len];
+        let max_len = self.decoder.max_utf8_buffer_length(input.len()).unwrap_or(BUFFER_SIZE * 4);
+        self.output.resize(std::cmp::max(max_len, 4), 0);
+
+        let (result, read, written) = match self.strict {
+            true  => self.decoder.decode_to_utf8_without_replacement(input, &mut self.output, self.eof),
+            false => {
+                let (result, read, written, _replaced) = self.decoder.decode_to_utf8(input, &mut self.output, self.eof);
+                let result = match result {
+                    encoding_rs::CoderResult::InputEmpty => DecoderResult::InputEmpty,
+                    encoding_rs::CoderResult::OutputFull => DecoderResult::OutputFull,
+                };
+                (result, read, written)
+            },
+        };
+
+        if let DecoderResult::Malformed(_, _) = result {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid {} byte sequence", self.decoder.encoding().name())))
+        }
+
+        self.input_pos += read;
+        self.output_pos = 0;
+        self.output_len = written;
+        self.done = self.eof && result == DecoderResult::InputEmpty;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Utf8Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output_len {
+            if self.done {
+                return Ok(0)
+            }
+            self.decode()?;
+        }
+
+        let len = std::cmp::min(buf.len(), self.output_len - self.output_pos);
+        buf[..len].copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+        self.output_pos += len;
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_bytes_replaced_unless_strict() {
+        let mut relaxed = String::new();
+        Utf8Transcoder::new(&b"a\xFFb"[..], encoding_rs::UTF_8, false).read_to_string(&mut relaxed).unwrap();
+        assert_eq!(relaxed, "a\u{FFFD}b");
+
+        let mut strict = String::new();
+        let err = Utf8Transcoder::new(&b"a\xFFb"[..], encoding_rs::UTF_8, true).read_to_string(&mut strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -24,18 +24,33 @@ pub enum JetwashError {
     #[error("Charter contained an invalid regular expression")]
     InvalidSourceFileRegEx { source: regex::Error },
 
+    #[error("Column mapping for {column} contained an invalid regular expression")]
+    InvalidMappingRegEx { column: String, source: regex::Error },
+
+    #[error("Unable to hash a value")]
+    CannotHashValue { source: openssl::error::ErrorStack },
+
+    #[error("Charter contained an unknown encoding {encoding}")]
+    UnknownEncoding { encoding: String },
+
     #[error("Unable to move file from {path} to {destination}")]
     CannotMoveFile { path: String, destination: String, source: std::io::Error },
 
     #[error("Unable to remove file from {path}")]
     CannotRemoveFile { path: String, source: std::io::Error },
 
+    #[error("The row has {fields} fields but there are {columns} column headers")]
+    FieldCountMismatch { fields: usize, columns: usize },
+
     #[error("Encountered one or more errors in inbox files during data analysis - job aborted")]
     AnalysisErrors,
 
     #[error("Charter failed to load")]
     CharterLoadError ( #[from] core::error::Error ),
 
+    #[error(transparent)]
+    JobLockError ( core::error::Error ),
+
     #[error("A problem occured mapping a record")]
     TransformRecordError { source: rlua::Error },
 
@@ -45,6 +60,21 @@ pub enum JetwashError {
     #[error("Value '{value}' in colume {column} can not be coerced into a {data_type}")]
     SchemaViolation { column: String, value: String, data_type: String},
 
+    #[error("Column mapping for {column} has an unknown timezone {timezone}")]
+    UnknownTimezone { column: String, timezone: String },
+
+    #[error("Date '{value}' in column {column} is ambiguous in timezone {timezone}, midnight occurs twice")]
+    AmbiguousLocalDate { column: String, value: String, timezone: String },
+
+    #[error("Date '{value}' in column {column} doesn't exist in timezone {timezone}, midnight is skipped by a daylight saving change")]
+    NonexistentLocalDate { column: String, value: String, timezone: String },
+
+    #[error("Value '{value}' in column {column} is not one of the configured true or false values")]
+    UnrecognisedBoolean { column: String, value: String },
+
+    #[error("Error in the charter's global_lua, {reason}")]
+    InvalidGlobalLua { reason: String },
+
     #[error(transparent)]
     LuaError(#[from] rlua::Error),
 
@@ -3,7 +3,8 @@ use regex::Regex;
 use lazy_static::lazy_static;
 use anyhow::Context as ErrContext;
 use crate::{error::{JetwashError, here}, Context};
-use std::{fs::{self, DirEntry}, path::{Path, PathBuf}};
+use flate2::{Compression, write::GzEncoder};
+use std::{ffi::OsStr, fs::{self, DirEntry, File}, io::{BufWriter, Write}, path::{Path, PathBuf}};
 
 lazy_static! {
     static ref CHANGESET_REGEX: Regex = Regex::new(r"^(\d{8}_\d{9})_changeset\.json$").expect("bad regex for CHANGESET_REGEX");
@@ -146,13 +147,24 @@ pub fn incomplete_in_waiting(ctx: &Context) -> Result<Vec<DirEntry>, JetwashErro
 ///
 pub fn move_to_archive(ctx: &Context, path: &Path) -> Result<(), JetwashError> {
     if ctx.charter().archive_files() {
+        // Files which arrived gzipped are archived as they are.
+        let gzip = ctx.charter().archive_compress() && path.extension() != Some(OsStr::new("gz"));
+        let suffix = if gzip { ".gz" } else { "" };
         let mut destination = archive(ctx);
-        destination.push(format!("{}_{}", ctx.ts(), path.file_name().expect("filename missing from original file").to_string_lossy()));
-
-        log::debug!("Moving {:?} to {:?}", path, destination);
-
-        fs::rename(path, destination.clone())
-            .map_err(|source| JetwashError::CannotMoveFile { path: path.to_canoncial_string(), destination: destination.to_canoncial_string(), source })
+        destination.push(format!("{}_{}{}", ctx.ts(), path.file_name().expect("filename missing from original file").to_string_lossy(), suffix));
+
+        match gzip {
+            true => {
+                log::debug!("Compressing {:?} to {:?}", path, destination);
+                compress(path, &destination)
+                    .map_err(|source| JetwashError::CannotMoveFile { path: path.to_canoncial_string(), destination: destination.to_canoncial_string(), source })
+            },
+            false => {
+                log::debug!("Moving {:?} to {:?}", path, destination);
+                fs::rename(path, destination.clone())
+                    .map_err(|source| JetwashError::CannotMoveFile { path: path.to_canoncial_string(), destination: destination.to_canoncial_string(), source })
+            },
+        }
 
     } else {
         log::debug!("Removing {:?}", path);
@@ -161,6 +173,17 @@ pub fn move_to_archive(ctx: &Context, path: &Path) -> Result<(), JetwashError> {
     }
 }
 
+///
+/// Gzip the file to the destination and remove the original.
+///
+fn compress(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut reader = File::open(from)?;
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    std::io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    fs::remove_file(from)
+}
+
 ///
 /// Rename xxx.csv.inprogress to xxx.csv
 ///
@@ -228,15 +251,38 @@ pub fn new_timestamp() -> String {
 /// Generate the path to a new waiting file, given a file in the inbox folder. e.g.
 ///
 /// ./tmp/inbox/invoices.csv -> ./tmp/waiting/20211229_063800123_invoices.csv.inprogress
+/// ./tmp/inbox/invoices.txt -> ./tmp/waiting/20211229_063800123_invoices.csv.inprogress
 ///
 pub fn new_waiting_file(ctx: &Context, file: &Path) -> PathBuf {
     let mut pb = waiting(ctx);
     pb.push(format!("{ts}_{filename}.inprogress",
         ts = new_timestamp(),
-        filename = file.file_name().expect("no filename available").to_string_lossy()));
+        filename = csv_filename(&file.file_name().expect("no filename available").to_string_lossy())));
     pb
 }
 
+///
+/// Celerity only sources .csv files, so replace the original file's extension. Any compression extension is removed
+/// first, e.g. feed.csv.gz -> feed.csv, feed.txt -> feed.csv, feed -> feed.csv.
+///
+fn csv_filename(filename: &str) -> String {
+    let mut shortname = filename;
+
+    for compression in [".gz", ".zip"] {
+        if let Some(stripped) = shortname.strip_suffix(compression) {
+            shortname = stripped;
+        }
+    }
+
+    if let Some((stem, _extension)) = shortname.rsplit_once('.') {
+        if !stem.is_empty() {
+            shortname = stem;
+        }
+    }
+
+    format!("{}.csv", shortname)
+}
+
 ///
 /// Put a .failed extension on the file.
 ///
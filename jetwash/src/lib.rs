@@ -2,21 +2,25 @@ mod error;
 mod folders;
 mod mapping;
 mod analyser;
+mod encoding;
 
 use uuid::Uuid;
 use ubyte::ToByteUnit;
 use error::JetwashError;
 use itertools::Itertools;
 use analyser::AnalysisResults;
+use mapping::MappingRegexes;
 use bytes::{Bytes, BytesMut, BufMut};
 use crate::folders::ToCanoncialString;
 use anyhow::{Result, Context as ErrContext};
-use std::{time::Instant, path::{PathBuf, Path}, str::FromStr, fs::{File, self}, sync::atomic::{AtomicUsize, Ordering}};
-use core::{charter::{Charter, JetwashSourceFile, ColumnMapping}, data_type::DataType, lua::init_context, blue, formatted_duration_rate};
+use flate2::read::GzDecoder;
+use encoding_rs::Encoding;
+use encoding::Utf8Transcoder;
+use std::{time::Instant, path::{PathBuf, Path}, str::FromStr, fs::{File, self}, io::{BufRead, BufReader, Read}, collections::VecDeque, sync::atomic::{AtomicUsize, Ordering}};
+use core::{charter::{Charter, JetwashSourceFile, ColumnMapping}, data_type::DataType, lock::JobLock, lua::{init_context, set_timeout, validate_global_lua}, blue, formatted_duration_rate};
 
 // TODO: If charter doesn't exist - log the path that's failing.
 // TODO: Logging - log files moved into waiting - reduce analyser spam
-// TODO: Ensure the output file ends in .csv (even if original didn't).
 
 ///
 /// Created for each match job. Used to pass the main top-level job 'things' around.
@@ -39,6 +43,11 @@ impl Context {
             Err(_) => uuid::Uuid::new_v4(),
         };
 
+        core::logging::set_field("job_id", &job_id.to_hyphenated().to_string());
+
+        let lua = rlua::Lua::new();
+        set_timeout(&lua, charter.lua_timeout_ms());
+
         Self {
             started: Instant::now(),
             job_id,
@@ -46,7 +55,7 @@ impl Context {
             charter_path,
             base_dir,
             timestamp: folders::new_timestamp(),
-            lua: rlua::Lua::new(),
+            lua,
             uuid_provider: UuidProvider::new(uuid_seed),
         }
     }
@@ -97,6 +106,9 @@ pub fn run_charter<P: AsRef<Path>>(charter_path: P, base_dir: P, uuid_seed: Opti
         base_dir.as_ref().to_path_buf().canonicalize().with_context(|| format!("base dir {:?}", base_dir.as_ref()))?,
         uuid_seed)?;
 
+    // Prevent any other job running against the same folders until this one completes.
+    let _lock = JobLock::acquire(ctx.base_dir(), ctx.charter().stale_lock()).map_err(JetwashError::JobLockError)?;
+
     // Create inbox, archive and waiting folders (if required).
     folders::ensure_dirs_exist(&ctx)?;
 
@@ -141,6 +153,7 @@ fn wash_file(ctx: &Context, file: &Path, results: &AnalysisResults) -> Result<()
         .from_path(new_file.clone())
         .map_err(|source| JetwashError::CannotOpenCsv{ path: new_file.to_canoncial_string(), source } )?;
 
+    let columns = source_columns(result.source_file(), &mut reader)?;
     let header_record = header_record(result.source_file(), &mut reader)?;
     writer.write_byte_record(&header_record)?;
 
@@ -149,15 +162,20 @@ fn wash_file(ctx: &Context, file: &Path, results: &AnalysisResults) -> Result<()
     writer.write_record(schema.iter().map(|dt| dt.as_str()).collect::<Vec<&str>>())
         .map_err(|source| JetwashError::CannotWriteSchema{ filename: new_file.to_canoncial_string(), source })?;
 
+    let regexes = mapping::compile_regexes(result.source_file())?;
+
     // Read each row in, write to new file.
     ctx.lua().context(|lua_ctx| {
         init_context(&lua_ctx, ctx.charter().global_lua(), &folders::lookups(ctx))?;
 
-        for record_result in reader.byte_records() {
+        for record_result in SkipFooter::new(reader.byte_records(), result.source_file().skip_footer_lines()) {
             let record = record_result // Ensure we can read the record - but ignore it at this point.
                 .map_err(|source| JetwashError::CannotParseCsvRow { source, path: new_file.to_canoncial_string() })?;
 
-            let record = transform_record(ctx, &lua_ctx, result.source_file(), &header_record, &record)?; // TODO: Track lua eval context for errors....
+            // Analysis has already rejected any row which can't be fitted.
+            let record = fit_to_columns(record, columns, result.source_file().ragged_tolerance())?;
+
+            let record = transform_record(ctx, &lua_ctx, result.source_file(), &regexes, &header_record, &record)?; // TODO: Track lua eval context for errors....
 
             writer.write_byte_record(&record).map_err(|source| JetwashError::CannotWriteCsvRow {source, path: new_file.to_canoncial_string() })?;
         }
@@ -186,7 +204,7 @@ fn wash_file(ctx: &Context, file: &Path, results: &AnalysisResults) -> Result<()
 /// Ensure the internal status and id columns are added first.
 /// Ensure new columns are appended to the end.
 ///
-fn header_record(source_file: &JetwashSourceFile, reader: &mut csv::Reader<File>) -> Result<csv::ByteRecord, JetwashError> {
+fn header_record(source_file: &JetwashSourceFile, reader: &mut csv::Reader<Box<dyn Read>>) -> Result<csv::ByteRecord, JetwashError> {
     let mut header_record = csv::ByteRecord::new();
     header_record.push_field(b"OpenRecStatus");
     header_record.push_field(b"OpenRecId");
@@ -196,6 +214,9 @@ fn header_record(source_file: &JetwashSourceFile, reader: &mut csv::Reader<File>
         None => reader.byte_headers()?.iter().for_each(|f| header_record.push_field(f)),
     }
 
+    // Add any column headers created by splitting a column.
+    mapping::split_headers(source_file).for_each(|hdr| header_record.push_field(hdr.as_bytes()));
+
     // Add any column headers for Jetwash-created columns.
     if let Some(new_cols) = source_file.new_columns() {
         new_cols.iter().for_each(|nc| header_record.push_field(nc.column().as_bytes()));
@@ -204,6 +225,48 @@ fn header_record(source_file: &JetwashSourceFile, reader: &mut csv::Reader<File>
     Ok(header_record)
 }
 
+///
+/// The number of columns in the source data, from the defined column headers or those in the source csv file.
+///
+fn source_columns(source_file: &JetwashSourceFile, reader: &mut csv::Reader<Box<dyn Read>>) -> Result<usize, JetwashError> {
+    match source_file.headers() {
+        Some(headers) => Ok(headers.len()),
+        None => Ok(reader.byte_headers()?.len()),
+    }
+}
+
+///
+/// Ensure the record has exactly one field per column.
+///
+/// Up to tolerance trailing fields may be missing (they're padded with blanks) or spare (they're dropped) as long as
+/// the spare fields are blank. Otherwise the record is rejected rather than have it's fields assigned to the wrong
+/// columns.
+///
+fn fit_to_columns(record: csv::ByteRecord, columns: usize, tolerance: usize) -> Result<csv::ByteRecord, JetwashError> {
+    let fields = record.len();
+
+    if fields == columns {
+        return Ok(record)
+    }
+
+    let fits = match fields < columns {
+        true  => columns - fields <= tolerance,
+        false => fields - columns <= tolerance && record.iter().skip(columns).all(|field| field.is_empty()),
+    };
+
+    if !fits {
+        return Err(JetwashError::FieldCountMismatch { fields, columns })
+    }
+
+    let mut fitted = csv::ByteRecord::new();
+    for idx in 0..columns {
+        fitted.push_field(record.get(idx).unwrap_or_default());
+    }
+    fitted.set_position(record.position().cloned());
+
+    Ok(fitted)
+}
+
 ///
 /// Perform any column Lua script transformations on the data.
 ///
@@ -211,6 +274,7 @@ fn transform_record(
     ctx: &Context,
     lua_ctx: &rlua::Context,
     source_file: &JetwashSourceFile,
+    regexes: &MappingRegexes,
     header_record: &csv::ByteRecord,
     record: &csv::ByteRecord) -> Result<csv::ByteRecord, JetwashError> {
 
@@ -220,15 +284,20 @@ fn transform_record(
     new_record.push_field(b"0"); // OpenRecStatus - 0 = unmatched
     new_record.push_field(ctx.uuid_provider().next_record_id().to_hyphenated().to_string().as_bytes()); // OpenRecId.
 
-    // Copy each existing field into the new record - applying a mapping if there is one.
+    // Copy each existing field into the new record - applying a mapping if there is one. If every field is trimmed,
+    // that's done first so any mapping is given the trimmed value.
     for (header, value) in header_record.iter().skip(2 /* hardcoded headers */).zip(record.iter()) {
         let header = String::from_utf8_lossy(header).to_string();
+        let value = match source_file.trim_all() {
+            true  => mapping::trim_field(value),
+            false => value,
+        };
 
         match source_file.column_mappings() {
             Some(mappings) => {
                 match mappings.iter().find(|m| m.column() == header) {
                     Some(mapping) => {
-                        let new_value = mapping::map_field(lua_ctx, mapping, bytes_from_slice(value))?;
+                        let new_value = mapping::map_field(lua_ctx, mapping, regexes, bytes_from_slice(value))?;
 
                         log::trace!("Mapping row {row}, column {column} from [{from}] to [{to}]",
                             column = header,
@@ -245,6 +314,19 @@ fn transform_record(
         }
     }
 
+    // Append the columns from any split mappings.
+    for mapping in source_file.column_mappings().iter().flatten() {
+        if let ColumnMapping::Split { column, delimiter, into } = mapping {
+            let value = header_record.iter().position(|hdr| hdr == column.as_bytes())
+                .and_then(|idx| new_record.get(idx))
+                .unwrap_or_default();
+
+            for field in mapping::split_field(value, delimiter, into.len()) {
+                new_record.push_field(field.as_bytes());
+            }
+        }
+    }
+
     // Transform new columns.
     if let Some(new_columns) = source_file.new_columns() {
         lua_ctx.globals().set("record", mapping::lua_record(lua_ctx, &new_record, header_record)?)?;
@@ -313,13 +395,20 @@ fn init_job(charter: PathBuf, base_dir: PathBuf, uuid_seed: Option<usize>) -> Re
     log::info!("   Charter: {} (v{})", ctx.charter().name(), ctx.charter().version());
     log::info!("  Base dir: {}", ctx.base_dir().to_canoncial_string());
 
+    // Report any error in the global Lua now, rather than when the first record is washed.
+    validate_global_lua(ctx.charter().global_lua(), &folders::lookups(&ctx))
+        .map_err(|reason| JetwashError::InvalidGlobalLua { reason })?;
+
     Ok(ctx)
 }
 
 ///
 /// Create a CSV reader configured from the source file options ready to read the file/path specified.
 ///
-fn csv_reader(path: &Path, source_file: &JetwashSourceFile) -> Result<csv::Reader<File>, JetwashError> {
+/// Gzipped files (with a .gz suffix) are decompressed and files with a configured encoding are converted to UTF-8 as
+/// they're read.
+///
+fn csv_reader(path: &Path, source_file: &JetwashSourceFile) -> Result<csv::Reader<Box<dyn Read>>, JetwashError> {
     let escape = source_file.escape().as_ref().map(|e| e.as_bytes()[0]);
 
     let quote = match source_file.quote() {
@@ -332,13 +421,68 @@ fn csv_reader(path: &Path, source_file: &JetwashSourceFile) -> Result<csv::Reade
         None => b',',
     };
 
-    csv::ReaderBuilder::new()
+    let file = File::open(path)
+        .map_err(|source| JetwashError::CannotOpenCsv { source: source.into(), path: path.to_canoncial_string() })?;
+
+    let input: Box<dyn Read> = match path.extension().unwrap_or_default() == "gz" {
+        true  => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+
+    // Transcode any non-UTF-8 files as they're read.
+    let input: Box<dyn Read> = match source_file.encoding() {
+        Some(label) => {
+            let encoding = Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| JetwashError::UnknownEncoding { encoding: label.clone() })?;
+            Box::new(Utf8Transcoder::new(input, encoding, source_file.strict_encoding()))
+        },
+        None => input,
+    };
+
+    // Discard any banner lines before the csv data (and column headers) start.
+    let mut input = BufReader::new(input);
+    for _ in 0..source_file.skip_header_lines() {
+        input.read_until(b'\n', &mut vec!())
+            .map_err(|source| JetwashError::CannotOpenCsv { source: source.into(), path: path.to_canoncial_string() })?;
+    }
+
+    // Rows are checked against the column headers when analysed, rather than the previous row, so a ragged_tolerance
+    // can be applied.
+    Ok(csv::ReaderBuilder::new()
         .has_headers(!source_file.headers().is_some())
+        .flexible(true)
         .escape(escape)
         .quote(quote)
         .delimiter(delimiter)
-        .from_path(path)
-            .map_err(|source| JetwashError::CannotOpenCsv { source, path: path.to_canoncial_string() })
+        .from_reader(Box::new(input)))
+}
+
+///
+/// Holds back the last n records of a file, i.e. any totals or trailer rows, so they aren't analysed or washed.
+///
+/// Parse errors are held back too, as footer rows often have a different number of fields to the data rows.
+///
+pub(crate) struct SkipFooter<I: Iterator> {
+    inner: I,
+    skip: usize,
+    buffer: VecDeque<I::Item>,
+}
+
+impl<I: Iterator> SkipFooter<I> {
+    pub fn new(inner: I, skip: usize) -> Self {
+        Self { inner, skip, buffer: VecDeque::new() }
+    }
+}
+
+impl<I: Iterator> Iterator for SkipFooter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.len() <= self.skip {
+            self.buffer.push_back(self.inner.next()?);
+        }
+        self.buffer.pop_front()
+    }
 }
 
 
@@ -371,6 +515,11 @@ fn final_schema(analysed_schema: &[DataType], source_file: &JetwashSourceFile, h
                             ColumnMapping::Mdy { .. } => DataType::Datetime,
                             ColumnMapping::Ymd { .. } => DataType::Datetime,
                             ColumnMapping::Trim { .. } => *analysed_schema.get(idx).unwrap_or_else(|| panic!("no analyed type for {}", header)),
+                            ColumnMapping::Regex { .. } => *analysed_schema.get(idx).unwrap_or_else(|| panic!("no analyed type for {}", header)),
+                            ColumnMapping::Split { .. } => *analysed_schema.get(idx).unwrap_or_else(|| panic!("no analyed type for {}", header)),
+                            ColumnMapping::Hash { .. } => DataType::String,
+                            ColumnMapping::Boolean { .. } => DataType::Boolean,
+                            ColumnMapping::Number { as_a, .. } => *as_a,
                             ColumnMapping::AsBoolean{ .. }  => DataType::Boolean,
                             ColumnMapping::AsDatetime{ .. } => DataType::Datetime,
                             ColumnMapping::AsDecimal{ .. }  => DataType::Decimal,
@@ -380,6 +529,12 @@ fn final_schema(analysed_schema: &[DataType], source_file: &JetwashSourceFile, h
                     None => None,
                 };
 
+                // Split columns are always strings.
+                let mapped_type = match mapped_type {
+                    Some(mt) => Some(mt),
+                    None => mapping::split_headers(source_file).find(|hdr| **hdr == header).map(|_| DataType::String),
+                };
+
                 // If there's a new column for this header, use the as_a type.
                 let mapped_type = match mapped_type {
                     Some(mt) => Some(mt),
@@ -392,6 +547,12 @@ fn final_schema(analysed_schema: &[DataType], source_file: &JetwashSourceFile, h
                     },
                 };
 
+                // If the column's type is declared, use it even if the file had no rows to analyse.
+                let mapped_type = match mapped_type {
+                    Some(mt) => Some(mt),
+                    None => source_file.column_types().as_ref().and_then(|types| types.get(&header).copied()),
+                };
+
                 match mapped_type {
                     Some(data_type) => data_type,
                     // Otherwise, use the analysed type for the header - or Unknown if the file is empty?
@@ -1,11 +1,13 @@
 use regex::Regex;
+use std::{collections::HashMap, str::FromStr};
 use bytes::Bytes;
-use rlua::FromLuaMulti;
 use rust_decimal::Decimal;
 use lazy_static::lazy_static;
 use crate::{error::JetwashError, analyser};
-use chrono::{Utc, TimeZone, SecondsFormat};
-use core::{data_type::DataType, lua::LuaDecimal, charter::ColumnMapping};
+use chrono_tz::Tz;
+use chrono::{Utc, TimeZone, SecondsFormat, NaiveDate, LocalResult};
+use core::{data_type::{DataType, TRUE, FALSE}, lua::{eval, LuaDecimal}, charter::{ColumnMapping, DateColumn, HashAlgorithm, JetwashSourceFile}};
+use openssl::hash::MessageDigest;
 
 lazy_static! {
     static ref DATES: Vec<Regex> = vec!(
@@ -49,12 +51,98 @@ fn int_to_string(value: i64) -> String {
     format!("{}", value)
 }
 
+///
+/// The compiled patterns of a source file's regex column mappings, keyed by column.
+///
+pub type MappingRegexes = HashMap<String /* column */, Regex>;
+
+///
+/// Compile the regex column mappings once, rather than for every row in the file.
+///
+pub fn compile_regexes(source_file: &JetwashSourceFile) -> Result<MappingRegexes, JetwashError> {
+    let mut regexes = MappingRegexes::new();
+
+    for mapping in source_file.column_mappings().iter().flatten() {
+        if let ColumnMapping::Regex { column, pattern, .. } = mapping {
+            let regex = Regex::new(pattern)
+                .map_err(|source| JetwashError::InvalidMappingRegEx { column: column.clone(), source })?;
+            regexes.insert(column.clone(), regex);
+        }
+    }
+
+    Ok(regexes)
+}
+
+///
+/// Apply any regex mappings to a source record so the analyser types the values celerity will be given.
+///
+/// The header_record is in the washed form, i.e. it starts with the OpenRecStatus and OpenRecId columns.
+///
+pub fn replace_regex_fields(
+    source_file: &JetwashSourceFile,
+    regexes: &MappingRegexes,
+    header_record: &csv::ByteRecord,
+    record: &csv::ByteRecord) -> csv::ByteRecord {
+
+    let mut replaced = csv::ByteRecord::new();
+
+    for (header, value) in header_record.iter().skip(2 /* hardcoded headers */).zip(record.iter()) {
+        let mapping = source_file.column_mappings().iter().flatten()
+            .find(|m| matches!(m, ColumnMapping::Regex { .. }) && m.column().as_bytes() == header);
+
+        match (mapping, std::str::from_utf8(value)) {
+            (Some(ColumnMapping::Regex { column, replace, .. }), Ok(value)) => {
+                let regex = regexes.get(column).expect("regex not compiled");
+                replaced.push_field(regex.replace_all(value, replace.as_str()).as_bytes());
+            },
+            _ => replaced.push_field(value),
+        }
+    }
+
+    replaced
+}
+
+///
+/// The new columns created by any split mappings, in the order they're appended to the source file's columns.
+///
+pub fn split_headers(source_file: &JetwashSourceFile) -> impl Iterator<Item = &String> {
+    source_file.column_mappings().iter().flatten()
+        .filter_map(|mapping| match mapping {
+            ColumnMapping::Split { into, .. } => Some(into),
+            _ => None,
+        })
+        .flatten()
+}
+
+///
+/// Trim leading and trailing whitespace from the value, the same as a trim mapping would.
+///
+pub fn trim_field(value: &[u8]) -> &[u8] {
+    match std::str::from_utf8(value) {
+        Ok(value) => value.trim().as_bytes(),
+        Err(_) => value,
+    }
+}
+
+///
+/// Split a value into exactly the number of fields requested. Missing segments are empty and the final field is
+/// given the remainder of the value if there are more segments than fields.
+///
+pub fn split_field(value: &[u8], delimiter: &str, fields: usize) -> Vec<String> {
+    let value = String::from_utf8_lossy(value);
+    let mut split: Vec<String> = value.splitn(fields, delimiter).map(String::from).collect();
+    split.resize(fields, String::default());
+    split
+}
+
 ///
 /// Perform a column mapping on the value specified.
 ///
 /// Mappings could be raw Lua script or one of a preset help mappings, trim, dmy, etc.
 ///
-pub fn map_field(lua_ctx: &rlua::Context, mapping: &ColumnMapping, original: Bytes) -> Result<Bytes, JetwashError> {
+pub fn map_field(lua_ctx: &rlua::Context, mapping: &ColumnMapping, regexes: &MappingRegexes, original: Bytes)
+    -> Result<Bytes, JetwashError> {
+
     // Provide the original value to the Lua script as a string variable called 'value'.
     let value = String::from_utf8_lossy(&original).to_string();
 
@@ -65,41 +153,45 @@ pub fn map_field(lua_ctx: &rlua::Context, mapping: &ColumnMapping, original: Byt
             eval_typed_lua(lua_ctx, from, *as_a)?
         },
 
-        ColumnMapping::Dmy( _column ) => {
+        ColumnMapping::Dmy( column ) => {
             // If there's a value, try to parse as d/m/y, then d-m-y, then d\m\y, then d m y.
             match date_captures(&value) {
-                Some(captures) => {
-                    let dt = Utc.ymd(captures.2 as i32, captures.1, captures.0).and_hms_milli(0, 0, 0, 0);
-                    dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
-                },
+                Some(captures) => date_to_string(captures.2 as i32, captures.1, captures.0, column, &value)?,
                 None => value,
             }
         },
 
-        ColumnMapping::Mdy( _column ) => {
+        ColumnMapping::Mdy( column ) => {
             // If there's a value, try to parse as m/d/y, then m-d-y, then m\d\y, then m d y.
             match date_captures(&value) {
-                Some(captures) => {
-                    let dt = Utc.ymd(captures.2 as i32, captures.0, captures.1).and_hms_milli(0, 0, 0, 0);
-                    dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
-                },
+                Some(captures) => date_to_string(captures.2 as i32, captures.0, captures.1, column, &value)?,
                 None => value,
             }
         },
 
-        ColumnMapping::Ymd( _column ) => {
+        ColumnMapping::Ymd( column ) => {
             // If there's a value, try to parse as y/m/d, then y-m-d, then y/m/d, then y m d.
             match date_captures(&value) {
-                Some(captures) => {
-                    let dt = Utc.ymd(captures.0 as i32, captures.1, captures.2).and_hms_milli(0, 0, 0, 0);
-                    dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
-                },
+                Some(captures) => date_to_string(captures.0 as i32, captures.1, captures.2, column, &value)?,
                 None => value,
             }
         },
 
         ColumnMapping::Trim( _column ) => value.trim().to_string(),
 
+        ColumnMapping::Regex { column, replace, .. } => {
+            let regex = regexes.get(column).expect("regex not compiled");
+            regex.replace_all(&value, replace.as_str()).to_string()
+        },
+
+        ColumnMapping::Split { .. } => value, // The source column is kept, the split columns are appended to the record.
+
+        ColumnMapping::Hash { algorithm, .. } => hash(*algorithm, &original)?,
+
+        ColumnMapping::Boolean { column, true_values, false_values } => to_boolean(&value, column, true_values, false_values)?,
+
+        ColumnMapping::Number { column, as_a } => to_number(&value, column, *as_a)?,
+
         ColumnMapping::AsBoolean( column )  => check_type(&value, column, DataType::Boolean)?.to_string(),
         ColumnMapping::AsDatetime( column ) => check_type(&value, column, DataType::Datetime)?.to_string(),
         ColumnMapping::AsDecimal( column )  => check_type(&value, column, DataType::Decimal)?.to_string(),
@@ -109,6 +201,119 @@ pub fn map_field(lua_ctx: &rlua::Context, mapping: &ColumnMapping, original: Byt
     Ok(mapped.into())
 }
 
+///
+/// Convert a parsed date to an ISO8601 UTC datetime. The date is taken to be midnight in the mapping's timezone, or in UTC
+/// if it doesn't have one.
+///
+fn date_to_string(year: i32, month: u32, day: u32, column: &DateColumn, value: &str) -> Result<String, JetwashError> {
+    let timezone = match column.timezone() {
+        Some(timezone) => timezone,
+        None => return Ok(Utc.ymd(year, month, day).and_hms_milli(0, 0, 0, 0).to_rfc3339_opts(SecondsFormat::Millis, true)),
+    };
+
+    let tz = Tz::from_str(timezone)
+        .map_err(|_| JetwashError::UnknownTimezone { column: column.column().to_string(), timezone: timezone.to_string() })?;
+
+    let midnight = NaiveDate::from_ymd(year, month, day).and_hms(0, 0, 0);
+
+    match tz.from_local_datetime(&midnight) {
+        LocalResult::Single(dt) => Ok(dt.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true)),
+        LocalResult::Ambiguous(_, _) => Err(JetwashError::AmbiguousLocalDate {
+            column: column.column().to_string(),
+            value: value.to_string(),
+            timezone: timezone.to_string() }),
+        LocalResult::None => Err(JetwashError::NonexistentLocalDate {
+            column: column.column().to_string(),
+            value: value.to_string(),
+            timezone: timezone.to_string() }),
+    }
+}
+
+///
+/// The lowercase hex digest of the original bytes, empty values are left empty.
+///
+fn hash(algorithm: HashAlgorithm, original: &[u8]) -> Result<String, JetwashError> {
+    if original.is_empty() {
+        return Ok(String::default())
+    }
+
+    let digest = match algorithm {
+        HashAlgorithm::Md5    => MessageDigest::md5(),
+        HashAlgorithm::Sha256 => MessageDigest::sha256(),
+    };
+
+    let hashed = openssl::hash::hash(digest, original).map_err(|source| JetwashError::CannotHashValue { source })?;
+    Ok(hashed.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+///
+/// Convert a recognised true or false token into 1 or 0, empty values are left empty.
+///
+fn to_boolean(value: &str, column: &str, true_values: &[String], false_values: &[String]) -> Result<String, JetwashError> {
+    if value.is_empty() {
+        return Ok(String::default())
+    }
+
+    if true_values.iter().any(|token| token == value) {
+        return Ok(TRUE.to_string())
+    }
+
+    if false_values.iter().any(|token| token == value) {
+        return Ok(FALSE.to_string())
+    }
+
+    Err(JetwashError::UnrecognisedBoolean { column: column.to_string(), value: value.to_string() })
+}
+
+///
+/// Strip currency symbols and thousands separators from an amount, treating (100.00), 100.00- and 100.00 CR as negatives
+/// (DR is positive). Empty values are left empty.
+///
+fn to_number(value: &str, column: &str, as_a: DataType) -> Result<String, JetwashError> {
+    let violation = || JetwashError::SchemaViolation { column: column.to_string(), value: value.to_string(), data_type: as_a.as_str().to_string() };
+
+    let mut cleaned = value.trim().to_uppercase();
+    if cleaned.is_empty() {
+        return Ok(String::default())
+    }
+
+    let mut negative = false;
+
+    if cleaned.starts_with('(') && cleaned.ends_with(')') {
+        negative = true;
+        cleaned = cleaned[1..cleaned.len() - 1].to_string();
+
+    } else if let Some(stripped) = cleaned.strip_suffix("CR") {
+        negative = true;
+        cleaned = stripped.to_string();
+
+    } else if let Some(stripped) = cleaned.strip_suffix("DR") {
+        cleaned = stripped.to_string();
+    }
+
+    let mut cleaned = cleaned.trim().to_string();
+    if let Some(stripped) = cleaned.strip_suffix('-') {
+        negative = !negative;
+        cleaned = stripped.to_string();
+    }
+
+    // Drop currency symbols, codes, separators and spaces - leaving the digits, decimal point and any leading sign.
+    let mut digits: String = cleaned.chars().filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-').collect();
+    if let Some(stripped) = digits.strip_prefix('-') {
+        negative = !negative;
+        digits = stripped.to_string();
+    }
+
+    let number = Decimal::from_str(&digits).map_err(|_| violation())?;
+    let number = if negative && !number.is_zero() { -number } else { number };
+
+    match as_a {
+        DataType::Integer if number.fract().is_zero() => Ok(number.trunc().to_string()),
+        DataType::Decimal => Ok(number.to_string()),
+        _ => Err(violation()),
+    }
+}
+
 ///
 /// If there's a value check it can be co-erced into the type.
 ///
@@ -159,18 +364,3 @@ pub fn lua_record<'a>(lua_ctx: &rlua::Context<'a>, record: &csv::ByteRecord, hea
 
     Ok(lua_record)
 }
-
-///
-/// Run the lua script provided. Reporting the failing script if it errors.
-///
-fn eval<'lua, R: FromLuaMulti<'lua>>(lua_ctx: &rlua::Context<'lua>, lua: &str)
-    -> Result<R, rlua::Error> {
-
-    match lua_ctx.load(lua).eval::<R>() {
-        Ok(result) => Ok(result),
-        Err(err) => {
-            log::error!("Error in Lua script:\n{}\n\n{}", lua, err.to_string());
-            Err(err)
-        },
-    }
-}
\ No newline at end of file
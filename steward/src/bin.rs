@@ -1,5 +1,6 @@
 use clap::{App, Arg};
 use anyhow::Result;
+use std::io::Write;
 
 pub fn main() -> Result<()> {
 
@@ -15,14 +16,53 @@ pub fn main() -> Result<()> {
             .help("The address to a prometheus pushgateway instance used to publish metrics to, eg. 'localhost:9091'")
             .required(false)
             .takes_value(true))
+        .arg(Arg::with_name("metrics_address")
+            .long("metrics-address")
+            .help("Serve metrics for prometheus to scrape on http://<address>/metrics, eg. '0.0.0.0:9898'. Defaults to the OPENREC_METRICS_ADDRESS environment variable")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("state_file")
+            .long("state-file")
+            .help("Write the state of every control as JSON to this file whenever it changes, for external dashboards. Use '-' for stdout (headless only)")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .help("Run without the terminal UI, logging control state changes instead. SIGINT or SIGTERM stop steward once running jobs complete"))
+        .arg(Arg::with_name("once")
+            .long("once")
+            .help("Run any pending match jobs to completion then exit, with a non-zero exit code if any control is suspended"))
         .get_matches();
 
     dotenv::dotenv().ok();
-    let _ = env_logger::try_init();
+    let mut logger = env_logger::Builder::from_default_env();
+    if core::logging::json_format() {
+        logger.format(|buf, record| writeln!(buf, "{}", core::logging::json_line(record)));
+    }
+    let _ = logger.try_init();
+
+    let metrics_address = options.value_of("metrics_address")
+        .map(String::from)
+        .or_else(|| std::env::var("OPENREC_METRICS_ADDRESS").ok());
+
+    if options.is_present("once") {
+        let success = steward::run_once(
+            options.value_of("register_path").expect("no registry specified"),
+            options.value_of("pushgateway_address"),
+            options.value_of("state_file"))?;
+
+        if !success {
+            std::process::exit(1);
+        }
+        return Ok(())
+    }
 
     steward::main_loop(
         options.value_of("register_path").expect("no registry specified"),
-        options.value_of("pushgateway_address")
+        options.value_of("pushgateway_address"),
+        metrics_address.as_deref(),
+        options.value_of("state_file"),
+        options.is_present("headless")
     )?;
 
     Ok(())
@@ -43,7 +43,7 @@ pub fn init(stdout: &mut RawTerminal<StdoutLock>) {
     writeln!(stdout, r#"{}       |_| Steward: Match Job Orchistrator"#, Goto(1, 8)).unwrap();
 }
 
-pub fn display(stdout: &mut RawTerminal<StdoutLock>, state: &mut State, app_state: &AppState, mut terminal_size: (u16, u16)/* , selected: Option<usize> */) -> (u16, u16) {
+pub fn display(stdout: &mut RawTerminal<StdoutLock>, state: &mut State, app_state: &AppState, mut terminal_size: (u16, u16)) -> (u16, u16) {
 
     // If the terminal has been resized then clear it.
     terminal_size = clear_if_resized(terminal_size, stdout);
@@ -118,9 +118,17 @@ pub fn display(stdout: &mut RawTerminal<StdoutLock>, state: &mut State, app_stat
         let row = idx as u16 + BANNER_HEIGHT + 1;
         let last_width = last_width(&widths, terminal_size.0 as usize);
 
-        write!(stdout, "{pos}{name:0w_name$}{gap}{state_colour}{state:0w_state$}{reset}{gap}{duration:>0w_duration$}{gap}{unmatched:>0w_unmatched$}{gap}{inbox:>0w_inbox$}{gap}{outbox:>0w_outbox$}{gap}{usage:>0w_usage$}{gap}{messages:0w_messages$}{clear}",
+        // Highlight the selected control's name.
+        let highlight = match idx == state.selected() {
+            true  => Bg(color::Rgb(70, 70, 70)).to_string(),
+            false => String::default(),
+        };
+
+        write!(stdout, "{pos}{highlight}{name:0w_name$}{bg_reset}{gap}{state_colour}{state:0w_state$}{reset}{gap}{duration:>0w_duration$}{gap}{unmatched:>0w_unmatched$}{gap}{inbox:>0w_inbox$}{gap}{outbox:>0w_outbox$}{gap}{usage:>0w_usage$}{gap}{messages:0w_messages$}{clear}",
             pos = Goto(2, row),
             gap = " ".repeat(GAP),
+            highlight = highlight,
+            bg_reset = Bg(color::Reset),
             state_colour = Fg(state_colours[idx]),
             reset = Fg(color::Reset),
             clear = clear::UntilNewline,
@@ -161,7 +169,7 @@ fn display_shortcuts(stdout: &mut RawTerminal<StdoutLock>, terminal_size: (u16,
         AppState::Terminating => " | [F]orce quit (jobs may be left running!)",
     };
 
-    write!(stdout, "{pos}{style}[Q]uit | [R]efresh (re-load register){force}{reset}",
+    write!(stdout, "{pos}{style}[Q]uit | [R]efresh (re-load register) | [N]ext/[P]revious control | [C]lear suspension{force}{reset}",
         pos = Goto(1, terminal_size.1),
         style = Fg(color::Rgb(100, 149, 237)),
         reset = Fg(color::Reset),
@@ -230,14 +238,11 @@ fn captions(control: &mut Control) -> [String; COLUMNS.len()] {
         // Name.
         control.name().to_string(),
 
-        // State.
-        match control.state() {
-            ControlState::StartedIdle     => "Running - idle",
-            ControlState::StartedQueued   => "Running - queued",
-            ControlState::StartedMatching => "Running - matching",
-            ControlState::Stopped         => "Stopped - disabled",
-            ControlState::Suspended       => "Suspended - Errors",
-        }.into(),
+        // State - with the retry count if a failed job is being retried.
+        match (control.state(), control.attempts()) {
+            (state, (0, _)) => state_caption(state).into(),
+            (state, (attempts, max)) => format!("{} (retry {}/{})", state_caption(state), attempts, max),
+        },
 
         // Duration.
         humantime::format_duration(Duration::from_secs(control.duration().as_secs())).to_string(),
@@ -271,6 +276,16 @@ fn captions(control: &mut Control) -> [String; COLUMNS.len()] {
     ]
 }
 
+fn state_caption(state: ControlState) -> &'static str {
+    match state {
+        ControlState::StartedIdle     => "Running - idle",
+        ControlState::StartedQueued   => "Running - queued",
+        ControlState::StartedMatching => "Running - matching",
+        ControlState::Stopped         => "Stopped - disabled",
+        ControlState::Suspended       => "Suspended - Errors",
+    }
+}
+
 ///
 /// If the terminal is resized, we'll clear it so the next render doesn't leave any
 /// left-over output in the wrong place. This means we don't have to clear the terminal
@@ -1,11 +1,13 @@
 mod state;
 mod display;
 mod metrics;
+mod notify;
 mod register;
+mod snapshot;
 
 use chrono::Utc;
 use crossbeam::channel;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 use register::Register;
 use itertools::Itertools;
 use prometheus::Histogram;
@@ -16,16 +18,49 @@ use fs_extra::dir::get_dir_content;
 use std::io::{Write, stdout, Read, BufReader};
 use termion::{terminal_size, raw::IntoRawMode};
 use state::{State, JobResult, ControlState, Control, MATCH_JOB_FILENAME_REGEX};
-use std::{time::Duration, thread, path::{Path, PathBuf}, process::Command, fs};
+use std::{thread, collections::HashMap, path::{Path, PathBuf}, process::Command, fs, sync::Once};
+
+static INSTALL_SIGNAL_HANDLER: Once = Once::new();
+
+// Created in a control's root folder to reset the control when it's suspended and Steward is headless.
+const RESET_TRIGGER: &str = "steward.reset";
 
-// TODO: Jetwash and celerity should create a .lock - prohibit starting a job if exists - incase of steward hang.
-// TODO: Default steward to noop - then use --ui --headless to control start mode.
-// TODO: Headless mode with Ctrl..c graceful shtdown
 // TODO: Recover unpublished outbox files on start-up (i.e. make it safe to kill sentinal).
 
 lazy_static! {
     static ref FORCE_QUIT: Mutex<bool> = Mutex::new(false);
+    static ref TERMINATE_REQUESTS: Mutex<usize> = Mutex::new(0);
     static ref SEMAPHORE: Semaphore = Semaphore::new(num_cpus::get() as isize);
+    static ref JOB_SLOTS: JobSlots = JobSlots::default();
+    static ref CHILD_BINARIES: Mutex<Option<(PathBuf, PathBuf)>> = Mutex::new(None); // The resolved jetwash and celerity paths.
+}
+
+///
+/// The match jobs running or waiting for each control, so a control's max_concurrent limit only counts it's own jobs.
+/// The global SEMAPHORE still caps the jobs across all controls.
+///
+#[derive(Default)]
+struct JobSlots {
+    controls: Mutex<HashMap<String /* control_id */, ControlSlots>>,
+    released: Condvar,
+}
+
+///
+/// Each job takes a ticket when it arrives and jobs start in ticket order, so a waiting job is never overtaken.
+///
+#[derive(Default)]
+struct ControlSlots {
+    running: usize,
+    next_ticket: u64,
+    serving: u64,
+}
+
+///
+/// A running job's place in the JobSlots, given up when dropped.
+///
+struct JobSlot<'a> {
+    slots: &'a JobSlots,
+    control_id: String,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -35,13 +70,79 @@ pub enum AppState  {
     Terminating,
 }
 
-pub fn main_loop<P: AsRef<Path>>(register_path: P, pushgateway: Option<&str>) -> Result<()> {
+pub fn main_loop<P: AsRef<Path>>(register_path: P, pushgateway: Option<&str>, metrics_address: Option<&str>, state_file: Option<&str>, headless: bool) -> Result<()> {
 
     // Check jetwash and celerity are where we expect them.
     check_child_binaries()?;
 
+    // The terminal UI owns stdout.
+    if !headless && state_file == Some("-") {
+        bail!("The state can only be written to stdout in headless mode")
+    }
+
+    // Expose metrics for Prometheus to scrape, if required.
+    if let Some(address) = metrics_address {
+        metrics::serve(address)?;
+    }
+
     // Parse and load the register of controls into a state model.
-    let mut state = load_state(register_path.as_ref())?;
+    let state = load_state(register_path.as_ref())?;
+
+    match headless {
+        true  => headless_loop(register_path.as_ref(), pushgateway, state_file, state),
+        false => ui_loop(register_path.as_ref(), pushgateway, state_file, state),
+    }
+}
+
+///
+/// Batch mode, e.g. for CI pipelines. Scan every control's inbox once, run any pending match jobs to completion and
+/// publish their outboxes, then return. Returns false if any control is suspended.
+///
+pub fn run_once<P: AsRef<Path>>(register_path: P, pushgateway: Option<&str>, state_file: Option<&str>) -> Result<bool> {
+
+    // Check jetwash and celerity are where we expect them.
+    check_child_binaries()?;
+
+    // Parse and load the register of controls into a state model.
+    let state = load_state(register_path.as_ref())?;
+
+    Ok(once_loop(pushgateway, state_file, state))
+}
+
+fn once_loop(pushgateway: Option<&str>, state_file: Option<&str>, mut state: State) -> bool {
+
+    log::info!("Steward running pending match jobs for {} controls", state.controls().len());
+
+    loop {
+        update_controls(&mut state, AppState::Running);
+
+        metrics::publish(pushgateway, &mut state);
+        snapshot::publish(state_file, &state);
+
+        if state.controls().iter().all(|c| !c.is_busy()) {
+            break
+        }
+
+        // Shush for a bit.
+        thread::sleep(state.poll_interval());
+    }
+
+    let mut success = true;
+    for control in state.controls() {
+        if control.state() == ControlState::Suspended {
+            log::error!("Control {} is suspended {}", control.name(), control.message());
+            success = false;
+        }
+    }
+
+    success
+}
+
+///
+/// Run with the terminal UI, controlled from the keyboard.
+///
+fn ui_loop(register_path: &Path, pushgateway: Option<&str>, state_file: Option<&str>, mut state: State) -> Result<()> {
+
     let mut app_state = AppState::Running;
 
     // Initialise the terminal and input buffers.
@@ -57,39 +158,19 @@ pub fn main_loop<P: AsRef<Path>>(register_path: P, pushgateway: Option<&str>) ->
 
     // Main application loop.
     loop {
-        app_state = handle_keyboard(app_state, stdin.next());
-
-        // Stop any controls which can be stopped - if required.
-        if app_state != AppState::Running {
-            for control in state
-                .controls_mut()
-                .filter(|c| c.state() == ControlState::StartedIdle)
-                .collect::<Vec<&mut Control>>() {
-                control.stop();
-            }
-        }
+        app_state = handle_keyboard(app_state, &mut state, stdin.next());
+
+        update_controls(&mut state, app_state);
 
         // Render the controls which will fit in the terminal
         terminal_size = display::display(&mut stdout, &mut state, &app_state, terminal_size);
 
-        for control in state.controls_mut() {
-            if !control.is_running() {
-                continue
-            }
-
-            // Is a running job complete?
-            handle_job_done(control);
-
-            // Are there new files to process?
-            check_inbox(control);
-        }
-
         // Check if we can quit or reload.
         match app_state {
             AppState::Running => {},
             AppState::Reloading => {
                 if state.controls().iter().all(|c| !c.is_running()) {
-                    state = load_state(register_path.as_ref())?;
+                    state = load_state(register_path)?;
                     app_state = AppState::Running;
                 }
             },
@@ -103,10 +184,144 @@ pub fn main_loop<P: AsRef<Path>>(register_path: P, pushgateway: Option<&str>) ->
             },
         }
 
-        metrics::push(pushgateway, &mut state);
+        metrics::publish(pushgateway, &mut state);
+        snapshot::publish(state_file, &state);
+
+        // Shush for a bit.
+        thread::sleep(state.poll_interval());
+    }
+}
+
+///
+/// Run without a terminal, e.g. as a service. Control state changes are logged and SIGINT/SIGTERM start a graceful
+/// shutdown - a second signal forces the shutdown without waiting for running jobs. A suspended control is reset by
+/// creating a steward.reset file in its root folder.
+///
+fn headless_loop(register_path: &Path, pushgateway: Option<&str>, state_file: Option<&str>, mut state: State) -> Result<()> {
+
+    *TERMINATE_REQUESTS.lock() = 0;
+    INSTALL_SIGNAL_HANDLER.call_once(|| {
+        if let Err(err) = ctrlc::set_handler(request_termination) {
+            log::error!("Unable to handle termination signals: {}", err);
+        }
+    });
+
+    log::info!("Steward started in headless mode with register {}", register_path.to_string_lossy());
+
+    let mut app_state = AppState::Running;
+    let mut previous = control_states(&mut state);
+
+    loop {
+        let requests = *TERMINATE_REQUESTS.lock();
+
+        if requests > 0 && app_state != AppState::Terminating {
+            log::info!("Steward terminating once running jobs complete");
+            app_state = AppState::Terminating;
+        }
+
+        if requests > 1 {
+            *FORCE_QUIT.lock() = true;
+        }
+
+        if app_state == AppState::Running {
+            check_reset_triggers(&mut state);
+        }
+
+        update_controls(&mut state, app_state);
+
+        // Log any controls which have changed state.
+        let current = control_states(&mut state);
+        for (before, after) in previous.iter().zip(current.iter()) {
+            if before != after {
+                log::info!("Control {} is {:?} {}", after.0, after.1, after.2);
+            }
+        }
+        previous = current;
+
+        if app_state == AppState::Terminating && (*FORCE_QUIT.lock() || state.controls().iter().all(|c| !c.is_running())) {
+            log::info!("Steward terminated.");
+            return Ok(())
+        }
+
+        metrics::publish(pushgateway, &mut state);
+        snapshot::publish(state_file, &state);
 
         // Shush for a bit.
-        thread::sleep(Duration::from_millis(500));
+        thread::sleep(state.poll_interval());
+    }
+}
+
+///
+/// Called by the signal handler in headless mode.
+///
+fn request_termination() {
+    *TERMINATE_REQUESTS.lock() += 1;
+}
+
+///
+/// Reset any control with a reset trigger file in its root folder - the headless equivalent of the UI's [C]lear
+/// suspension. The trigger is removed once it's been actioned, whether or not the control was suspended.
+///
+fn check_reset_triggers(state: &mut State) {
+    for control in state.controls_mut() {
+        let trigger = control.root().join(RESET_TRIGGER);
+        if !trigger.exists() {
+            continue
+        }
+
+        if let Err(err) = fs::remove_file(&trigger) {
+            log::error!("Unable to remove the reset trigger {}: {}", trigger.to_string_lossy(), err);
+            continue
+        }
+
+        match control.reset() {
+            Ok(_) => log::info!("Control {} has been reset", control.name()),
+            Err(err) => log::warn!("Control {} can't be reset: {}", control.name(), err),
+        }
+    }
+}
+
+///
+/// The name, state and message of each control - used to detect state changes in headless mode.
+///
+fn control_states(state: &mut State) -> Vec<(String, ControlState, String)> {
+    state.controls_mut()
+        .map(|c| (c.name().to_string(), c.state(), c.message().to_string()))
+        .collect()
+}
+
+///
+/// Stop any idle controls if we're not running, otherwise progress any completed jobs and queue new ones.
+///
+fn update_controls(state: &mut State, app_state: AppState) {
+
+    // Stop any controls which can be stopped - if required.
+    if app_state != AppState::Running {
+        for control in state
+            .controls_mut()
+            .filter(|c| c.state() == ControlState::StartedIdle)
+            .collect::<Vec<&mut Control>>() {
+            control.stop();
+        }
+    }
+
+    let notify_url = state.notify_url().map(String::from);
+
+    for control in state.controls_mut() {
+        if !control.is_running() {
+            continue
+        }
+
+        // Is a running job complete?
+        handle_job_done(control, notify_url.as_deref());
+
+        // Re-run a failed job once it's backoff has elapsed.
+        if control.retry_due() {
+            control.queue_job();
+        }
+
+        // Are there new files to process?
+        check_inbox(control);
     }
 }
 
@@ -125,15 +340,22 @@ fn load_state(register_path: &Path) -> Result<State, anyhow::Error> {
 ///
 /// Process any keyboard input if approriate.
 ///
-fn handle_keyboard(app_state: AppState, key: Option<Result<u8, std::io::Error>>) -> AppState {
+fn handle_keyboard(app_state: AppState, state: &mut State, key: Option<Result<u8, std::io::Error>>) -> AppState {
     // Ignore input if we're reloading or terminating.
     if app_state == AppState::Running {
-        if let Some(Ok(b'q')) = key {
-            return AppState::Terminating;
-        }
-
-        if let Some(Ok(b'r')) = key {
-            return AppState::Reloading;
+        match key {
+            Some(Ok(b'q')) => return AppState::Terminating,
+            Some(Ok(b'r')) => return AppState::Reloading,
+            Some(Ok(b'n')) => state.select_next(),
+            Some(Ok(b'p')) => state.select_previous(),
+            Some(Ok(b'c')) => {
+                if let Some(control) = state.selected_mut() {
+                    if let Err(err) = control.reset() {
+                        control.set_message(err.to_string());
+                    }
+                }
+            },
+            _ => {},
         }
     }
 
@@ -150,33 +372,103 @@ fn handle_keyboard(app_state: AppState, key: Option<Result<u8, std::io::Error>>)
 /// Queue a match job if there are NEW files in the inbox.
 ///
 fn check_inbox(control: &mut Control) {
-    if !control.scan_inbox().is_empty() { // Push this into fn.
+    let (new_files, outside): (Vec<String>, Vec<String>) = control.scan_inbox()
+        .into_iter()
+        .partition(|file| control.in_window(file));
+
+    // Files outside the control's date window are left in the inbox.
+    for file in outside {
+        log::info!("Ignoring {} for control {} as its timestamp is outside the control's from/to dates", file, control.name());
+    }
+
+    if !new_files.is_empty() {
         control.queue_job();
     }
 }
 
 ///
-/// Ensure the jetwash binary and celerity binary are where we expect them to be.
+/// Ensure the jetwash binary and celerity binary can be found and resolve their absolute paths, so match jobs don't
+/// depend on the folder Steward was started from.
 ///
 fn check_child_binaries() -> Result<()> {
+    let jetwash = find_binary("jetwash", "JETWASH_HOME", std::env::var_os("JETWASH_HOME").map(PathBuf::from))?;
+    let celerity = find_binary("celerity", "CELERITY_HOME", std::env::var_os("CELERITY_HOME").map(PathBuf::from))?;
 
-    if !Path::new(&jetwash()).exists() {
-        bail!("The Jetwash binary '{}' is not found - you can use JETWASH_HOME to force it's location to be know", jetwash())
-    }
+    log::info!("Using jetwash {} and celerity {}", jetwash.to_string_lossy(), celerity.to_string_lossy());
+    *CHILD_BINARIES.lock() = Some((jetwash, celerity));
+    Ok(())
+}
+
+///
+/// Find the absolute path of a child binary. If its home folder is set, only that folder is tried. Otherwise the current
+/// folder, the folder Steward is in and then each folder on the PATH are tried in turn.
+///
+fn find_binary(name: &str, home_var: &str, home: Option<PathBuf>) -> Result<PathBuf> {
+    let candidates: Vec<PathBuf> = match home {
+        Some(home) => vec!(home.join(name)),
+        None => std::iter::once(PathBuf::from(".").join(name))
+            .chain(std::env::current_exe().ok().and_then(|exe| exe.parent().map(|dir| dir.join(name))))
+            .chain(std::env::var_os("PATH").iter().flat_map(std::env::split_paths).map(|dir| dir.join(name)))
+            .collect(),
+    };
 
-    if !Path::new(&celerity()).exists() {
-        bail!("The Celerity binary '{}' is not found - you can use CELERITY_HOME to force it's location to be know", celerity())
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(found) => Ok(found.canonicalize()?),
+        None => bail!("The {} binary was not found - you can use {} to set the folder it's in. Tried: {}",
+            name,
+            home_var,
+            candidates.iter().map(|candidate| candidate.to_string_lossy()).join(", ")),
     }
+}
 
-    Ok(())
+fn jetwash() -> PathBuf {
+    CHILD_BINARIES.lock().as_ref().map(|(jetwash, _)| jetwash.clone()).unwrap_or_else(|| PathBuf::from("./jetwash"))
+}
+
+fn celerity() -> PathBuf {
+    CHILD_BINARIES.lock().as_ref().map(|(_, celerity)| celerity.clone()).unwrap_or_else(|| PathBuf::from("./celerity"))
 }
 
-fn jetwash() -> String {
-    format!("{}jetwash", std::env::var("JETWASH_HOME").unwrap_or_else(|_| "./".into()))
+impl JobSlots {
+    ///
+    /// Block until every earlier job for the control has started and fewer than max_concurrent of it's jobs are
+    /// running.
+    ///
+    fn acquire(&self, control_id: &str, max_concurrent: Option<usize>) -> JobSlot<'_> {
+        let limit = max_concurrent.unwrap_or(usize::MAX);
+        let mut controls = self.controls.lock();
+
+        let control = controls.entry(control_id.to_string()).or_default();
+        let ticket = control.next_ticket;
+        control.next_ticket += 1;
+
+        loop {
+            let control = controls.get_mut(control_id).expect("control slots removed while a job was waiting");
+            if control.serving == ticket && control.running < limit {
+                control.serving += 1;
+                control.running += 1;
+                break;
+            }
+            self.released.wait(&mut controls);
+        }
+
+        // The next ticket may also be able to start.
+        self.released.notify_all();
+        JobSlot { slots: self, control_id: control_id.to_string() }
+    }
 }
 
-fn celerity() -> String {
-    format!("{}celerity", std::env::var("CELERITY_HOME").unwrap_or_else(|_| "./".into()))
+impl Drop for JobSlot<'_> {
+    fn drop(&mut self) {
+        let mut controls = self.slots.controls.lock();
+        if let Some(control) = controls.get_mut(&self.control_id) {
+            control.running -= 1;
+            if control.running == 0 && control.serving == control.next_ticket {
+                controls.remove(&self.control_id);
+            }
+        }
+        self.slots.released.notify_all();
+    }
 }
 
 ///
@@ -188,17 +480,21 @@ fn do_match_job(
     control_id: String,
     charter: PathBuf,
     root: PathBuf,
+    max_concurrent: Option<usize>,
     sender: channel::Sender<JobResult>,
     jetwash_histogram: Box<Histogram>,
     celerity_histogram: Box<Histogram>) {
 
-    // Block until capacity is available to run the job.
+    core::logging::set_field("control_id", &control_id);
+
+    // Block until the control's own limit, then the overall capacity, allow the job to run.
+    let _slot = JOB_SLOTS.acquire(&control_id, max_concurrent);
     let _guard = SEMAPHORE.access();
     let _ignored = sender.send(JobResult::Started);
 
     // JETWASH
     let _jw_timer = jetwash_histogram.start_timer();
-    match Command::new(jetwash()).arg(&charter).arg(&root).output() {
+    match Command::new(jetwash()).env(core::logging::CONTROL_ID, &control_id).arg(&charter).arg(&root).output() {
         Ok(output) => {
             if !output.status.success() {
                 let _ignore = sender.send(JobResult::new_failure(format!("{} jetwash status: {}", control_id, output.status)));
@@ -213,7 +509,7 @@ fn do_match_job(
 
     // CELERITY
     let _c_timer = celerity_histogram.start_timer();
-    match Command::new(celerity()).arg(&charter).arg(&root).output() {
+    match Command::new(celerity()).env(core::logging::CONTROL_ID, &control_id).arg(&charter).arg(&root).output() {
         Ok(output) => {
             if !output.status.success() {
                 let _ignore = sender.send(JobResult::new_failure(format!("{} celerity status: {}", control_id, output.status)));
@@ -232,7 +528,7 @@ fn do_match_job(
 ///
 /// Check if the control has completed a match job and needs state updating.
 ///
-fn handle_job_done(control: &mut Control) {
+fn handle_job_done(control: &mut Control, notify_url: Option<&str>) {
 
     // Is a running job complete?
     if let Some(callback) = control.callback() {
@@ -240,66 +536,78 @@ fn handle_job_done(control: &mut Control) {
             match result {
                 JobResult::Started => control.start(),
                 JobResult::Completed { success, message } => {
-                    control.job_done();
-
-                    if success {
-                        // Has the latest report changed?
-                        let latest = find_latest_match_file(control.root());
-                        if  latest.is_some() && (latest != *control.latest_report()) {
-                            // Package results into outbox.
-                            let latest = latest.expect("latest");
-                            let filename = latest.file_name().expect("filename").to_string_lossy().to_string();
-
-                            // Get the ts from it's name.
-                            let ts = timestamp(&latest);
-                            let out_dir = control.root().join("outbox").join(ts);
-
-                            // TODO: .inprogress on all these files until they are written.
-
-                            // Create an outbox folder.
-                            if let Err(err) = fs::create_dir_all(&out_dir) {
-                                control.suspend(&format!("Can't create outbox: {}", err));
-                                return
-                            }
-
-                            // Copy the match report into the outbox/ts/ folder.
-                            if let Err(err) = fs::copy(&latest, out_dir.join(filename)) {
-                                control.suspend(&format!("Can't copy match report: {}", err));
-                                return
-                            }
-
-                            // Copy all the unmatched files from the report into the outbox/ts folder
-                            match unmatched_filenames(&latest) {
-                                Ok(filenames) => {
-                                    for filename in filenames {
-                                        let path = control.root().join("unmatched").join(&filename);
-                                        if let Err(err) = fs::copy(&path, out_dir.join(&filename)) {
-                                            control.suspend(&format!("Can't copy unmatched file {} to outbox : {}", filename, err));
-                                            return
-                                        }
-                                    }
-                                },
-                                Err(err) => {
-                                    control.suspend(&format!("Can't find the unmatched files: {}", err));
-                                    return
-                                },
-                            }
-
-                            // Update the latest match report in the control.
-                            control.set_latest_report(latest);
-                        }
+                    control.job_done(success);
+                    complete_job(control, success, message);
+                    notify::job_done(notify_url, control, success);
+                },
+            }
+        }
+    }
+}
 
-                        control.set_message("Match job complete".into());
+///
+/// Publish a successful job's results to the outbox, or retry/suspend the control if the job failed.
+///
+fn complete_job(control: &mut Control, success: bool, message: Option<String>) {
+    if success {
+        // Has the latest report changed?
+        let latest = find_latest_match_file(control.root());
+        if  latest.is_some() && (latest != *control.latest_report()) {
+            // Package results into outbox.
+            let latest = latest.expect("latest");
+            let filename = latest.file_name().expect("filename").to_string_lossy().to_string();
+
+            // Get the ts from it's name.
+            let ts = timestamp(&latest);
+            let out_dir = control.root().join("outbox").join(ts);
+
+            // TODO: .inprogress on all these files until they are written.
+
+            // Create an outbox folder.
+            if let Err(err) = fs::create_dir_all(&out_dir) {
+                control.suspend(&format!("Can't create outbox: {}", err));
+                return
+            }
 
-                        if control.is_more() {
-                            control.queue_job();
-                        }
+            // Copy the match report into the outbox/ts/ folder.
+            if let Err(err) = fs::copy(&latest, out_dir.join(filename)) {
+                control.suspend(&format!("Can't copy match report: {}", err));
+                return
+            }
 
-                    } else {
-                        control.suspend(message.as_ref().expect("should have message"));
+            // Copy all the unmatched files from the report into the outbox/ts folder
+            match unmatched_filenames(&latest) {
+                Ok(filenames) => {
+                    for filename in filenames {
+                        // Unmatched files split by group instruction are in a sub-folder, e.g. 2/ts_invoices.unmatched.csv
+                        let path = control.root().join("unmatched").join(&filename);
+                        let dest = out_dir.join(&filename);
+                        if let Err(err) = dest.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::copy(&path, &dest)) {
+                            control.suspend(&format!("Can't copy unmatched file {} to outbox : {}", filename, err));
+                            return
+                        }
                     }
                 },
+                Err(err) => {
+                    control.suspend(&format!("Can't find the unmatched files: {}", err));
+                    return
+                },
             }
+
+            // Update the latest match report in the control.
+            control.set_latest_report(latest);
+        }
+
+        control.set_message("Match job complete".into());
+
+        if control.is_more() {
+            control.queue_job();
+        }
+
+    } else {
+        let message = message.as_ref().expect("should have message");
+        if !control.retry(message) {
+            control.suspend(message);
         }
     }
 }
@@ -361,4 +669,177 @@ pub fn timestamp(path: &Path) -> String {
 
     // Fall-back to a default timestamp.
     Utc::now().format("%Y%m%d_%H%M%S%3f").to_string()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    lazy_static! {
+        // Held by tests which run match jobs, as the child binaries they use are shared.
+        pub static ref CHILD_BINARIES_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn test_headless_loop_stops_on_termination_request() {
+        let register_path = std::env::temp_dir().join("steward_headless_register.yml");
+        fs::write(&register_path, "controls: []\n").unwrap();
+        let state = load_state(&register_path).unwrap();
+
+        // Simulate a SIGTERM shortly after starting.
+        let signal = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            request_termination();
+        });
+
+        headless_loop(&register_path, None, None, state).unwrap();
+        signal.join().unwrap();
+    }
+
+    #[test]
+    fn test_reset_trigger_resets_a_suspended_control() {
+        let root = std::env::temp_dir().join("test_reset_trigger_resets_a_suspended_control");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        // The charter doesn't exist, so the control is suspended when the state is created.
+        let register_path = root.join("register.yml");
+        fs::write(&register_path, format!("controls:\n  - charter: no-such-charter.yaml\n    root: {}\n", root.to_string_lossy())).unwrap();
+        let mut state = load_state(&register_path).unwrap();
+
+        check_reset_triggers(&mut state);
+        assert!(state.controls()[0].state() == ControlState::Suspended);
+
+        fs::write(root.join(RESET_TRIGGER), "").unwrap();
+        check_reset_triggers(&mut state);
+
+        assert!(state.controls()[0].state() == ControlState::StartedIdle);
+        assert!(!root.join(RESET_TRIGGER).exists());
+    }
+
+    #[test]
+    fn test_max_concurrent_only_counts_the_controls_own_jobs() {
+        let slots = std::sync::Arc::new(JobSlots::default());
+        let first = slots.acquire("heavy", Some(1));
+
+        // Other controls, limited or not, aren't held up by the heavy control's limit.
+        let _light = slots.acquire("light", None);
+        let _other = slots.acquire("other", Some(1));
+
+        let (s, r) = channel::unbounded();
+        let waiting = (0..2)
+            .map(|idx| {
+                let (slots, s) = (slots.clone(), s.clone());
+                let job = thread::spawn(move || {
+                    let _slot = slots.acquire("heavy", Some(1));
+                    s.send(idx).unwrap();
+                });
+                thread::sleep(Duration::from_millis(50)); // Queue the jobs in order.
+                job
+            })
+            .collect::<Vec<_>>();
+
+        // The heavy control's other jobs wait for the first, then start in the order they were queued.
+        assert!(r.recv_timeout(Duration::from_millis(200)).is_err(), "second job started alongside the first");
+        drop(first);
+        assert_eq!(r.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+        assert_eq!(r.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+
+        for job in waiting {
+            job.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_find_binary_honours_home_and_reports_locations_tried() {
+        let home = std::env::temp_dir().join("test_find_binary_honours_home");
+        fs::create_dir_all(&home).unwrap();
+        fs::write(home.join("jetwash"), "").unwrap();
+
+        // An absolute home folder is used as-is.
+        let found = find_binary("jetwash", "JETWASH_HOME", Some(home.clone())).unwrap();
+        assert_eq!(found, home.join("jetwash").canonicalize().unwrap());
+
+        // A missing binary lists where it looked.
+        let err = find_binary("celerity", "CELERITY_HOME", Some(home.clone())).unwrap_err();
+        assert_eq!(err.to_string(), format!("The celerity binary was not found - you can use CELERITY_HOME to set the folder it's in. Tried: {}",
+            home.join("celerity").to_string_lossy()));
+
+        // Without a home folder, the current folder is tried before the PATH.
+        let err = find_binary("no-such-binary", "NO_SUCH_HOME", None).unwrap_err();
+        assert!(err.to_string().contains("Tried: ./no-such-binary, "), "{}", err);
+    }
+
+    #[test]
+    fn test_once_runs_pending_jobs_and_reports_suspended_controls() {
+        let _lock = CHILD_BINARIES_LOCK.lock();
+        let charter = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/01-Basic-Match.yaml");
+
+        let run_once = |name: &str, jetwash: &str, celerity: &str| {
+            let root = std::env::temp_dir().join(name);
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("inbox")).unwrap();
+            fs::write(root.join("inbox/20211201_053700000_invoices.csv"), "").unwrap();
+
+            let register_path = root.join("register.yml");
+            fs::write(&register_path, format!("controls:\n  - charter: {}\n    root: {}\n",
+                charter.to_string_lossy(),
+                root.to_string_lossy())).unwrap();
+
+            *CHILD_BINARIES.lock() = Some((PathBuf::from(jetwash), PathBuf::from(celerity)));
+            let state = load_state(&register_path).unwrap();
+            let success = once_loop(None, None, state);
+            *CHILD_BINARIES.lock() = None;
+            success
+        };
+
+        // The pending job runs and the control remains idle.
+        assert!(run_once("test_once_succeeds", "/bin/true", "/bin/true"));
+
+        // A failed job suspends the control.
+        assert!(!run_once("test_once_fails", "/bin/true", "/bin/false"));
+    }
+
+    #[test]
+    fn test_poll_interval_is_respected() {
+        let _lock = CHILD_BINARIES_LOCK.lock();
+        let charter = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/01-Basic-Match.yaml");
+
+        // A job takes at least three iterations - queue it, see it start, then see it complete.
+        let run_job = |name: &str, poll_interval_ms: u64| {
+            let root = std::env::temp_dir().join(name);
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("inbox")).unwrap();
+            fs::write(root.join("inbox/20211201_053700000_invoices.csv"), "").unwrap();
+
+            let register_path = root.join("register.yml");
+            fs::write(&register_path, format!("poll_interval_ms: {}\ncontrols:\n  - charter: {}\n    root: {}\n",
+                poll_interval_ms,
+                charter.to_string_lossy(),
+                root.to_string_lossy())).unwrap();
+
+            *CHILD_BINARIES.lock() = Some((PathBuf::from("/bin/true"), PathBuf::from("/bin/true")));
+            let state = load_state(&register_path).unwrap();
+            assert_eq!(state.poll_interval(), Duration::from_millis(poll_interval_ms));
+
+            let started = Instant::now();
+            assert!(once_loop(None, None, state));
+            *CHILD_BINARIES.lock() = None;
+            started.elapsed()
+        };
+
+        // Faster than the three default 500ms iterations it would otherwise take.
+        let elapsed = run_job("test_poll_interval_fast", 50);
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+
+        let elapsed = run_job("test_poll_interval_slow", 400);
+        assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+
+        // Too aggressive an interval is rejected.
+        let register_path = std::env::temp_dir().join("test_poll_interval_minimum.yml");
+        fs::write(&register_path, "poll_interval_ms: 10\ncontrols: []\n").unwrap();
+        let err = load_state(&register_path).err().expect("poll interval should be rejected");
+        assert_eq!(err.to_string(), "The register's poll_interval_ms of 10 is below the minimum of 50ms");
+    }
+}
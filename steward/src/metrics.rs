@@ -1,8 +1,9 @@
+use anyhow::{Context, Result};
 use parking_lot::Mutex;
 use lazy_static::lazy_static;
-use std::{time::{Instant, Duration}, collections::HashMap};
-use prometheus::{IntGauge, register_int_gauge};
+use prometheus::{IntGauge, Encoder, TextEncoder, register_int_gauge, proto::MetricFamily};
 use crate::{state::{State, ControlState}, display};
+use std::{time::{Instant, Duration}, collections::{BTreeMap, HashMap}, io::{BufRead, BufReader, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::atomic::{AtomicBool, Ordering}, thread};
 
 lazy_static! {
     static ref CONTROLS_GAUGE: IntGauge = register_int_gauge!("controls_total", "Total number of controls in the register.").expect("cannot create controls_total gauge");
@@ -13,56 +14,217 @@ lazy_static! {
     static ref UNMATCHED_GAUGE: IntGauge = register_int_gauge!("unmatched_total", "Total number of unmatched transactions across the system").expect("cannot create unmatched_total gauge");
     static ref DISKUSAGE_GAUGE: IntGauge = register_int_gauge!("disk_usage_total", "The total amount of disk space consumed by all control data (in bytes)").expect("cannot create disk_usage_total gauge");
 
-    // Prohibit metrics being pushed to frequently.
-    static ref TIME_BARRIER: Mutex<Instant> = Mutex::new(Instant::now());
+    // Prohibit metrics being published to frequently - the first call always publishes.
+    static ref TIME_BARRIER: Mutex<Option<Instant>> = Mutex::new(None);
+
+    // The latest metrics in the Prometheus text format, served to scrapers by the embedded HTTP server.
+    static ref SCRAPE: Mutex<String> = Mutex::new(String::new());
 }
 
+static SERVING: AtomicBool = AtomicBool::new(false);
+
 ///
-/// Update the pushgateway with control metrics - only actioned every n seconds.
+/// Update the pushgateway and/or the /metrics endpoint with control metrics - only actioned every n seconds.
 ///
-pub fn push(pushgateway: Option<&str>, state: &mut State) {
+pub fn publish(pushgateway: Option<&str>, state: &mut State) {
+
+    let serving = SERVING.load(Ordering::Relaxed);
+    if pushgateway.is_none() && !serving {
+        return
+    }
+
+    let mut lock = TIME_BARRIER.lock();
+    if matches!(*lock, Some(last) if last.elapsed() <= Duration::from_secs(5)) {
+        return
+    }
+    *lock = Some(Instant::now());
+
+    update_gauges(state);
 
     if let Some(address) = pushgateway {
-        let mut lock = TIME_BARRIER.lock();
-
-        if lock.elapsed() > Duration::from_secs(5) {
-            *lock = Instant::now();
-
-            CONTROLS_GAUGE.set(state.controls().len() as i64);
-            RUNNING_GAUGE.set(state.controls().iter().filter(|cn| cn.is_running()).count() as i64);
-            MATCHING_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::StartedMatching).count() as i64);
-            DISABLED_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::Stopped).count() as i64);
-            SUSPENDED_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::Suspended).count() as i64);
-            UNMATCHED_GAUGE.set(state.controls().iter().map(|cn| cn.unmatched()).sum::<usize>() as i64);
-            DISKUSAGE_GAUGE.set(state.controls().iter().map(|cn| cn.root_len()).sum::<usize>() as i64);
-
-            let metric_families = prometheus::gather();
-
-            if let Err(err) = prometheus::push_metrics(
-                "overview",
-                prometheus::labels! { "instance".to_owned() => "OpenRec_Steward".to_owned(),},
-                address,
-                metric_families,
-                None, // Credentials.
-            ) {
-                display::error(format!("Metrics error: {}", err));
-            }
-
-            for control in state.controls_mut() {
-                let registry = control.update_metrics();
-
-                let metric_families = registry.gather();
-
-                if let Err(err) = prometheus::push_metrics(
-                    &control.name().replace("/", "_"), // '/' (in unparseable filenames) is a prohibited job character
-                    HashMap::new(),
-                    address,
-                    metric_families,
-                    None) {
-
-                    display::error(format!("Control {} metrics error: {}", control.name(), err));
+        push(address, state);
+    }
+
+    if serving {
+        *SCRAPE.lock() = encode(state);
+    }
+}
+
+///
+/// Start an HTTP server on a background thread which exposes the metrics on /metrics for Prometheus to scrape.
+///
+/// Returns the address the server is bound to.
+///
+pub fn serve(address: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address).with_context(|| format!("binding metrics server to {}", address))?;
+    let local = listener.local_addr()?;
+
+    SERVING.store(true, Ordering::Relaxed);
+    thread::spawn(move || listen(listener, &SCRAPE));
+
+    log::info!("Serving metrics on http://{}/metrics", local);
+    Ok(local)
+}
+
+fn update_gauges(state: &mut State) {
+    CONTROLS_GAUGE.set(state.controls().len() as i64);
+    RUNNING_GAUGE.set(state.controls().iter().filter(|cn| cn.is_running()).count() as i64);
+    MATCHING_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::StartedMatching).count() as i64);
+    DISABLED_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::Stopped).count() as i64);
+    SUSPENDED_GAUGE.set(state.controls().iter().filter(|cn| cn.state() == ControlState::Suspended).count() as i64);
+    UNMATCHED_GAUGE.set(state.controls().iter().map(|cn| cn.unmatched()).sum::<usize>() as i64);
+    DISKUSAGE_GAUGE.set(state.controls().iter().map(|cn| cn.root_len()).sum::<usize>() as i64);
+
+    for control in state.controls_mut() {
+        control.update_metrics();
+    }
+}
+
+fn push(address: &str, state: &State) {
+    let metric_families = prometheus::gather();
+
+    if let Err(err) = prometheus::push_metrics(
+        "overview",
+        prometheus::labels! { "instance".to_owned() => "OpenRec_Steward".to_owned(),},
+        address,
+        metric_families,
+        None, // Credentials.
+    ) {
+        display::error(format!("Metrics error: {}", err));
+    }
+
+    for control in state.controls() {
+        let metric_families = control.registry().gather();
+
+        if let Err(err) = prometheus::push_metrics(
+            &control.name().replace("/", "_"), // '/' (in unparseable filenames) is a prohibited job character
+            HashMap::new(),
+            address,
+            metric_families,
+            None) {
+
+            display::error(format!("Control {} metrics error: {}", control.name(), err));
+        }
+    }
+}
+
+///
+/// Render the overview and every control's metrics in the Prometheus text format.
+///
+/// Each control has it's own registry, so the families of the same name (distinguished by the control_name label)
+/// are merged together - the text format only allows a family to appear once.
+///
+fn encode(state: &State) -> String {
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+
+    let gathered = prometheus::gather().into_iter()
+        .chain(state.controls().iter().flat_map(|control| control.registry().gather()));
+
+    for mut family in gathered {
+        match families.get_mut(family.get_name()) {
+            Some(existing) => {
+                for metric in family.take_metric().into_iter() {
+                    existing.mut_metric().push(metric);
                 }
-            }
+            },
+            None => { families.insert(family.get_name().to_string(), family); },
+        }
+    }
+
+    let mut buffer = vec!();
+    if let Err(err) = TextEncoder::new().encode(&families.into_values().collect::<Vec<MetricFamily>>(), &mut buffer) {
+        log::error!("Unable to encode metrics: {}", err);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+///
+/// Handle each connection in turn, scrapes are infrequent and the response is pre-rendered.
+///
+fn listen(listener: TcpListener, scrape: &'static Mutex<String>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => if let Err(err) = respond(stream, scrape) {
+                log::warn!("Metrics request failed: {}", err);
+            },
+            Err(err) => log::warn!("Metrics connection failed: {}", err),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, scrape: &Mutex<String>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // Consume the headers before responding.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", scrape.lock().clone()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        prometheus::TEXT_FORMAT,
+        body.len(),
+        body)?;
+
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::register::Register;
+
+    lazy_static! {
+        static ref TEST_SCRAPE: Mutex<String> = Mutex::new(String::new());
+    }
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics_endpoint_serves_control_metrics() {
+        let dir = std::env::temp_dir().join("steward_metrics_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let register_path = dir.join("register.yml");
+        std::fs::write(&register_path, "controls:\n - charter: no-charter-a.yaml\n   root: ./tmp/no-control-a\n - charter: no-charter-b.yaml\n   root: ./tmp/no-control-b\n").unwrap();
+        let mut state = State::new(&Register::load(&register_path).unwrap(), &register_path);
+
+        update_gauges(&mut state);
+        *TEST_SCRAPE.lock() = encode(&state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || listen(listener, &TEST_SCRAPE));
+
+        let response = get(address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        for name in ["controls_total", "control_state", "control_jobs_completed", "control_jobs_failed", "control_celerity_duration"] {
+            assert!(response.contains(&format!("# TYPE {} ", name)), "{} missing from {}", name, response);
         }
+
+        // Both controls are reported under a single family.
+        assert_eq!(response.matches("# TYPE control_state ").count(), 1);
+        assert!(response.contains(r#"control_state{state="Suspended",control_name="no-charter-a.yaml"} 1"#), "{}", response);
+        assert!(response.contains(r#"control_state{state="StartedIdle",control_name="no-charter-b.yaml"} 0"#), "{}", response);
+
+        assert!(get(address, "/").starts_with("HTTP/1.1 404 Not Found"));
     }
 }
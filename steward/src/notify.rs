@@ -0,0 +1,110 @@
+use chrono::Utc;
+use serde_json::json;
+use std::{thread::{self, JoinHandle}, time::Duration};
+use crate::{state::{Control, ControlState}, unmatched_filenames};
+
+///
+/// POST a summary of a completed (or failed) match job to the register's notify_url.
+///
+/// The request is sent from a background thread so a slow or unavailable endpoint can't stall the main loop, any
+/// failure is logged and otherwise ignored.
+///
+pub fn job_done(notify_url: Option<&str>, control: &Control, success: bool) -> Option<JoinHandle<()>> {
+    let url = notify_url?.to_string();
+    let payload = payload(control, success);
+    let control_name = control.name().to_string();
+
+    Some(thread::spawn(move || {
+        let result = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .and_then(|client| client.post(&url).json(&payload).send())
+            .and_then(|response| response.error_for_status());
+
+        if let Err(err) = result {
+            log::error!("Unable to notify {} that control {} completed a job: {}", url, control_name, err);
+        }
+    }))
+}
+
+fn payload(control: &Control, success: bool) -> serde_json::Value {
+    let unmatched_files = match control.latest_report() {
+        Some(report) => unmatched_filenames(report).unwrap_or_default(),
+        None => vec!(),
+    };
+
+    json!({
+        "control": control.name(),
+        "result": if success { "success" } else { "failure" },
+        "suspended": control.state() == ControlState::Suspended,
+        "message": control.message(),
+        "timestamp": Utc::now().to_rfc3339(),
+        "unmatched_records": control.unmatched(),
+        "unmatched_files": unmatched_files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register;
+    use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener};
+
+    ///
+    /// Accept a single request and return it's body.
+    ///
+    fn mock_server() -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                if line.trim().is_empty() {
+                    break
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8(body).unwrap()
+        });
+
+        (url, server)
+    }
+
+    #[test]
+    fn test_suspended_control_notification() {
+        let (url, server) = mock_server();
+
+        let inner: register::Control = serde_yaml::from_str("name: Broken\ncharter: charter.yaml\nroot: ./tmp/no-such-control").unwrap();
+        let mut control = Control::new(&inner);
+        control.suspend("Charter is broken");
+
+        job_done(Some(&url), &control, false).expect("no notification").join().unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(payload["control"], "Broken");
+        assert_eq!(payload["result"], "failure");
+        assert_eq!(payload["suspended"], true);
+        assert_eq!(payload["unmatched_records"], 0);
+        assert_eq!(payload["unmatched_files"], json!([]));
+        assert!(payload["message"].as_str().unwrap().ends_with("Charter is broken"));
+        assert!(payload["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_no_notification_without_url() {
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control").unwrap();
+        assert!(job_done(None, &Control::new(&inner), true).is_none());
+    }
+}
@@ -1,14 +1,26 @@
+use regex::Regex;
+use chrono::NaiveDate;
 use serde::Deserialize;
 use core::charter::Charter;
-use anyhow::{Context, Result};
-use std::{path::{PathBuf, Path}, io::BufReader};
+use lazy_static::lazy_static;
+use anyhow::{Context, Result, bail};
+use std::{path::{PathBuf, Path}, io::BufReader, time::Duration};
+
+lazy_static! {
+    static ref TIMESTAMP_REGEX: Regex = Regex::new(r"^(\d{8})_\d{9}_").expect("bad regex for TIMESTAMP_REGEX");
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Register {
-    controls: Vec<Control>
+    controls: Vec<Control>,
+    notify_url: Option<String>, // A webhook to POST match job results to.
+    poll_interval_ms: Option<u64>, // How often controls' inboxes are checked, defaults to 500ms.
 }
 
+const DEFAULT_POLL_INTERVAL_MS: u64 = 500;
+const MIN_POLL_INTERVAL_MS: u64 = 50;
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Control {
@@ -20,6 +32,14 @@ pub struct Control {
     #[serde(default)]
     disabled: bool,
 
+    #[serde(default)]
+    retry: RetryPolicy,
+
+    max_concurrent: Option<usize>, // The most match jobs this control may run at once.
+
+    from: Option<NaiveDate>, // Inbox files with a timestamp prefix before this date don't queue a job.
+    to: Option<NaiveDate>,   // Inbox files with a timestamp prefix after this date don't queue a job.
+
     #[serde(skip)]
     parsed: bool,
 
@@ -27,6 +47,20 @@ pub struct Control {
     parse_err: Option<String>,
 }
 
+///
+/// How many times a failed match job is re-run before the control is suspended. The backoff is the delay before the
+/// first retry and doubles for each subsequent one.
+///
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    #[serde(default)]
+    attempts: usize,
+
+    #[serde(default = "default_backoff")]
+    backoff: String,
+}
+
 impl Register {
     pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
         let rdr = BufReader::new(std::fs::File::open(&path)
@@ -35,6 +69,21 @@ impl Register {
         let mut register: Self = serde_yaml::from_reader(rdr)
             .with_context(|| format!("parsing register {}", path.to_string_lossy()))?;
 
+        if let Some(poll_interval_ms) = register.poll_interval_ms {
+            if poll_interval_ms < MIN_POLL_INTERVAL_MS {
+                bail!("The register's poll_interval_ms of {} is below the minimum of {}ms", poll_interval_ms, MIN_POLL_INTERVAL_MS)
+            }
+        }
+
+        for control in &register.controls {
+            if control.max_concurrent == Some(0) {
+                bail!("The max_concurrent for charter {} must be at least 1", control.charter().to_string_lossy())
+            }
+
+            humantime::parse_duration(&control.retry.backoff)
+                .with_context(|| format!("parsing retry backoff for charter {}", control.charter().to_string_lossy()))?;
+        }
+
         // Attempt to parse each charter to get the control's name.
         for control in &mut register.controls {
             match Charter::load(control.charter()) {
@@ -57,6 +106,14 @@ impl Register {
     pub fn controls(&self) -> &[Control] {
         &self.controls
     }
+
+    pub fn notify_url(&self) -> Option<&str> {
+        self.notify_url.as_deref()
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.unwrap_or(DEFAULT_POLL_INTERVAL_MS))
+    }
 }
 
 impl Control {
@@ -84,10 +141,55 @@ impl Control {
         self.parsed
     }
 
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn max_concurrent(&self) -> Option<usize> {
+        self.max_concurrent
+    }
+
+    ///
+    /// True if the file's timestamp prefix, e.g. 20211201_053700000_invoices.csv, is within the control's from and to
+    /// dates (inclusive). Files without a timestamp prefix are always within the window.
+    ///
+    pub fn in_window(&self, file: &str) -> bool {
+        let filename = Path::new(file).file_name().unwrap_or_default().to_string_lossy();
+
+        match TIMESTAMP_REGEX.captures(&filename).and_then(|cap| NaiveDate::parse_from_str(&cap[1], "%Y%m%d").ok()) {
+            Some(date) => self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to),
+            None => true,
+        }
+    }
+
     pub fn parse_err(&self) -> String {
         match &self.parse_err {
             Some(err) => err.to_string(),
             None => String::default(),
         }
     }
-}
\ No newline at end of file
+}
+
+impl RetryPolicy {
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    ///
+    /// The delay before the given retry (starting at 1). The backoff is validated when the register is loaded.
+    ///
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let backoff = humantime::parse_duration(&self.backoff).unwrap_or_default();
+        backoff.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1) as u32))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { attempts: 0, backoff: default_backoff() }
+    }
+}
+
+fn default_backoff() -> String {
+    "30s".into()
+}
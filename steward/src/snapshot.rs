@@ -0,0 +1,93 @@
+use chrono::Utc;
+use serde_json::json;
+use parking_lot::Mutex;
+use lazy_static::lazy_static;
+use crate::state::{Control, State};
+use std::{fs, io::Write, path::Path};
+
+lazy_static! {
+    // The controls last written, so an unchanged state isn't written again.
+    static ref LAST_CONTROLS: Mutex<Option<serde_json::Value>> = Mutex::new(None);
+}
+
+///
+/// Write the state of every control as JSON to the state file (or stdout if it's '-') for external dashboards to
+/// consume. Only actioned if a control has changed since the last write.
+///
+/// The file is written as .inprogress and then renamed, so readers never see a partial file.
+///
+pub fn publish(state_file: Option<&str>, state: &State) {
+    let state_file = match state_file {
+        Some(state_file) => state_file,
+        None => return,
+    };
+
+    let controls = controls(state);
+
+    let mut lock = LAST_CONTROLS.lock();
+    if lock.as_ref() == Some(&controls) {
+        return
+    }
+
+    let snapshot = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "register": state.register().to_string_lossy(),
+        "controls": controls,
+    });
+
+    let result = match state_file {
+        "-" => writeln!(std::io::stdout(), "{}", snapshot),
+        path => write_file(Path::new(path), &snapshot),
+    };
+
+    match result {
+        Ok(_) => *lock = Some(controls),
+        Err(err) => log::error!("Unable to write the steward state to {}: {}", state_file, err),
+    }
+}
+
+fn write_file(path: &Path, snapshot: &serde_json::Value) -> std::io::Result<()> {
+    let inprogress = format!("{}.inprogress", path.to_string_lossy());
+    fs::write(&inprogress, serde_json::to_string_pretty(snapshot)?)?;
+    fs::rename(&inprogress, path)
+}
+
+fn controls(state: &State) -> serde_json::Value {
+    state.controls().iter().map(control).collect()
+}
+
+fn control(control: &Control) -> serde_json::Value {
+    json!({
+        "id": control.name(),
+        "state": format!("{:?}", control.state()),
+        "latest_report": control.latest_report().as_ref().map(|report| report.to_string_lossy()),
+        "message": control.message(),
+        "queue_depth": control.queue_depth(),
+        "unmatched_records": control.unmatched(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_file_contains_each_control_and_its_state() {
+        let root = std::env::temp_dir().join("test_state_file_contains_each_control");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let register_path = root.join("register.yml");
+        fs::write(&register_path, "controls:\n  - charter: no-such-charter.yaml\n    root: ./tmp/no-such-control\n").unwrap();
+        let state = State::new(&crate::register::Register::load(&register_path).unwrap(), &register_path);
+
+        let state_file = root.join("steward-state.json");
+        publish(Some(&state_file.to_string_lossy()), &state);
+
+        let snapshot: serde_json::Value = serde_json::from_str(&fs::read_to_string(&state_file).unwrap()).unwrap();
+        assert_eq!(snapshot["controls"][0]["id"], "no-such-charter.yaml");
+        assert_eq!(snapshot["controls"][0]["state"], "Suspended");
+        assert_eq!(snapshot["controls"][0]["queue_depth"], 0);
+        assert!(!root.join("steward-state.json.inprogress").exists());
+    }
+}
@@ -1,9 +1,10 @@
 use regex::Regex;
 use chrono::Local;
+use anyhow::{Result, bail};
 use crossbeam::channel;
 use lazy_static::lazy_static;
 use fs_extra::dir::get_dir_content;
-use prometheus::{Registry, Histogram, Opts, HistogramOpts, IntGauge, labels};
+use prometheus::{Registry, Histogram, Opts, HistogramOpts, IntCounter, IntGauge, IntGaugeVec, labels};
 use crate::{register::{Register, self}, do_match_job, find_latest_match_file};
 use std::{thread::JoinHandle, path::{Path, PathBuf}, slice::IterMut, fs, time::{Instant, Duration}, io::BufReader};
 
@@ -11,7 +12,7 @@ lazy_static! {
     pub static ref MATCH_JOB_FILENAME_REGEX: Regex = Regex::new(r".*(\d{8}_\d{9})_matched\.json$").expect("bad regex for FILENAME_REGEX");
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ControlState {
     StartedIdle,
     StartedQueued,
@@ -20,6 +21,14 @@ pub enum ControlState {
     Suspended,
 }
 
+const CONTROL_STATES: [ControlState; 5] = [
+    ControlState::StartedIdle,
+    ControlState::StartedQueued,
+    ControlState::StartedMatching,
+    ControlState::Stopped,
+    ControlState::Suspended,
+];
+
 pub struct Control {
     state: ControlState,
     state_changed: Instant,                // When did the state get state to it's current value.
@@ -30,12 +39,17 @@ pub struct Control {
     inbox_files: Vec<String>,              // Filenames of files we know are in the inbox.
     latest_report: Option<PathBuf>,        // The latest match report file.
     message: String,                       // A message to display next to the control.
+    attempts: usize,                       // The number of retries since the last successful job.
+    retry_at: Option<Instant>,             // When to re-run a failed job.
     metrics: ControlMetrics,
 }
 
 pub struct State {
     register: PathBuf,
+    notify_url: Option<String>,
+    poll_interval: Duration,
     controls: Vec<Control>,
+    selected: usize, // The control highlighted in the display.
 }
 
 #[derive(PartialEq)]
@@ -54,10 +68,13 @@ pub struct ControlMetrics {
     outbox_usage_bytes: Box<IntGauge>,
     jetwash_duration: Box<Histogram>,
     celerity_duration: Box<Histogram>,
+    state: Box<IntGaugeVec>,
+    jobs_completed: Box<IntCounter>,
+    jobs_failed: Box<IntCounter>,
 }
 
 impl Control {
-    fn new(c: &register::Control) -> Self {
+    pub fn new(c: &register::Control) -> Self {
         let latest_match_file = find_latest_match_file(c.root());
 
         // Suspend un-parseable controls, unless they are already disabled.
@@ -83,6 +100,8 @@ impl Control {
             } else {
                 c.parse_err()
             },
+            attempts: 0,
+            retry_at: None,
             metrics: ControlMetrics::new(c.name(), &latest_match_file),
         }
     }
@@ -111,6 +130,60 @@ impl Control {
         self.state = ControlState::Suspended;
     }
 
+    ///
+    /// Clear a suspended control's error and return it to idle. Any files already in the inbox will be
+    /// seen as new on the next scan, so a match job is queued to pick them up.
+    ///
+    pub fn reset(&mut self) -> Result<()> {
+        if self.state != ControlState::Suspended {
+            bail!("Only suspended controls can be reset")
+        }
+
+        self.message = String::default();
+        self.attempts = 0;
+        self.inbox_files.clear();
+        self.state_changed = Instant::now();
+        self.state = ControlState::StartedIdle;
+        Ok(())
+    }
+
+    ///
+    /// Schedule a failed job to be re-run if the control's retry policy allows it, otherwise return false.
+    ///
+    pub fn retry(&mut self, msg: &str) -> bool {
+        let policy = self.inner.retry();
+        if self.attempts >= policy.attempts() {
+            return false
+        }
+
+        self.attempts += 1;
+        let delay = policy.delay(self.attempts);
+        self.retry_at = Some(Instant::now() + delay);
+        self.set_message(format!("Retrying in {} : {}", humantime::format_duration(delay), msg));
+        true
+    }
+
+    ///
+    /// True if a failed job is waiting to be re-run and it's backoff has elapsed.
+    ///
+    pub fn retry_due(&self) -> bool {
+        self.is_running() && matches!(self.retry_at, Some(retry_at) if Instant::now() >= retry_at)
+    }
+
+    ///
+    /// The number of retries since the last successful job and the maximum allowed.
+    ///
+    pub fn attempts(&self) -> (usize, usize) {
+        (self.attempts, self.inner.retry().attempts())
+    }
+
+    ///
+    /// True if the control has a match job running or queued, or a failed job waiting to be retried.
+    ///
+    pub fn is_busy(&self) -> bool {
+        self.is_running() && (self.job.is_some() || self.queued || self.retry_at.is_some())
+    }
+
     pub fn is_running(&self) -> bool {
         match self.state {
             ControlState::StartedIdle     => true,
@@ -125,7 +198,7 @@ impl Control {
         self.state_changed.elapsed()
     }
 
-    pub fn update_metrics(&mut self) -> &Registry {
+    pub fn update_metrics(&mut self) {
         // The job_done handler will keep the matched/unmatched counts up to date.
 
         // Use the disk scrape fns below to do this.
@@ -133,6 +206,13 @@ impl Control {
         self.metrics.outbox_usage_bytes.set(self.outbox_len() as i64);
         self.metrics.disk_usage_bytes.set(self.root_len() as i64);
 
+        // One series per state, only the current state is set.
+        for state in CONTROL_STATES {
+            self.metrics.state.with_label_values(&[&format!("{:?}", state)]).set((state == self.state) as i64);
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
         &self.metrics.registry
     }
 
@@ -148,6 +228,10 @@ impl Control {
         self.inner.root()
     }
 
+    pub fn in_window(&self, file: &str) -> bool {
+        self.inner.in_window(file)
+    }
+
     pub fn latest_report(&self) -> &Option<PathBuf> {
         &self.latest_report
     }
@@ -180,11 +264,18 @@ impl Control {
         self.queued
     }
 
+    ///
+    /// The number of match jobs running or waiting to run for this control.
+    ///
+    pub fn queue_depth(&self) -> usize {
+        self.job.is_some() as usize + self.queued as usize
+    }
+
     pub fn set_message(&mut self, msg: String) {
         self.message = format!("[{}] {}", Local::now().format("%a %T"), msg) // e.g. SUN 12:45:12
     }
 
-    pub fn message(&mut self) -> &str {
+    pub fn message(&self) -> &str {
         &self.message
     }
 
@@ -244,6 +335,7 @@ impl Control {
     ///
     pub fn queue_job(&mut self) {
         self.set_message("Running match job".into());
+        self.retry_at = None;
         match self.job() {
             Some(_) => self.queued = true, // Queue the job. Note this is not the same as being in a
                                            // queued state - it means we have a follow-up job to run
@@ -255,10 +347,11 @@ impl Control {
                 let root = self.root().to_path_buf();
                 let jetwash_histogram = self.metrics.jetwash_duration.clone();
                 let celerity_histogram = self.metrics.celerity_duration.clone();
+                let max_concurrent = self.inner.max_concurrent();
                 self.state = ControlState::StartedQueued;
                 self.callback = Some(r);
                 self.queued = false;
-                self.job = Some(std::thread::spawn(|| do_match_job(control_name, charter, root, s, jetwash_histogram, celerity_histogram)))
+                self.job = Some(std::thread::spawn(move || do_match_job(control_name, charter, root, max_concurrent, s, jetwash_histogram, celerity_histogram)))
             },
         }
     }
@@ -266,7 +359,15 @@ impl Control {
     ///
     /// Mark the control as idle - after a job has completed.
     ///
-    pub fn job_done(&mut self) {
+    pub fn job_done(&mut self, success: bool) {
+        match success {
+            true  => {
+                self.metrics.jobs_completed.inc();
+                self.attempts = 0;
+            },
+            false => self.metrics.jobs_failed.inc(),
+        }
+
         self.state_changed = Instant::now();
         self.state = ControlState::StartedIdle;
         self.callback = None;
@@ -314,6 +415,9 @@ impl State {
         Self {
             controls,
             register: path.to_path_buf(),
+            notify_url: register.notify_url().map(String::from),
+            poll_interval: register.poll_interval(),
+            selected: 0,
         }
     }
 
@@ -321,6 +425,14 @@ impl State {
         &self.register
     }
 
+    pub fn notify_url(&self) -> Option<&str> {
+        self.notify_url.as_deref()
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
     pub fn controls(&self) -> &[Control] {
         &self.controls
     }
@@ -328,6 +440,24 @@ impl State {
     pub fn controls_mut(&mut self) -> IterMut<'_, Control> {
         self.controls.iter_mut()
     }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.controls.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn selected_mut(&mut self) -> Option<&mut Control> {
+        self.controls.get_mut(self.selected)
+    }
 }
 
 
@@ -359,6 +489,9 @@ impl ControlMetrics {
             matched_groups: Box::new(IntGauge::with_opts(Opts::new("matched_groups", "the number of matched groups this control currently has")).expect("bad opts")),
             jetwash_duration: Box::new(Histogram::with_opts(HistogramOpts::new("jetwash_duration", "the duration of the jetwash phase of a match job")).expect("bad opts")),
             celerity_duration: Box::new(Histogram::with_opts(HistogramOpts::new("celerity_duration", "the duration of the celerity phase of a match job")).expect("bad opts")),
+            state: Box::new(IntGaugeVec::new(Opts::new("state", "1 for the control's current state, 0 for the others"), &["state"]).expect("bad opts")),
+            jobs_completed: Box::new(IntCounter::with_opts(Opts::new("jobs_completed", "the number of match jobs which have completed successfully")).expect("bad opts")),
+            jobs_failed: Box::new(IntCounter::with_opts(Opts::new("jobs_failed", "the number of match jobs which have failed")).expect("bad opts")),
         };
 
         me.registry.register(me.unmatched_txs.clone()).expect("bad metric");
@@ -369,6 +502,9 @@ impl ControlMetrics {
         me.registry.register(me.matched_groups.clone()).expect("bad metric");
         me.registry.register(me.jetwash_duration.clone()).expect("bad metric");
         me.registry.register(me.celerity_duration.clone()).expect("bad metric");
+        me.registry.register(me.state.clone()).expect("bad metric");
+        me.registry.register(me.jobs_completed.clone()).expect("bad metric");
+        me.registry.register(me.jobs_failed.clone()).expect("bad metric");
 
         // Get the latest match report statistics if available.
         if let Some(match_report) = latest_match_file {
@@ -397,4 +533,148 @@ fn get_json_report_footer(matched_json: &Path) -> Option<serde_json::Value> {
     }
 
     None
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suspended_control() -> Control {
+        // Suspend the control as a failed match job would.
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control").unwrap();
+        let mut control = Control::new(&inner);
+        control.suspend("Charter is broken");
+        control
+    }
+
+    #[test]
+    fn test_reset_suspended_control_returns_to_idle() {
+        let mut control = suspended_control();
+        assert!(control.state() == ControlState::Suspended);
+
+        control.reset().unwrap();
+        assert!(control.state() == ControlState::StartedIdle);
+        assert!(control.is_running());
+        assert_eq!(control.message(), "");
+    }
+
+    #[test]
+    fn test_only_suspended_controls_can_be_reset() {
+        let mut control = suspended_control();
+        control.stop();
+
+        assert!(control.reset().is_err());
+        assert!(control.state() == ControlState::Stopped);
+    }
+
+    #[test]
+    fn test_second_job_waits_for_the_running_job() {
+        let _lock = crate::tests::CHILD_BINARIES_LOCK.lock();
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control").unwrap();
+        let mut control = Control::new(&inner);
+
+        control.queue_job();
+        assert!(control.job().is_some());
+        assert!(!control.is_more());
+
+        // Queuing again while the job is running flags a follow-up job rather than starting another.
+        control.queue_job();
+        assert!(control.is_more());
+
+        let callback = control.callback().clone().expect("no callback");
+        control.job.take().expect("no job").join().unwrap();
+
+        // Only the one job ran, it's results are a start and a completion (it fails, there's no jetwash here).
+        let results = callback.iter().collect::<Vec<JobResult>>();
+        assert_eq!(results.len(), 2);
+        assert!(results[0] == JobResult::Started);
+        assert!(matches!(results[1], JobResult::Completed { success: false, .. }));
+    }
+
+    #[test]
+    fn test_job_waits_while_the_control_is_at_its_max_concurrent() {
+        let _lock = crate::tests::CHILD_BINARIES_LOCK.lock();
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control\nmax_concurrent: 1").unwrap();
+        let mut control = Control::new(&inner);
+
+        // Another job for this control already holds it's only slot.
+        let slot = crate::JOB_SLOTS.acquire(control.name(), Some(1));
+
+        control.queue_job();
+        let callback = control.callback().clone().expect("no callback");
+        assert!(callback.recv_timeout(std::time::Duration::from_millis(200)).is_err(), "job started with the control at it's limit");
+
+        // Once the other job finishes, the queued job runs.
+        drop(slot);
+        control.job.take().expect("no job").join().unwrap();
+        let results = callback.iter().collect::<Vec<JobResult>>();
+        assert!(results[0] == JobResult::Started);
+        assert!(matches!(results[1], JobResult::Completed { success: false, .. }));
+    }
+
+    ///
+    /// Feed a job's results to the control as if a job thread had run.
+    ///
+    fn simulate_job(control: &mut Control, result: JobResult) {
+        let (s, r) = channel::unbounded();
+        control.callback = Some(r);
+        s.send(JobResult::Started).unwrap();
+        s.send(result).unwrap();
+        crate::handle_job_done(control, None);
+        crate::handle_job_done(control, None);
+    }
+
+    #[test]
+    fn test_failed_job_is_retried_before_suspending() {
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control\nretry:\n  attempts: 2\n  backoff: 0s").unwrap();
+        let mut control = Control::new(&inner);
+        control.reset().unwrap();
+
+        // A transient failure schedules a retry rather than suspending.
+        simulate_job(&mut control, JobResult::new_failure("locked".into()));
+        assert!(control.state() == ControlState::StartedIdle);
+        assert_eq!(control.attempts(), (1, 2));
+        assert!(control.retry_due());
+
+        // The retry succeeds and the count is reset.
+        simulate_job(&mut control, JobResult::new_success());
+        assert!(control.state() == ControlState::StartedIdle);
+        assert_eq!(control.attempts(), (0, 2));
+
+        // Once the retries are exhausted the control is suspended.
+        for _ in 0..3 {
+            simulate_job(&mut control, JobResult::new_failure("broken".into()));
+        }
+        assert!(control.state() == ControlState::Suspended);
+        assert!(!control.retry_due());
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles() {
+        let inner: register::Control = serde_yaml::from_str("charter: charter.yaml\nroot: ./tmp/no-such-control\nretry:\n  attempts: 3\n  backoff: 10s").unwrap();
+        assert_eq!(inner.retry().delay(1), Duration::from_secs(10));
+        assert_eq!(inner.retry().delay(3), Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_inbox_file_outside_window_doesnt_queue_a_job() {
+        let root = std::env::temp_dir().join("test_inbox_file_outside_window_doesnt_queue_a_job");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("inbox")).unwrap();
+        fs::write(root.join("inbox/20211130_053700000_invoices.csv"), "").unwrap();
+
+        let register = format!("charter: charter.yaml\nroot: {}\nfrom: 2021-12-01\nto: 2021-12-31", root.to_string_lossy());
+        let inner: register::Control = serde_yaml::from_str(&register).unwrap();
+        let mut control = Control::new(&inner);
+
+        // The back-dated file is left in the inbox without queuing a job.
+        crate::check_inbox(&mut control);
+        assert!(control.job().is_none());
+        assert!(root.join("inbox/20211130_053700000_invoices.csv").exists());
+
+        assert!(control.in_window("20211201_000000000_invoices.csv"));
+        assert!(control.in_window("20211231_235959999_invoices.csv"));
+        assert!(!control.in_window("20220101_000000000_invoices.csv"));
+        assert!(control.in_window("invoices.csv"));
+    }
+}